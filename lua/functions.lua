@@ -1,10 +1,22 @@
 #!lua name=turbodiesel
 
+-- Bump whenever the functions change. load_redis_functions only replaces a
+-- loaded library with an older version, so a newer version must keep every
+-- function and argument that older deployments still call.
+local TD_VERSION = 7
+
+local function td_version(keys, args)
+  return TD_VERSION
+end
+
+redis.register_function{function_name='td_version', callback=td_version, flags={'no-writes'}}
+
 local function td_set(keys, args)
   local key = keys[1]
   local value = args[1]
   local input_sec = tonumber(args[2])
   local input_nsec = tonumber(args[3])
+  local ttl_ms = tonumber(args[4]) or 0
 
   local invalidate_ts = redis.call("HMGET", key, 'inv_sec', 'inv_nsec')
   local inv_sec = tonumber(invalidate_ts[1]) or 0
@@ -14,28 +26,212 @@ local function td_set(keys, args)
     return 0 -- Skipped (data might be stale)
   else
     redis.call("HSET", key, 'ts_sec', input_sec, 'ts_nsec', input_nsec, 'v', value)
+    -- A new value starts with fresh read statistics, see td_get_tracked.
+    redis.call("HDEL", key, 'hits', 'at_sec', 'at_nsec')
+    -- A value without a TTL replaces any earlier expiry, including the
+    -- tombstone's.
+    if ttl_ms > 0 then
+      redis.call("PEXPIRE", key, ttl_ms)
+    else
+      redis.call("PERSIST", key)
+    end
     return 1
   end
 end
 
 redis.register_function('td_set', td_set)
 
+-- Returns the position of the first non-whitespace character of text at or
+-- after pos.
+local function skip_ws(text, pos)
+  return string.find(text, "[^ \t\r\n]", pos) or #text + 1
+end
+
+-- Returns the position just past the JSON string starting at pos.
+local function skip_string(text, pos)
+  local i = pos + 1
+  while true do
+    local c = string.find(text, '["\\]', i)
+    if not c then
+      return nil
+    end
+    if string.sub(text, c, c) == '"' then
+      return c + 1
+    end
+    i = c + 2 -- Skip the escaped character
+  end
+end
+
+-- Returns the position just past the JSON value starting at pos, or nil if
+-- there is none.
+local function skip_value(text, pos)
+  local c = string.sub(text, pos, pos)
+  if c == '"' then
+    return skip_string(text, pos)
+  end
+  if c == '{' or c == '[' then
+    local depth = 0
+    local i = pos
+    while true do
+      local j = string.find(text, '[%[%]{}"]', i)
+      if not j then
+        return nil
+      end
+      local d = string.sub(text, j, j)
+      if d == '"' then
+        i = skip_string(text, j)
+        if not i then
+          return nil
+        end
+      else
+        depth = depth + ((d == '{' or d == '[') and 1 or -1)
+        i = j + 1
+        if depth == 0 then
+          return i
+        end
+      end
+    end
+  end
+  -- A number, true, false or null runs up to the next delimiter.
+  local stop = string.find(text, "[,}%] \t\r\n]", pos) or #text + 1
+  if stop == pos then
+    return nil
+  end
+  return stop
+end
+
+-- Finds the value the JSON Pointer designates in the JSON text, without
+-- decoding it. Returns its first position and the position just past it,
+-- or nil if the text has no such value.
+local function find_pointer(text, pointer)
+  local first = skip_ws(text, 1)
+  if pointer ~= "" and string.sub(pointer, 1, 1) ~= "/" then
+    return nil
+  end
+  for token in string.gmatch(string.sub(pointer, 2) .. "/", "([^/]*)/") do
+    if pointer == "" then
+      break
+    end
+    token = string.gsub((string.gsub(token, "~1", "/")), "~0", "~")
+    local c = string.sub(text, first, first)
+    local found = nil
+    if c == '{' then
+      local i = skip_ws(text, first + 1)
+      while string.sub(text, i, i) == '"' do
+        local name_end = skip_string(text, i)
+        if not name_end then
+          return nil
+        end
+        local name = cjson.decode(string.sub(text, i, name_end - 1))
+        local colon = skip_ws(text, name_end)
+        if string.sub(text, colon, colon) ~= ':' then
+          return nil
+        end
+        local value_start = skip_ws(text, colon + 1)
+        if name == token then
+          found = value_start
+          break
+        end
+        local value_end = skip_value(text, value_start)
+        if not value_end then
+          return nil
+        end
+        i = skip_ws(text, value_end)
+        if string.sub(text, i, i) ~= ',' then
+          break
+        end
+        i = skip_ws(text, i + 1)
+      end
+    elseif c == '[' and string.match(token, "^%d+$") and (token == "0" or string.sub(token, 1, 1) ~= "0") then
+      local i = skip_ws(text, first + 1)
+      if string.sub(text, i, i) ~= ']' then
+        found = i
+        for _ = 1, tonumber(token) do
+          local value_end = skip_value(text, found)
+          if not value_end then
+            return nil
+          end
+          local comma = skip_ws(text, value_end)
+          if string.sub(text, comma, comma) ~= ',' then
+            found = nil
+            break
+          end
+          found = skip_ws(text, comma + 1)
+        end
+      end
+    end
+    if not found then
+      return nil
+    end
+    first = found
+  end
+  local last = skip_value(text, first)
+  if not last then
+    return nil
+  end
+  return first, last
+end
+
+-- Replaces the value at the JSON Pointer args[1] in the JSON value of a key
+-- with the JSON text args[2], leaving the rest of the text and the expiry
+-- untouched. Like td_set, the patch is stamped with the time in args[3] and
+-- args[4], and is rejected if the key was invalidated after that time.
+local function td_patch(keys, args)
+  local key = keys[1]
+  local pointer = args[1]
+  local patch = args[2]
+  local input_sec = tonumber(args[3])
+  local input_nsec = tonumber(args[4])
+
+  local record = redis.call("HMGET", key, 'ts_sec', 'ts_nsec', 'inv_sec', 'inv_nsec', 'v')
+  if not record[5] then
+    return 0 -- Not in cache
+  end
+  local ts_sec = tonumber(record[1]) or 0
+  local ts_nsec = tonumber(record[2]) or 0
+  local inv_sec = tonumber(record[3]) or 0
+  local inv_nsec = tonumber(record[4]) or 0
+  if ts_sec < inv_sec or (ts_sec == inv_sec and ts_nsec < inv_nsec) then
+    return 0 -- Invalidated
+  end
+  if input_sec < inv_sec or (input_sec == inv_sec and input_nsec < inv_nsec) then
+    return 0 -- Skipped (the patch predates the invalidation)
+  end
+
+  local value = record[5]
+  local first, last = find_pointer(value, pointer)
+  if not first then
+    return redis.error_reply("No JSON value at pointer " .. pointer)
+  end
+  redis.call("HSET", key, 'v', string.sub(value, 1, first - 1) .. patch .. string.sub(value, last))
+  return 1
+end
+
+redis.register_function('td_patch', td_patch)
+
 local function td_invalidate(keys, args)
   local key = keys[1]
   local input_sec = tonumber(args[1])
   local input_nsec = tonumber(args[2])
+  -- How long the invalidation keeps out writes stamped before it. Callers
+  -- from before it was configurable do not pass it.
+  local marker_ms = tonumber(args[3]) or 120000
 
-  local invalidate_ts = redis.call("HMGET", key, 'inv_sec', 'inv_nsec')
-  local inv_sec = tonumber(invalidate_ts[1]) or 0
-  local inv_nsec = tonumber(invalidate_ts[2]) or 0
+  local record = redis.call("HMGET", key, 'ts_sec', 'ts_nsec', 'inv_sec', 'inv_nsec', 'v')
+  local ts_sec = tonumber(record[1]) or 0
+  local ts_nsec = tonumber(record[2]) or 0
+  local inv_sec = tonumber(record[3]) or 0
+  local inv_nsec = tonumber(record[4]) or 0
 
   if input_sec < inv_sec or (input_sec == inv_sec and input_nsec < inv_nsec) then
     return 0 -- Skipped (existing invalidation is newer than the one requested)
-  else
-    redis.call("HSET", key, 'inv_sec', input_sec, 'inv_nsec', input_nsec)
-    redis.call("EXPIRE", key, 120)
-    return 1
   end
+  redis.call("HSET", key, 'inv_sec', input_sec, 'inv_nsec', input_nsec)
+  redis.call("PEXPIRE", key, marker_ms)
+  if record[5] and not (ts_sec < inv_sec or (ts_sec == inv_sec and ts_nsec < inv_nsec)) then
+    return 1 -- A live value was invalidated
+  end
+  return 0 -- Nothing was cached
 end
 
 redis.register_function('td_invalidate', td_invalidate)
@@ -60,4 +256,111 @@ local function td_get(keys, args)
   end
 end
 
-redis.register_function('td_get', td_get)
+redis.register_function{function_name='td_get', callback=td_get, flags={'no-writes'}}
+
+local function td_get_with_ttl(keys, args)
+  local value = td_get(keys, args)
+  if value == nil then
+    return nil
+  end
+  return {value, redis.call("PTTL", keys[1])}
+end
+
+redis.register_function{function_name='td_get_with_ttl', callback=td_get_with_ttl, flags={'no-writes'}}
+
+-- Counts a read of the live value of a key, at the time given in args.
+local function track_read(key, args)
+  redis.call("HINCRBY", key, 'hits', 1)
+  redis.call("HSET", key, 'at_sec', args[1], 'at_nsec', args[2])
+end
+
+local function td_get_tracked(keys, args)
+  local value = td_get(keys, args)
+  -- td_get reports a miss as false, from HMGET, rather than nil.
+  if value then
+    track_read(keys[1], args)
+  end
+  return value
+end
+
+redis.register_function('td_get_tracked', td_get_tracked)
+
+local function td_get_with_ttl_tracked(keys, args)
+  local result = td_get_with_ttl(keys, args)
+  if result and result[1] then
+    track_read(keys[1], args)
+  end
+  return result
+end
+
+redis.register_function('td_get_with_ttl_tracked', td_get_with_ttl_tracked)
+
+local function td_entry_info(keys, args)
+  if not td_get(keys, args) then
+    return nil
+  end
+  return redis.call("HMGET", keys[1], 'ts_sec', 'ts_nsec', 'at_sec', 'at_nsec', 'hits')
+end
+
+redis.register_function{function_name='td_entry_info', callback=td_entry_info, flags={'no-writes'}}
+
+local function td_exists(keys, args)
+  local key = keys[1]
+
+  local record = redis.call("HMGET", key, 'ts_sec', 'ts_nsec', 'inv_sec', 'inv_nsec')
+  if redis.call("HEXISTS", key, 'v') == 0 then
+    return 0 -- Not in cache
+  end
+  local ts_sec = tonumber(record[1]) or 0
+  local ts_nsec = tonumber(record[2]) or 0
+  local inv_sec = tonumber(record[3]) or 0
+  local inv_nsec = tonumber(record[4]) or 0
+
+  if ts_sec < inv_sec or (ts_sec == inv_sec and ts_nsec < inv_nsec) then
+    return 0 -- invalidated
+  else
+    return 1
+  end
+end
+
+redis.register_function{function_name='td_exists', callback=td_exists, flags={'no-writes'}}
+
+local function td_setnx(keys, args)
+  if td_exists(keys, {}) == 1 then
+    return 0 -- A live value is already cached
+  end
+  return td_set(keys, args)
+end
+
+redis.register_function('td_setnx', td_setnx)
+
+-- Takes the lock for the owner token in args[2], which td_unlock checks.
+-- Callers from before locks had owners do not pass it.
+local function td_lock(keys, args)
+  local key = keys[1]
+  local ttl_ms = tonumber(args[1])
+  local token = args[2] or '1'
+
+  if redis.call("EXISTS", key) == 1 then
+    return 0 -- Already locked
+  end
+  -- Stored as a hash without a value so that td_get treats it as a miss.
+  redis.call("HSET", key, 'lock', token)
+  redis.call("PEXPIRE", key, ttl_ms)
+  return 1
+end
+
+redis.register_function('td_lock', td_lock)
+
+-- Releases the lock only if the token in args[1] still owns it, so a holder
+-- whose lock expired does not release the next holder's.
+local function td_unlock(keys, args)
+  local key = keys[1]
+  if redis.call("HGET", key, 'lock') == args[1] then
+    redis.call("DEL", key)
+    return 1
+  end
+  return 0
+end
+
+redis.register_function('td_unlock', td_unlock)
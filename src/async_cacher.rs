@@ -0,0 +1,103 @@
+use crate::cacher::{CacheError, CacheHandle, HashmapCacheHandle};
+use crate::serializer::Serializer;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::future::Future;
+use std::time::Duration;
+
+/// Asynchronous counterpart of `CacheHandle`.
+///
+/// Implementations must not block the executor while talking to the cache
+/// backend, which lets the caching layer run on async stacks such as
+/// `diesel-async` with tokio.
+pub trait AsyncCacheHandle: Clone + Send + Sync {
+    fn get<V: Serialize + DeserializeOwned + Send>(
+        &self,
+        key: &str,
+    ) -> impl Future<Output = Result<Option<V>, CacheError>> + Send;
+    /// Looks up several keys at once. The returned values line up with `keys`.
+    fn get_multi<V: Serialize + DeserializeOwned + Send>(
+        &self,
+        keys: &[String],
+    ) -> impl Future<Output = Result<Vec<Option<V>>, CacheError>> + Send;
+    fn put<V: Serialize + DeserializeOwned + Sync>(
+        &mut self,
+        key: &str,
+        value: &V,
+    ) -> impl Future<Output = Result<(), CacheError>> + Send;
+    /// Stores a value that expires after `ttl`, after which `get` treats it as a miss.
+    fn put_with_ttl<V: Serialize + DeserializeOwned + Sync>(
+        &mut self,
+        key: &str,
+        value: &V,
+        ttl: Duration,
+    ) -> impl Future<Output = Result<(), CacheError>> + Send;
+    fn delete(&mut self, key: &str) -> impl Future<Output = Result<(), CacheError>> + Send;
+}
+
+/// The in-memory cache never blocks on I/O, so its async interface simply
+/// runs the synchronous operations.
+impl<S: Serializer> AsyncCacheHandle for HashmapCacheHandle<S> {
+    async fn get<V: Serialize + DeserializeOwned + Send>(
+        &self,
+        key: &str,
+    ) -> Result<Option<V>, CacheError> {
+        CacheHandle::get(self, &key.to_string())
+    }
+
+    async fn get_multi<V: Serialize + DeserializeOwned + Send>(
+        &self,
+        keys: &[String],
+    ) -> Result<Vec<Option<V>>, CacheError> {
+        CacheHandle::get_multi(self, keys)
+    }
+
+    async fn put<V: Serialize + DeserializeOwned + Sync>(
+        &mut self,
+        key: &str,
+        value: &V,
+    ) -> Result<(), CacheError> {
+        CacheHandle::put(self, &key.to_string(), value)
+    }
+
+    async fn put_with_ttl<V: Serialize + DeserializeOwned + Sync>(
+        &mut self,
+        key: &str,
+        value: &V,
+        ttl: Duration,
+    ) -> Result<(), CacheError> {
+        CacheHandle::put_with_ttl(self, &key.to_string(), value, ttl)
+    }
+
+    async fn delete(&mut self, key: &str) -> Result<(), CacheError> {
+        CacheHandle::delete(self, &key.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cacher::HashmapCache;
+
+    #[tokio::test]
+    async fn test_hashmap_async_put_and_get() {
+        let cache = HashmapCache::new();
+        let mut handle = cache.handle();
+
+        AsyncCacheHandle::put(&mut handle, "async_key", &"async_value".to_string())
+            .await
+            .expect("Failed to put value into cache");
+        let value: Option<String> = AsyncCacheHandle::get(&handle, "async_key")
+            .await
+            .expect("Failed to get value from cache");
+        assert_eq!(value, Some("async_value".to_string()));
+
+        AsyncCacheHandle::delete(&mut handle, "async_key")
+            .await
+            .expect("Failed to delete key from cache");
+        let value: Option<String> = AsyncCacheHandle::get(&handle, "async_key")
+            .await
+            .expect("Failed to get value from cache");
+        assert_eq!(value, None);
+    }
+}
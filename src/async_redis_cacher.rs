@@ -0,0 +1,240 @@
+use crate::async_cacher::AsyncCacheHandle;
+use crate::cache_key::KeyFormat;
+use crate::cacher::CacheError;
+use crate::redis_cacher::{DEFAULT_TOMBSTONE_TTL, RedisCacheHandle, redis_error};
+use crate::serializer::{JsonSerializer, Serializer};
+use log::debug;
+use redis::aio::MultiplexedConnection;
+use serde::de::DeserializeOwned;
+use serde::ser::Serialize;
+use std::time::{Duration, SystemTime};
+
+/// Async Redis cache handle backed by a `redis::aio::MultiplexedConnection`.
+///
+/// Uses the same `td_get`/`td_set`/`td_invalidate` functions as
+/// `RedisCacheHandle`, so both handles can operate on the same keys.
+#[derive(Clone)]
+pub struct AsyncRedisCacheHandle<S: Serializer = JsonSerializer> {
+    con: MultiplexedConnection,
+    serializer: S,
+    namespace: Option<String>,
+    key_format: KeyFormat,
+    tombstone_ttl: Duration,
+    invalidation_channel: Option<String>,
+}
+
+impl AsyncRedisCacheHandle {
+    pub fn new(con: MultiplexedConnection) -> Self {
+        Self::with_serializer(con, JsonSerializer)
+    }
+}
+
+impl<S: Serializer> AsyncRedisCacheHandle<S> {
+    pub fn with_serializer(con: MultiplexedConnection, serializer: S) -> Self {
+        AsyncRedisCacheHandle {
+            con,
+            serializer,
+            namespace: None,
+            key_format: KeyFormat::default(),
+            tombstone_ttl: DEFAULT_TOMBSTONE_TTL,
+            invalidation_channel: None,
+        }
+    }
+
+    pub(crate) fn with_namespace(
+        mut self,
+        namespace: Option<String>,
+        key_format: KeyFormat,
+    ) -> Self {
+        self.namespace = namespace;
+        self.key_format = key_format;
+        self
+    }
+
+    /// Sets how long invalidations keep out older writes; see
+    /// `RedisCache::with_tombstone_ttl`.
+    pub fn with_tombstone_ttl(mut self, ttl: Duration) -> Self {
+        self.tombstone_ttl = ttl;
+        self
+    }
+
+    /// Sets the pub/sub channel on which invalidated keys are published.
+    pub(crate) fn with_invalidation_channel(mut self, channel: Option<String>) -> Self {
+        self.invalidation_channel = channel;
+        self
+    }
+
+    fn qualify(&self, key: &str) -> String {
+        self.key_format.qualify(self.namespace.as_deref(), key)
+    }
+
+    async fn set<V: Serialize>(
+        &mut self,
+        key: &str,
+        value: &V,
+        ttl: Option<Duration>,
+    ) -> Result<(), CacheError> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_err(|e| CacheError::with_cause("Failed to get current time", e))?;
+        let serialized = self.serializer.serialize(value)?;
+        let response: redis::Value = redis::cmd("FCALL")
+            .arg("td_set")
+            .arg(1)
+            .arg(self.qualify(key))
+            .arg(serialized)
+            .arg(now.as_secs())
+            .arg(now.subsec_nanos())
+            .arg(ttl.map_or(0, |ttl| ttl.as_millis()))
+            .query_async(&mut self.con)
+            .await
+            .map_err(|e| redis_error("Failed to call Redis td_set function", e))?;
+        debug!("Response from Redis td_set function call: {:?}", response);
+        Ok(())
+    }
+}
+
+impl<S: Serializer> AsyncCacheHandle for AsyncRedisCacheHandle<S> {
+    async fn get<V: Serialize + DeserializeOwned + Send>(
+        &self,
+        key: &str,
+    ) -> Result<Option<V>, CacheError> {
+        let mut con = self.con.clone();
+        let response: redis::Value = redis::cmd("FCALL")
+            .arg("td_get")
+            .arg(1)
+            .arg(self.qualify(key))
+            .query_async(&mut con)
+            .await
+            .map_err(|e| redis_error("Failed to call Redis td_get function", e))?;
+        debug!("Response from Redis td_get function call: {:?}", response);
+        RedisCacheHandle::decode_value(&self.serializer, response)
+    }
+
+    async fn get_multi<V: Serialize + DeserializeOwned + Send>(
+        &self,
+        keys: &[String],
+    ) -> Result<Vec<Option<V>>, CacheError> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut con = self.con.clone();
+        let mut pipe = redis::pipe();
+        for key in keys {
+            pipe.cmd("FCALL")
+                .arg("td_get")
+                .arg(1)
+                .arg(self.qualify(key));
+        }
+        let responses: Vec<redis::Value> = pipe
+            .query_async(&mut con)
+            .await
+            .map_err(|e| redis_error("Failed to call Redis td_get function", e))?;
+        debug!(
+            "Responses from pipelined Redis td_get function calls: {:?}",
+            responses
+        );
+        responses
+            .into_iter()
+            .map(|value| RedisCacheHandle::decode_value(&self.serializer, value))
+            .collect()
+    }
+
+    async fn put<V: Serialize + DeserializeOwned + Sync>(
+        &mut self,
+        key: &str,
+        value: &V,
+    ) -> Result<(), CacheError> {
+        self.set(key, value, None).await
+    }
+
+    async fn put_with_ttl<V: Serialize + DeserializeOwned + Sync>(
+        &mut self,
+        key: &str,
+        value: &V,
+        ttl: Duration,
+    ) -> Result<(), CacheError> {
+        self.set(key, value, Some(ttl)).await
+    }
+
+    async fn delete(&mut self, key: &str) -> Result<(), CacheError> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_err(|e| CacheError::with_cause("Failed to get current time", e))?;
+        let response: redis::Value = redis::cmd("FCALL")
+            .arg("td_invalidate")
+            .arg(1)
+            .arg(self.qualify(key))
+            .arg(now.as_secs())
+            .arg(now.subsec_nanos())
+            .arg(self.tombstone_ttl.as_millis())
+            .query_async(&mut self.con)
+            .await
+            .map_err(|e| redis_error("Failed to call Redis td_invalidate function", e))?;
+        debug!(
+            "Response from Redis td_invalidate function call: {:?}",
+            response
+        );
+        if let Some(channel) = &self.invalidation_channel {
+            redis::cmd("PUBLISH")
+                .arg(channel)
+                .arg(key)
+                .query_async::<()>(&mut self.con)
+                .await
+                .map_err(|e| redis_error("Failed to publish invalidations", e))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::redis_cacher::RedisCache;
+    use crate::redis_test_util::RedisTestUtil;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_async_redis_get_and_set() {
+        let redis_test = RedisTestUtil::new();
+        redis_test
+            .run_test_with_redis(async move |redis_url, _| {
+                let cache =
+                    RedisCache::new(redis_url.as_str()).expect("Failed to create RedisCache");
+                let mut handle = cache
+                    .async_handle()
+                    .await
+                    .expect("Failed to create async handle");
+
+                let key = "async_test_key".to_string();
+                let value = "async_test_value".to_string();
+
+                handle
+                    .put(&key, &value)
+                    .await
+                    .expect("Failed to put value into cache");
+                let retrieved_value: Option<String> = handle
+                    .get(&key)
+                    .await
+                    .expect("Failed to get value from cache");
+                assert_eq!(retrieved_value, Some(value.clone()));
+
+                let multi_values: Vec<Option<String>> = handle
+                    .get_multi(&[key.clone(), "missing_key".to_string()])
+                    .await
+                    .expect("Failed to get values from cache");
+                assert_eq!(multi_values, vec![Some(value), None]);
+
+                handle
+                    .delete(&key)
+                    .await
+                    .expect("Failed to delete key from cache");
+                let empty: Option<String> = handle
+                    .get(&key)
+                    .await
+                    .expect("Failed to get value from cache");
+                assert_eq!(empty, None);
+            })
+            .await;
+    }
+}
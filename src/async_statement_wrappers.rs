@@ -0,0 +1,315 @@
+use crate::async_cacher::AsyncCacheHandle;
+use crate::cache_key::{IntoCacheKey, SelectedCacheKey};
+use crate::cacher::CacheError;
+use diesel::query_builder::SelectStatement;
+use diesel::result::QueryResult;
+use diesel_async::AsyncConnection;
+use diesel_async::methods::LoadQuery;
+use futures_util::future::{BoxFuture, FutureExt, TryFutureExt};
+use futures_util::stream::{self, BoxStream, Stream, StreamExt};
+use log::{debug, warn};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::time::Duration;
+
+/// Async wrapper for a Diesel select query that populates the cache as results
+/// are streamed from the database.
+///
+/// Returned by `populate_cache_async` and `populate_cache_with_ttl_async`.
+pub struct AsyncSelectCachingWrapper<T, C>
+where
+    C: AsyncCacheHandle,
+{
+    inner_select: T,
+    cache: C,
+    ttl: Option<Duration>,
+}
+
+impl<T, C> AsyncSelectCachingWrapper<T, C>
+where
+    C: AsyncCacheHandle,
+{
+    fn new(inner_select: T, cache: C, ttl: Option<Duration>) -> Self {
+        Self {
+            inner_select,
+            cache,
+            ttl,
+        }
+    }
+}
+
+impl<T, Conn, U, C> LoadQuery<'static, Conn, U> for AsyncSelectCachingWrapper<T, C>
+where
+    T: LoadQuery<'static, Conn, (U, SelectedCacheKey)> + 'static,
+    Conn: AsyncConnection,
+    U: Serialize + DeserializeOwned + std::fmt::Debug + Send + Sync + 'static,
+    C: AsyncCacheHandle + 'static,
+{
+    type LoadFuture<'conn>
+        = BoxFuture<'conn, QueryResult<Self::Stream<'conn>>>
+    where
+        Conn: 'conn;
+
+    type Stream<'conn>
+        = BoxStream<'conn, QueryResult<U>>
+    where
+        Conn: 'conn;
+
+    fn internal_load(self, conn: &mut Conn) -> Self::LoadFuture<'_> {
+        debug!("In AsyncSelectCachingWrapper internal_load");
+
+        let cache = self.cache;
+        let ttl = self.ttl;
+        self.inner_select
+            .internal_load(conn)
+            .map_ok(move |rows| {
+                rows.then(move |item| {
+                    let mut cache = cache.clone();
+                    async move {
+                        let (row, key) = item?;
+                        let Some(key) = key.0 else {
+                            warn!("Cache key of row {:?} is NULL, not caching it", row);
+                            return Ok(row);
+                        };
+                        let res = match ttl {
+                            Some(ttl) => cache.put_with_ttl::<U>(&key, &row, ttl).await,
+                            None => cache.put::<U>(&key, &row).await,
+                        };
+                        match res {
+                            Err(e) => warn!("Error caching value for key {}: {}", key, e),
+                            Ok(()) => debug!("Item cached"),
+                        }
+                        Ok(row)
+                    }
+                })
+                .boxed()
+            })
+            .boxed()
+    }
+}
+
+/// Async wrapper for a Diesel select query that attempts to read results from
+/// the cache before falling back to the database, optionally populating the
+/// cache on misses.
+///
+/// Returned by `try_from_cache_async`, `try_from_cache_multi_async`, and
+/// `try_from_cache_and_populate_async`.
+pub struct AsyncSelectCacheReadWrapper<T, C>
+where
+    C: AsyncCacheHandle,
+{
+    inner_select: T,
+    keys: Vec<String>,
+    cache: C,
+    populate: bool,
+}
+
+impl<T, C> AsyncSelectCacheReadWrapper<T, C>
+where
+    C: AsyncCacheHandle,
+{
+    fn new(inner_select: T, keys: Vec<String>, cache: C, populate: bool) -> Self {
+        Self {
+            inner_select,
+            keys,
+            cache,
+            populate,
+        }
+    }
+}
+
+/// State threaded through the stream returned by `AsyncSelectCacheReadWrapper`.
+struct AsyncLookupState<S, U, C> {
+    inner: S,
+    keys: std::vec::IntoIter<String>,
+    cached: std::vec::IntoIter<Result<Option<U>, CacheError>>,
+    cache: C,
+    populate: bool,
+}
+
+impl<S, U, C> AsyncLookupState<S, U, C>
+where
+    S: Stream<Item = QueryResult<U>> + Unpin,
+    U: Serialize + DeserializeOwned + Sync,
+    C: AsyncCacheHandle,
+{
+    async fn call_inner_and_cache(&mut self, key: &str) -> Option<QueryResult<U>> {
+        match self.inner.next().await {
+            Some(Ok(val)) => {
+                if self.populate {
+                    let res = self.cache.put::<U>(key, &val).await;
+                    if let Err(e) = res {
+                        warn!("Error caching value for key {}: {}", key, e);
+                    }
+                }
+                Some(Ok(val))
+            }
+            Some(Err(e)) => Some(Err(e)),
+            None => None,
+        }
+    }
+
+    async fn next_row(&mut self) -> Option<QueryResult<U>> {
+        let key = self.keys.next()?;
+        match self.cached.next() {
+            Some(Ok(Some(cached_val))) => {
+                debug!("Cache hit for key: {}", key);
+                Some(Ok(cached_val))
+            }
+            Some(Ok(None)) | None => {
+                debug!("Cache miss for key: {}, reading from inner", key);
+                self.call_inner_and_cache(&key).await
+            }
+            Some(Err(e)) => {
+                warn!(
+                    "Error retrieving from cache for key: {}, reading from inner; error {}",
+                    key, e
+                );
+                self.call_inner_and_cache(&key).await
+            }
+        }
+    }
+}
+
+impl<T, Conn, U, C> LoadQuery<'static, Conn, U> for AsyncSelectCacheReadWrapper<T, C>
+where
+    T: LoadQuery<'static, Conn, U> + 'static,
+    Conn: AsyncConnection,
+    U: Serialize + DeserializeOwned + std::fmt::Debug + Send + Sync + 'static,
+    C: AsyncCacheHandle + 'static,
+{
+    type LoadFuture<'conn>
+        = BoxFuture<'conn, QueryResult<Self::Stream<'conn>>>
+    where
+        Conn: 'conn;
+
+    type Stream<'conn>
+        = BoxStream<'conn, QueryResult<U>>
+    where
+        Conn: 'conn;
+
+    fn internal_load(self, conn: &mut Conn) -> Self::LoadFuture<'_> {
+        debug!("In AsyncSelectCacheReadWrapper internal_load");
+
+        let keys = self.keys;
+        let cache = self.cache;
+        let populate = self.populate;
+        let load = self.inner_select.internal_load(conn);
+        async move {
+            let cached = match cache.get_multi::<U>(&keys).await {
+                Ok(values) => values.into_iter().map(Ok).collect::<Vec<_>>(),
+                Err(e) => {
+                    // Retry key by key so that a single failing key doesn't turn
+                    // every other lookup into a database read.
+                    warn!("Error retrieving keys from cache in batch; error {}", e);
+                    let mut cached = Vec::with_capacity(keys.len());
+                    for key in &keys {
+                        cached.push(cache.get::<U>(key).await);
+                    }
+                    cached
+                }
+            };
+            let state = AsyncLookupState {
+                inner: load.await?.boxed(),
+                keys: keys.into_iter(),
+                cached: cached.into_iter(),
+                cache,
+                populate,
+            };
+            let rows = stream::unfold(state, |mut state| async move {
+                let row = state.next_row().await?;
+                Some((row, state))
+            });
+            Ok(rows.boxed())
+        }
+        .boxed()
+    }
+}
+
+/// Async counterpart of `WrappableQuery`, for use with `diesel_async::RunQueryDsl`.
+///
+/// The wrappers returned here stream rows through `load`, `load_stream`,
+/// `get_result` and `get_results` of `diesel-async`, and talk to the cache
+/// through an `AsyncCacheHandle` so the executor is never blocked.
+///
+/// Because the returned streams are boxed, the wrapped query must own its
+/// bind values (i.e. be `'static`).
+pub trait AsyncWrappableQuery<C: AsyncCacheHandle> {
+    /// Async variant of `populate_cache`. The query must select a pair of the
+    /// data row and a SQL expression producing the cache key.
+    fn populate_cache_async<U>(self, cache: C) -> AsyncSelectCachingWrapper<Self, C>
+    where
+        Self: Sized,
+        U: Serialize + DeserializeOwned,
+    {
+        AsyncSelectCachingWrapper::new(self, cache, None)
+    }
+
+    /// Async variant of `populate_cache_with_ttl`.
+    fn populate_cache_with_ttl_async<U>(
+        self,
+        cache: C,
+        ttl: Duration,
+    ) -> AsyncSelectCachingWrapper<Self, C>
+    where
+        Self: Sized,
+        U: Serialize + DeserializeOwned,
+    {
+        AsyncSelectCachingWrapper::new(self, cache, Some(ttl))
+    }
+
+    /// Async variant of `try_from_cache`.
+    fn try_from_cache_async<U>(
+        self,
+        cache: C,
+        key: impl IntoCacheKey<U>,
+    ) -> AsyncSelectCacheReadWrapper<Self, C>
+    where
+        Self: Sized,
+        U: Serialize + DeserializeOwned,
+    {
+        AsyncSelectCacheReadWrapper::new(self, vec![key.into_cache_key()], cache, false)
+    }
+
+    /// Async variant of `try_from_cache_and_populate`.
+    fn try_from_cache_and_populate_async<U>(
+        self,
+        cache: C,
+        key: impl IntoCacheKey<U>,
+    ) -> AsyncSelectCacheReadWrapper<Self, C>
+    where
+        Self: Sized,
+        U: Serialize + DeserializeOwned,
+    {
+        AsyncSelectCacheReadWrapper::new(self, vec![key.into_cache_key()], cache, true)
+    }
+
+    /// Async variant of `try_from_cache_multi`.
+    fn try_from_cache_multi_async<U, K>(
+        self,
+        cache: C,
+        keys: K,
+    ) -> AsyncSelectCacheReadWrapper<Self, C>
+    where
+        Self: Sized,
+        U: Serialize + DeserializeOwned,
+        K: Iterator<Item = String>,
+    {
+        AsyncSelectCacheReadWrapper::new(self, keys.collect(), cache, false)
+    }
+}
+
+impl<From, Select, Distinct, Where, Order, LimitOffset, GroupBy, Having, Locking, C>
+    AsyncWrappableQuery<C>
+    for SelectStatement<From, Select, Distinct, Where, Order, LimitOffset, GroupBy, Having, Locking>
+where
+    C: AsyncCacheHandle,
+{
+}
+
+impl<T, C, C2> AsyncWrappableQuery<C2> for AsyncSelectCachingWrapper<T, C>
+where
+    C: AsyncCacheHandle,
+    C2: AsyncCacheHandle,
+{
+}
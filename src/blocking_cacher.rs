@@ -0,0 +1,235 @@
+use crate::async_cacher::AsyncCacheHandle;
+use crate::cacher::{CacheError, CacheErrorKind, CacheHandle};
+use crate::dyn_cacher::{from_json, to_json};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::{Builder, Runtime};
+
+/// Key read by `health_check`, which has no async counterpart.
+const HEALTH_CHECK_KEY: &str = "__turbodiesel_health_check";
+
+/// Synchronous `CacheHandle` driving an `AsyncCacheHandle` to completion on
+/// a small Tokio runtime of its own, so synchronous Diesel code can use an
+/// async backend such as `AsyncRedisCacheHandle` unchanged.
+///
+/// Connections whose I/O runs on a Tokio task, like a Redis
+/// `MultiplexedConnection`, should be opened on the handle's runtime:
+///
+/// ```ignore
+/// let runtime = new_runtime()?;
+/// let con = runtime.block_on(client.get_multiplexed_async_connection())?;
+/// let handle = BlockingCacheHandle::with_runtime(AsyncRedisCacheHandle::new(con), runtime);
+/// ```
+///
+/// Every call blocks the current thread until the async operation finishes.
+/// It must therefore not be made from within an async context, e.g. a task
+/// on another Tokio runtime, where it would stall that runtime's worker;
+/// such calls fail with an error instead. Use the `AsyncCacheHandle`
+/// directly there.
+///
+/// `AsyncCacheHandle` only reads, writes and deletes single entries, so
+/// `exists` reads the whole value, and the other operations (raw bytes,
+/// `put_if_absent`, `compare_and_set`, scans, tags and `clear`) fail with a
+/// `Backend` error. Like `DynCacheHandle`, values pass through a
+/// `serde_json::Value` on the way, which needs a self-describing serializer
+/// on the inner handle.
+#[derive(Clone)]
+pub struct BlockingCacheHandle<A: AsyncCacheHandle> {
+    inner: A,
+    runtime: Arc<Runtime>,
+}
+
+impl<A: AsyncCacheHandle> BlockingCacheHandle<A> {
+    /// Wraps `inner` with a new single-worker runtime.
+    pub fn new(inner: A) -> Result<Self, CacheError> {
+        Ok(Self::with_runtime(inner, new_runtime()?))
+    }
+
+    /// Wraps `inner` with `runtime`, e.g. the one its connection was opened
+    /// on, or one shared by several handles.
+    pub fn with_runtime(inner: A, runtime: Arc<Runtime>) -> Self {
+        BlockingCacheHandle { inner, runtime }
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    pub fn runtime(&self) -> &Arc<Runtime> {
+        &self.runtime
+    }
+
+    fn block_on<F: Future>(runtime: &Runtime, future: F) -> Result<F::Output, CacheError> {
+        // `Runtime::block_on` panics when called on a runtime worker thread.
+        if tokio::runtime::Handle::try_current().is_ok() {
+            return Err(CacheError::new(
+                "BlockingCacheHandle called from within an async context",
+            ));
+        }
+        Ok(runtime.block_on(future))
+    }
+}
+
+/// Builds the single-worker runtime `BlockingCacheHandle::new` uses, for
+/// opening connections before the handle exists.
+pub fn new_runtime() -> Result<Arc<Runtime>, CacheError> {
+    Builder::new_multi_thread()
+        .worker_threads(1)
+        .thread_name("turbodiesel-blocking")
+        .enable_all()
+        .build()
+        .map(Arc::new)
+        .map_err(|e| CacheError::with_cause("Failed to start Tokio runtime", e))
+}
+
+fn unsupported(operation: &str) -> CacheError {
+    CacheError::new(&format!(
+        "{} is not supported by BlockingCacheHandle",
+        operation
+    ))
+    .with_kind(CacheErrorKind::Backend)
+}
+
+impl<A: AsyncCacheHandle> CacheHandle for BlockingCacheHandle<A> {
+    fn get<V: Serialize + DeserializeOwned>(&self, key: &String) -> Result<Option<V>, CacheError> {
+        let value = Self::block_on(&self.runtime, self.inner.get::<Value>(key))??;
+        value.map(from_json).transpose()
+    }
+
+    fn get_multi<V: Serialize + DeserializeOwned>(
+        &self,
+        keys: &[String],
+    ) -> Result<Vec<Option<V>>, CacheError> {
+        let values = Self::block_on(&self.runtime, self.inner.get_multi::<Value>(keys))??;
+        values
+            .into_iter()
+            .map(|value| value.map(from_json).transpose())
+            .collect()
+    }
+
+    /// Reads the value, as `AsyncCacheHandle` has no cheaper check.
+    fn exists(&self, key: &String) -> Result<bool, CacheError> {
+        let value = Self::block_on(&self.runtime, self.inner.get::<Value>(key))??;
+        Ok(value.is_some())
+    }
+
+    fn put<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+    ) -> Result<(), CacheError> {
+        let value = to_json(value)?;
+        Self::block_on(&self.runtime, self.inner.put(key, &value))?
+    }
+
+    fn get_raw(&self, _key: &String) -> Result<Option<Vec<u8>>, CacheError> {
+        Err(unsupported("get_raw"))
+    }
+
+    fn put_raw(&mut self, _key: &String, _bytes: &[u8]) -> Result<(), CacheError> {
+        Err(unsupported("put_raw"))
+    }
+
+    fn put_with_ttl<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+        ttl: Duration,
+    ) -> Result<(), CacheError> {
+        let value = to_json(value)?;
+        Self::block_on(&self.runtime, self.inner.put_with_ttl(key, &value, ttl))?
+    }
+
+    fn put_if_absent<V: Serialize + DeserializeOwned>(
+        &mut self,
+        _key: &String,
+        _value: &V,
+    ) -> Result<bool, CacheError> {
+        Err(unsupported("put_if_absent"))
+    }
+
+    fn delete(&mut self, key: &String) -> Result<(), CacheError> {
+        Self::block_on(&self.runtime, self.inner.delete(key))?
+    }
+
+    fn delete_multi(&mut self, keys: &[String]) -> Result<(), CacheError> {
+        let inner = &mut self.inner;
+        Self::block_on(&self.runtime, async move {
+            for key in keys {
+                inner.delete(key).await?;
+            }
+            Ok(())
+        })?
+    }
+
+    fn clear(&mut self) -> Result<(), CacheError> {
+        Err(unsupported("clear"))
+    }
+
+    fn scan_keys(&self, _pattern: &str) -> Result<HashMap<String, String>, CacheError> {
+        Err(unsupported("scan_keys"))
+    }
+
+    fn count_keys(&self, _pattern: &str) -> Result<usize, CacheError> {
+        Err(unsupported("count_keys"))
+    }
+
+    fn put_tagged<V: Serialize + DeserializeOwned>(
+        &mut self,
+        _key: &String,
+        _value: &V,
+        _tags: &[&str],
+    ) -> Result<(), CacheError> {
+        Err(unsupported("put_tagged"))
+    }
+
+    fn invalidate_tag(&mut self, _tag: &str) -> Result<Vec<String>, CacheError> {
+        Err(unsupported("invalidate_tag"))
+    }
+
+    /// Reads a probe key, which fails if the backend cannot be reached.
+    fn health_check(&self) -> Result<(), CacheError> {
+        Self::block_on(&self.runtime, self.inner.get::<Value>(HEALTH_CHECK_KEY))??;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cacher::HashmapCache;
+
+    #[test]
+    fn test_blocking_handle_drives_async_calls() {
+        let cache = HashmapCache::new();
+        let mut handle = BlockingCacheHandle::new(cache.handle()).unwrap();
+        let key = "student:2".to_string();
+
+        handle.put(&key, &"Ori".to_string()).unwrap();
+        assert_eq!(handle.get::<String>(&key).unwrap(), Some("Ori".to_string()));
+        assert!(CacheHandle::exists(&cache.handle(), &key).unwrap());
+        assert!(handle.exists(&key).unwrap());
+        assert!(!handle.exists(&"student:3".to_string()).unwrap());
+
+        handle.delete_multi(std::slice::from_ref(&key)).unwrap();
+        assert_eq!(handle.get_multi::<String>(&[key]).unwrap(), vec![None]);
+        assert!(handle.scan_keys("*").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_blocking_handle_refuses_async_context() {
+        let cache = HashmapCache::new();
+        let runtime = new_runtime().unwrap();
+        let handle = BlockingCacheHandle::with_runtime(cache.handle(), runtime.clone());
+        assert!(handle.get::<String>(&"student:2".to_string()).is_err());
+        // Dropping a runtime is not allowed in an async context either.
+        std::thread::spawn(move || drop((handle, runtime)))
+            .join()
+            .unwrap();
+    }
+}
@@ -0,0 +1,720 @@
+use diesel::backend::Backend;
+use diesel::deserialize::{self, FromSql, Queryable};
+use diesel::expression::{AppearsOnTable, Expression, SelectableExpression, ValidGrouping};
+#[cfg(feature = "mysql")]
+use diesel::mysql::Mysql;
+use diesel::pg::Pg;
+use diesel::query_builder::{AstPass, QueryFragment, QueryId, debug_query};
+use diesel::result::QueryResult;
+use diesel::sql_types::{Nullable, SqlType, Text, is_nullable};
+#[cfg(feature = "sqlite")]
+use diesel::sqlite::Sqlite;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+
+/// Cache key of a row of type `T`.
+///
+/// Wrappers that take a key accept either a plain `&str` or a `CacheKey<U>`
+/// for the row type `U` they read or write, so passing a `CacheKey<Student>`
+/// to `try_from_cache::<Teacher>` is a compile error. Build keys with
+/// `KeyBuilder::cache_key` rather than `new` so every key of a type shares
+/// one prefix.
+pub struct CacheKey<T> {
+    key: String,
+    row: PhantomData<fn() -> T>,
+}
+
+impl<T> CacheKey<T> {
+    pub fn new(key: impl Into<String>) -> Self {
+        CacheKey {
+            key: key.into(),
+            row: PhantomData,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.key
+    }
+
+    pub fn into_string(self) -> String {
+        self.key
+    }
+}
+
+// Implemented by hand so that `T` itself need not be `Clone`, `Eq`, etc.
+impl<T> Clone for CacheKey<T> {
+    fn clone(&self) -> Self {
+        CacheKey::new(self.key.clone())
+    }
+}
+
+impl<T> PartialEq for CacheKey<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl<T> Eq for CacheKey<T> {}
+
+impl<T> Hash for CacheKey<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key.hash(state);
+    }
+}
+
+impl<T> fmt::Debug for CacheKey<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("CacheKey").field(&self.key).finish()
+    }
+}
+
+impl<T> fmt::Display for CacheKey<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.key)
+    }
+}
+
+/// Builds the typed cache keys of a model, e.g. `Student::cache_key(2)` for
+/// `student:2`.
+///
+/// ```ignore
+/// impl KeyBuilder for Student {
+///     const PREFIX: &'static str = "student";
+///     type Id = i32;
+/// }
+///
+/// let row_with_cache_key = (Student::as_select(), Student::key_expr(students::id));
+/// let student = students::dsl::students
+///     .filter(students::dsl::id.eq(2))
+///     .try_from_cache(handle.clone(), Student::cache_key(2))
+///     .get_result::<Student>(connection)?;
+/// ```
+pub trait KeyBuilder: Sized {
+    /// Prefix shared by every key of this type.
+    const PREFIX: &'static str;
+    /// Identifier appended to the prefix, usually the primary key.
+    type Id: fmt::Display;
+
+    fn cache_key(id: Self::Id) -> CacheKey<Self> {
+        CacheKey::new(format!("{}:{}", Self::PREFIX, id))
+    }
+
+    /// Same keys as `cache_key`, computed in SQL for `populate_cache`.
+    fn key_expr<C>(column: C) -> CacheKeyExpr<C>
+    where
+        C: Expression,
+        C::SqlType: SqlType<IsNull = is_nullable::NotNull>,
+    {
+        cache_key_expr(Self::PREFIX, column)
+    }
+}
+
+/// A model that knows its own cache key, so the code populating the cache
+/// and the code invalidating it cannot disagree on the key of a row.
+///
+/// Usually derived, building the key like `KeyBuilder` does. The derive
+/// also adds `cache_key_for`, building the key from the values of the key
+/// fields, e.g. for `try_from_cache` before the row is loaded. Composite
+/// keys list several fields, e.g. `key(tenant_id, id)`, and `separator`
+/// replaces the `:` between the parts.
+///
+/// ```ignore
+/// #[derive(Cacheable)]
+/// #[cache(prefix = "student", key = "id")]
+/// pub struct Student { pub id: i32, pub name: String }
+///
+/// let student = students::table
+///     .filter(students::id.eq(2))
+///     .try_from_cache(handle.clone(), Student::cache_key_for(2))
+///     .get_result::<Student>(connection)?;
+///
+/// let students = students::table
+///     .populate_cache_with_key_fn(handle.clone(), Student::cache_key)
+///     .load::<Student>(connection)?;
+/// diesel::update(students::table)
+///     .set(students::name.eq("Ori2"))
+///     .filter(students::id.eq(student.id))
+///     .invalidate_key(handle.clone(), &student.cache_key(), InvalidationOrder::Both)
+///     .execute(connection)?;
+/// ```
+pub trait Cacheable {
+    /// Prefix shared by every key of this type.
+    const CACHE_PREFIX: &'static str;
+
+    fn cache_key(&self) -> String;
+}
+
+pub use turbodiesel_derive::Cacheable;
+
+/// A cache key for rows of type `T`: a typed `CacheKey<T>`, or a plain string,
+/// which is accepted for any row type.
+pub trait IntoCacheKey<T> {
+    fn into_cache_key(self) -> String;
+}
+
+impl<T> IntoCacheKey<T> for CacheKey<T> {
+    fn into_cache_key(self) -> String {
+        self.key
+    }
+}
+
+impl<T> IntoCacheKey<T> for &CacheKey<T> {
+    fn into_cache_key(self) -> String {
+        self.key.clone()
+    }
+}
+
+impl<T> IntoCacheKey<T> for &str {
+    fn into_cache_key(self) -> String {
+        self.to_string()
+    }
+}
+
+impl<T> IntoCacheKey<T> for String {
+    fn into_cache_key(self) -> String {
+        self
+    }
+}
+
+/// Computes the cache key of a loaded row of type `U`.
+///
+/// Every wrapper that derives keys from the rows a statement returns takes
+/// one: `populate_cache_with_key_fn`, `populate_on_insert`,
+/// `refresh_from_returning` and `invalidate_from_returning`. Closures and
+/// functions such as `Student::cache_key` implement it; implement it on a
+/// type of your own to write key logic that needs more than the row once
+/// and reuse it with all of them:
+///
+/// ```ignore
+/// struct TenantKey { tenant: String }
+///
+/// impl KeyExtractor<Student> for TenantKey {
+///     fn extract_key(&self, student: &Student) -> String {
+///         format!("{}:student:{}", self.tenant, student.id)
+///     }
+/// }
+/// ```
+pub trait KeyExtractor<U> {
+    fn extract_key(&self, row: &U) -> String;
+}
+
+impl<U, F> KeyExtractor<U> for F
+where
+    F: Fn(&U) -> String,
+{
+    fn extract_key(&self, row: &U) -> String {
+        self(row)
+    }
+}
+
+/// How a cache assembles the keys it stores: the separator between the parts
+/// of a composite key, and an optional prefix and suffix around every key.
+///
+/// The default, a `:` separator without prefix or suffix, stores keys as
+/// given and matches `KeyBuilder` and `cache_key_expr`. With a prefix
+/// `v2` and a suffix `json`, the key `student:2` is stored as
+/// `v2:student:2:json`; behind a cache namespace `app`, as
+/// `app:v2:student:2:json`. Keys are passed to and returned from the cache
+/// without the prefix and suffix, so `scan_keys` patterns are unaffected.
+///
+/// ```ignore
+/// let format = KeyFormat::new().with_separator('/').with_prefix("v2");
+/// let cache = RedisCache::with_namespace(redis_url, "app")?.with_key_format(format.clone());
+/// let key = format.join("student", 2); // stored as `app/v2/student/2`
+/// let row_with_cache_key = (Student::as_select(), format.key_expr("student", students::id));
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyFormat {
+    separator: char,
+    prefix: Option<String>,
+    suffix: Option<String>,
+}
+
+impl Default for KeyFormat {
+    fn default() -> Self {
+        KeyFormat {
+            separator: ':',
+            prefix: None,
+            suffix: None,
+        }
+    }
+}
+
+impl KeyFormat {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_separator(mut self, separator: char) -> Self {
+        self.separator = separator;
+        self
+    }
+
+    /// Adds `prefix` in front of every stored key.
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = Some(prefix.to_string());
+        self
+    }
+
+    /// Adds `suffix` after every stored key.
+    pub fn with_suffix(mut self, suffix: &str) -> Self {
+        self.suffix = Some(suffix.to_string());
+        self
+    }
+
+    pub fn separator(&self) -> char {
+        self.separator
+    }
+
+    /// Builds the composite key of `id` under `prefix`, e.g. `student:2`.
+    pub fn join(&self, prefix: &str, id: impl fmt::Display) -> String {
+        format!("{}{}{}", prefix, self.separator, id)
+    }
+
+    /// Same keys as `join`, computed in SQL for `populate_cache`.
+    pub fn key_expr<C>(&self, prefix: &str, column: C) -> CacheKeyExpr<C>
+    where
+        C: Expression,
+        C::SqlType: SqlType<IsNull = is_nullable::NotNull>,
+    {
+        CacheKeyExpr {
+            prefix: format!("{}{}", prefix, self.separator),
+            column,
+        }
+    }
+
+    /// Returns the key stored for `key` under `namespace`.
+    pub(crate) fn qualify(&self, namespace: Option<&str>, key: &str) -> String {
+        let mut qualified = String::new();
+        for part in namespace.into_iter().chain(self.prefix.as_deref()) {
+            qualified.push_str(part);
+            qualified.push(self.separator);
+        }
+        qualified.push_str(key);
+        if let Some(suffix) = &self.suffix {
+            qualified.push(self.separator);
+            qualified.push_str(suffix);
+        }
+        qualified
+    }
+
+    /// Reverses `qualify`, returning keys that were not qualified as is.
+    pub(crate) fn unqualify<'a>(&self, namespace: Option<&str>, key: &'a str) -> &'a str {
+        let mut unqualified = Some(key);
+        for part in namespace.into_iter().chain(self.prefix.as_deref()) {
+            unqualified = unqualified
+                .and_then(|k| k.strip_prefix(part))
+                .and_then(|k| k.strip_prefix(self.separator));
+        }
+        if let Some(suffix) = &self.suffix {
+            unqualified = unqualified
+                .and_then(|k| k.strip_suffix(suffix.as_str()))
+                .and_then(|k| k.strip_suffix(self.separator));
+        }
+        unqualified.unwrap_or(key)
+    }
+}
+
+/// Prefix of the keys derived by `query_cache_key`.
+pub const QUERY_KEY_PREFIX: &str = "query";
+
+/// Derives a cache key from the SQL and bind parameters of `query` as
+/// rendered for backend `DB`, as `query:` followed by the first 128 bits of
+/// their SHA-256 in hex.
+///
+/// Used by `try_from_cache_auto`. The same query with the same binds always
+/// gets the same key, so call this to find the key to invalidate:
+///
+/// ```ignore
+/// let query = students::table.filter(students::id.eq(2)).select(Student::as_select());
+/// let key = query_cache_key::<Pg, _>(&query);
+/// ```
+pub fn query_cache_key<DB, T>(query: &T) -> String
+where
+    DB: Backend + Default,
+    DB::QueryBuilder: Default,
+    T: QueryFragment<DB>,
+{
+    let rendered = debug_query::<DB, _>(query).to_string();
+    let digest = Sha256::digest(rendered.as_bytes());
+    let hex = digest[..16]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    format!("{}:{}", QUERY_KEY_PREFIX, hex)
+}
+
+/// SQL expression producing a cache key of the form `prefix:value`.
+///
+/// Returned by `cache_key_expr`.
+#[derive(Debug, Clone)]
+pub struct CacheKeyExpr<C> {
+    prefix: String,
+    column: C,
+}
+
+/// Builds the cache key half of the tuple selected by `populate_cache`, as
+/// `'prefix:' || column::text` on Postgres.
+///
+/// The column is cast to text, so integer and other non-text keys work as-is,
+/// and must not be nullable. With the `sqlite` and `mysql` features the same
+/// expression renders as `? || CAST(column AS TEXT)` and
+/// `CONCAT(?, column)` respectively. The prefix is sent as a bind parameter rather
+/// than spliced into the SQL, so it needs no escaping.
+///
+/// ```ignore
+/// let row_with_cache_key = (Student::as_select(), cache_key_expr("student", students::id));
+/// ```
+pub fn cache_key_expr<C>(prefix: &str, column: C) -> CacheKeyExpr<C>
+where
+    C: Expression,
+    C::SqlType: SqlType<IsNull = is_nullable::NotNull>,
+{
+    CacheKeyExpr {
+        prefix: format!("{}:", prefix),
+        column,
+    }
+}
+
+impl<C: Expression> Expression for CacheKeyExpr<C> {
+    type SqlType = Text;
+}
+
+impl<C: QueryFragment<Pg>> QueryFragment<Pg> for CacheKeyExpr<C> {
+    fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, Pg>) -> QueryResult<()> {
+        out.push_sql("(");
+        out.push_bind_param::<Text, _>(&self.prefix)?;
+        out.push_sql(" || (");
+        self.column.walk_ast(out.reborrow())?;
+        out.push_sql(")::text)");
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl<C: QueryFragment<Sqlite>> QueryFragment<Sqlite> for CacheKeyExpr<C> {
+    fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, Sqlite>) -> QueryResult<()> {
+        out.push_sql("(");
+        out.push_bind_param::<Text, _>(&self.prefix)?;
+        out.push_sql(" || CAST(");
+        self.column.walk_ast(out.reborrow())?;
+        out.push_sql(" AS TEXT))");
+        Ok(())
+    }
+}
+
+#[cfg(feature = "mysql")]
+impl<C: QueryFragment<Mysql>> QueryFragment<Mysql> for CacheKeyExpr<C> {
+    fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, Mysql>) -> QueryResult<()> {
+        // `||` is a logical OR in MySQL by default.
+        out.push_sql("CONCAT(");
+        out.push_bind_param::<Text, _>(&self.prefix)?;
+        out.push_sql(", ");
+        self.column.walk_ast(out.reborrow())?;
+        out.push_sql(")");
+        Ok(())
+    }
+}
+
+impl<C> QueryId for CacheKeyExpr<C> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<C, QS> AppearsOnTable<QS> for CacheKeyExpr<C> where C: AppearsOnTable<QS> {}
+
+impl<C, QS> SelectableExpression<QS> for CacheKeyExpr<C> where C: SelectableExpression<QS> {}
+
+impl<C, GB> ValidGrouping<GB> for CacheKeyExpr<C>
+where
+    C: ValidGrouping<GB>,
+{
+    type IsAggregate = C::IsAggregate;
+}
+
+/// Cache key selected next to each row for `populate_cache`, loaded from
+/// either a `Text` or a `Nullable<Text>` expression.
+///
+/// A key expression over a nullable column, such as `'student:' || email`,
+/// is NULL for rows where the column is NULL. Those rows are returned by
+/// `populate_cache` without being cached.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SelectedCacheKey(pub Option<String>);
+
+impl<DB> FromSql<Text, DB> for SelectedCacheKey
+where
+    DB: Backend,
+    String: FromSql<Text, DB>,
+{
+    fn from_sql(bytes: DB::RawValue<'_>) -> deserialize::Result<Self> {
+        String::from_sql(bytes).map(|key| SelectedCacheKey(Some(key)))
+    }
+
+    fn from_nullable_sql(bytes: Option<DB::RawValue<'_>>) -> deserialize::Result<Self> {
+        match bytes {
+            Some(bytes) => <Self as FromSql<Text, DB>>::from_sql(bytes),
+            None => Ok(SelectedCacheKey(None)),
+        }
+    }
+}
+
+impl<DB> FromSql<Nullable<Text>, DB> for SelectedCacheKey
+where
+    DB: Backend,
+    String: FromSql<Text, DB>,
+{
+    fn from_sql(bytes: DB::RawValue<'_>) -> deserialize::Result<Self> {
+        <Self as FromSql<Text, DB>>::from_sql(bytes)
+    }
+
+    fn from_nullable_sql(bytes: Option<DB::RawValue<'_>>) -> deserialize::Result<Self> {
+        <Self as FromSql<Text, DB>>::from_nullable_sql(bytes)
+    }
+}
+
+/// SQL type of a `(row, cache key)` selection, as `populate_cache` expects.
+///
+/// Only used to turn a query that selects no cache key into a readable
+/// compile error, instead of an unsatisfied `LoadQuery` bound.
+#[diagnostic::on_unimplemented(
+    message = "`populate_cache` requires selecting `(row, cache_key)`, but the query selects `{Self}`",
+    label = "this query selects no cache key",
+    note = "did you forget the key expression? e.g. `.select((Student::as_select(), cache_key_expr(\"student\", students::id)))`"
+)]
+pub trait RowWithCacheKey {}
+
+impl<Row, Key: CacheKeySqlType> RowWithCacheKey for (Row, Key) {}
+
+/// SQL type of the cache key selected next to each row: `Text`, or
+/// `Nullable<Text>` for a key over a nullable column.
+///
+/// Only used to turn a key selected as another type, e.g. the integer
+/// `sql::<Integer>("id")`, into a readable compile error, instead of an
+/// unsatisfied `LoadQuery` bound.
+#[diagnostic::on_unimplemented(
+    message = "the cache key selected for `populate_cache` must be `Text`, but it is `{Self}`",
+    label = "this cache key is not text",
+    note = "build the key with `cache_key_expr(\"student\", students::id)`, or select it as text, e.g. `sql::<Text>(\"'student:' || id\")`"
+)]
+pub trait CacheKeySqlType {}
+
+impl CacheKeySqlType for Text {}
+
+impl CacheKeySqlType for Nullable<Text> {}
+
+impl<DB> Queryable<Text, DB> for SelectedCacheKey
+where
+    DB: Backend,
+    Self: FromSql<Text, DB>,
+{
+    type Row = Self;
+
+    fn build(row: Self) -> deserialize::Result<Self> {
+        Ok(row)
+    }
+}
+
+impl<DB> Queryable<Nullable<Text>, DB> for SelectedCacheKey
+where
+    DB: Backend,
+    Self: FromSql<Nullable<Text>, DB>,
+{
+    type Row = Self;
+
+    fn build(row: Self) -> deserialize::Result<Self> {
+        Ok(row)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    diesel::table! {
+        students (id) {
+            id -> Int4,
+            name -> Text,
+        }
+    }
+
+    #[test]
+    fn test_cache_key_expr_sql() {
+        use diesel::prelude::*;
+
+        let query =
+            students::table.select((students::name, cache_key_expr("stu'dent", students::id)));
+        let sql = diesel::debug_query::<Pg, _>(&query).to_string();
+        assert!(
+            sql.contains(r#"($1 || ("students"."id")::text)"#),
+            "Unexpected SQL: {}",
+            sql
+        );
+        assert!(
+            sql.contains(r#"binds: ["stu'dent:"]"#),
+            "Unexpected SQL: {}",
+            sql
+        );
+    }
+
+    struct Student;
+
+    impl KeyBuilder for Student {
+        const PREFIX: &'static str = "student";
+        type Id = i32;
+    }
+
+    #[test]
+    fn test_key_format_qualifies_keys() {
+        let default = KeyFormat::new();
+        assert_eq!(default.join("student", 2), "student:2");
+        assert_eq!(
+            default.join("student", 2),
+            Student::cache_key(2).into_string()
+        );
+        assert_eq!(default.qualify(None, "student:2"), "student:2");
+        assert_eq!(default.qualify(Some("app"), "student:2"), "app:student:2");
+
+        let format = KeyFormat::new()
+            .with_separator('/')
+            .with_prefix("v2")
+            .with_suffix("json");
+        let key = format.join("student", 2);
+        assert_eq!(key, "student/2");
+        let qualified = format.qualify(Some("app"), &key);
+        assert_eq!(qualified, "app/v2/student/2/json");
+        assert_eq!(format.unqualify(Some("app"), &qualified), key);
+        assert_eq!(
+            format.qualify(Some("app"), "student/*"),
+            "app/v2/student/*/json"
+        );
+        // A key stored without the format is returned unchanged.
+        assert_eq!(format.unqualify(Some("app"), "other:2"), "other:2");
+    }
+
+    #[test]
+    fn test_query_cache_key_depends_on_sql_and_binds() {
+        use diesel::prelude::*;
+
+        let key_for = |id: i32| {
+            query_cache_key::<Pg, _>(
+                &students::table
+                    .filter(students::id.eq(id))
+                    .select(students::name),
+            )
+        };
+        assert_eq!(key_for(2), key_for(2));
+        assert_ne!(key_for(2), key_for(3));
+        assert_ne!(
+            key_for(2),
+            query_cache_key::<Pg, _>(
+                &students::table
+                    .filter(students::id.eq(2))
+                    .select(students::id)
+            )
+        );
+        assert!(key_for(2).starts_with("query:"));
+        assert_eq!(key_for(2).len(), "query:".len() + 32);
+    }
+
+    fn key_of<U>(key: impl IntoCacheKey<U>) -> String {
+        key.into_cache_key()
+    }
+
+    #[test]
+    fn test_key_builder_matches_key_expr() {
+        use diesel::prelude::*;
+
+        let key = Student::cache_key(2);
+        assert_eq!(key.as_str(), "student:2");
+        assert_eq!(key_of::<Student>(&key), "student:2");
+        assert_eq!(key_of::<Student>("student:3"), "student:3");
+
+        let query = students::table.select(Student::key_expr(students::id));
+        let sql = diesel::debug_query::<Pg, _>(&query).to_string();
+        assert!(
+            sql.contains(r#"binds: ["student:"]"#),
+            "Unexpected SQL: {}",
+            sql
+        );
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_cache_key_expr_populates_cache_on_sqlite() {
+        use crate::cacher::{CacheHandle, HashmapCache};
+        use crate::statement_wrappers::WrappableQuery;
+        use diesel::connection::DefaultLoadingMode;
+        use diesel::prelude::*;
+
+        let mut con = SqliteConnection::establish(":memory:").unwrap();
+        diesel::sql_query("CREATE TABLE students (id INTEGER PRIMARY KEY, name TEXT NOT NULL)")
+            .execute(&mut con)
+            .unwrap();
+        diesel::insert_into(students::table)
+            .values(vec![
+                (students::id.eq(1), students::name.eq("John")),
+                (students::id.eq(2), students::name.eq("Ori")),
+            ])
+            .execute(&mut con)
+            .unwrap();
+
+        let handle = HashmapCache::new().handle();
+        let names = students::table
+            .select((students::name, cache_key_expr("student", students::id)))
+            .order(students::id)
+            .populate_cache::<String>(handle.clone())
+            .load_iter::<String, DefaultLoadingMode>(&mut con)
+            .unwrap()
+            .collect::<QueryResult<Vec<_>>>()
+            .unwrap();
+        assert_eq!(names, vec!["John", "Ori"]);
+        assert_eq!(
+            handle.get::<String>(&"student:2".to_string()).unwrap(),
+            Some("Ori".to_string())
+        );
+    }
+
+    #[derive(Cacheable)]
+    #[cache(prefix = "student", key = "id")]
+    struct CachedStudent {
+        id: i32,
+        #[allow(dead_code)]
+        name: String,
+    }
+
+    #[test]
+    fn test_derived_cacheable_key() {
+        let student = CachedStudent {
+            id: 2,
+            name: "Ori".to_string(),
+        };
+        assert_eq!(CachedStudent::CACHE_PREFIX, "student");
+        assert_eq!(student.cache_key(), "student:2");
+        let keys: Vec<String> = [student].iter().map(CachedStudent::cache_key).collect();
+        assert_eq!(keys, vec!["student:2"]);
+        assert_eq!(CachedStudent::cache_key_for(2), "student:2");
+    }
+
+    #[derive(Cacheable)]
+    #[cache(prefix = "enrollment", key(tenant_id, id), separator = "/")]
+    struct Enrollment {
+        tenant_id: String,
+        id: i64,
+    }
+
+    #[test]
+    fn test_derived_cacheable_composite_key() {
+        let enrollment = Enrollment {
+            tenant_id: "acme".to_string(),
+            id: 2,
+        };
+        assert_eq!(enrollment.cache_key(), "enrollment/acme/2");
+        assert_eq!(
+            Enrollment::cache_key_for("acme".to_string(), 2),
+            enrollment.cache_key()
+        );
+    }
+}
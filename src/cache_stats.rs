@@ -0,0 +1,244 @@
+use crate::cacher::{CacheError, CacheErrorKind};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Hit, miss, write and error counters recorded by the statement wrappers.
+///
+/// Share one instance through an `Arc` between queries and read it at any
+/// time, e.g. to export cache performance to a metrics system. Errors are
+/// counted separately and do not affect `hit_rate`.
+///
+/// The serialization counters are only recorded by a handle whose
+/// serializer is wrapped in a `serializer::MeasuringSerializer`, and the
+/// compression counters by a `compression::CompressingSerializer` given the
+/// stats with `with_stats`.
+#[derive(Debug, Default)]
+pub struct CacheStats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    errors: AtomicU64,
+    populated: AtomicU64,
+    serialization_errors: AtomicU64,
+    serializations: AtomicU64,
+    serialized_bytes: AtomicU64,
+    serialization_nanos: AtomicU64,
+    deserializations: AtomicU64,
+    deserialized_bytes: AtomicU64,
+    deserialization_nanos: AtomicU64,
+    compressed: AtomicU64,
+    stored_uncompressed: AtomicU64,
+    compression_saved_bytes: AtomicU64,
+}
+
+impl CacheStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    /// Returns how many rows were written to the cache, by `populate_cache`
+    /// or by a lookup that populates its misses.
+    pub fn populated(&self) -> u64 {
+        self.populated.load(Ordering::Relaxed)
+    }
+
+    /// Returns how many rows could not be written to the cache because they
+    /// failed to serialize. These are also counted in `errors`.
+    pub fn serialization_errors(&self) -> u64 {
+        self.serialization_errors.load(Ordering::Relaxed)
+    }
+
+    /// Returns how many values were serialized.
+    pub fn serializations(&self) -> u64 {
+        self.serializations.load(Ordering::Relaxed)
+    }
+
+    /// Returns the total size, in bytes, of the serialized values.
+    pub fn serialized_bytes(&self) -> u64 {
+        self.serialized_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Returns the total time spent serializing values.
+    pub fn serialization_time(&self) -> Duration {
+        Duration::from_nanos(self.serialization_nanos.load(Ordering::Relaxed))
+    }
+
+    /// Returns how many values were deserialized.
+    pub fn deserializations(&self) -> u64 {
+        self.deserializations.load(Ordering::Relaxed)
+    }
+
+    /// Returns the total size, in bytes, of the payloads deserialized.
+    pub fn deserialized_bytes(&self) -> u64 {
+        self.deserialized_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Returns the total time spent deserializing values.
+    pub fn deserialization_time(&self) -> Duration {
+        Duration::from_nanos(self.deserialization_nanos.load(Ordering::Relaxed))
+    }
+
+    /// Returns how many values were stored compressed.
+    pub fn compressed(&self) -> u64 {
+        self.compressed.load(Ordering::Relaxed)
+    }
+
+    /// Returns how many values were stored uncompressed, either because they
+    /// were below the threshold or because compression did not shrink them.
+    pub fn stored_uncompressed(&self) -> u64 {
+        self.stored_uncompressed.load(Ordering::Relaxed)
+    }
+
+    /// Returns the total number of bytes saved by compressing values.
+    pub fn compression_saved_bytes(&self) -> u64 {
+        self.compression_saved_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Returns the fraction of lookups served from the cache, or `0.0` if no
+    /// lookups have been recorded yet.
+    pub fn hit_rate(&self) -> f64 {
+        let hits = self.hits();
+        let lookups = hits + self.misses();
+        if lookups == 0 {
+            0.0
+        } else {
+            hits as f64 / lookups as f64
+        }
+    }
+
+    /// Resets all counters to zero.
+    pub fn reset(&self) {
+        self.hits.store(0, Ordering::Relaxed);
+        self.misses.store(0, Ordering::Relaxed);
+        self.errors.store(0, Ordering::Relaxed);
+        self.populated.store(0, Ordering::Relaxed);
+        self.serialization_errors.store(0, Ordering::Relaxed);
+        self.serializations.store(0, Ordering::Relaxed);
+        self.serialized_bytes.store(0, Ordering::Relaxed);
+        self.serialization_nanos.store(0, Ordering::Relaxed);
+        self.deserializations.store(0, Ordering::Relaxed);
+        self.deserialized_bytes.store(0, Ordering::Relaxed);
+        self.deserialization_nanos.store(0, Ordering::Relaxed);
+        self.compressed.store(0, Ordering::Relaxed);
+        self.stored_uncompressed.store(0, Ordering::Relaxed);
+        self.compression_saved_bytes.store(0, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_populated(&self) {
+        self.populated.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a failed cache write, telling serialization failures apart.
+    pub(crate) fn record_write_error(&self, error: &CacheError) {
+        self.record_error();
+        if error.kind() == CacheErrorKind::Serialization {
+            self.serialization_errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn record_serialized(&self, bytes: usize, elapsed: Duration) {
+        self.serializations.fetch_add(1, Ordering::Relaxed);
+        self.serialized_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.serialization_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_deserialized(&self, bytes: usize, elapsed: Duration) {
+        self.deserializations.fetch_add(1, Ordering::Relaxed);
+        self.deserialized_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.deserialization_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    pub(crate) fn record_compressed(&self, original_bytes: usize, stored_bytes: usize) {
+        self.compressed.fetch_add(1, Ordering::Relaxed);
+        self.compression_saved_bytes.fetch_add(
+            original_bytes.saturating_sub(stored_bytes) as u64,
+            Ordering::Relaxed,
+        );
+    }
+
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    pub(crate) fn record_stored_uncompressed(&self) {
+        self.stored_uncompressed.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl std::fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "hits: {}, misses: {}, errors: {}, hit rate: {:.2}%",
+            self.hits(),
+            self.misses(),
+            self.errors(),
+            self.hit_rate() * 100.0
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hit_rate_and_display() {
+        let stats = CacheStats::new();
+        assert_eq!(stats.hit_rate(), 0.0);
+
+        stats.record_hit();
+        stats.record_hit();
+        stats.record_hit();
+        stats.record_miss();
+        stats.record_error();
+
+        assert_eq!(stats.hit_rate(), 0.75);
+        assert_eq!(
+            stats.to_string(),
+            "hits: 3, misses: 1, errors: 1, hit rate: 75.00%"
+        );
+
+        stats.reset();
+        assert_eq!(stats.hits() + stats.misses() + stats.errors(), 0);
+    }
+
+    #[test]
+    fn test_write_errors_count_serialization_failures_separately() {
+        let stats = CacheStats::new();
+        stats.record_populated();
+        stats.record_write_error(&CacheError::new("Connection reset"));
+        stats.record_write_error(
+            &CacheError::new("Failed to serialize value").with_kind(CacheErrorKind::Serialization),
+        );
+
+        assert_eq!(stats.populated(), 1);
+        assert_eq!(stats.errors(), 2);
+        assert_eq!(stats.serialization_errors(), 1);
+    }
+}
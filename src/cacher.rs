@@ -1,13 +1,90 @@
-use serde::Serialize;
+use crate::instrumentation;
+use crate::serializer::{JsonConfig, JsonSerializer, Serializer, printable};
+use log::debug;
 use serde::de::DeserializeOwned;
-use std::cell::RefCell;
-use std::collections::HashMap;
-use std::rc::Rc;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Number of values `list` reads per `get_multi` call by default.
+const LIST_BATCH_SIZE: usize = 100;
+
+/// Iterator over the keys and values of a cache returned by `list`.
+pub type CacheListing<'a, V> = Box<dyn Iterator<Item = Result<(String, V), CacheError>> + 'a>;
+
+/// Lists `keys` by reading their values with `get_multi` in batches of
+/// `LIST_BATCH_SIZE`, only as the iterator advances. Keys that disappeared
+/// before their batch was read are skipped, and the first error ends the
+/// iteration.
+pub(crate) fn list_entries<'a, C, V>(cache: &'a C, mut keys: Vec<String>) -> CacheListing<'a, V>
+where
+    C: CacheHandle,
+    V: Serialize + DeserializeOwned + 'a,
+{
+    keys.sort_unstable();
+    let batches = keys
+        .chunks(LIST_BATCH_SIZE)
+        .map(<[String]>::to_vec)
+        .collect::<Vec<_>>();
+    let mut failed = false;
+    Box::new(
+        batches
+            .into_iter()
+            .map_while(move |batch| {
+                if failed {
+                    return None;
+                }
+                let entries: Vec<Result<(String, V), CacheError>> =
+                    match cache.get_multi::<V>(&batch) {
+                        Ok(values) => batch
+                            .into_iter()
+                            .zip(values)
+                            .filter_map(|(key, value)| value.map(|value| Ok((key, value))))
+                            .collect(),
+                        Err(e) => {
+                            failed = true;
+                            vec![Err(e)]
+                        }
+                    };
+                Some(entries)
+            })
+            .flatten(),
+    )
+}
+
+/// Category of a `CacheError`, for callers that handle failures differently
+/// depending on their cause.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheErrorKind {
+    /// A value could not be encoded for storage.
+    Serialization,
+    /// A stored value could not be decoded, e.g. after a schema change.
+    Deserialization,
+    /// The cache backend could not be reached or the connection dropped.
+    Connection,
+    /// The backend rejected or failed the operation.
+    Backend,
+    /// The requested entry does not exist, for operations that require it.
+    NotFound,
+    /// A stored value was written under another schema version than the
+    /// one configured with `serializer::VersionedSerializer`. Handles of
+    /// shared caches treat it as a miss.
+    StaleSchema,
+    /// A stored value failed the checksum added by
+    /// `serializer::ChecksumSerializer`, e.g. after a truncated write.
+    Corruption,
+    /// A value did not match the JSON Schema of a
+    /// `validating_cacher::ValidatingCache`.
+    Validation,
+}
 
 #[derive(Debug)]
 pub struct CacheError {
+    kind: CacheErrorKind,
     message: String,
-    cause: Option<Box<dyn std::error::Error>>,
+    cause: Option<Box<dyn std::error::Error + Send + Sync>>,
 }
 
 impl std::fmt::Display for CacheError {
@@ -20,104 +97,1059 @@ impl std::fmt::Display for CacheError {
     }
 }
 
-impl std::error::Error for CacheError {}
+impl std::error::Error for CacheError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.cause
+            .as_deref()
+            .map(|cause| cause as &(dyn std::error::Error + 'static))
+    }
+}
 
 impl CacheError {
+    /// Creates an error of kind `Backend`; use `with_kind` to change it.
     pub fn new(message: &str) -> Self {
         CacheError {
+            kind: CacheErrorKind::Backend,
             message: message.to_string(),
             cause: None,
         }
     }
 
-    pub fn with_cause<E: std::error::Error + 'static>(message: &str, cause: E) -> Self {
+    /// Creates an error of kind `Backend` caused by `cause`.
+    pub fn with_cause<E: std::error::Error + Send + Sync + 'static>(message: &str, cause: E) -> Self {
         CacheError {
+            kind: CacheErrorKind::Backend,
             message: message.to_string(),
             cause: Some(Box::new(cause)),
         }
     }
+
+    pub fn with_kind(mut self, kind: CacheErrorKind) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    pub fn kind(&self) -> CacheErrorKind {
+        self.kind
+    }
 }
 
+#[allow(clippy::ptr_arg)]
 pub trait CacheHandle: Clone {
     fn get<V: Serialize + DeserializeOwned>(&self, key: &String) -> Result<Option<V>, CacheError>;
+    /// Looks up several keys at once. The returned values line up with `keys`.
+    fn get_multi<V: Serialize + DeserializeOwned>(
+        &self,
+        keys: &[String],
+    ) -> Result<Vec<Option<V>>, CacheError>;
+    /// Returns whether a live value is cached under `key`, without
+    /// deserializing it.
+    fn exists(&self, key: &String) -> Result<bool, CacheError>;
     fn put<V: Serialize + DeserializeOwned>(
         &mut self,
         key: &String,
         value: &V,
     ) -> Result<(), CacheError>;
+    /// Returns the bytes stored under `key` as they are, without
+    /// deserializing them.
+    fn get_raw(&self, key: &String) -> Result<Option<Vec<u8>>, CacheError>;
+    /// Stores already serialized `bytes` under `key` as they are, bypassing
+    /// the cache's serializer. To be read back with `get`, they must be in
+    /// that serializer's format, e.g. JSON by default.
+    fn put_raw(&mut self, key: &String, bytes: &[u8]) -> Result<(), CacheError>;
+    /// Stores a value that expires after `ttl`, after which `get` treats it as a miss.
+    fn put_with_ttl<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+        ttl: Duration,
+    ) -> Result<(), CacheError>;
+    /// Stores a value only if no live value is cached under `key`. Returns
+    /// whether the value was written.
+    fn put_if_absent<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+    ) -> Result<bool, CacheError>;
     fn delete(&mut self, key: &String) -> Result<(), CacheError>;
+    /// Deletes several keys at once, in a single round trip where the backend
+    /// supports it.
+    fn delete_multi(&mut self, keys: &[String]) -> Result<(), CacheError>;
+    /// Removes every entry owned by this cache.
+    fn clear(&mut self) -> Result<(), CacheError>;
     fn scan_keys(&self, pattern: &str) -> Result<HashMap<String, String>, CacheError>;
+    /// Returns the number of keys matching `pattern`.
+    fn count_keys(&self, pattern: &str) -> Result<usize, CacheError>;
+    /// Stores a value and associates `key` with each of `tags`, so that a
+    /// later `invalidate_tag` on any of them deletes it.
+    fn put_tagged<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+        tags: &[&str],
+    ) -> Result<(), CacheError>;
+    /// Deletes every key associated with `tag` and forgets the tag. Returns
+    /// every key recorded under the tag, including keys that were already
+    /// deleted, evicted or expired.
+    fn invalidate_tag(&mut self, tag: &str) -> Result<Vec<String>, CacheError>;
+    /// Checks that the backend is reachable without reading or writing any
+    /// key, e.g. for a readiness probe. In-process caches are always healthy.
+    fn health_check(&self) -> Result<(), CacheError>;
+
+    /// Like `get`, but also returns how long the value has left to live, or
+    /// `None` if it never expires.
+    ///
+    /// The default implementation reports every value as never expiring;
+    /// backends that track expiry override it.
+    fn get_with_ttl<V: Serialize + DeserializeOwned>(
+        &self,
+        key: &String,
+    ) -> Result<Option<(V, Option<Duration>)>, CacheError> {
+        Ok(self.get(key)?.map(|value| (value, None)))
+    }
+
+    /// Like `delete_multi`, but returns how many of `keys` held a live value
+    /// before being deleted.
+    ///
+    /// The default implementation checks each key with `exists` first, so the
+    /// count can race with concurrent writers; backends override it to count
+    /// as part of the delete.
+    fn delete_multi_counted(&mut self, keys: &[String]) -> Result<usize, CacheError> {
+        let mut existed = 0;
+        for key in keys {
+            if self.exists(key)? {
+                existed += 1;
+            }
+        }
+        self.delete_multi(keys)?;
+        Ok(existed)
+    }
+
+    /// Deletes every key matching `pattern`, with the same wildcards as
+    /// `scan_keys`, and returns how many held a live value.
+    ///
+    /// This visits every key of the cache, so it is expensive on a large
+    /// cache whatever the pattern, e.g. `student:*` makes Redis scan its
+    /// whole keyspace. Prefer known keys or `invalidate_tag` for frequent
+    /// invalidations, and keep patterns for occasional bulk operations.
+    ///
+    /// The default implementation scans the keys with `scan_keys` and then
+    /// deletes them with `delete_multi_counted`.
+    fn delete_pattern(&mut self, pattern: &str) -> Result<usize, CacheError> {
+        let keys = self.scan_keys(pattern)?.into_keys().collect::<Vec<_>>();
+        self.delete_multi_counted(&keys)
+    }
+
+    /// Like `scan_keys`, but deserializes each matching value into `V`.
+    ///
+    /// The default implementation scans the keys with `scan_keys` and then
+    /// reads them with `get_multi`, skipping keys that disappeared between
+    /// the two.
+    fn scan_typed<V: Serialize + DeserializeOwned>(
+        &self,
+        pattern: &str,
+    ) -> Result<HashMap<String, V>, CacheError> {
+        let keys = self.scan_keys(pattern)?.into_keys().collect::<Vec<_>>();
+        let values = self.get_multi::<V>(&keys)?;
+        Ok(keys
+            .into_iter()
+            .zip(values)
+            .filter_map(|(key, value)| value.map(|value| (key, value)))
+            .collect())
+    }
+
+    /// Iterates over the keys matching `pattern` along with their values
+    /// deserialized into `V`, e.g. for a cache-inspection endpoint:
+    ///
+    /// ```ignore
+    /// for entry in handle.list::<Student>("student:*")? {
+    ///     let (key, student) = entry?;
+    ///     println!("{}: {}", key, student.name);
+    /// }
+    /// ```
+    ///
+    /// Keys are returned without any namespace. Unlike `scan_typed`, values
+    /// are read in batches as the iterator advances rather than all at once,
+    /// so a large cache can be listed without holding it in memory. The
+    /// iteration ends after the first error, e.g. a value that fails to
+    /// deserialize.
+    ///
+    /// The default implementation collects the matching keys with
+    /// `scan_keys` and reads their values with `get_multi`, in key order.
+    fn list<V: Serialize + DeserializeOwned + 'static>(
+        &self,
+        pattern: &str,
+    ) -> Result<CacheListing<'_, V>, CacheError> {
+        let keys = self.scan_keys(pattern)?.into_keys().collect::<Vec<_>>();
+        Ok(list_entries(self, keys))
+    }
+
+    /// Returns the keys matching `pattern`, with the same wildcards as
+    /// `scan_keys`, without reading their values, e.g. to page through a
+    /// large cache before deciding which entries to load. Keys are returned
+    /// without any namespace, in no particular order.
+    ///
+    /// The default implementation takes the keys of `scan_keys`; backends
+    /// override it to skip reading the values altogether.
+    fn list_keys(&self, pattern: &str) -> Result<Vec<String>, CacheError> {
+        Ok(self.scan_keys(pattern)?.into_keys().collect())
+    }
+
+    /// Stores several values at once, in a single round trip where the
+    /// backend supports it.
+    ///
+    /// The default implementation calls `put` for each entry.
+    fn put_multi<V: Serialize + DeserializeOwned>(
+        &mut self,
+        entries: &[(String, V)],
+    ) -> Result<(), CacheError> {
+        for (key, value) in entries {
+            self.put(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Preloads the cache with known key/value pairs, e.g. a snapshot restored
+    /// at boot or entries known to be hot, without going through Diesel.
+    /// Returns the number of entries written.
+    fn warm<V: Serialize + DeserializeOwned>(
+        &mut self,
+        pairs: Vec<(String, V)>,
+    ) -> Result<usize, CacheError> {
+        self.put_multi(&pairs)?;
+        Ok(pairs.len())
+    }
+
+    /// Stores a value read from the database at `read_at`, for `ttl` if set.
+    /// Returns whether it was written: backends that keep invalidation
+    /// tombstones, like Redis, reject the value if the key was invalidated
+    /// after `read_at`, so a row read before a concurrent update does not
+    /// replace the invalidation made by that update.
+    ///
+    /// The default implementation ignores `read_at` and always writes.
+    fn put_read_at<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+        ttl: Option<Duration>,
+        read_at: SystemTime,
+    ) -> Result<bool, CacheError> {
+        let _ = read_at;
+        match ttl {
+            Some(ttl) => self.put_with_ttl(key, value, ttl)?,
+            None => self.put(key, value)?,
+        }
+        Ok(true)
+    }
+
+    /// Stores several values read from the database at `read_at`, as
+    /// `put_read_at` does for one. Returns the number of entries written,
+    /// leaving out those rejected because their key was invalidated after
+    /// `read_at`.
+    ///
+    /// The default implementation calls `put_read_at` for each entry.
+    fn put_multi_read_at<V: Serialize + DeserializeOwned>(
+        &mut self,
+        entries: &[(String, V)],
+        read_at: SystemTime,
+    ) -> Result<usize, CacheError> {
+        let mut written = 0;
+        for (key, value) in entries {
+            if self.put_read_at(key, value, None, read_at)? {
+                written += 1;
+            }
+        }
+        Ok(written)
+    }
+
+    /// Returns the value cached under `key`, or computes it with `f`, caches
+    /// it, and returns it.
+    ///
+    /// The default implementation does not stop concurrent callers from all
+    /// running `f` for the same missing key; backends may override it to do so.
+    fn get_or_insert_with<V, F>(&mut self, key: &String, f: F) -> Result<V, CacheError>
+    where
+        V: Serialize + DeserializeOwned,
+        F: FnOnce() -> Result<V, CacheError>,
+    {
+        if let Some(value) = self.get(key)? {
+            return Ok(value);
+        }
+        let value = f()?;
+        self.put(key, &value)?;
+        Ok(value)
+    }
+
+    /// Reads a value written by `compare_and_set`, along with its version.
+    fn get_versioned<V: Serialize + DeserializeOwned>(
+        &self,
+        key: &String,
+    ) -> Result<Option<VersionedValue<V>>, CacheError> {
+        self.get(key)
+    }
+
+    /// Stores `value` under `key` only if the version currently stored there
+    /// is `expected_version`, where a missing or expired key is version 0.
+    /// The value is stored as a `VersionedValue` with the next version, to be
+    /// read back with `get_versioned`. Returns whether the value was written;
+    /// on `false`, another writer got there first, and the caller can read
+    /// the new version and retry:
+    ///
+    /// ```ignore
+    /// loop {
+    ///     let (count, version) = match handle.get_versioned::<u64>(&key)? {
+    ///         Some(current) => (current.value, current.version),
+    ///         None => (0, 0),
+    ///     };
+    ///     if handle.compare_and_set(&key, version, &(count + 1))? {
+    ///         break;
+    ///     }
+    /// }
+    /// ```
+    ///
+    /// A key holding a value written some other way, e.g. by `put`, fails to
+    /// deserialize as a `VersionedValue`.
+    ///
+    /// The default implementation fails with a `Backend` error; backends that
+    /// can compare and swap atomically override it.
+    fn compare_and_set<V: Serialize + DeserializeOwned>(
+        &mut self,
+        _key: &String,
+        _expected_version: u64,
+        _value: &V,
+    ) -> Result<bool, CacheError> {
+        Err(
+            CacheError::new("compare_and_set is not supported by this cache")
+                .with_kind(CacheErrorKind::Backend),
+        )
+    }
+
+    /// Returns when the live value under `key` was stored, when it was last
+    /// read and how many times, or `None` if no live value is cached, e.g.
+    /// for cache analytics. Reading the metadata does not count as a read.
+    ///
+    /// The default implementation fails with a `Backend` error; backends
+    /// that keep this metadata override it.
+    fn entry_info(&self, _key: &String) -> Result<Option<EntryInfo>, CacheError> {
+        Err(
+            CacheError::new("entry_info is not supported by this cache")
+                .with_kind(CacheErrorKind::Backend),
+        )
+    }
+}
+
+/// Metadata of a cached value, returned by `CacheHandle::entry_info`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EntryInfo {
+    /// When the value was stored. Overwriting a key resets its metadata.
+    pub created_at: SystemTime,
+    /// When the value was last read, or `None` if it has not been read.
+    pub last_accessed: Option<SystemTime>,
+    /// How many times the value has been read, e.g. with `get` or
+    /// `get_multi`.
+    pub hit_count: u64,
+}
+
+/// Value stored by `compare_and_set`, tagged with a version that each
+/// successful `compare_and_set` increments.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionedValue<V> {
+    pub version: u64,
+    pub value: V,
+}
+
+/// Returns the version of a live value written by `compare_and_set`, or 0
+/// if there is none.
+pub(crate) fn stored_version<V, S>(serializer: &S, bytes: Option<&[u8]>) -> Result<u64, CacheError>
+where
+    V: DeserializeOwned,
+    S: Serializer,
+{
+    match bytes {
+        Some(bytes) => Ok(serializer.deserialize::<VersionedValue<V>>(bytes)?.version),
+        None => Ok(0),
+    }
 }
 
 #[derive(Debug)]
-pub struct HashmapCache {
-    map: Rc<RefCell<HashMap<String, String>>>,
+struct HashmapEntry {
+    value: Vec<u8>,
+    expires_at: Option<Instant>,
+    last_used: AtomicU64,
+    created_at: SystemTime,
+    hits: AtomicU64,
+    /// Milliseconds from the Unix epoch to the last read, or 0 if the value
+    /// has not been read.
+    last_read_ms: AtomicU64,
+}
+
+impl HashmapEntry {
+    fn new(value: Vec<u8>, expires_at: Option<Instant>, last_used: u64) -> Self {
+        HashmapEntry {
+            value,
+            expires_at,
+            last_used: AtomicU64::new(last_used),
+            created_at: SystemTime::now(),
+            hits: AtomicU64::new(0),
+            last_read_ms: AtomicU64::new(0),
+        }
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|t| t <= now)
+    }
+
+    fn info(&self) -> EntryInfo {
+        let last_read_ms = self.last_read_ms.load(Ordering::Relaxed);
+        EntryInfo {
+            created_at: self.created_at,
+            last_accessed: (last_read_ms > 0)
+                .then(|| SystemTime::UNIX_EPOCH + Duration::from_millis(last_read_ms)),
+            hit_count: self.hits.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Keys of a bounded cache ordered by last use and by expiry, so that
+/// eviction finds its victims without scanning every entry.
+#[derive(Debug, Default)]
+struct EvictionOrder {
+    by_use: BTreeMap<u64, String>,
+    by_expiry: BTreeSet<(Instant, String)>,
+}
+
+#[derive(Debug)]
+struct HashmapStore {
+    entries: RwLock<HashMap<String, HashmapEntry>>,
+    /// Keys associated with each tag by `put_tagged`. Keys are not removed
+    /// when their entry is deleted or evicted, only when the tag is
+    /// invalidated or the cache is cleared.
+    tags: RwLock<HashMap<String, HashSet<String>>>,
+    max_entries: Option<usize>,
+    max_bytes: Option<usize>,
+    /// Only kept when the cache is bounded. Updated with the entries, under
+    /// their write lock, except for reads, which reorder their key under
+    /// the read lock.
+    order: Option<Mutex<EvictionOrder>>,
+    /// Serialized size of all entries, only updated under the entries' write
+    /// lock.
+    bytes: AtomicUsize,
+    clock: AtomicU64,
+}
+
+impl HashmapStore {
+    fn new(max_entries: Option<usize>, max_bytes: Option<usize>) -> Self {
+        HashmapStore {
+            entries: RwLock::new(HashMap::new()),
+            tags: RwLock::new(HashMap::new()),
+            max_entries,
+            max_bytes,
+            order: (max_entries.is_some() || max_bytes.is_some())
+                .then(|| Mutex::new(EvictionOrder::default())),
+            bytes: AtomicUsize::new(0),
+            clock: AtomicU64::new(0),
+        }
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn order(&self) -> Option<MutexGuard<'_, EvictionOrder>> {
+        self.order
+            .as_ref()
+            .map(|order| order.lock().unwrap_or_else(|poisoned| poisoned.into_inner()))
+    }
+
+    /// Marks the `entry` under `key` as read, for eviction and `entry_info`.
+    fn touch(&self, key: &str, entry: &HashmapEntry) {
+        let tick = self.tick();
+        match self.order() {
+            Some(mut order) => {
+                let previous = entry.last_used.swap(tick, Ordering::Relaxed);
+                order.by_use.remove(&previous);
+                order.by_use.insert(tick, key.to_string());
+            }
+            None => entry.last_used.store(tick, Ordering::Relaxed),
+        }
+        entry.hits.fetch_add(1, Ordering::Relaxed);
+        let now_ms = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        entry.last_read_ms.store(now_ms, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug)]
+pub struct HashmapCache<S: Serializer = JsonSerializer> {
+    store: Arc<HashmapStore>,
+    serializer: S,
+}
+
+impl Default for HashmapCache {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl HashmapCache {
     pub fn new() -> Self {
         HashmapCache {
-            map: Rc::new(RefCell::new(HashMap::new())),
+            store: Arc::new(HashmapStore::new(None, None)),
+            serializer: JsonSerializer,
         }
     }
 
-    pub fn handle(&self) -> HashmapCacheHandle {
+    /// Creates a cache holding at most `max_entries` entries. Once full,
+    /// storing a new key evicts the least-recently-used entry, where both
+    /// `get` and `put` count as a use. A capacity of zero stores nothing.
+    pub fn with_capacity(max_entries: usize) -> Self {
+        HashmapCache {
+            store: Arc::new(HashmapStore::new(Some(max_entries), None)),
+            serializer: JsonSerializer,
+        }
+    }
+
+    /// Creates a cache holding at most `max_bytes` of serialized values. Once
+    /// full, storing a value evicts least-recently-used entries until it
+    /// fits. A value larger than `max_bytes` on its own is not stored.
+    pub fn with_byte_capacity(max_bytes: usize) -> Self {
+        HashmapCache {
+            store: Arc::new(HashmapStore::new(None, Some(max_bytes))),
+            serializer: JsonSerializer,
+        }
+    }
+}
+
+impl<S: Serializer> HashmapCache<S> {
+    /// Replaces the serializer used to encode stored values. Must be called
+    /// before any handles are created.
+    pub fn with_serializer<S2: Serializer>(self, serializer: S2) -> HashmapCache<S2> {
+        HashmapCache {
+            store: self.store,
+            serializer,
+        }
+    }
+
+    /// Stores values as JSON formatted by `config`, e.g. pretty-printed for
+    /// inspection. Must be called before any handles are created.
+    pub fn with_json_config(self, config: JsonConfig) -> HashmapCache<JsonConfig> {
+        self.with_serializer(config)
+    }
+
+    pub fn handle(&self) -> HashmapCacheHandle<S> {
         HashmapCacheHandle {
-            map: Rc::clone(&self.map),
+            store: Arc::clone(&self.store),
+            serializer: self.serializer.clone(),
         }
     }
 }
 
-pub struct HashmapCacheHandle {
-    map: Rc<RefCell<HashMap<String, String>>>,
+/// Handle to a `HashmapCache`.
+///
+/// Handles are cheap to clone and can be shared between threads; all clones
+/// operate on the same underlying map. Reads take a shared lock while writes
+/// take an exclusive one.
+pub struct HashmapCacheHandle<S: Serializer = JsonSerializer> {
+    store: Arc<HashmapStore>,
+    serializer: S,
+}
+
+impl<S: Serializer> HashmapCacheHandle<S> {
+    /// Returns the number of entries currently held by the cache.
+    pub fn len(&self) -> usize {
+        self.store
+            .entries
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the total serialized size of the values currently held by the
+    /// cache, including expired entries that have not been evicted yet.
+    pub fn current_bytes(&self) -> usize {
+        self.store.bytes.load(Ordering::Relaxed)
+    }
+
+    fn read(&self) -> Result<RwLockReadGuard<'_, HashMap<String, HashmapEntry>>, CacheError> {
+        self.store
+            .entries
+            .read()
+            .map_err(|_| CacheError::new("Cache lock is poisoned"))
+    }
+
+    fn write(&self) -> Result<RwLockWriteGuard<'_, HashMap<String, HashmapEntry>>, CacheError> {
+        self.store
+            .entries
+            .write()
+            .map_err(|_| CacheError::new("Cache lock is poisoned"))
+    }
+
+    fn tags(&self) -> Result<RwLockWriteGuard<'_, HashMap<String, HashSet<String>>>, CacheError> {
+        self.store
+            .tags
+            .write()
+            .map_err(|_| CacheError::new("Cache lock is poisoned"))
+    }
+
+    /// Makes room for a value of `size` bytes under `key`, evicting expired
+    /// and then least-recently-used entries. Returns false if the value does
+    /// not fit even in an empty cache.
+    fn evict_for_insert(
+        &self,
+        map: &mut HashMap<String, HashmapEntry>,
+        key: &str,
+        size: usize,
+    ) -> bool {
+        let (max_entries, max_bytes) = (self.store.max_entries, self.store.max_bytes);
+        if max_bytes.is_some_and(|max_bytes| size > max_bytes) {
+            return false;
+        }
+        if max_bytes.is_none() && (max_entries.is_none() || map.contains_key(key)) {
+            return true;
+        }
+        let now = Instant::now();
+        while let Some(expired_key) = self.store.order().and_then(|order| {
+            order
+                .by_expiry
+                .first()
+                .filter(|(expires_at, _)| *expires_at <= now)
+                .map(|(_, k)| k.clone())
+        }) {
+            self.remove_entry(map, &expired_key);
+        }
+        loop {
+            let replaced = map.get(key).map(|entry| entry.value.len());
+            let over_entries =
+                max_entries.is_some_and(|max| replaced.is_none() && map.len() >= max);
+            let over_bytes = max_bytes.is_some_and(|max| {
+                self.store.bytes.load(Ordering::Relaxed) - replaced.unwrap_or(0) + size > max
+            });
+            if !over_entries && !over_bytes {
+                return true;
+            }
+            let lru_key = self.store.order().and_then(|order| {
+                order
+                    .by_use
+                    .values()
+                    .find(|k| k.as_str() != key)
+                    .cloned()
+            });
+            // Nothing else to evict, which only happens with a capacity of
+            // zero.
+            let Some(lru_key) = lru_key else {
+                return false;
+            };
+            debug!("Evicting least recently used key: {}", lru_key);
+            self.remove_entry(map, &lru_key);
+        }
+    }
+
+    /// Stores `entry` under `key` if it fits, keeping the byte count current.
+    fn insert_entry(
+        &self,
+        map: &mut HashMap<String, HashmapEntry>,
+        key: &str,
+        entry: HashmapEntry,
+    ) {
+        let size = entry.value.len();
+        let fits = self.evict_for_insert(map, key, size);
+        self.remove_entry(map, key);
+        if !fits {
+            debug!("Not caching key {}: {} bytes do not fit in the cache", key, size);
+            return;
+        }
+        self.store.bytes.fetch_add(size, Ordering::Relaxed);
+        if let Some(mut order) = self.store.order() {
+            order
+                .by_use
+                .insert(entry.last_used.load(Ordering::Relaxed), key.to_string());
+            if let Some(expires_at) = entry.expires_at {
+                order.by_expiry.insert((expires_at, key.to_string()));
+            }
+        }
+        map.insert(key.to_string(), entry);
+    }
+
+    fn remove_entry(
+        &self,
+        map: &mut HashMap<String, HashmapEntry>,
+        key: &str,
+    ) -> Option<HashmapEntry> {
+        let entry = map.remove(key)?;
+        self.store.bytes.fetch_sub(entry.value.len(), Ordering::Relaxed);
+        if let Some(mut order) = self.store.order() {
+            order.by_use.remove(&entry.last_used.load(Ordering::Relaxed));
+            if let Some(expires_at) = entry.expires_at {
+                order.by_expiry.remove(&(expires_at, key.to_string()));
+            }
+        }
+        Some(entry)
+    }
+
+    fn insert<V: Serialize>(
+        &mut self,
+        key: &str,
+        value: &V,
+        ttl: Option<Duration>,
+    ) -> Result<(), CacheError> {
+        let value = self.serializer.serialize(value)?;
+        self.insert_raw(key, value, ttl)
+    }
+
+    fn insert_raw(
+        &mut self,
+        key: &str,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> Result<(), CacheError> {
+        let entry = HashmapEntry::new(
+            value,
+            ttl.map(|ttl| Instant::now() + ttl),
+            self.store.tick(),
+        );
+        instrumentation::stored(key, entry.value.len());
+        let mut map = self.write()?;
+        self.insert_entry(&mut map, key, entry);
+        Ok(())
+    }
+
+    /// Passes the bytes of the live entry under `key` to `f`, evicting the
+    /// entry instead if it has expired.
+    fn read_live<T>(
+        &self,
+        key: &str,
+        f: impl FnOnce(&[u8]) -> Result<T, CacheError>,
+    ) -> Result<Option<T>, CacheError> {
+        let now = Instant::now();
+        {
+            let map = self.read()?;
+            match map.get(key) {
+                Some(entry) if !entry.is_expired(now) => {
+                    self.store.touch(key, entry);
+                    return f(&entry.value).map(Some);
+                }
+                Some(_) => {}
+                None => return Ok(None),
+            }
+        }
+        // The entry has expired; evict it so the map doesn't grow unbounded.
+        let mut map = self.write()?;
+        if map.get(key).is_some_and(|entry| entry.is_expired(now)) {
+            self.remove_entry(&mut map, key);
+        }
+        Ok(None)
+    }
 }
 
-impl CacheHandle for HashmapCacheHandle {
+impl<S: Serializer> CacheHandle for HashmapCacheHandle<S> {
     fn get<V: Serialize + DeserializeOwned>(&self, key: &String) -> Result<Option<V>, CacheError> {
-        let map = self.map.borrow();
-        let value = map.get(key);
-        match value {
-            Some(v) => serde_json::from_str::<V>(v.as_str())
-                .map(|x| Some(x))
-                .map_err(|e| CacheError::with_cause("Failed to deserialize value", e)),
-            None => Ok(None),
+        self.read_live(key, |bytes| self.serializer.deserialize::<V>(bytes))
+    }
+
+    fn get_with_ttl<V: Serialize + DeserializeOwned>(
+        &self,
+        key: &String,
+    ) -> Result<Option<(V, Option<Duration>)>, CacheError> {
+        let now = Instant::now();
+        let map = self.read()?;
+        match map.get(key) {
+            Some(entry) if !entry.is_expired(now) => {
+                self.store.touch(key, entry);
+                let value = self.serializer.deserialize::<V>(&entry.value)?;
+                let remaining = entry.expires_at.map(|t| t.saturating_duration_since(now));
+                Ok(Some((value, remaining)))
+            }
+            _ => Ok(None),
         }
     }
 
+    fn get_multi<V: Serialize + DeserializeOwned>(
+        &self,
+        keys: &[String],
+    ) -> Result<Vec<Option<V>>, CacheError> {
+        let now = Instant::now();
+        let map = self.read()?;
+        keys.iter()
+            .map(|key| match map.get(key) {
+                Some(entry) if !entry.is_expired(now) => {
+                    self.store.touch(key, entry);
+                    self.serializer.deserialize::<V>(&entry.value).map(Some)
+                }
+                _ => Ok(None),
+            })
+            .collect()
+    }
+
+    fn exists(&self, key: &String) -> Result<bool, CacheError> {
+        let now = Instant::now();
+        Ok(self
+            .read()?
+            .get(key)
+            .is_some_and(|entry| !entry.is_expired(now)))
+    }
+
+    /// Every read of the value counts, e.g. with `get`, `get_multi`,
+    /// `get_with_ttl` or `list`, but not `exists`.
+    fn entry_info(&self, key: &String) -> Result<Option<EntryInfo>, CacheError> {
+        let now = Instant::now();
+        Ok(self
+            .read()?
+            .get(key)
+            .filter(|entry| !entry.is_expired(now))
+            .map(HashmapEntry::info))
+    }
+
     fn put<V: Serialize + DeserializeOwned>(
         &mut self,
         key: &String,
         value: &V,
     ) -> Result<(), CacheError> {
-        self.map.borrow_mut().insert(
-            key.clone(),
-            serde_json::to_string(value)
-                .map_err(|e| CacheError::with_cause("Failed to serialize value", e))?,
-        );
+        self.insert(key, value, None)
+    }
+
+    fn get_raw(&self, key: &String) -> Result<Option<Vec<u8>>, CacheError> {
+        self.read_live(key, |bytes| Ok(bytes.to_vec()))
+    }
+
+    fn put_raw(&mut self, key: &String, bytes: &[u8]) -> Result<(), CacheError> {
+        self.insert_raw(key, bytes.to_vec(), None)
+    }
+
+    fn put_with_ttl<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+        ttl: Duration,
+    ) -> Result<(), CacheError> {
+        self.insert(key, value, Some(ttl))
+    }
+
+    /// Serializes every value first, then inserts them all under one write
+    /// lock.
+    fn put_multi<V: Serialize + DeserializeOwned>(
+        &mut self,
+        entries: &[(String, V)],
+    ) -> Result<(), CacheError> {
+        let serialized = entries
+            .iter()
+            .map(|(key, value)| Ok((key, self.serializer.serialize(value)?)))
+            .collect::<Result<Vec<_>, CacheError>>()?;
+        let mut map = self.write()?;
+        for (key, value) in serialized {
+            instrumentation::stored(key, value.len());
+            let entry = HashmapEntry::new(value, None, self.store.tick());
+            self.insert_entry(&mut map, key, entry);
+        }
         Ok(())
     }
 
+    /// Keeps no tombstones, so writes every entry in one `put_multi`.
+    fn put_multi_read_at<V: Serialize + DeserializeOwned>(
+        &mut self,
+        entries: &[(String, V)],
+        read_at: SystemTime,
+    ) -> Result<usize, CacheError> {
+        let _ = read_at;
+        self.put_multi(entries)?;
+        Ok(entries.len())
+    }
+
+    fn put_if_absent<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+    ) -> Result<bool, CacheError> {
+        let value = self.serializer.serialize(value)?;
+        let now = Instant::now();
+        let mut map = self.write()?;
+        if map.get(key).is_some_and(|entry| !entry.is_expired(now)) {
+            return Ok(false);
+        }
+        let entry = HashmapEntry::new(value, None, self.store.tick());
+        self.insert_entry(&mut map, key, entry);
+        Ok(true)
+    }
+
+    /// Compares and swaps under the write lock.
+    fn compare_and_set<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        expected_version: u64,
+        value: &V,
+    ) -> Result<bool, CacheError> {
+        let value = self.serializer.serialize(&VersionedValue {
+            version: expected_version + 1,
+            value,
+        })?;
+        let now = Instant::now();
+        let mut map = self.write()?;
+        let current = map
+            .get(key)
+            .filter(|entry| !entry.is_expired(now))
+            .map(|entry| entry.value.as_slice());
+        if stored_version::<V, S>(&self.serializer, current)? != expected_version {
+            return Ok(false);
+        }
+        instrumentation::stored(key, value.len());
+        let entry = HashmapEntry::new(value, None, self.store.tick());
+        self.insert_entry(&mut map, key, entry);
+        Ok(true)
+    }
+
     fn delete(&mut self, key: &String) -> Result<(), CacheError> {
-        self.map.borrow_mut().remove(key);
+        let mut map = self.write()?;
+        self.remove_entry(&mut map, key);
+        Ok(())
+    }
+
+    fn delete_multi(&mut self, keys: &[String]) -> Result<(), CacheError> {
+        self.delete_multi_counted(keys).map(|_| ())
+    }
+
+    fn delete_multi_counted(&mut self, keys: &[String]) -> Result<usize, CacheError> {
+        let now = Instant::now();
+        let mut map = self.write()?;
+        Ok(keys
+            .iter()
+            .filter_map(|key| self.remove_entry(&mut map, key))
+            .filter(|entry| !entry.is_expired(now))
+            .count())
+    }
+
+    /// Selects and removes the matching keys under a single write lock.
+    fn delete_pattern(&mut self, pattern: &str) -> Result<usize, CacheError> {
+        let wild = wildmatch::WildMatch::new(pattern);
+        let now = Instant::now();
+        let mut map = self.write()?;
+        let keys = map
+            .keys()
+            .filter(|k| wild.matches(k))
+            .cloned()
+            .collect::<Vec<_>>();
+        Ok(keys
+            .iter()
+            .filter_map(|key| self.remove_entry(&mut map, key))
+            .filter(|entry| !entry.is_expired(now))
+            .count())
+    }
+
+    fn clear(&mut self) -> Result<(), CacheError> {
+        {
+            let mut map = self.write()?;
+            map.clear();
+            self.store.bytes.store(0, Ordering::Relaxed);
+            if let Some(mut order) = self.store.order() {
+                *order = EvictionOrder::default();
+            }
+        }
+        self.tags()?.clear();
         Ok(())
     }
 
     fn scan_keys(&self, pattern: &str) -> Result<HashMap<String, String>, CacheError> {
         let wild = wildmatch::WildMatch::new(pattern);
+        let now = Instant::now();
         Ok(self
-            .map
-            .borrow()
+            .read()?
             .iter()
-            .filter(|(k, _)| wild.matches(k))
-            .map(|(k, v)| (k.clone(), v.clone()))
+            .filter(|(k, entry)| !entry.is_expired(now) && wild.matches(k))
+            .map(|(k, entry)| (k.clone(), printable(&entry.value)))
             .collect::<HashMap<String, String>>())
     }
+
+    fn scan_typed<V: Serialize + DeserializeOwned>(
+        &self,
+        pattern: &str,
+    ) -> Result<HashMap<String, V>, CacheError> {
+        let wild = wildmatch::WildMatch::new(pattern);
+        let now = Instant::now();
+        self.read()?
+            .iter()
+            .filter(|(k, entry)| !entry.is_expired(now) && wild.matches(k))
+            .map(|(k, entry)| Ok((k.clone(), self.serializer.deserialize(&entry.value)?)))
+            .collect()
+    }
+
+    /// Collects the matching keys under the read lock, without copying their
+    /// values, which are then read in batches.
+    fn list<V: Serialize + DeserializeOwned + 'static>(
+        &self,
+        pattern: &str,
+    ) -> Result<CacheListing<'_, V>, CacheError> {
+        let wild = wildmatch::WildMatch::new(pattern);
+        let now = Instant::now();
+        let keys = self
+            .read()?
+            .iter()
+            .filter(|(k, entry)| !entry.is_expired(now) && wild.matches(k))
+            .map(|(k, _)| k.clone())
+            .collect();
+        Ok(list_entries(self, keys))
+    }
+
+    fn list_keys(&self, pattern: &str) -> Result<Vec<String>, CacheError> {
+        let wild = wildmatch::WildMatch::new(pattern);
+        let now = Instant::now();
+        Ok(self
+            .read()?
+            .iter()
+            .filter(|(k, entry)| !entry.is_expired(now) && wild.matches(k))
+            .map(|(k, _)| k.clone())
+            .collect())
+    }
+
+    fn count_keys(&self, pattern: &str) -> Result<usize, CacheError> {
+        let wild = wildmatch::WildMatch::new(pattern);
+        let now = Instant::now();
+        Ok(self
+            .read()?
+            .iter()
+            .filter(|(k, entry)| !entry.is_expired(now) && wild.matches(k))
+            .count())
+    }
+
+    fn put_tagged<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+        tags: &[&str],
+    ) -> Result<(), CacheError> {
+        // Index the key first, so a concurrent `invalidate_tag` can't miss a
+        // value that is already visible.
+        {
+            let mut index = self.tags()?;
+            for tag in tags {
+                index.entry(tag.to_string()).or_default().insert(key.clone());
+            }
+        }
+        self.insert(key, value, None)
+    }
+
+    fn invalidate_tag(&mut self, tag: &str) -> Result<Vec<String>, CacheError> {
+        let keys = self
+            .tags()?
+            .remove(tag)
+            .map(|keys| keys.into_iter().collect::<Vec<_>>())
+            .unwrap_or_default();
+        self.delete_multi(&keys)?;
+        Ok(keys)
+    }
+
+    fn health_check(&self) -> Result<(), CacheError> {
+        Ok(())
+    }
 }
 
-impl Clone for HashmapCacheHandle {
+impl<S: Serializer> Clone for HashmapCacheHandle<S> {
     fn clone(&self) -> Self {
         HashmapCacheHandle {
-            map: Rc::clone(&self.map),
+            store: Arc::clone(&self.store),
+            serializer: self.serializer.clone(),
         }
     }
 }
@@ -150,5 +1182,438 @@ mod tests {
             .expect("Failed to get value from cache");
 
         assert_eq!(retrieved_not_found, None);
+
+        assert!(handle.exists(&key).unwrap());
+        assert!(!handle.exists(&non_existing_key).unwrap());
+    }
+
+    #[test]
+    fn test_put_with_ttl_expires_entry() {
+        let cache = HashmapCache::new();
+        let mut handle = cache.handle();
+
+        let key = "ttl_key".to_string();
+        let value = "ttl_value".to_string();
+        handle
+            .put_with_ttl(&key, &value, Duration::from_millis(20))
+            .expect("Failed to put value into cache");
+        assert_eq!(handle.get(&key).unwrap(), Some(value));
+
+        std::thread::sleep(Duration::from_millis(30));
+
+        // The expired entry is a miss, and it is evicted from the map on access.
+        assert_eq!(handle.get::<String>(&key).unwrap(), None);
+        assert!(cache.handle().is_empty());
+    }
+
+    #[test]
+    fn test_warm_respects_capacity() {
+        let cache = HashmapCache::with_capacity(2);
+        let mut handle = cache.handle();
+        let pairs = (1..=3)
+            .map(|id| (format!("student:{}", id), id))
+            .collect::<Vec<_>>();
+        assert_eq!(handle.warm(pairs).unwrap(), 3);
+
+        // The first pair is the least recently used, so it was evicted.
+        let keys = ["student:1", "student:2", "student:3"].map(String::from);
+        assert_eq!(
+            handle.get_multi::<i32>(&keys).unwrap(),
+            vec![None, Some(2), Some(3)]
+        );
+    }
+
+    #[test]
+    fn test_byte_capacity_evicts_until_value_fits() {
+        let cache = HashmapCache::with_byte_capacity(20);
+        let mut handle = cache.handle();
+        let [a, b, c, big] = ["a", "b", "c", "big"].map(String::from);
+
+        // JSON strings take two bytes for the quotes.
+        handle.put(&a, &"aaaa".to_string()).unwrap();
+        handle.put(&b, &"bbbb".to_string()).unwrap();
+        assert_eq!(handle.current_bytes(), 12);
+
+        // "a" was read more recently, so "b" is evicted to make room.
+        handle.get::<String>(&a).unwrap();
+        handle.put(&c, &"cccccccc".to_string()).unwrap();
+        assert_eq!(handle.current_bytes(), 16);
+        assert!(handle.exists(&a).unwrap() && !handle.exists(&b).unwrap());
+
+        // Replacing a key only counts the difference in size.
+        handle.put(&c, &"cc".to_string()).unwrap();
+        assert_eq!(handle.current_bytes(), 10);
+
+        // A value larger than the whole budget is not stored.
+        handle.put(&big, &"x".repeat(30)).unwrap();
+        assert!(!handle.exists(&big).unwrap());
+        assert_eq!(handle.current_bytes(), 10);
+
+        handle.delete(&a).unwrap();
+        assert_eq!(handle.current_bytes(), 4);
+        handle.clear().unwrap();
+        assert_eq!(handle.current_bytes(), 0);
+    }
+
+    #[test]
+    fn test_scan_typed_deserializes_matching_values() {
+        let mut handle = HashmapCache::new().handle();
+        handle.put(&"student:1".to_string(), &1).unwrap();
+        handle.put(&"student:2".to_string(), &2).unwrap();
+        handle.put(&"course:1".to_string(), &10).unwrap();
+        handle
+            .put_with_ttl(&"student:3".to_string(), &3, Duration::from_millis(10))
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+
+        let values = handle.scan_typed::<i32>("student:*").unwrap();
+        assert_eq!(
+            values,
+            HashMap::from([("student:1".to_string(), 1), ("student:2".to_string(), 2)])
+        );
+        assert!(handle.scan_typed::<String>("student:*").is_err());
+    }
+
+    #[test]
+    fn test_list_reads_matching_values_in_batches() {
+        let mut handle = HashmapCache::new().handle();
+        let entries = (0..250)
+            .map(|i| (format!("student:{:03}", i), i))
+            .collect::<Vec<_>>();
+        handle.put_multi(&entries).unwrap();
+        handle.put(&"course:1".to_string(), &1000).unwrap();
+
+        let listed = handle
+            .list::<i32>("student:*")
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(listed, entries);
+
+        // The first batch fails to deserialize, and nothing follows it.
+        let mut strings = handle.list::<String>("student:*").unwrap();
+        assert!(strings.next().unwrap().is_err());
+        assert!(strings.next().is_none());
+    }
+
+    #[test]
+    fn test_list_keys_skips_values() {
+        let mut handle = HashmapCache::new().handle();
+        handle.put(&"student:1".to_string(), &1).unwrap();
+        // Not valid JSON, so reading it would fail.
+        handle.put_raw(&"student:2".to_string(), b"{").unwrap();
+        handle.put(&"course:1".to_string(), &10).unwrap();
+        handle
+            .put_with_ttl(&"student:3".to_string(), &3, Duration::from_millis(10))
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+
+        let mut keys = handle.list_keys("student:*").unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["student:1".to_string(), "student:2".to_string()]);
+        assert!(handle.list_keys("teacher:*").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_entry_info_counts_reads_until_overwritten() {
+        let mut handle = HashmapCache::new().handle();
+        let key = "student:2".to_string();
+        assert_eq!(handle.entry_info(&key).unwrap(), None);
+
+        let before = SystemTime::now();
+        handle.put(&key, &2).unwrap();
+        let info = handle.entry_info(&key).unwrap().unwrap();
+        assert!(info.created_at >= before);
+        assert_eq!(info.last_accessed, None);
+        assert_eq!(info.hit_count, 0);
+
+        handle.get::<i32>(&key).unwrap();
+        handle.get_multi::<i32>(std::slice::from_ref(&key)).unwrap();
+        assert!(handle.exists(&key).unwrap());
+        let info = handle.entry_info(&key).unwrap().unwrap();
+        assert_eq!(info.hit_count, 2);
+        assert!(info.last_accessed.is_some());
+
+        handle.put(&key, &3).unwrap();
+        assert_eq!(handle.entry_info(&key).unwrap().unwrap().hit_count, 0);
+
+        handle.put_with_ttl(&key, &4, Duration::from_millis(10)).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(handle.entry_info(&key).unwrap(), None);
+    }
+
+    #[test]
+    fn test_raw_bytes_round_trip_through_typed_get() {
+        let mut handle = HashmapCache::new().handle();
+        let key = "student:2".to_string();
+        assert_eq!(handle.get_raw(&key).unwrap(), None);
+
+        // Bytes serialized elsewhere, in the cache's JSON format.
+        handle.put_raw(&key, br#"{"name":"Ori"}"#).unwrap();
+        assert_eq!(
+            handle.get::<HashMap<String, String>>(&key).unwrap(),
+            Some(HashMap::from([("name".to_string(), "Ori".to_string())]))
+        );
+
+        handle.put(&key, &2).unwrap();
+        assert_eq!(handle.get_raw(&key).unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn test_delete_multi_counted_skips_missing_and_expired_keys() {
+        let mut handle = HashmapCache::new().handle();
+        let live = "live".to_string();
+        let expired = "expired".to_string();
+        handle.put(&live, &1).unwrap();
+        handle
+            .put_with_ttl(&expired, &2, Duration::from_millis(10))
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+
+        let keys = [live.clone(), expired.clone(), "missing".to_string()];
+        assert_eq!(handle.delete_multi_counted(&keys).unwrap(), 1);
+        assert!(handle.is_empty());
+        assert_eq!(handle.delete_multi_counted(&keys).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_delete_pattern_removes_matching_keys() {
+        let mut handle = HashmapCache::new().handle();
+        handle.put(&"student:1".to_string(), &1).unwrap();
+        handle.put(&"student:2".to_string(), &2).unwrap();
+        handle.put(&"course:1".to_string(), &3).unwrap();
+
+        assert_eq!(handle.delete_pattern("student:*").unwrap(), 2);
+        assert_eq!(handle.count_keys("*").unwrap(), 1);
+        assert!(handle.exists(&"course:1".to_string()).unwrap());
+        assert_eq!(handle.delete_pattern("student:*").unwrap(), 0);
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn test_put_and_get_with_msgpack_serializer() {
+        use crate::serializer::MessagePackSerializer;
+
+        let cache = HashmapCache::new().with_serializer(MessagePackSerializer);
+        let mut handle = cache.handle();
+
+        let key = "packed_key".to_string();
+        let value = (42u32, "packed_value".to_string());
+        handle.put(&key, &value).expect("Failed to put value into cache");
+        assert_eq!(handle.get(&key).unwrap(), Some(value));
+    }
+
+    #[test]
+    fn test_get_multi_preserves_key_order() {
+        let cache = HashmapCache::new();
+        let mut handle = cache.handle();
+
+        handle.put(&"a".to_string(), &1).unwrap();
+        handle.put(&"c".to_string(), &3).unwrap();
+
+        let keys = vec!["c".to_string(), "b".to_string(), "a".to_string()];
+        let values: Vec<Option<i32>> = handle.get_multi(&keys).unwrap();
+        assert_eq!(values, vec![Some(3), None, Some(1)]);
+    }
+
+    #[test]
+    fn test_with_capacity_evicts_least_recently_used() {
+        let cache = HashmapCache::with_capacity(2);
+        let mut handle = cache.handle();
+
+        handle.put(&"a".to_string(), &1).unwrap();
+        handle.put(&"b".to_string(), &2).unwrap();
+        // Reading "a" makes "b" the least recently used entry.
+        assert_eq!(handle.get::<i32>(&"a".to_string()).unwrap(), Some(1));
+        handle.put(&"c".to_string(), &3).unwrap();
+
+        assert_eq!(handle.len(), 2);
+        assert_eq!(handle.get::<i32>(&"b".to_string()).unwrap(), None);
+        assert_eq!(handle.get::<i32>(&"a".to_string()).unwrap(), Some(1));
+        assert_eq!(handle.get::<i32>(&"c".to_string()).unwrap(), Some(3));
+
+        // Overwriting an existing key never evicts.
+        handle.put(&"c".to_string(), &4).unwrap();
+        assert_eq!(handle.len(), 2);
+        assert_eq!(handle.get::<i32>(&"a".to_string()).unwrap(), Some(1));
+    }
+
+    #[test]
+    fn test_with_capacity_evicts_expired_entries_first() {
+        let cache = HashmapCache::with_capacity(2);
+        let mut handle = cache.handle();
+
+        handle
+            .put_with_ttl(&"a".to_string(), &1, Duration::from_millis(10))
+            .unwrap();
+        handle.put(&"b".to_string(), &2).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        // "b" is the least recently used live entry, but "a" has expired.
+        handle.put(&"c".to_string(), &3).unwrap();
+
+        assert_eq!(handle.len(), 2);
+        assert_eq!(handle.get::<i32>(&"b".to_string()).unwrap(), Some(2));
+        assert_eq!(handle.get::<i32>(&"c".to_string()).unwrap(), Some(3));
+    }
+
+    #[test]
+    fn test_zero_capacity_stores_nothing() {
+        let cache = HashmapCache::with_capacity(0);
+        let mut handle = cache.handle();
+
+        handle.put(&"a".to_string(), &1).unwrap();
+        assert!(handle.is_empty());
+        assert_eq!(handle.get::<i32>(&"a".to_string()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_get_with_ttl_reports_remaining_ttl() {
+        let cache = HashmapCache::new();
+        let mut handle = cache.handle();
+
+        let key = "key".to_string();
+        handle.put(&key, &1).unwrap();
+        assert_eq!(handle.get_with_ttl::<i32>(&key).unwrap(), Some((1, None)));
+
+        handle.put_with_ttl(&key, &2, Duration::from_secs(60)).unwrap();
+        let (value, remaining) = handle.get_with_ttl::<i32>(&key).unwrap().unwrap();
+        assert_eq!(value, 2);
+        assert!(remaining.is_some_and(|r| r > Duration::from_secs(50)));
+
+        assert_eq!(handle.get_with_ttl::<i32>(&"missing".to_string()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_put_if_absent_writes_only_missing_keys() {
+        let cache = HashmapCache::new();
+        let mut handle = cache.handle();
+        let key = "student:1".to_string();
+
+        assert!(handle.put_if_absent(&key, &"John".to_string()).unwrap());
+        assert!(!handle.put_if_absent(&key, &"Other".to_string()).unwrap());
+        assert_eq!(handle.get::<String>(&key).unwrap(), Some("John".to_string()));
+
+        // An expired entry counts as absent.
+        let ttl_key = "ttl_key".to_string();
+        handle.put_with_ttl(&ttl_key, &1, Duration::from_millis(20)).unwrap();
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(handle.put_if_absent(&ttl_key, &2).unwrap());
+        assert_eq!(handle.get::<i32>(&ttl_key).unwrap(), Some(2));
+    }
+
+    #[test]
+    fn test_compare_and_set_checks_version() {
+        let cache = HashmapCache::new();
+        let mut handle = cache.handle();
+        let key = "enrollment_count:5".to_string();
+
+        // A missing key is version 0.
+        assert!(!handle.compare_and_set(&key, 1, &10).unwrap());
+        assert!(handle.compare_and_set(&key, 0, &10).unwrap());
+        let current = handle.get_versioned::<i32>(&key).unwrap().unwrap();
+        assert_eq!(current, VersionedValue { version: 1, value: 10 });
+
+        // A writer holding the old version loses to the one that got there first.
+        assert!(handle.compare_and_set(&key, 1, &11).unwrap());
+        assert!(!handle.compare_and_set(&key, 1, &12).unwrap());
+        assert_eq!(
+            handle.get_versioned::<i32>(&key).unwrap(),
+            Some(VersionedValue { version: 2, value: 11 })
+        );
+
+        handle.delete(&key).unwrap();
+        assert!(handle.compare_and_set(&key, 0, &20).unwrap());
+    }
+
+    #[test]
+    fn test_get_or_insert_with_computes_once() {
+        let cache = HashmapCache::new();
+        let mut handle = cache.handle();
+        let key = "computed".to_string();
+        let mut calls = 0;
+
+        for _ in 0..2 {
+            let value = handle
+                .get_or_insert_with(&key, || {
+                    calls += 1;
+                    Ok(42)
+                })
+                .expect("Failed to get or insert value");
+            assert_eq!(value, 42);
+        }
+        assert_eq!(calls, 1);
+
+        let err = handle.get_or_insert_with::<i32, _>(&"failing".to_string(), || {
+            Err(CacheError::new("computation failed"))
+        });
+        assert!(err.is_err());
+        assert!(!handle.exists(&"failing".to_string()).unwrap());
+    }
+
+    #[test]
+    fn test_clear_removes_all_entries() {
+        let cache = HashmapCache::new();
+        let mut handle = cache.handle();
+
+        handle.put(&"a".to_string(), &1).unwrap();
+        handle.put(&"b".to_string(), &2).unwrap();
+        handle
+            .delete_multi(&["a".to_string(), "missing".to_string()])
+            .expect("Failed to delete keys from cache");
+        assert_eq!(handle.len(), 1);
+
+        handle.clear().expect("Failed to clear cache");
+
+        assert!(handle.is_empty());
+        assert_eq!(handle.get::<i32>(&"a".to_string()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_invalidate_tag_deletes_tagged_keys() {
+        let cache = HashmapCache::new();
+        let mut handle = cache.handle();
+
+        let student = "student:2".to_string();
+        let roster = "class:roster:5".to_string();
+        handle.put_tagged(&student, &"Ori".to_string(), &["student:2"]).unwrap();
+        handle
+            .put_tagged(&roster, &vec!["Ori".to_string()], &["student:2", "class:5"])
+            .unwrap();
+        handle.put(&"other".to_string(), &1).unwrap();
+
+        let mut deleted = handle.invalidate_tag("student:2").unwrap();
+        deleted.sort();
+        assert_eq!(deleted, vec![roster.clone(), student.clone()]);
+        assert!(!handle.exists(&student).unwrap());
+        assert!(!handle.exists(&roster).unwrap());
+        assert!(handle.exists(&"other".to_string()).unwrap());
+        assert!(handle.invalidate_tag("student:2").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_handle_shared_across_threads() {
+        let cache = HashmapCache::new();
+
+        let workers = (0..4)
+            .map(|i| {
+                let mut handle = cache.handle();
+                std::thread::spawn(move || {
+                    let key = format!("thread:{}", i);
+                    handle.put(&key, &i).expect("Failed to put value into cache");
+                })
+            })
+            .collect::<Vec<_>>();
+        for worker in workers {
+            worker.join().expect("Worker thread panicked");
+        }
+
+        let handle = cache.handle();
+        assert_eq!(handle.scan_keys("thread:*").unwrap().len(), 4);
+        assert_eq!(handle.count_keys("thread:*").unwrap(), 4);
+        assert_eq!(handle.count_keys("other:*").unwrap(), 0);
+        for i in 0..4 {
+            let value: Option<i32> = handle.get(&format!("thread:{}", i)).unwrap();
+            assert_eq!(value, Some(i));
+        }
     }
 }
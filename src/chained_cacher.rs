@@ -0,0 +1,426 @@
+use crate::cacher::{CacheError, CacheHandle, EntryInfo};
+use crate::dyn_cacher::DynCacheHandle;
+use log::{debug, warn};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+/// Cache handle chaining any number of tiers, e.g. an in-process cache in
+/// front of a regional Redis in front of a global one.
+///
+/// This generalizes `TieredCache` to a list of tiers picked at runtime. Reads
+/// try the tiers in order and copy a hit into every tier before it. Writes
+/// and deletes go to every tier, the last one first, since the last tier is
+/// the authoritative one: `scan_keys`, `count_keys`, `get_with_ttl` and the
+/// counts returned by deletes come from it alone. Read errors from the other
+/// tiers are logged and treated as misses, so a failing tier only costs the
+/// round trip to the next one.
+///
+/// ```ignore
+/// let handle = ChainedCache::new(vec![
+///     Box::new(HashmapCache::with_capacity(10_000).handle()),
+///     Box::new(RedisCache::new(&regional_url)?.handle()),
+///     Box::new(RedisCache::new(&global_url)?.handle()),
+/// ])?;
+/// ```
+#[derive(Clone)]
+pub struct ChainedCache {
+    tiers: Vec<DynCacheHandle>,
+}
+
+impl ChainedCache {
+    /// Chains `tiers`, from the first one read to the authoritative one.
+    pub fn new(tiers: Vec<DynCacheHandle>) -> Result<Self, CacheError> {
+        if tiers.is_empty() {
+            return Err(CacheError::new("ChainedCache needs at least one tier"));
+        }
+        Ok(ChainedCache { tiers })
+    }
+
+    pub fn tiers(&self) -> &[DynCacheHandle] {
+        &self.tiers
+    }
+
+    fn last(&self) -> &DynCacheHandle {
+        self.tiers
+            .last()
+            .expect("ChainedCache has at least one tier")
+    }
+
+    fn last_mut(&mut self) -> &mut DynCacheHandle {
+        self.tiers
+            .last_mut()
+            .expect("ChainedCache has at least one tier")
+    }
+
+    /// The tiers in front of the authoritative one.
+    fn front_mut(&mut self) -> &mut [DynCacheHandle] {
+        let n = self.tiers.len() - 1;
+        &mut self.tiers[..n]
+    }
+
+    /// Copies a value found in tier `depth` into every tier before it.
+    fn backfill<V: Serialize + DeserializeOwned>(&mut self, depth: usize, key: &String, value: &V) {
+        for (i, tier) in self.tiers[..depth].iter_mut().enumerate() {
+            if let Err(e) = tier.put(key, value) {
+                warn!("Error populating cache tier {} for key {}: {}", i, key, e);
+            }
+        }
+    }
+}
+
+impl CacheHandle for ChainedCache {
+    fn get<V: Serialize + DeserializeOwned>(&self, key: &String) -> Result<Option<V>, CacheError> {
+        let last = self.tiers.len() - 1;
+        for (i, tier) in self.tiers[..last].iter().enumerate() {
+            match tier.get::<V>(key) {
+                Ok(Some(value)) => {
+                    // `get` takes `&self`; backfill through a clone, which
+                    // shares the underlying stores.
+                    self.clone().backfill(i, key, &value);
+                    return Ok(Some(value));
+                }
+                Ok(None) => debug!("Cache tier {} miss for key {}", i, key),
+                Err(e) => warn!("Error reading key {} from cache tier {}: {}", key, i, e),
+            }
+        }
+        let value = self.last().get::<V>(key)?;
+        if let Some(value) = &value {
+            self.clone().backfill(last, key, value);
+        }
+        Ok(value)
+    }
+
+    fn get_multi<V: Serialize + DeserializeOwned>(
+        &self,
+        keys: &[String],
+    ) -> Result<Vec<Option<V>>, CacheError> {
+        let last = self.tiers.len() - 1;
+        let mut values = keys.iter().map(|_| None).collect::<Vec<Option<V>>>();
+        let mut missing = (0..keys.len()).collect::<Vec<_>>();
+        let mut this = self.clone();
+        for (depth, tier) in self.tiers.iter().enumerate() {
+            if missing.is_empty() {
+                break;
+            }
+            let missing_keys = missing.iter().map(|&i| keys[i].clone()).collect::<Vec<_>>();
+            let fetched = match tier.get_multi::<V>(&missing_keys) {
+                Ok(fetched) => fetched,
+                Err(e) if depth < last => {
+                    warn!("Error reading keys from cache tier {}: {}", depth, e);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let mut still_missing = Vec::new();
+            for (i, value) in missing.into_iter().zip(fetched) {
+                match value {
+                    Some(value) => {
+                        this.backfill(depth, &keys[i], &value);
+                        values[i] = Some(value);
+                    }
+                    None => still_missing.push(i),
+                }
+            }
+            missing = still_missing;
+        }
+        Ok(values)
+    }
+
+    /// Read from the last tier, which holds the authoritative expiry; copies
+    /// backfilled into the other tiers do not carry one.
+    fn get_with_ttl<V: Serialize + DeserializeOwned>(
+        &self,
+        key: &String,
+    ) -> Result<Option<(V, Option<Duration>)>, CacheError> {
+        self.last().get_with_ttl(key)
+    }
+
+    fn exists(&self, key: &String) -> Result<bool, CacheError> {
+        let last = self.tiers.len() - 1;
+        if self.tiers[..last]
+            .iter()
+            .any(|tier| tier.exists(key).unwrap_or(false))
+        {
+            return Ok(true);
+        }
+        self.last().exists(key)
+    }
+
+    /// Reports the last tier's metadata, since it holds every key stored
+    /// through this handle. Reads served by the other tiers are not counted
+    /// there.
+    fn entry_info(&self, key: &String) -> Result<Option<EntryInfo>, CacheError> {
+        self.last().entry_info(key)
+    }
+
+    fn put<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+    ) -> Result<(), CacheError> {
+        for tier in self.tiers.iter_mut().rev() {
+            tier.put(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the last tier only: the tiers may store values in different
+    /// formats, so raw bytes are never copied from one to another.
+    fn get_raw(&self, key: &String) -> Result<Option<Vec<u8>>, CacheError> {
+        self.last().get_raw(key)
+    }
+
+    /// Writes the last tier and evicts the key from the others, which then
+    /// read it back in their own format.
+    fn put_raw(&mut self, key: &String, bytes: &[u8]) -> Result<(), CacheError> {
+        self.last_mut().put_raw(key, bytes)?;
+        for tier in self.front_mut().iter_mut().rev() {
+            tier.delete(key)?;
+        }
+        Ok(())
+    }
+
+    fn put_with_ttl<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+        ttl: Duration,
+    ) -> Result<(), CacheError> {
+        for tier in self.tiers.iter_mut().rev() {
+            tier.put_with_ttl(key, value, ttl)?;
+        }
+        Ok(())
+    }
+
+    fn put_multi<V: Serialize + DeserializeOwned>(
+        &mut self,
+        entries: &[(String, V)],
+    ) -> Result<(), CacheError> {
+        for tier in self.tiers.iter_mut().rev() {
+            tier.put_multi(entries)?;
+        }
+        Ok(())
+    }
+
+    /// Decided by the last tier. If it already holds a value, copies in the
+    /// other tiers are left as is.
+    fn put_if_absent<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+    ) -> Result<bool, CacheError> {
+        let written = self.last_mut().put_if_absent(key, value)?;
+        if written {
+            for tier in self.front_mut().iter_mut().rev() {
+                tier.put(key, value)?;
+            }
+        }
+        Ok(written)
+    }
+
+    /// Decided by the last tier. On success, the key is evicted from the
+    /// other tiers, which then read the new version back from it.
+    fn compare_and_set<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        expected_version: u64,
+        value: &V,
+    ) -> Result<bool, CacheError> {
+        let written = self
+            .last_mut()
+            .compare_and_set(key, expected_version, value)?;
+        if written {
+            for tier in self.front_mut().iter_mut().rev() {
+                tier.delete(key)?;
+            }
+        }
+        Ok(written)
+    }
+
+    /// Decided by the last tier, like `compare_and_set`.
+    fn put_read_at<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+        ttl: Option<Duration>,
+        read_at: SystemTime,
+    ) -> Result<bool, CacheError> {
+        let written = self.last_mut().put_read_at(key, value, ttl, read_at)?;
+        if written {
+            for tier in self.front_mut().iter_mut().rev() {
+                tier.delete(key)?;
+            }
+        }
+        Ok(written)
+    }
+
+    /// Decided by the last tier. Every key is evicted from the other tiers,
+    /// written or not.
+    fn put_multi_read_at<V: Serialize + DeserializeOwned>(
+        &mut self,
+        entries: &[(String, V)],
+        read_at: SystemTime,
+    ) -> Result<usize, CacheError> {
+        let written = self.last_mut().put_multi_read_at(entries, read_at)?;
+        let keys = entries.iter().map(|(key, _)| key.clone()).collect::<Vec<_>>();
+        for tier in self.front_mut().iter_mut().rev() {
+            tier.delete_multi(&keys)?;
+        }
+        Ok(written)
+    }
+
+    /// Deletes from the last tier first. The reverse order would let a
+    /// concurrent read copy the old value back into a tier already evicted.
+    fn delete(&mut self, key: &String) -> Result<(), CacheError> {
+        for tier in self.tiers.iter_mut().rev() {
+            tier.delete(key)?;
+        }
+        Ok(())
+    }
+
+    fn delete_multi(&mut self, keys: &[String]) -> Result<(), CacheError> {
+        for tier in self.tiers.iter_mut().rev() {
+            tier.delete_multi(keys)?;
+        }
+        Ok(())
+    }
+
+    /// Counts the keys live in the last tier, since it holds every key
+    /// stored through this handle.
+    fn delete_multi_counted(&mut self, keys: &[String]) -> Result<usize, CacheError> {
+        let existed = self.last_mut().delete_multi_counted(keys)?;
+        for tier in self.front_mut().iter_mut().rev() {
+            tier.delete_multi(keys)?;
+        }
+        Ok(existed)
+    }
+
+    /// Counts the keys live in the last tier, like `delete_multi_counted`.
+    fn delete_pattern(&mut self, pattern: &str) -> Result<usize, CacheError> {
+        let deleted = self.last_mut().delete_pattern(pattern)?;
+        for tier in self.front_mut().iter_mut().rev() {
+            tier.delete_pattern(pattern)?;
+        }
+        Ok(deleted)
+    }
+
+    fn clear(&mut self) -> Result<(), CacheError> {
+        for tier in self.tiers.iter_mut().rev() {
+            tier.clear()?;
+        }
+        Ok(())
+    }
+
+    /// Scans the last tier only, since it holds every key stored through
+    /// this handle.
+    fn scan_keys(&self, pattern: &str) -> Result<HashMap<String, String>, CacheError> {
+        self.last().scan_keys(pattern)
+    }
+
+    fn list_keys(&self, pattern: &str) -> Result<Vec<String>, CacheError> {
+        self.last().list_keys(pattern)
+    }
+
+    fn count_keys(&self, pattern: &str) -> Result<usize, CacheError> {
+        self.last().count_keys(pattern)
+    }
+
+    /// Tags the key in the last tier only; copies in the other tiers are
+    /// evicted by `invalidate_tag` using the keys the last tier reports.
+    fn put_tagged<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+        tags: &[&str],
+    ) -> Result<(), CacheError> {
+        self.last_mut().put_tagged(key, value, tags)?;
+        for tier in self.front_mut().iter_mut().rev() {
+            tier.put(key, value)?;
+        }
+        Ok(())
+    }
+
+    fn invalidate_tag(&mut self, tag: &str) -> Result<Vec<String>, CacheError> {
+        let keys = self.last_mut().invalidate_tag(tag)?;
+        for tier in self.front_mut().iter_mut().rev() {
+            tier.delete_multi(&keys)?;
+        }
+        Ok(keys)
+    }
+
+    fn health_check(&self) -> Result<(), CacheError> {
+        self.tiers.iter().try_for_each(|tier| tier.health_check())
+    }
+
+    /// Serves the value from the first tier holding it, otherwise defers to
+    /// the last tier's `get_or_insert_with`, so that a backend lock (e.g.
+    /// Redis) still guards the computation, and backfills the result.
+    fn get_or_insert_with<V, F>(&mut self, key: &String, f: F) -> Result<V, CacheError>
+    where
+        V: Serialize + DeserializeOwned,
+        F: FnOnce() -> Result<V, CacheError>,
+    {
+        let last = self.tiers.len() - 1;
+        for (i, tier) in self.tiers[..last].iter().enumerate() {
+            match tier.get::<V>(key) {
+                Ok(Some(value)) => {
+                    self.backfill(i, key, &value);
+                    return Ok(value);
+                }
+                Ok(None) => {}
+                Err(e) => warn!("Error reading key {} from cache tier {}: {}", key, i, e),
+            }
+        }
+        let value = self.last_mut().get_or_insert_with(key, f)?;
+        self.backfill(last, key, &value);
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cacher::HashmapCache;
+
+    #[test]
+    fn test_reads_in_order_and_backfills_earlier_tiers() {
+        let tiers = [
+            HashmapCache::new().handle(),
+            HashmapCache::new().handle(),
+            HashmapCache::new().handle(),
+        ];
+        let mut chain = ChainedCache::new(
+            tiers
+                .iter()
+                .map(|tier| Box::new(tier.clone()) as DynCacheHandle)
+                .collect(),
+        )
+        .unwrap();
+        let (mut l2, mut l3) = (tiers[1].clone(), tiers[2].clone());
+        let key = "student:2".to_string();
+
+        l3.put(&key, &"Ori".to_string()).unwrap();
+        assert_eq!(chain.get::<String>(&key).unwrap(), Some("Ori".to_string()));
+        assert!(tiers.iter().all(|tier| tier.exists(&key).unwrap()));
+
+        // A hit in the middle tier only backfills the first one.
+        let other = "student:3".to_string();
+        l2.put(&other, &"Noa".to_string()).unwrap();
+        let keys = [key.clone(), other.clone(), "student:4".to_string()];
+        assert_eq!(
+            chain.get_multi::<String>(&keys).unwrap(),
+            vec![Some("Ori".to_string()), Some("Noa".to_string()), None]
+        );
+        assert!(tiers[0].exists(&other).unwrap());
+        assert!(!l3.exists(&other).unwrap());
+
+        // Scans and counts only see the authoritative tier.
+        assert_eq!(chain.count_keys("student:*").unwrap(), 1);
+
+        chain.delete(&key).unwrap();
+        assert!(tiers.iter().all(|tier| !tier.exists(&key).unwrap()));
+        assert!(ChainedCache::new(Vec::new()).is_err());
+    }
+}
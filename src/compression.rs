@@ -0,0 +1,246 @@
+use crate::cache_stats::CacheStats;
+use crate::cacher::{CacheError, CacheErrorKind};
+use crate::serializer::Serializer;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::io::Read;
+use std::sync::Arc;
+
+/// Header byte of a payload stored without compression.
+const HEADER_PLAIN: u8 = 0;
+/// Header byte of a gzip-compressed payload.
+const HEADER_GZIP: u8 = 1;
+/// Header byte of a zstd-compressed payload.
+const HEADER_ZSTD: u8 = 2;
+
+/// Payloads smaller than this many bytes are stored uncompressed by default.
+const DEFAULT_THRESHOLD: usize = 1024;
+
+/// Compression algorithm applied by `CompressingSerializer`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompressionCodec {
+    #[cfg(feature = "gzip")]
+    Gzip,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl CompressionCodec {
+    fn header(self) -> u8 {
+        match self {
+            #[cfg(feature = "gzip")]
+            CompressionCodec::Gzip => HEADER_GZIP,
+            #[cfg(feature = "zstd")]
+            CompressionCodec::Zstd => HEADER_ZSTD,
+        }
+    }
+
+    fn compress(self, bytes: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            #[cfg(feature = "gzip")]
+            CompressionCodec::Gzip => {
+                use std::io::Write;
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(bytes)?;
+                encoder.finish()
+            }
+            #[cfg(feature = "zstd")]
+            CompressionCodec::Zstd => zstd::encode_all(bytes, 0),
+        }
+    }
+}
+
+fn decompress(header: u8, bytes: &[u8]) -> Result<Vec<u8>, CacheError> {
+    let mut out = Vec::new();
+    let res = match header {
+        #[cfg(feature = "gzip")]
+        HEADER_GZIP => flate2::read::GzDecoder::new(bytes).read_to_end(&mut out),
+        #[cfg(feature = "zstd")]
+        HEADER_ZSTD => zstd::Decoder::new(bytes).and_then(|mut d| d.read_to_end(&mut out)),
+        _ => {
+            return Err(
+                CacheError::new(&format!("Unsupported compression header {}", header))
+                    .with_kind(CacheErrorKind::Deserialization),
+            );
+        }
+    };
+    res.map_err(|e| {
+        CacheError::with_cause("Failed to decompress value", e)
+            .with_kind(CacheErrorKind::Deserialization)
+    })?;
+    Ok(out)
+}
+
+/// Serializer decorator that compresses the payload produced by `inner`.
+///
+/// Every stored value starts with a header byte telling whether, and how, it
+/// was compressed, so values below the size threshold are kept as-is and a
+/// cache can mix compressed and uncompressed entries. Entries written before
+/// compression was enabled carry no header; they are still read correctly as
+/// long as `inner` produces text (e.g. JSON), whose first byte never collides
+/// with a header.
+///
+/// A payload that does not shrink when compressed, e.g. one that is already
+/// compressed, is stored uncompressed too. Given a `CacheStats` with
+/// `with_stats`, the serializer counts the values stored each way and the
+/// bytes saved, which helps tune the threshold:
+///
+/// ```ignore
+/// let stats = Arc::new(CacheStats::new());
+/// let serializer = CompressingSerializer::new(JsonSerializer, CompressionCodec::Zstd)
+///     .with_threshold(256)
+///     .with_stats(stats.clone());
+/// let cache = RedisCache::new(url)?.with_serializer(serializer);
+/// // ... later
+/// println!("{} compressed, {} raw, {} bytes saved",
+///     stats.compressed(), stats.stored_uncompressed(), stats.compression_saved_bytes());
+/// ```
+#[derive(Clone, Debug)]
+pub struct CompressingSerializer<S: Serializer> {
+    inner: S,
+    codec: CompressionCodec,
+    threshold: usize,
+    stats: Option<Arc<CacheStats>>,
+}
+
+impl<S: Serializer> CompressingSerializer<S> {
+    pub fn new(inner: S, codec: CompressionCodec) -> Self {
+        CompressingSerializer {
+            inner,
+            codec,
+            threshold: DEFAULT_THRESHOLD,
+            stats: None,
+        }
+    }
+
+    /// Sets the payload size, in bytes, below which values are stored
+    /// uncompressed.
+    pub fn with_threshold(mut self, threshold: usize) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Records how many values are stored compressed or uncompressed, and
+    /// the bytes saved, into `stats`.
+    pub fn with_stats(mut self, stats: Arc<CacheStats>) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    fn compress(&self, bytes: Vec<u8>) -> Result<(u8, Vec<u8>), CacheError> {
+        if bytes.len() >= self.threshold {
+            let compressed = self.codec.compress(&bytes).map_err(|e| {
+                CacheError::with_cause("Failed to compress value", e)
+                    .with_kind(CacheErrorKind::Serialization)
+            })?;
+            if compressed.len() < bytes.len() {
+                if let Some(stats) = &self.stats {
+                    stats.record_compressed(bytes.len(), compressed.len());
+                }
+                return Ok((self.codec.header(), compressed));
+            }
+        }
+        if let Some(stats) = &self.stats {
+            stats.record_stored_uncompressed();
+        }
+        Ok((HEADER_PLAIN, bytes))
+    }
+}
+
+impl<S: Serializer> Serializer for CompressingSerializer<S> {
+    fn serialize<V: Serialize>(&self, value: &V) -> Result<Vec<u8>, CacheError> {
+        let (header, payload) = self.compress(self.inner.serialize(value)?)?;
+        let mut out = Vec::with_capacity(payload.len() + 1);
+        out.push(header);
+        out.extend_from_slice(&payload);
+        Ok(out)
+    }
+
+    fn deserialize<V: DeserializeOwned>(&self, bytes: &[u8]) -> Result<V, CacheError> {
+        match bytes.split_first() {
+            Some((&HEADER_PLAIN, payload)) => self.inner.deserialize(payload),
+            Some((&header, payload)) if header == HEADER_GZIP || header == HEADER_ZSTD => {
+                self.inner.deserialize(&decompress(header, payload)?)
+            }
+            // No header: an entry written before compression was enabled.
+            _ => self.inner.deserialize(bytes),
+        }
+    }
+
+    fn is_miss(&self, error: &CacheError) -> bool {
+        self.inner.is_miss(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serializer::JsonSerializer;
+
+    fn codec() -> CompressionCodec {
+        #[cfg(feature = "gzip")]
+        return CompressionCodec::Gzip;
+        #[cfg(not(feature = "gzip"))]
+        return CompressionCodec::Zstd;
+    }
+
+    #[test]
+    fn test_compresses_above_threshold_only() {
+        let serializer = CompressingSerializer::new(JsonSerializer, codec()).with_threshold(64);
+
+        let small = "short".to_string();
+        let stored = serializer.serialize(&small).unwrap();
+        assert_eq!(stored[0], HEADER_PLAIN);
+        assert_eq!(serializer.deserialize::<String>(&stored).unwrap(), small);
+
+        let large = "turbodiesel ".repeat(100);
+        let stored = serializer.serialize(&large).unwrap();
+        assert_eq!(stored[0], codec().header());
+        assert!(stored.len() < large.len());
+        assert_eq!(serializer.deserialize::<String>(&stored).unwrap(), large);
+    }
+
+    #[test]
+    fn test_records_compressed_and_raw_values() {
+        let stats = Arc::new(CacheStats::new());
+        let serializer = CompressingSerializer::new(JsonSerializer, codec())
+            .with_threshold(64)
+            .with_stats(stats.clone());
+
+        let small = serializer.serialize(&"short".to_string()).unwrap();
+        let large_value = "turbodiesel ".repeat(100);
+        let large = serializer.serialize(&large_value).unwrap();
+        // Pseudo-random bytes above the threshold do not shrink.
+        let mut seed = 1u32;
+        let noise = (0..256)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (seed >> 24) as u8
+            })
+            .collect::<Vec<_>>();
+        let incompressible = serializer.compress(noise).unwrap();
+
+        assert_eq!(stats.compressed(), 1);
+        assert_eq!(stats.stored_uncompressed(), 2);
+        assert_eq!(
+            stats.compression_saved_bytes(),
+            (large_value.len() + 2 - (large.len() - 1)) as u64
+        );
+        assert_eq!(incompressible.0, HEADER_PLAIN);
+
+        // Both formats read back through the same path.
+        assert_eq!(serializer.deserialize::<String>(&small).unwrap(), "short");
+        assert_eq!(
+            serializer.deserialize::<String>(&large).unwrap(),
+            large_value
+        );
+    }
+
+    #[test]
+    fn test_reads_entries_without_header() {
+        let serializer = CompressingSerializer::new(JsonSerializer, codec());
+        let legacy = JsonSerializer.serialize(&"legacy".to_string()).unwrap();
+        assert_eq!(serializer.deserialize::<String>(&legacy).unwrap(), "legacy");
+    }
+}
@@ -0,0 +1,476 @@
+use crate::cacher::{
+    CacheError, CacheHandle, CacheListing, VersionedValue, list_entries, stored_version,
+};
+use crate::instrumentation;
+use crate::serializer::{JsonSerializer, Serializer, printable};
+use dashmap::DashMap;
+use dashmap::mapref::entry::Entry;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+struct DashmapEntry {
+    value: Vec<u8>,
+    expires_at: Option<Instant>,
+}
+
+impl DashmapEntry {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|t| t <= now)
+    }
+}
+
+/// In-memory cache backed by a sharded `DashMap`.
+///
+/// Unlike `HashmapCache`, which guards the whole map with a single lock,
+/// concurrent `get`/`put` calls on keys in different shards never contend.
+#[derive(Debug)]
+pub struct DashmapCache<S: Serializer = JsonSerializer> {
+    map: Arc<DashMap<String, DashmapEntry>>,
+    tags: Arc<DashMap<String, HashSet<String>>>,
+    serializer: S,
+}
+
+impl Default for DashmapCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DashmapCache {
+    pub fn new() -> Self {
+        DashmapCache {
+            map: Arc::new(DashMap::new()),
+            tags: Arc::new(DashMap::new()),
+            serializer: JsonSerializer,
+        }
+    }
+}
+
+impl<S: Serializer> DashmapCache<S> {
+    /// Replaces the serializer used to encode stored values. Must be called
+    /// before any handles are created.
+    pub fn with_serializer<S2: Serializer>(self, serializer: S2) -> DashmapCache<S2> {
+        DashmapCache {
+            map: self.map,
+            tags: self.tags,
+            serializer,
+        }
+    }
+
+    pub fn handle(&self) -> DashmapCacheHandle<S> {
+        DashmapCacheHandle {
+            map: Arc::clone(&self.map),
+            tags: Arc::clone(&self.tags),
+            serializer: self.serializer.clone(),
+        }
+    }
+}
+
+/// Handle to a `DashmapCache`.
+///
+/// Handles are cheap to clone and can be shared between threads; all clones
+/// operate on the same underlying map.
+pub struct DashmapCacheHandle<S: Serializer = JsonSerializer> {
+    map: Arc<DashMap<String, DashmapEntry>>,
+    tags: Arc<DashMap<String, HashSet<String>>>,
+    serializer: S,
+}
+
+impl<S: Serializer> DashmapCacheHandle<S> {
+    /// Returns the number of entries currently held by the cache.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    fn insert<V: Serialize>(
+        &mut self,
+        key: &str,
+        value: &V,
+        ttl: Option<Duration>,
+    ) -> Result<(), CacheError> {
+        let value = self.serializer.serialize(value)?;
+        self.insert_raw(key, value, ttl);
+        Ok(())
+    }
+
+    fn insert_raw(&mut self, key: &str, value: Vec<u8>, ttl: Option<Duration>) {
+        let entry = DashmapEntry {
+            value,
+            expires_at: ttl.map(|ttl| Instant::now() + ttl),
+        };
+        instrumentation::stored(key, entry.value.len());
+        self.map.insert(key.to_string(), entry);
+    }
+
+    /// Passes the bytes of the live entry under `key` to `f`, evicting the
+    /// entry instead if it has expired.
+    fn read_live<T>(
+        &self,
+        key: &str,
+        f: impl FnOnce(&[u8]) -> Result<T, CacheError>,
+    ) -> Result<Option<T>, CacheError> {
+        let now = Instant::now();
+        if let Some(entry) = self.map.get(key) {
+            if !entry.is_expired(now) {
+                return f(&entry.value).map(Some);
+            }
+        } else {
+            return Ok(None);
+        }
+        // The entry has expired; evict it so the map doesn't grow unbounded.
+        self.map.remove_if(key, |_, entry| entry.is_expired(now));
+        Ok(None)
+    }
+}
+
+impl<S: Serializer> CacheHandle for DashmapCacheHandle<S> {
+    fn get<V: Serialize + DeserializeOwned>(&self, key: &String) -> Result<Option<V>, CacheError> {
+        self.read_live(key, |bytes| self.serializer.deserialize::<V>(bytes))
+    }
+
+    fn get_with_ttl<V: Serialize + DeserializeOwned>(
+        &self,
+        key: &String,
+    ) -> Result<Option<(V, Option<Duration>)>, CacheError> {
+        let now = Instant::now();
+        match self.map.get(key) {
+            Some(entry) if !entry.is_expired(now) => {
+                let value = self.serializer.deserialize::<V>(&entry.value)?;
+                let remaining = entry.expires_at.map(|t| t.saturating_duration_since(now));
+                Ok(Some((value, remaining)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn get_multi<V: Serialize + DeserializeOwned>(
+        &self,
+        keys: &[String],
+    ) -> Result<Vec<Option<V>>, CacheError> {
+        keys.iter().map(|key| self.get(key)).collect()
+    }
+
+    fn exists(&self, key: &String) -> Result<bool, CacheError> {
+        let now = Instant::now();
+        Ok(self
+            .map
+            .get(key)
+            .is_some_and(|entry| !entry.is_expired(now)))
+    }
+
+    fn put<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+    ) -> Result<(), CacheError> {
+        self.insert(key, value, None)
+    }
+
+    fn get_raw(&self, key: &String) -> Result<Option<Vec<u8>>, CacheError> {
+        self.read_live(key, |bytes| Ok(bytes.to_vec()))
+    }
+
+    fn put_raw(&mut self, key: &String, bytes: &[u8]) -> Result<(), CacheError> {
+        self.insert_raw(key, bytes.to_vec(), None);
+        Ok(())
+    }
+
+    fn put_with_ttl<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+        ttl: Duration,
+    ) -> Result<(), CacheError> {
+        self.insert(key, value, Some(ttl))
+    }
+
+    fn put_if_absent<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+    ) -> Result<bool, CacheError> {
+        let entry = DashmapEntry {
+            value: self.serializer.serialize(value)?,
+            expires_at: None,
+        };
+        let now = Instant::now();
+        match self.map.entry(key.clone()) {
+            Entry::Occupied(existing) if !existing.get().is_expired(now) => Ok(false),
+            Entry::Occupied(mut expired) => {
+                expired.insert(entry);
+                Ok(true)
+            }
+            Entry::Vacant(vacant) => {
+                vacant.insert(entry);
+                Ok(true)
+            }
+        }
+    }
+
+    /// Compares and swaps while holding the lock of the key's shard.
+    fn compare_and_set<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        expected_version: u64,
+        value: &V,
+    ) -> Result<bool, CacheError> {
+        let entry = DashmapEntry {
+            value: self.serializer.serialize(&VersionedValue {
+                version: expected_version + 1,
+                value,
+            })?,
+            expires_at: None,
+        };
+        let now = Instant::now();
+        match self.map.entry(key.clone()) {
+            Entry::Occupied(mut existing) => {
+                let current = existing.get();
+                let current = (!current.is_expired(now)).then_some(current.value.as_slice());
+                if stored_version::<V, S>(&self.serializer, current)? != expected_version {
+                    return Ok(false);
+                }
+                existing.insert(entry);
+                Ok(true)
+            }
+            Entry::Vacant(vacant) if expected_version == 0 => {
+                vacant.insert(entry);
+                Ok(true)
+            }
+            Entry::Vacant(_) => Ok(false),
+        }
+    }
+
+    fn delete(&mut self, key: &String) -> Result<(), CacheError> {
+        self.map.remove(key);
+        Ok(())
+    }
+
+    fn delete_multi(&mut self, keys: &[String]) -> Result<(), CacheError> {
+        for key in keys {
+            self.map.remove(key);
+        }
+        Ok(())
+    }
+
+    fn delete_multi_counted(&mut self, keys: &[String]) -> Result<usize, CacheError> {
+        let now = Instant::now();
+        Ok(keys
+            .iter()
+            .filter_map(|key| self.map.remove(key))
+            .filter(|(_, entry)| !entry.is_expired(now))
+            .count())
+    }
+
+    /// Removes the matching keys one shard at a time with `retain`, so
+    /// writers on other shards are not blocked.
+    fn delete_pattern(&mut self, pattern: &str) -> Result<usize, CacheError> {
+        let wild = wildmatch::WildMatch::new(pattern);
+        let now = Instant::now();
+        let mut deleted = 0;
+        self.map.retain(|key, entry| {
+            if !wild.matches(key) {
+                return true;
+            }
+            if !entry.is_expired(now) {
+                deleted += 1;
+            }
+            false
+        });
+        Ok(deleted)
+    }
+
+    fn clear(&mut self) -> Result<(), CacheError> {
+        self.map.clear();
+        self.tags.clear();
+        Ok(())
+    }
+
+    fn scan_keys(&self, pattern: &str) -> Result<HashMap<String, String>, CacheError> {
+        let wild = wildmatch::WildMatch::new(pattern);
+        let now = Instant::now();
+        // `iter` locks one shard at a time, so writers on other shards are
+        // not blocked while scanning.
+        Ok(self
+            .map
+            .iter()
+            .filter(|entry| !entry.is_expired(now) && wild.matches(entry.key()))
+            .map(|entry| (entry.key().clone(), printable(&entry.value)))
+            .collect::<HashMap<String, String>>())
+    }
+
+    /// Collects the matching keys one shard at a time, without copying their
+    /// values, which are then read in batches.
+    fn list<V: Serialize + DeserializeOwned + 'static>(
+        &self,
+        pattern: &str,
+    ) -> Result<CacheListing<'_, V>, CacheError> {
+        let wild = wildmatch::WildMatch::new(pattern);
+        let now = Instant::now();
+        let keys = self
+            .map
+            .iter()
+            .filter(|entry| !entry.is_expired(now) && wild.matches(entry.key()))
+            .map(|entry| entry.key().clone())
+            .collect();
+        Ok(list_entries(self, keys))
+    }
+
+    fn list_keys(&self, pattern: &str) -> Result<Vec<String>, CacheError> {
+        let wild = wildmatch::WildMatch::new(pattern);
+        let now = Instant::now();
+        Ok(self
+            .map
+            .iter()
+            .filter(|entry| !entry.is_expired(now) && wild.matches(entry.key()))
+            .map(|entry| entry.key().clone())
+            .collect())
+    }
+
+    fn count_keys(&self, pattern: &str) -> Result<usize, CacheError> {
+        let wild = wildmatch::WildMatch::new(pattern);
+        let now = Instant::now();
+        Ok(self
+            .map
+            .iter()
+            .filter(|entry| !entry.is_expired(now) && wild.matches(entry.key()))
+            .count())
+    }
+
+    fn put_tagged<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+        tags: &[&str],
+    ) -> Result<(), CacheError> {
+        for tag in tags {
+            self.tags
+                .entry(tag.to_string())
+                .or_default()
+                .insert(key.clone());
+        }
+        self.insert(key, value, None)
+    }
+
+    fn invalidate_tag(&mut self, tag: &str) -> Result<Vec<String>, CacheError> {
+        let keys = self
+            .tags
+            .remove(tag)
+            .map(|(_, keys)| keys.into_iter().collect::<Vec<_>>())
+            .unwrap_or_default();
+        self.delete_multi(&keys)?;
+        Ok(keys)
+    }
+
+    fn health_check(&self) -> Result<(), CacheError> {
+        Ok(())
+    }
+}
+
+impl<S: Serializer> Clone for DashmapCacheHandle<S> {
+    fn clone(&self) -> Self {
+        DashmapCacheHandle {
+            map: Arc::clone(&self.map),
+            tags: Arc::clone(&self.tags),
+            serializer: self.serializer.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dashmap_put_get_and_expire() {
+        let cache = DashmapCache::new();
+        let mut handle = cache.handle();
+
+        let key = "test_key".to_string();
+        handle
+            .put(&key, &"test_value".to_string())
+            .expect("Failed to put value into cache");
+        assert_eq!(
+            handle.get::<String>(&key).unwrap(),
+            Some("test_value".to_string())
+        );
+
+        let ttl_key = "ttl_key".to_string();
+        handle
+            .put_with_ttl(&ttl_key, &1, Duration::from_millis(20))
+            .expect("Failed to put value into cache");
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(handle.get::<i32>(&ttl_key).unwrap(), None);
+        assert_eq!(handle.len(), 1);
+    }
+
+    #[test]
+    fn test_dashmap_compare_and_set_under_contention() {
+        let cache = DashmapCache::new();
+        let key = "counter".to_string();
+
+        let workers = (0..4)
+            .map(|_| {
+                let mut handle = cache.handle();
+                let key = key.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..100 {
+                        loop {
+                            let current = handle.get_versioned::<u64>(&key).unwrap();
+                            let (count, version) =
+                                current.map_or((0, 0), |current| (current.value, current.version));
+                            if handle.compare_and_set(&key, version, &(count + 1)).unwrap() {
+                                break;
+                            }
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        // No increment was lost to another writer.
+        assert_eq!(
+            cache.handle().get_versioned::<u64>(&key).unwrap(),
+            Some(VersionedValue {
+                version: 400,
+                value: 400
+            })
+        );
+    }
+
+    #[test]
+    fn test_dashmap_handle_shared_across_threads() {
+        let cache = DashmapCache::new();
+
+        let workers = (0..4)
+            .map(|i| {
+                let mut handle = cache.handle();
+                std::thread::spawn(move || {
+                    for j in 0..100 {
+                        let key = format!("thread:{}:{}", i, j);
+                        handle
+                            .put(&key, &j)
+                            .expect("Failed to put value into cache");
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for worker in workers {
+            worker.join().expect("Worker thread panicked");
+        }
+
+        let handle = cache.handle();
+        assert_eq!(handle.count_keys("thread:*").unwrap(), 400);
+        assert_eq!(handle.scan_keys("thread:3:*").unwrap().len(), 100);
+    }
+}
@@ -0,0 +1,206 @@
+use crate::cacher::{CacheError, CacheHandle};
+use diesel::connection::{Connection, TransactionManager};
+use log::{debug, error};
+use std::sync::{Mutex, MutexGuard};
+
+/// Cache keys to invalidate once a database transaction commits.
+///
+/// Updates wrapped with `defer_invalidate_key` or `defer_invalidate_keys`
+/// add their keys here instead of deleting them right away, so a transaction
+/// that rolls back leaves the cache untouched. Share one instance through an
+/// `Arc` between the updates of a transaction, and run the transaction with
+/// `transaction`, which applies the deletions after a successful commit and
+/// drops them after a rollback. `commit` and `discard` do the same by hand.
+///
+/// A `transaction` nested in another one only commits a savepoint, so its
+/// keys stay queued until the outermost `transaction` commits.
+///
+/// Keys are deleted only after the commit has returned, so readers may be
+/// served the old cached value until then, but never a value cached from
+/// the rolled-back state. A reader that loaded the old row from the database
+/// before the commit and caches it after the deletion can still leave a
+/// stale entry behind; give such keys a TTL if that matters.
+///
+/// ```ignore
+/// let invalidations = Arc::new(DeferredInvalidations::new(handle.clone()));
+/// invalidations.transaction(connection, |connection| {
+///     diesel::update(students::table)
+///         .set(students::dsl::name.eq("Ori2"))
+///         .filter(students::dsl::id.eq(2))
+///         .defer_invalidate_key(invalidations.clone(), "student:2")
+///         .execute(connection)
+/// })?;
+/// ```
+#[derive(Debug)]
+pub struct DeferredInvalidations<C: CacheHandle> {
+    cache: C,
+    pending: Mutex<Vec<String>>,
+}
+
+impl<C: CacheHandle> DeferredInvalidations<C> {
+    pub fn new(cache: C) -> Self {
+        DeferredInvalidations {
+            cache,
+            pending: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Queues `keys` for deletion on the next `commit`.
+    pub fn add(&self, keys: impl IntoIterator<Item = String>) {
+        self.lock().extend(keys);
+    }
+
+    /// Returns the keys queued for deletion.
+    pub fn pending(&self) -> Vec<String> {
+        self.lock().clone()
+    }
+
+    /// Deletes every queued key from the cache and returns how many there
+    /// were. If the cache fails, the keys stay queued so `commit` can be
+    /// retried.
+    pub fn commit(&self) -> Result<usize, CacheError> {
+        let keys = std::mem::take(&mut *self.lock());
+        if keys.is_empty() {
+            return Ok(0);
+        }
+        debug!("Applying deferred invalidation of keys: {:?}", keys);
+        if let Err(e) = self.cache.clone().delete_multi(&keys) {
+            self.lock().splice(0..0, keys);
+            return Err(e);
+        }
+        Ok(keys.len())
+    }
+
+    /// Forgets every queued key without touching the cache.
+    pub fn discard(&self) {
+        self.lock().clear();
+    }
+
+    /// Runs `f` in a database transaction, then commits the queued
+    /// invalidations if the transaction committed, or drops the keys queued
+    /// by `f` if it rolled back. Keys queued before, e.g. by a commit that
+    /// failed, are kept.
+    ///
+    /// Inside another transaction, `f` runs in a savepoint instead, and its
+    /// keys are left queued for the outer transaction to commit.
+    ///
+    /// A cache failure after the database commit is logged rather than
+    /// returned, since the transaction can no longer be undone; the keys stay
+    /// queued for a later `commit`.
+    pub fn transaction<Conn, T, E, F>(&self, conn: &mut Conn, f: F) -> Result<T, E>
+    where
+        Conn: Connection,
+        F: FnOnce(&mut Conn) -> Result<T, E>,
+        E: From<diesel::result::Error>,
+    {
+        let nested = matches!(
+            Conn::TransactionManager::transaction_manager_status_mut(conn).transaction_depth(),
+            Ok(Some(_))
+        );
+        let queued = self.lock().len();
+        match conn.transaction(f) {
+            Ok(value) => {
+                if nested {
+                    debug!("Leaving deferred invalidations to the outer transaction");
+                } else if let Err(e) = self.commit() {
+                    error!("Error applying deferred invalidations after commit: {}", e);
+                }
+                Ok(value)
+            }
+            Err(e) => {
+                self.lock().truncate(queued);
+                Err(e)
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<String>> {
+        // The queue is a plain list of keys, so it is still usable after a
+        // panic elsewhere while the lock was held.
+        self.pending
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cacher::HashmapCache;
+
+    #[test]
+    fn test_commit_deletes_only_queued_keys() {
+        let mut handle = HashmapCache::new().handle();
+        for key in ["student:1", "student:2", "student:3"] {
+            handle.put(&key.to_string(), &key.to_string()).unwrap();
+        }
+        let invalidations = DeferredInvalidations::new(handle.clone());
+
+        invalidations.add(["student:1".to_string()]);
+        invalidations.discard();
+        invalidations.add(["student:2".to_string(), "student:3".to_string()]);
+        assert_eq!(invalidations.pending(), vec!["student:2", "student:3"]);
+        // Nothing is deleted until the commit.
+        assert!(handle.exists(&"student:2".to_string()).unwrap());
+
+        assert_eq!(invalidations.commit().unwrap(), 2);
+        assert!(invalidations.pending().is_empty());
+        assert!(handle.exists(&"student:1".to_string()).unwrap());
+        assert!(!handle.exists(&"student:2".to_string()).unwrap());
+        assert!(!handle.exists(&"student:3".to_string()).unwrap());
+        assert_eq!(invalidations.commit().unwrap(), 0);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_rollback_keeps_keys_queued_before_the_transaction() {
+        use diesel::prelude::*;
+
+        let mut con = SqliteConnection::establish(":memory:").unwrap();
+        let invalidations = DeferredInvalidations::new(HashmapCache::new().handle());
+        // Left over from a commit whose cache delete failed.
+        invalidations.add(["student:1".to_string()]);
+
+        let res = invalidations.transaction(&mut con, |_| {
+            invalidations.add(["student:2".to_string()]);
+            Err::<(), _>(diesel::result::Error::RollbackTransaction)
+        });
+        assert!(res.is_err());
+        assert_eq!(invalidations.pending(), vec!["student:1"]);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_nested_transaction_defers_to_outer_commit() {
+        use diesel::prelude::*;
+
+        let mut con = SqliteConnection::establish(":memory:").unwrap();
+        let mut handle = HashmapCache::new().handle();
+        for key in ["student:1", "student:2"] {
+            handle.put(&key.to_string(), &key.to_string()).unwrap();
+        }
+        let invalidations = DeferredInvalidations::new(handle.clone());
+
+        invalidations
+            .transaction(&mut con, |con| {
+                invalidations.transaction(con, |_| {
+                    invalidations.add(["student:1".to_string()]);
+                    Ok::<_, diesel::result::Error>(())
+                })?;
+                // Only the savepoint was released, so nothing is deleted yet.
+                assert!(handle.exists(&"student:1".to_string()).unwrap());
+
+                let res = invalidations.transaction(con, |_| {
+                    invalidations.add(["student:2".to_string()]);
+                    Err::<(), _>(diesel::result::Error::RollbackTransaction)
+                });
+                assert!(res.is_err());
+                assert_eq!(invalidations.pending(), vec!["student:1"]);
+                Ok::<_, diesel::result::Error>(())
+            })
+            .unwrap();
+        assert!(!handle.exists(&"student:1".to_string()).unwrap());
+        assert!(handle.exists(&"student:2".to_string()).unwrap());
+        assert!(invalidations.pending().is_empty());
+    }
+}
@@ -0,0 +1,433 @@
+use crate::cacher::{CacheError, CacheErrorKind, CacheHandle, EntryInfo};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+/// Object-safe core of `CacheHandle`, dealing in raw bytes and JSON values
+/// instead of generic types.
+///
+/// `CacheHandle` cannot be used as a trait object, because its typed methods
+/// are generic and it requires `Clone`. Every `CacheHandle` that is `Send`
+/// and `'static` implements `RawCacheHandle`, and `DynCacheHandle` puts one
+/// behind a box that implements `CacheHandle` again, so the backend can be
+/// picked at runtime and still be passed to the statement wrappers. Like a
+/// `RedisCacheHandle`, it can be sent to another thread but not shared
+/// between threads; clone it instead.
+///
+/// The JSON values are encoded with the inner handle's own serializer, so
+/// entries stay readable by typed handles on the same backend. This needs a
+/// self-describing format such as JSON or MessagePack; `bincode` cannot
+/// decode into a `serde_json::Value`.
+///
+/// Both traits have methods of the same names, so call them through a
+/// `DynCacheHandle` with only `CacheHandle` in scope.
+#[allow(clippy::ptr_arg)]
+pub trait RawCacheHandle: Send {
+    fn get_json(&self, key: &String) -> Result<Option<Value>, CacheError>;
+    fn get_json_multi(&self, keys: &[String]) -> Result<Vec<Option<Value>>, CacheError>;
+    fn get_json_with_ttl(
+        &self,
+        key: &String,
+    ) -> Result<Option<(Value, Option<Duration>)>, CacheError>;
+    fn put_json(&mut self, key: &String, value: &Value) -> Result<(), CacheError>;
+    fn put_json_with_ttl(
+        &mut self,
+        key: &String,
+        value: &Value,
+        ttl: Duration,
+    ) -> Result<(), CacheError>;
+    fn put_json_if_absent(&mut self, key: &String, value: &Value) -> Result<bool, CacheError>;
+    fn compare_and_set_json(
+        &mut self,
+        key: &String,
+        expected_version: u64,
+        value: &Value,
+    ) -> Result<bool, CacheError>;
+    fn put_json_tagged(
+        &mut self,
+        key: &String,
+        value: &Value,
+        tags: &[&str],
+    ) -> Result<(), CacheError>;
+    fn put_json_read_at(
+        &mut self,
+        key: &String,
+        value: &Value,
+        ttl: Option<Duration>,
+        read_at: SystemTime,
+    ) -> Result<bool, CacheError>;
+    fn get_raw(&self, key: &String) -> Result<Option<Vec<u8>>, CacheError>;
+    fn put_raw(&mut self, key: &String, bytes: &[u8]) -> Result<(), CacheError>;
+    fn exists(&self, key: &String) -> Result<bool, CacheError>;
+    fn entry_info(&self, key: &String) -> Result<Option<EntryInfo>, CacheError>;
+    fn delete(&mut self, key: &String) -> Result<(), CacheError>;
+    fn delete_multi(&mut self, keys: &[String]) -> Result<(), CacheError>;
+    fn delete_multi_counted(&mut self, keys: &[String]) -> Result<usize, CacheError>;
+    fn delete_pattern(&mut self, pattern: &str) -> Result<usize, CacheError>;
+    fn clear(&mut self) -> Result<(), CacheError>;
+    fn scan_keys(&self, pattern: &str) -> Result<HashMap<String, String>, CacheError>;
+    fn list_keys(&self, pattern: &str) -> Result<Vec<String>, CacheError>;
+    fn count_keys(&self, pattern: &str) -> Result<usize, CacheError>;
+    fn invalidate_tag(&mut self, tag: &str) -> Result<Vec<String>, CacheError>;
+    fn health_check(&self) -> Result<(), CacheError>;
+    /// Returns a boxed clone of this handle, sharing the same backend.
+    fn clone_box(&self) -> DynCacheHandle;
+}
+
+/// A cache handle whose backend is chosen at runtime.
+///
+/// ```ignore
+/// let handle: DynCacheHandle = match config.cache_url {
+///     Some(url) => Box::new(RedisCache::new(&url)?.handle()),
+///     None => Box::new(HashmapCache::new().handle()),
+/// };
+/// let results = students::table
+///     .select((Student::as_select(), cache_key_expr("student", students::id)))
+///     .populate_cache(handle.clone())
+///     .load::<Student>(connection)?;
+/// ```
+pub type DynCacheHandle = Box<dyn RawCacheHandle>;
+
+impl<C: CacheHandle + Send + 'static> RawCacheHandle for C {
+    fn get_json(&self, key: &String) -> Result<Option<Value>, CacheError> {
+        CacheHandle::get(self, key)
+    }
+
+    fn get_json_multi(&self, keys: &[String]) -> Result<Vec<Option<Value>>, CacheError> {
+        CacheHandle::get_multi(self, keys)
+    }
+
+    fn get_json_with_ttl(
+        &self,
+        key: &String,
+    ) -> Result<Option<(Value, Option<Duration>)>, CacheError> {
+        CacheHandle::get_with_ttl(self, key)
+    }
+
+    fn put_json(&mut self, key: &String, value: &Value) -> Result<(), CacheError> {
+        CacheHandle::put(self, key, value)
+    }
+
+    fn put_json_with_ttl(
+        &mut self,
+        key: &String,
+        value: &Value,
+        ttl: Duration,
+    ) -> Result<(), CacheError> {
+        CacheHandle::put_with_ttl(self, key, value, ttl)
+    }
+
+    fn put_json_if_absent(&mut self, key: &String, value: &Value) -> Result<bool, CacheError> {
+        CacheHandle::put_if_absent(self, key, value)
+    }
+
+    fn compare_and_set_json(
+        &mut self,
+        key: &String,
+        expected_version: u64,
+        value: &Value,
+    ) -> Result<bool, CacheError> {
+        CacheHandle::compare_and_set(self, key, expected_version, value)
+    }
+
+    fn put_json_tagged(
+        &mut self,
+        key: &String,
+        value: &Value,
+        tags: &[&str],
+    ) -> Result<(), CacheError> {
+        CacheHandle::put_tagged(self, key, value, tags)
+    }
+
+    fn put_json_read_at(
+        &mut self,
+        key: &String,
+        value: &Value,
+        ttl: Option<Duration>,
+        read_at: SystemTime,
+    ) -> Result<bool, CacheError> {
+        CacheHandle::put_read_at(self, key, value, ttl, read_at)
+    }
+
+    fn get_raw(&self, key: &String) -> Result<Option<Vec<u8>>, CacheError> {
+        CacheHandle::get_raw(self, key)
+    }
+
+    fn put_raw(&mut self, key: &String, bytes: &[u8]) -> Result<(), CacheError> {
+        CacheHandle::put_raw(self, key, bytes)
+    }
+
+    fn exists(&self, key: &String) -> Result<bool, CacheError> {
+        CacheHandle::exists(self, key)
+    }
+
+    fn entry_info(&self, key: &String) -> Result<Option<EntryInfo>, CacheError> {
+        CacheHandle::entry_info(self, key)
+    }
+
+    fn delete(&mut self, key: &String) -> Result<(), CacheError> {
+        CacheHandle::delete(self, key)
+    }
+
+    fn delete_multi(&mut self, keys: &[String]) -> Result<(), CacheError> {
+        CacheHandle::delete_multi(self, keys)
+    }
+
+    fn delete_multi_counted(&mut self, keys: &[String]) -> Result<usize, CacheError> {
+        CacheHandle::delete_multi_counted(self, keys)
+    }
+
+    fn delete_pattern(&mut self, pattern: &str) -> Result<usize, CacheError> {
+        CacheHandle::delete_pattern(self, pattern)
+    }
+
+    fn clear(&mut self) -> Result<(), CacheError> {
+        CacheHandle::clear(self)
+    }
+
+    fn scan_keys(&self, pattern: &str) -> Result<HashMap<String, String>, CacheError> {
+        CacheHandle::scan_keys(self, pattern)
+    }
+
+    fn list_keys(&self, pattern: &str) -> Result<Vec<String>, CacheError> {
+        CacheHandle::list_keys(self, pattern)
+    }
+
+    fn count_keys(&self, pattern: &str) -> Result<usize, CacheError> {
+        CacheHandle::count_keys(self, pattern)
+    }
+
+    fn invalidate_tag(&mut self, tag: &str) -> Result<Vec<String>, CacheError> {
+        CacheHandle::invalidate_tag(self, tag)
+    }
+
+    fn health_check(&self) -> Result<(), CacheError> {
+        CacheHandle::health_check(self)
+    }
+
+    fn clone_box(&self) -> DynCacheHandle {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for DynCacheHandle {
+    fn clone(&self) -> Self {
+        self.as_ref().clone_box()
+    }
+}
+
+pub(crate) fn to_json<V: Serialize>(value: &V) -> Result<Value, CacheError> {
+    serde_json::to_value(value).map_err(|e| {
+        CacheError::with_cause("Failed to serialize value", e)
+            .with_kind(CacheErrorKind::Serialization)
+    })
+}
+
+pub(crate) fn from_json<V: DeserializeOwned>(value: Value) -> Result<V, CacheError> {
+    serde_json::from_value(value).map_err(|e| {
+        CacheError::with_cause("Failed to deserialize value", e)
+            .with_kind(CacheErrorKind::Deserialization)
+    })
+}
+
+// The calls go through `self.as_ref()` / `self.as_mut()` so they resolve to
+// the trait object's `RawCacheHandle` methods, not back to this impl.
+impl CacheHandle for DynCacheHandle {
+    fn get<V: Serialize + DeserializeOwned>(&self, key: &String) -> Result<Option<V>, CacheError> {
+        self.as_ref().get_json(key)?.map(from_json).transpose()
+    }
+
+    fn get_multi<V: Serialize + DeserializeOwned>(
+        &self,
+        keys: &[String],
+    ) -> Result<Vec<Option<V>>, CacheError> {
+        self.as_ref()
+            .get_json_multi(keys)?
+            .into_iter()
+            .map(|value| value.map(from_json).transpose())
+            .collect()
+    }
+
+    fn get_with_ttl<V: Serialize + DeserializeOwned>(
+        &self,
+        key: &String,
+    ) -> Result<Option<(V, Option<Duration>)>, CacheError> {
+        self.as_ref()
+            .get_json_with_ttl(key)?
+            .map(|(value, ttl)| Ok((from_json(value)?, ttl)))
+            .transpose()
+    }
+
+    fn exists(&self, key: &String) -> Result<bool, CacheError> {
+        self.as_ref().exists(key)
+    }
+
+    fn entry_info(&self, key: &String) -> Result<Option<EntryInfo>, CacheError> {
+        self.as_ref().entry_info(key)
+    }
+
+    fn put<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+    ) -> Result<(), CacheError> {
+        self.as_mut().put_json(key, &to_json(value)?)
+    }
+
+    fn get_raw(&self, key: &String) -> Result<Option<Vec<u8>>, CacheError> {
+        self.as_ref().get_raw(key)
+    }
+
+    fn put_raw(&mut self, key: &String, bytes: &[u8]) -> Result<(), CacheError> {
+        self.as_mut().put_raw(key, bytes)
+    }
+
+    fn put_with_ttl<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+        ttl: Duration,
+    ) -> Result<(), CacheError> {
+        self.as_mut().put_json_with_ttl(key, &to_json(value)?, ttl)
+    }
+
+    fn put_if_absent<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+    ) -> Result<bool, CacheError> {
+        self.as_mut().put_json_if_absent(key, &to_json(value)?)
+    }
+
+    fn compare_and_set<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        expected_version: u64,
+        value: &V,
+    ) -> Result<bool, CacheError> {
+        self.as_mut()
+            .compare_and_set_json(key, expected_version, &to_json(value)?)
+    }
+
+    fn put_read_at<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+        ttl: Option<Duration>,
+        read_at: SystemTime,
+    ) -> Result<bool, CacheError> {
+        self.as_mut()
+            .put_json_read_at(key, &to_json(value)?, ttl, read_at)
+    }
+
+    fn delete(&mut self, key: &String) -> Result<(), CacheError> {
+        self.as_mut().delete(key)
+    }
+
+    fn delete_multi(&mut self, keys: &[String]) -> Result<(), CacheError> {
+        self.as_mut().delete_multi(keys)
+    }
+
+    fn delete_multi_counted(&mut self, keys: &[String]) -> Result<usize, CacheError> {
+        self.as_mut().delete_multi_counted(keys)
+    }
+
+    fn delete_pattern(&mut self, pattern: &str) -> Result<usize, CacheError> {
+        self.as_mut().delete_pattern(pattern)
+    }
+
+    fn clear(&mut self) -> Result<(), CacheError> {
+        self.as_mut().clear()
+    }
+
+    fn scan_keys(&self, pattern: &str) -> Result<HashMap<String, String>, CacheError> {
+        self.as_ref().scan_keys(pattern)
+    }
+
+    fn list_keys(&self, pattern: &str) -> Result<Vec<String>, CacheError> {
+        self.as_ref().list_keys(pattern)
+    }
+
+    fn count_keys(&self, pattern: &str) -> Result<usize, CacheError> {
+        self.as_ref().count_keys(pattern)
+    }
+
+    fn put_tagged<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+        tags: &[&str],
+    ) -> Result<(), CacheError> {
+        self.as_mut().put_json_tagged(key, &to_json(value)?, tags)
+    }
+
+    fn invalidate_tag(&mut self, tag: &str) -> Result<Vec<String>, CacheError> {
+        self.as_mut().invalidate_tag(tag)
+    }
+
+    fn health_check(&self) -> Result<(), CacheError> {
+        self.as_ref().health_check()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Only `CacheHandle` is imported, as the trait docs recommend.
+    use super::DynCacheHandle;
+    use crate::cacher::{CacheErrorKind, CacheHandle, HashmapCache};
+    use crate::null_cacher::NullCache;
+    use crate::redis_cacher::RedisCache;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Student {
+        id: i32,
+        name: String,
+    }
+
+    fn handle_for(backend: &str) -> DynCacheHandle {
+        match backend {
+            "memory" => Box::new(HashmapCache::new().handle()),
+            // Connects lazily, so this needs no server until first used.
+            "redis" => Box::new(RedisCache::new("redis://127.0.0.1:1/").unwrap().handle()),
+            _ => Box::new(NullCache::new()),
+        }
+    }
+
+    #[test]
+    fn test_backend_picked_at_runtime() {
+        let key = "student:2".to_string();
+        let student = Student {
+            id: 2,
+            name: "Ori".to_string(),
+        };
+
+        let mut handle = handle_for("memory");
+        handle.put(&key, &student).unwrap();
+        let clone = handle.clone();
+        assert_eq!(clone.get::<Student>(&key).unwrap(), Some(student));
+        // Entries are stored in the inner handle's format.
+        assert_eq!(
+            clone.get_raw(&key).unwrap().unwrap(),
+            br#"{"id":2,"name":"Ori"}"#
+        );
+        assert!(
+            clone
+                .get::<i32>(&key)
+                .is_err_and(|e| e.kind() == CacheErrorKind::Deserialization)
+        );
+
+        let mut handle = handle_for("none");
+        handle.put(&key, &"ignored".to_string()).unwrap();
+        assert_eq!(handle.get::<String>(&key).unwrap(), None);
+
+        let handle = handle_for("redis");
+        assert!(
+            handle
+                .health_check()
+                .is_err_and(|e| e.kind() == CacheErrorKind::Connection)
+        );
+    }
+}
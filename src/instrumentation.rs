@@ -0,0 +1,124 @@
+//! Structured instrumentation of the statement wrappers.
+//!
+//! With the `tracing` feature, every wrapped query runs inside a
+//! `turbodiesel.query` span and each cache operation inside a nested
+//! `turbodiesel.cache` span, with hits, misses and writes recorded as events
+//! carrying `key`, `cache_hit` and `bytes` fields. Without it, spans are
+//! no-ops and the same events are written through `log`.
+
+use std::fmt::Display;
+
+#[cfg(feature = "tracing")]
+pub(crate) type Span = tracing::Span;
+
+/// Stand-in for `tracing::Span` when the `tracing` feature is off.
+#[cfg(not(feature = "tracing"))]
+#[derive(Clone, Debug)]
+pub(crate) struct Span;
+
+#[cfg(not(feature = "tracing"))]
+impl Span {
+    #[cfg(test)]
+    pub(crate) fn none() -> Self {
+        Span
+    }
+
+    pub(crate) fn in_scope<T>(&self, f: impl FnOnce() -> T) -> T {
+        f()
+    }
+
+    pub(crate) fn enter(&self) -> Entered {
+        Entered
+    }
+}
+
+/// Stand-in for the guard returned by `tracing::Span::enter`.
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct Entered;
+
+/// Span covering one wrapped query, from `internal_load` (or `execute`) to the
+/// last row.
+pub(crate) fn query_span(wrapper: &'static str) -> Span {
+    #[cfg(feature = "tracing")]
+    return tracing::debug_span!("turbodiesel.query", wrapper);
+    #[cfg(not(feature = "tracing"))]
+    {
+        log::debug!("In {} internal_load", wrapper);
+        Span
+    }
+}
+
+/// Runs the cache operation `f` inside its own span.
+pub(crate) fn cache_op<T>(op: &'static str, key: &str, f: impl FnOnce() -> T) -> T {
+    #[cfg(feature = "tracing")]
+    return tracing::trace_span!("turbodiesel.cache", op, key).in_scope(f);
+    #[cfg(not(feature = "tracing"))]
+    {
+        _ = (op, key);
+        f()
+    }
+}
+
+/// Runs the cache operation `f` on `keys` keys inside its own span.
+pub(crate) fn cache_batch_op<T>(op: &'static str, keys: usize, f: impl FnOnce() -> T) -> T {
+    #[cfg(feature = "tracing")]
+    return tracing::trace_span!("turbodiesel.cache", op, keys).in_scope(f);
+    #[cfg(not(feature = "tracing"))]
+    {
+        _ = (op, keys);
+        f()
+    }
+}
+
+pub(crate) fn cache_hit(key: &str) {
+    #[cfg(feature = "tracing")]
+    tracing::debug!(key, cache_hit = true, "cache hit");
+    #[cfg(not(feature = "tracing"))]
+    log::debug!("Cache hit for key: {}", key);
+}
+
+pub(crate) fn cache_miss(key: &str) {
+    #[cfg(feature = "tracing")]
+    tracing::debug!(key, cache_hit = false, "cache miss");
+    #[cfg(not(feature = "tracing"))]
+    log::debug!("Cache miss for key: {}, reading from inner", key);
+}
+
+/// A cached not-found marker, which counts as a hit.
+pub(crate) fn cache_not_found(key: &str) {
+    #[cfg(feature = "tracing")]
+    tracing::debug!(
+        key,
+        cache_hit = true,
+        not_found = true,
+        "cached not-found marker"
+    );
+    #[cfg(not(feature = "tracing"))]
+    log::debug!("Cached not-found marker for key: {}", key);
+}
+
+pub(crate) fn lookup_failed(key: &str, error: &impl Display) {
+    #[cfg(feature = "tracing")]
+    tracing::warn!(key, %error, "cache lookup failed, reading from inner");
+    #[cfg(not(feature = "tracing"))]
+    log::warn!(
+        "Error retrieving from cache for key: {}, reading from inner; error {}",
+        key,
+        error
+    );
+}
+
+pub(crate) fn write_failed(key: &str, error: &impl Display) {
+    #[cfg(feature = "tracing")]
+    tracing::warn!(key, %error, "cache write failed");
+    #[cfg(not(feature = "tracing"))]
+    log::warn!("Error caching value for key {}: {}", key, error);
+}
+
+/// A value of `bytes` serialized bytes was handed to the backend for `key`.
+pub(crate) fn stored(key: &str, bytes: usize) {
+    #[cfg(feature = "tracing")]
+    tracing::trace!(key, bytes, "value stored");
+    #[cfg(not(feature = "tracing"))]
+    log::trace!("Stored {} bytes for key {}", bytes, key);
+}
@@ -5,13 +5,102 @@
 //!
 //! It introduces a family of *statement wrappers* that allow caching behaviors to be applied transparently to Diesel query builders:
 //!
-//! - `populate_cache`: executes the query and populates a cache with the results
+//! - `populate_cache`: executes the query and populates a cache with the results; `quiet` logs only the number of rows
+//!   cached instead of a line per row
+//! - `populate_cache_with_ttl`: same as `populate_cache` but every cached entry expires after a fixed TTL; `with_ttl_jitter`
+//!   adds a random per-row offset so entries loaded together do not all expire at once
+//! - `populate_cache_with_row_ttl`: same as `populate_cache` but caches each row for a TTL selected alongside it, e.g.
+//!   longer for recent rows than for archived ones
+//! - `populate_cache_batched`: same as `populate_cache` but writes the rows with one `put_multi` call per batch of N rows
+//! - `populate_cache_by_ids`: same as `populate_cache` but filters on a list of ids and builds each row's key from its id
+//! - `populate_cache_with_key_fn`: same as `populate_cache` but computes each row's key in Rust from the loaded row
 //! - `try_from_cache`: attempts to load from cache first, falling back to the database if the key is missing
 //! - `try_from_cache_multi`: same as `try_from_cache` but supports multiple keys at once
+//! - `populate_cache_list` / `try_from_cache_list`: cache all rows of a query as one list under a single key, and read it back
 //! - `try_from_cache_and_populate`: first attempts cache lookup, then falls back to DB if missing, and updates the cache afterward
-//! - `invalidate_key`: invalidates a specific cache key in a single Diesel update statement
+//! - `try_from_cache_negative`: same as `try_from_cache_and_populate` but also caches a short-lived marker for missing rows
+//! - `try_from_cache_once`: same as `try_from_cache` but deletes a cache hit once read, for single-use values
+//! - `try_from_cache_refresh_ahead`: serves a key from the cache and re-reads it in the background shortly before it expires
+//! - `try_from_cache_auto`: same as `try_from_cache_and_populate` but derives the key from the query's SQL and binds
+//! - `try_from_cache_stale_while_revalidate`: serves a key past its soft TTL while re-reading it in the background, until its hard TTL
+//! - `try_from_cache_serve_stale_on_error`: reads through the cache, keeping expired rows for a grace window and serving them
+//!   only when the database read fails
+//! - `try_from_cache_with_stats`: same as `try_from_cache` but records hits, misses and errors into a shared `CacheStats`
+//! - `invalidate_key`: invalidates a specific cache key in a single Diesel update statement, before the update, after it,
+//!   or both, as chosen by an `InvalidationOrder`
+//! - `invalidate_key_best_effort`: same as `invalidate_key` but still runs the update when the cache is unreachable;
+//!   `statement_wrappers::set_best_effort` turns this on for every invalidation and write-through
+//! - `defer_invalidate_key`: queues a cache key in a `DeferredInvalidations`, deleted only once the transaction commits
+//! - `invalidate_tag`: invalidates every cache key stored with `put_tagged` under a tag, e.g. a row and its derived aggregates
+//! - `invalidate_pattern`: invalidates every cache key matching a wildcard pattern; it scans the whole cache, so keep it
+//!   for occasional bulk updates
+//! - `write_through_update`: writes the row returned by an update back into the cache instead of invalidating it
+//! - `refresh_from_returning`: replaces the cached copies of every row returned by an update, keyed by a function of the row
+//! - `invalidate_from_returning`: invalidates the cache keys of exactly the rows returned by a delete, keyed by a function
+//!   of the row
+//! - `populate_on_insert`: writes the rows returned by an insert, or an upsert with `ON CONFLICT DO UPDATE`, into the cache
+//!
+//! `pg_invalidation::PgInvalidationListener` evicts the keys sent by Postgres `NOTIFY`, e.g. from a trigger, so that updates
+//! made outside turbodiesel are invalidated too.
+//!
+//! The read and populate wrappers also apply to raw SQL from `diesel::sql_query`, boxed with `into_boxed` once parameters
+//! are bound, with the cache key given explicitly.
+//!
+//! The cache key selected alongside each row can be built with `cache_key::cache_key_expr`, e.g.
+//! `cache_key_expr("student", students::id)` for `'student:' || id::text`. A `cache_key::KeyFormat` passed to
+//! `RedisCache::with_key_format` changes the separator and adds a prefix or suffix to every stored key.
+//! Models deriving `cache_key::Cacheable`, e.g. with `#[cache(prefix = "student", key = "id")]`, compute their own key
+//! with `student.cache_key()`, for `populate_cache_with_key_fn` and `invalidate_key` alike, or from the key fields with
+//! `Student::cache_key_for(2)`. `key(tenant_id, id)` builds composite keys.
+//! The wrappers keying rows in Rust take a `cache_key::KeyExtractor`, implemented by closures, so custom key logic can
+//! be written once as a type and shared by `populate_cache_with_key_fn`, `populate_on_insert` and the rest.
 //!
 //! The design supports both in-memory and Redis-backed cache handles, providing flexibility for unit tests and production environments.
+//! `TieredCache` combines the two, serving hot keys from an in-process cache in front of Redis.
+//! `ChainedCache` does the same for any number of `DynCacheHandle` tiers, the last one being
+//! authoritative.
+//! `ShardedRedisCache` spreads keys over several independent Redis instances by consistent hashing.
+//! Redis writes and invalidations are stamped with a time, and an invalidation keeps out writes stamped before it for a
+//! while: `RedisCacheHandle::put_as_of` stamps a row with the time it was read, so that a row read before a concurrent
+//! update is not cached again after the update invalidated it. `with_tombstone_ttl` sets that window, after which
+//! the invalidated key and its tombstone expire.
+//! `RedisCacheHandle::patch_field` replaces one field of a cached JSON value, addressed by a JSON Pointer, after an
+//! update of a single column, without rewriting the whole row.
+//! `RedisCache::with_read_replica` writes to a Redis primary and reads from its replica; `with_read_your_writes` sends
+//! reads of recently written keys to the primary, so replication lag does not hide them.
+//! `NullCache` stores nothing, turning caching off without changing query code.
+//! `dyn_cacher::DynCacheHandle` boxes any of them behind an object-safe trait, so the backend can be picked at runtime.
+//! `swappable_cacher::SwappableCache` holds one whose backend can be replaced while running, e.g. to migrate to Redis,
+//! with every clone switching over at its next operation.
+//! `RecordingCache` logs every operation on an inner handle, so tests can assert on the cache traffic of a query.
+//! `RecordingCache::dry_run` records the operations without touching any cache, and its `report` explains which keys the
+//! wrappers would look up, populate and invalidate.
+//! `CacheHandle::list` streams the keys matching a pattern along with their typed values, e.g. for a cache-inspection endpoint.
+//! `CacheHandle::list_keys` returns just the matching keys, without reading any value.
+//! `CacheHandle::compare_and_set` writes a `VersionedValue` only if its version is unchanged, for read-modify-write updates
+//! of cached aggregates that must not overwrite each other.
+//! `CacheHandle::entry_info` reports when a value was stored, when it was last read and how many times, for cache
+//! analytics; `RedisCache::with_entry_tracking` turns on the read counting in Redis.
+//! The `memcached` feature adds `MemcachedCache` for infrastructure standardized on memcached instead of Redis.
+//! Values are stored as JSON by default; the `bincode` and `msgpack` features add compact binary serializers that can be
+//! selected with `with_serializer` on either cache. The `gzip` and `zstd` features add transparent compression of large values.
+//! `with_json_config` keeps JSON but pretty-prints values or sorts their object keys, for inspection or stable bytes.
+//! `CompressingSerializer::with_stats` counts the values stored compressed and raw, and the bytes saved, to tune its threshold.
+//! Wrapping the serializer in a `serializer::MeasuringSerializer` records payload sizes and serialization time into a `CacheStats`.
+//! `RedisCache::with_schema_version` tags values with a schema version, bumped whenever a model changes shape, so that
+//! entries cached by a previous deployment are treated as misses instead of being deserialized into the new shape.
+//! `RedisCache::with_integrity_check` stores a CRC32 checksum with every value, so damaged values fail with a
+//! `CacheErrorKind::Corruption` error, or read as misses with `corruption_as_miss`, instead of a cryptic serde error.
+//! The `jsonschema` feature adds `validating_cacher::ValidatingCache`, which checks the values written, and optionally
+//! read, against a JSON Schema shared with other readers of the cache, failing with a `CacheErrorKind::Validation` error.
+//! A row type can also be cached in a different shape than its own serde impls by passing a `serializer::CacheCodec` to
+//! `with_codec` on `populate_cache` and the `try_from_cache` family.
+//! The `tracing` feature emits `tracing` spans and events for every wrapped query and cache operation instead of `log` records.
+//!
+//! For async stacks built on `diesel-async`, the `async_statement_wrappers` module provides `_async` variants of the
+//! select wrappers that talk to the cache through an `AsyncCacheHandle`, so the executor is never blocked on cache I/O.
+//! Going the other way, `blocking_cacher::BlockingCacheHandle` drives an `AsyncCacheHandle` on a small runtime of its
+//! own, so synchronous query code can use an async backend.
 //!
 //! These primitives integrate directly into Diesel’s query DSL with minimal friction, while allowing fine-grained control
 //! over cache population, invalidation, and fallback behavior. They enable safe, testable caching around Diesel’s transactional
@@ -19,19 +108,52 @@
 //!
 //! Typical usage patterns include populating the cache on bulk loads, invalidating cache entries on updates, and verifying
 //! cache coherence under concurrent conditions, as demonstrated in the included integration tests.
+// Lets the code generated by `turbodiesel-derive` name `::turbodiesel` in
+// this crate's own tests, too.
+extern crate self as turbodiesel;
+
+pub mod async_cacher;
+pub mod async_redis_cacher;
+pub mod async_statement_wrappers;
+pub mod blocking_cacher;
+pub mod cache_key;
+pub mod cache_stats;
 pub mod cacher;
+pub mod chained_cacher;
+pub mod deferred_invalidation;
+pub mod dyn_cacher;
+mod instrumentation;
+pub mod null_cacher;
+pub mod pg_invalidation;
+pub mod recording_cacher;
 pub mod redis_cacher;
+pub mod serializer;
+pub mod sharded_redis_cacher;
 pub mod statement_wrappers;
+pub mod swappable_cacher;
+pub mod tiered_cacher;
 
-#[cfg(all(feature = "inmemory", feature = "redis"))]
-compile_error!("feature \"inmemory\" and feature \"redis\" cannot be enabled at the same time");
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+pub mod compression;
 
 #[cfg(feature = "inmemory")]
-pub mod statement_extension_inmemory;
+pub mod dashmap_cacher;
 
-#[cfg(feature = "redis")]
-pub mod statement_extension_redis;
+#[cfg(feature = "memcached")]
+pub mod memcached_cacher;
+
+#[cfg(feature = "jsonschema")]
+pub mod validating_cacher;
 
 pub mod test_utils;
 pub mod redis_test_util;
 pub mod postgres_test_util;
+
+#[cfg(feature = "mysql")]
+pub mod mysql_test_util;
+
+#[cfg(feature = "sqlite")]
+pub mod sqlite_test_util;
+
+#[cfg(feature = "memcached")]
+pub mod memcached_test_util;
@@ -0,0 +1,383 @@
+use crate::cacher::{CacheError, CacheErrorKind, CacheHandle};
+use crate::instrumentation;
+use crate::serializer::{JsonSerializer, Serializer};
+use log::debug;
+use memcache::{CommandError, MemcacheError};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime};
+
+/// Longest expiration memcached accepts as a relative number of seconds;
+/// larger values are read as an absolute Unix timestamp.
+const MAX_RELATIVE_EXPIRATION: u64 = 60 * 60 * 24 * 30;
+
+/// Name of the memcached entry listing the keys tagged with `tag`.
+fn tag_key(tag: &str) -> String {
+    format!("tag:{}", tag)
+}
+
+/// Wraps a memcache error, classifying I/O and pool failures as
+/// `Connection` errors and anything else as `Backend`.
+fn memcache_error(message: &str, e: MemcacheError) -> CacheError {
+    let kind = match e {
+        MemcacheError::IOError(_) | MemcacheError::PoolError(_) => CacheErrorKind::Connection,
+        _ => CacheErrorKind::Backend,
+    };
+    CacheError::with_cause(message, e).with_kind(kind)
+}
+
+/// Converts a TTL into a memcached expiration, rounding up to whole seconds
+/// so a short TTL never becomes 0, which means "never expires".
+fn expiration(ttl: Duration) -> Result<u32, CacheError> {
+    let secs = ttl.as_secs() + u64::from(ttl.subsec_nanos() > 0);
+    let secs = secs.max(1);
+    if secs <= MAX_RELATIVE_EXPIRATION {
+        return Ok(secs as u32);
+    }
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(|e| CacheError::with_cause("Failed to get current time", e))?;
+    u32::try_from(now.as_secs() + secs)
+        .map_err(|e| CacheError::with_cause("TTL is too long for memcached", e))
+}
+
+/// Cache backed by one or more memcached servers, through the `memcache`
+/// crate.
+///
+/// Memcached keys are limited to 250 bytes without spaces or control
+/// characters. Memcached cannot enumerate its keys, so `scan_keys` and
+/// `count_keys` return an error, and `clear` flushes every key on the
+/// servers, not only those written through this cache.
+pub struct MemcachedCache<S: Serializer = JsonSerializer> {
+    client: memcache::Client,
+    serializer: S,
+}
+
+impl MemcachedCache {
+    /// Connects to `memcached_url`, e.g. `memcache://127.0.0.1:11211`.
+    pub fn new(memcached_url: &str) -> Result<Self, MemcacheError> {
+        let client = memcache::Client::connect(memcached_url)?;
+        Ok(MemcachedCache {
+            client,
+            serializer: JsonSerializer,
+        })
+    }
+}
+
+impl<S: Serializer> MemcachedCache<S> {
+    /// Replaces the serializer used to encode stored values. All handles
+    /// reading the same keys must use the same serializer.
+    pub fn with_serializer<S2: Serializer>(self, serializer: S2) -> MemcachedCache<S2> {
+        MemcachedCache {
+            client: self.client,
+            serializer,
+        }
+    }
+
+    pub fn handle(&self) -> MemcachedCacheHandle<S> {
+        MemcachedCacheHandle {
+            client: self.client.clone(),
+            serializer: self.serializer.clone(),
+        }
+    }
+}
+
+/// Handle to a `MemcachedCache`. Clones share the client's connection pool.
+#[derive(Clone)]
+pub struct MemcachedCacheHandle<S: Serializer = JsonSerializer> {
+    client: memcache::Client,
+    serializer: S,
+}
+
+impl<S: Serializer> MemcachedCacheHandle<S> {
+    fn set<V: Serialize>(
+        &mut self,
+        key: &str,
+        value: &V,
+        ttl: Option<Duration>,
+    ) -> Result<(), CacheError> {
+        let serialized = self.serializer.serialize(value)?;
+        self.set_raw(key, &serialized, ttl)
+    }
+
+    fn set_raw(
+        &mut self,
+        key: &str,
+        serialized: &[u8],
+        ttl: Option<Duration>,
+    ) -> Result<(), CacheError> {
+        instrumentation::stored(key, serialized.len());
+        let expiration = ttl.map_or(Ok(0), expiration)?;
+        self.client
+            .set(key, serialized, expiration)
+            .map_err(|e| memcache_error("Failed to set memcached key", e))
+    }
+
+    /// Deserializes a stored value, treating one the serializer reports as a
+    /// miss, e.g. written under another schema version, as such.
+    fn decode<V: DeserializeOwned>(&self, bytes: &[u8]) -> Result<Option<V>, CacheError> {
+        match self.serializer.deserialize(bytes) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if self.serializer.is_miss(&e) => {
+                debug!("Ignoring cached value: {}", e);
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl<S: Serializer> CacheHandle for MemcachedCacheHandle<S> {
+    fn get<V: Serialize + DeserializeOwned>(&self, key: &String) -> Result<Option<V>, CacheError> {
+        match self.get_raw(key)? {
+            Some(value) => self.decode(&value),
+            None => Ok(None),
+        }
+    }
+
+    fn get_multi<V: Serialize + DeserializeOwned>(
+        &self,
+        keys: &[String],
+    ) -> Result<Vec<Option<V>>, CacheError> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let key_refs = keys.iter().map(String::as_str).collect::<Vec<_>>();
+        let mut values: HashMap<String, Vec<u8>> = self
+            .client
+            .gets(&key_refs)
+            .map_err(|e| memcache_error("Failed to get memcached keys", e))?;
+        keys.iter()
+            .map(|key| match values.remove(key) {
+                Some(value) => self.decode(&value),
+                None => Ok(None),
+            })
+            .collect()
+    }
+
+    /// Memcached has no existence check, so this fetches the value without
+    /// deserializing it.
+    fn exists(&self, key: &String) -> Result<bool, CacheError> {
+        let value: Option<Vec<u8>> = self
+            .client
+            .get(key)
+            .map_err(|e| memcache_error("Failed to get memcached key", e))?;
+        Ok(value.is_some())
+    }
+
+    fn put<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+    ) -> Result<(), CacheError> {
+        self.set(key, value, None)
+    }
+
+    fn get_raw(&self, key: &String) -> Result<Option<Vec<u8>>, CacheError> {
+        self.client
+            .get(key)
+            .map_err(|e| memcache_error("Failed to get memcached key", e))
+    }
+
+    fn put_raw(&mut self, key: &String, bytes: &[u8]) -> Result<(), CacheError> {
+        self.set_raw(key, bytes, None)
+    }
+
+    fn put_with_ttl<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+        ttl: Duration,
+    ) -> Result<(), CacheError> {
+        self.set(key, value, Some(ttl))
+    }
+
+    /// Uses memcached's atomic `add`. Only the binary protocol, the default,
+    /// reports an `add` that was not stored; over `?protocol=ascii` this
+    /// always returns `Ok(true)`.
+    fn put_if_absent<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+    ) -> Result<bool, CacheError> {
+        let serialized = self.serializer.serialize(value)?;
+        match self.client.add(key, serialized.as_slice(), 0) {
+            Ok(()) => {
+                instrumentation::stored(key, serialized.len());
+                Ok(true)
+            }
+            Err(MemcacheError::CommandError(CommandError::KeyExists)) => Ok(false),
+            Err(e) => Err(memcache_error("Failed to add memcached key", e)),
+        }
+    }
+
+    fn delete(&mut self, key: &String) -> Result<(), CacheError> {
+        self.client
+            .delete(key)
+            .map_err(|e| memcache_error("Failed to delete memcached key", e))?;
+        Ok(())
+    }
+
+    /// Memcached has no multi-key delete, so this deletes the keys one by one.
+    fn delete_multi(&mut self, keys: &[String]) -> Result<(), CacheError> {
+        self.delete_multi_counted(keys).map(|_| ())
+    }
+
+    fn delete_multi_counted(&mut self, keys: &[String]) -> Result<usize, CacheError> {
+        let mut existed = 0;
+        for key in keys {
+            let deleted = self
+                .client
+                .delete(key)
+                .map_err(|e| memcache_error("Failed to delete memcached key", e))?;
+            existed += usize::from(deleted);
+        }
+        Ok(existed)
+    }
+
+    /// Flushes every key on the memcached servers.
+    fn clear(&mut self) -> Result<(), CacheError> {
+        self.client
+            .flush()
+            .map_err(|e| memcache_error("Failed to flush memcached", e))
+    }
+
+    fn scan_keys(&self, _pattern: &str) -> Result<HashMap<String, String>, CacheError> {
+        Err(CacheError::new("memcached does not support scanning keys"))
+    }
+
+    fn count_keys(&self, _pattern: &str) -> Result<usize, CacheError> {
+        Err(CacheError::new("memcached does not support scanning keys"))
+    }
+
+    /// Each tag is an entry listing its keys, one per line, which `append`
+    /// extends atomically.
+    fn put_tagged<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+        tags: &[&str],
+    ) -> Result<(), CacheError> {
+        for tag in tags {
+            let tag_key = tag_key(tag);
+            // `append` fails on a missing entry, so create it empty first;
+            // `add` leaves an existing list alone.
+            match self.client.add(&tag_key, "", 0) {
+                Ok(()) | Err(MemcacheError::CommandError(CommandError::KeyExists)) => {}
+                Err(e) => return Err(memcache_error("Failed to add memcached tag", e)),
+            }
+            self.client
+                .append(&tag_key, format!("{}\n", key).as_str())
+                .map_err(|e| memcache_error("Failed to append memcached tag", e))?;
+        }
+        self.set(key, value, None)
+    }
+
+    fn invalidate_tag(&mut self, tag: &str) -> Result<Vec<String>, CacheError> {
+        let tag_key = tag_key(tag);
+        let listed: Option<String> = self
+            .client
+            .get(&tag_key)
+            .map_err(|e| memcache_error("Failed to get memcached tag", e))?;
+        self.delete(&tag_key)?;
+        let mut seen = HashSet::new();
+        let keys = listed
+            .unwrap_or_default()
+            .lines()
+            .filter(|key| seen.insert(*key))
+            .map(String::from)
+            .collect::<Vec<_>>();
+        debug!("Invalidating keys {:?} tagged {}", keys, tag);
+        self.delete_multi(&keys)?;
+        Ok(keys)
+    }
+
+    fn health_check(&self) -> Result<(), CacheError> {
+        self.client
+            .version()
+            .map(|_| ())
+            .map_err(|e| memcache_error("Failed to reach memcached", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memcached_test_util::MemcachedTestUtil;
+
+    #[test]
+    fn test_expiration_rounds_up_and_switches_to_timestamps() {
+        assert_eq!(expiration(Duration::from_millis(1)).unwrap(), 1);
+        assert_eq!(expiration(Duration::from_millis(1500)).unwrap(), 2);
+        assert_eq!(expiration(Duration::from_secs(60)).unwrap(), 60);
+
+        let long = expiration(Duration::from_secs(MAX_RELATIVE_EXPIRATION + 1)).unwrap();
+        assert!(u64::from(long) > MAX_RELATIVE_EXPIRATION);
+    }
+
+    #[tokio::test]
+    async fn test_memcached_get_put_delete() {
+        let memcached_test = MemcachedTestUtil::new();
+        memcached_test
+            .run_test_with_memcached(async move |memcached_url, _| {
+                let cache = MemcachedCache::new(memcached_url.as_str())
+                    .expect("Failed to create MemcachedCache");
+                let mut handle = cache.handle();
+
+                let key = "student:2".to_string();
+                handle.put(&key, &"Ori".to_string()).unwrap();
+                assert_eq!(handle.get::<String>(&key).unwrap(), Some("Ori".to_string()));
+                assert_eq!(
+                    handle
+                        .get_multi::<String>(&[key.clone(), "missing".to_string()])
+                        .unwrap(),
+                    vec![Some("Ori".to_string()), None]
+                );
+                assert!(!handle.put_if_absent(&key, &"Other".to_string()).unwrap());
+
+                handle.delete(&key).unwrap();
+                assert!(!handle.exists(&key).unwrap());
+                assert!(handle.put_if_absent(&key, &"Other".to_string()).unwrap());
+
+                let ttl_key = "ttl_key".to_string();
+                handle
+                    .put_with_ttl(&ttl_key, &1, Duration::from_secs(1))
+                    .unwrap();
+                assert_eq!(handle.get::<i32>(&ttl_key).unwrap(), Some(1));
+                std::thread::sleep(Duration::from_millis(2100));
+                assert_eq!(handle.get::<i32>(&ttl_key).unwrap(), None);
+
+                assert!(handle.scan_keys("*").is_err());
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_memcached_invalidate_tag() {
+        let memcached_test = MemcachedTestUtil::new();
+        memcached_test
+            .run_test_with_memcached(async move |memcached_url, _| {
+                let mut handle = MemcachedCache::new(memcached_url.as_str())
+                    .expect("Failed to create MemcachedCache")
+                    .handle();
+
+                let student = "student:2".to_string();
+                let roster = "class:roster:5".to_string();
+                handle
+                    .put_tagged(&student, &"Ori".to_string(), &["student:2"])
+                    .unwrap();
+                handle
+                    .put_tagged(&roster, &vec!["Ori".to_string()], &["student:2"])
+                    .unwrap();
+
+                let mut deleted = handle.invalidate_tag("student:2").unwrap();
+                deleted.sort();
+                assert_eq!(deleted, vec![roster.clone(), student.clone()]);
+                assert!(!handle.exists(&student).unwrap());
+                assert!(!handle.exists(&roster).unwrap());
+                assert!(handle.invalidate_tag("student:2").unwrap().is_empty());
+            })
+            .await;
+    }
+}
@@ -0,0 +1,61 @@
+use async_std::task;
+use dockertest::DockerOperations;
+use dockertest::{DockerTest, TestBodySpecification};
+use log::info;
+use memcache::MemcacheError;
+use port_check::free_local_ipv4_port;
+use std::time::Duration;
+
+pub struct MemcachedTestUtil {
+    url: String,
+    port: u16,
+}
+
+impl Default for MemcachedTestUtil {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemcachedTestUtil {
+    pub fn new() -> Self {
+        let port = free_local_ipv4_port().unwrap();
+        let url = format!("memcache://127.0.0.1:{}", port);
+        MemcachedTestUtil { url, port }
+    }
+
+    pub async fn run_test_with_memcached<Fun, Fut>(&self, f: Fun)
+    where
+        Fut: Future<Output = ()> + Send + 'static,
+        Fun: FnOnce(String, DockerOperations) -> Fut + Send + 'static,
+    {
+        let mut test = DockerTest::new();
+        let image =
+            dockertest::Image::with_repository("memcached").source(dockertest::Source::DockerHub);
+        let mut container = TestBodySpecification::with_image(image);
+        container.modify_port_map(11211, self.port.into());
+        test.provide_container(container);
+        info!("Running inside memcached: {}", self.url);
+        let url = self.url.clone();
+        test.run_async(|ops| async move {
+            Self::wait_until_memcached_online(&url, 6)
+                .await
+                .expect("memcached is not online");
+            f(url, ops).await;
+        })
+        .await;
+        info!("Finished running inside memcached.");
+    }
+
+    async fn wait_until_memcached_online(url: &str, retries: usize) -> Result<(), MemcacheError> {
+        let mut last_error = None;
+        for _ in 0..retries {
+            match memcache::Client::connect(url).and_then(|client| client.version()) {
+                Ok(_) => return Ok(()),
+                Err(e) => last_error = Some(e),
+            }
+            task::sleep(Duration::from_secs(1)).await;
+        }
+        Err(last_error.expect("retries must be positive"))
+    }
+}
@@ -0,0 +1,98 @@
+use async_std::task;
+use diesel::dsl;
+use diesel::prelude::MysqlConnection;
+use diesel::sql_types::Integer;
+use diesel::{Connection, RunQueryDsl};
+use dockertest::DockerOperations;
+use dockertest::{DockerTest, TestBodySpecification};
+use log::info;
+use port_check::free_local_ipv4_port;
+use std::error::Error;
+use std::time::Duration;
+
+pub struct MysqlTestUtil {}
+
+impl Default for MysqlTestUtil {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MysqlTestUtil {
+    pub fn new() -> Self {
+        MysqlTestUtil {}
+    }
+
+    pub async fn run_test_with_mysql<Fun, Fut>(&self, f: Fun)
+    where
+        Fut: Future<Output = ()> + Send + 'static,
+        Fun: FnOnce(String, DockerOperations) -> Fut + Send + 'static,
+    {
+        let mut test = DockerTest::new();
+        let image =
+            dockertest::Image::with_repository("mysql").source(dockertest::Source::DockerHub);
+        let mut container = TestBodySpecification::with_image(image);
+        let port = free_local_ipv4_port().unwrap();
+        let url = format!("mysql://root@127.0.0.1:{}/test", port);
+        container.modify_port_map(3306, port.into());
+        container.modify_env("MYSQL_ALLOW_EMPTY_PASSWORD", "yes");
+        container.modify_env("MYSQL_DATABASE", "test");
+        test.provide_container(container);
+        info!("Running inside MySQL: {}", url);
+        test.run_async(async move |ops| {
+            // MySQL takes considerably longer than Postgres to accept
+            // connections after the container starts.
+            Self::wait_until_mysql_online(&url, 60)
+                .await
+                .expect("mysql is not online");
+            f(url, ops).await;
+        })
+        .await;
+        info!("Finished running inside MySQL.");
+    }
+
+    async fn wait_until_mysql_online(url: &str, retries: usize) -> Result<(), Box<dyn Error>> {
+        for i in 0..retries {
+            let con_res = MysqlConnection::establish(url).map_err(Box::new);
+            match con_res {
+                Ok(mut con) => {
+                    let res = diesel::select(dsl::sql::<Integer>("1")).execute(&mut con);
+                    match res {
+                        Ok(_) => return Ok(()),
+                        Err(e) => {
+                            if i == retries - 1 {
+                                return Err(Box::new(e));
+                            }
+                        }
+                    }
+                }
+                Err(e) => {
+                    if i == retries - 1 {
+                        return Err(e);
+                    }
+                }
+            }
+            task::sleep(Duration::from_secs(1)).await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_basic_connect() {
+        let util = MysqlTestUtil::new();
+
+        util.run_test_with_mysql(async move |url, _| {
+            let mut con = MysqlConnection::establish(&url).expect("Error connecting to mysql");
+            let result = diesel::select(dsl::sql::<Integer>("1"))
+                .get_result::<i32>(&mut con)
+                .unwrap();
+            assert_eq!(result, 1);
+        })
+        .await;
+    }
+}
@@ -0,0 +1,171 @@
+use crate::cacher::{CacheError, CacheHandle, EntryInfo};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Cache handle that stores nothing.
+///
+/// Every lookup misses and every write is discarded, so wrappers such as
+/// `try_from_cache` always read from the database. Useful to turn caching off
+/// without changing query code, e.g. when chasing stale-data bugs or
+/// benchmarking the uncached path.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NullCache;
+
+impl NullCache {
+    pub fn new() -> Self {
+        NullCache
+    }
+}
+
+impl CacheHandle for NullCache {
+    fn get<V: Serialize + DeserializeOwned>(&self, _key: &String) -> Result<Option<V>, CacheError> {
+        Ok(None)
+    }
+
+    fn get_multi<V: Serialize + DeserializeOwned>(
+        &self,
+        keys: &[String],
+    ) -> Result<Vec<Option<V>>, CacheError> {
+        Ok(keys.iter().map(|_| None).collect())
+    }
+
+    fn exists(&self, _key: &String) -> Result<bool, CacheError> {
+        Ok(false)
+    }
+
+    fn entry_info(&self, _key: &String) -> Result<Option<EntryInfo>, CacheError> {
+        Ok(None)
+    }
+
+    fn put<V: Serialize + DeserializeOwned>(
+        &mut self,
+        _key: &String,
+        _value: &V,
+    ) -> Result<(), CacheError> {
+        Ok(())
+    }
+
+    fn get_raw(&self, _key: &String) -> Result<Option<Vec<u8>>, CacheError> {
+        Ok(None)
+    }
+
+    fn put_raw(&mut self, _key: &String, _bytes: &[u8]) -> Result<(), CacheError> {
+        Ok(())
+    }
+
+    fn put_with_ttl<V: Serialize + DeserializeOwned>(
+        &mut self,
+        _key: &String,
+        _value: &V,
+        _ttl: Duration,
+    ) -> Result<(), CacheError> {
+        Ok(())
+    }
+
+    fn put_multi<V: Serialize + DeserializeOwned>(
+        &mut self,
+        _entries: &[(String, V)],
+    ) -> Result<(), CacheError> {
+        Ok(())
+    }
+
+    /// Always reports the value as written, since no key is ever present.
+    fn put_if_absent<V: Serialize + DeserializeOwned>(
+        &mut self,
+        _key: &String,
+        _value: &V,
+    ) -> Result<bool, CacheError> {
+        Ok(true)
+    }
+
+    /// Nothing is ever stored, so every key is at version 0.
+    fn compare_and_set<V: Serialize + DeserializeOwned>(
+        &mut self,
+        _key: &String,
+        expected_version: u64,
+        _value: &V,
+    ) -> Result<bool, CacheError> {
+        Ok(expected_version == 0)
+    }
+
+    fn delete(&mut self, _key: &String) -> Result<(), CacheError> {
+        Ok(())
+    }
+
+    fn delete_multi(&mut self, _keys: &[String]) -> Result<(), CacheError> {
+        Ok(())
+    }
+
+    fn delete_multi_counted(&mut self, _keys: &[String]) -> Result<usize, CacheError> {
+        Ok(0)
+    }
+
+    fn clear(&mut self) -> Result<(), CacheError> {
+        Ok(())
+    }
+
+    fn scan_keys(&self, _pattern: &str) -> Result<HashMap<String, String>, CacheError> {
+        Ok(HashMap::new())
+    }
+
+    fn list_keys(&self, _pattern: &str) -> Result<Vec<String>, CacheError> {
+        Ok(Vec::new())
+    }
+
+    fn count_keys(&self, _pattern: &str) -> Result<usize, CacheError> {
+        Ok(0)
+    }
+
+    fn put_tagged<V: Serialize + DeserializeOwned>(
+        &mut self,
+        _key: &String,
+        _value: &V,
+        _tags: &[&str],
+    ) -> Result<(), CacheError> {
+        Ok(())
+    }
+
+    fn invalidate_tag(&mut self, _tag: &str) -> Result<Vec<String>, CacheError> {
+        Ok(Vec::new())
+    }
+
+    fn health_check(&self) -> Result<(), CacheError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_null_cache_stores_nothing() {
+        let mut cache = NullCache::new();
+        let key = "student:2".to_string();
+
+        cache.put(&key, &"Ori".to_string()).unwrap();
+        assert_eq!(cache.get::<String>(&key).unwrap(), None);
+        assert_eq!(
+            cache
+                .get_multi::<String>(&[key.clone(), key.clone()])
+                .unwrap(),
+            vec![None, None]
+        );
+        assert!(!cache.exists(&key).unwrap());
+        assert!(cache.scan_keys("*").unwrap().is_empty());
+
+        let mut calls = 0;
+        for _ in 0..2 {
+            let value: i32 = cache
+                .get_or_insert_with(&key, || {
+                    calls += 1;
+                    Ok(7)
+                })
+                .unwrap();
+            assert_eq!(value, 7);
+        }
+        assert_eq!(calls, 2);
+    }
+}
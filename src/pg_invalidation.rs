@@ -0,0 +1,104 @@
+use crate::cacher::CacheHandle;
+use log::{debug, error, info, warn};
+use postgres::fallible_iterator::FallibleIterator;
+use postgres::{Client, NoTls, Notification};
+use std::time::Duration;
+
+/// Evicts cache keys named by Postgres notifications, so that the database
+/// itself drives invalidation.
+///
+/// The listener opens its own connection and runs `LISTEN` on a channel.
+/// Every notification on the channel carries one cache key as its payload,
+/// which is deleted from the cache. A trigger can send them for every
+/// change to a table, which also catches updates made outside turbodiesel,
+/// e.g. by migrations or other services:
+///
+/// ```sql
+/// CREATE FUNCTION students_invalidate() RETURNS trigger AS $$
+/// BEGIN
+///     PERFORM pg_notify('cache_invalidate', 'student:' || OLD.id);
+///     RETURN NULL;
+/// END $$ LANGUAGE plpgsql;
+///
+/// CREATE TRIGGER students_invalidate AFTER UPDATE OR DELETE ON students
+///     FOR EACH ROW EXECUTE FUNCTION students_invalidate();
+/// ```
+///
+/// ```ignore
+/// let mut listener = PgInvalidationListener::connect(&database_url, "cache_invalidate", handle)?;
+/// std::thread::spawn(move || listener.run());
+/// ```
+///
+/// Postgres sends a notification only once its transaction commits, and
+/// sends it again for every listening connection, so deleting its key is
+/// safe to repeat and delivery is at-least-once while the listener is
+/// connected. Notifications sent while it is not connected are lost, though:
+/// when `run` or `poll` fails, the connection is gone, and the caller has to
+/// `connect` again and assume that any key may have gone stale in between,
+/// e.g. by clearing the cache or relying on TTLs.
+pub struct PgInvalidationListener<C: CacheHandle> {
+    client: Client,
+    channel: String,
+    cache: C,
+}
+
+impl<C: CacheHandle> PgInvalidationListener<C> {
+    /// Opens a dedicated connection to the database at `url` and starts
+    /// listening on `channel`.
+    pub fn connect(url: &str, channel: &str, cache: C) -> Result<Self, postgres::Error> {
+        let mut client = Client::connect(url, NoTls)?;
+        client.batch_execute(&format!("LISTEN {}", quote_identifier(channel)))?;
+        info!("Listening for cache invalidations on channel {}", channel);
+        Ok(PgInvalidationListener {
+            client,
+            channel: channel.to_string(),
+            cache,
+        })
+    }
+
+    /// Waits up to `timeout` for notifications and evicts their keys, until
+    /// no notification has arrived for `timeout`. Returns the number of
+    /// notifications handled.
+    pub fn poll(&mut self, timeout: Duration) -> Result<usize, postgres::Error> {
+        let mut notifications = self.client.notifications();
+        let mut iter = notifications.timeout_iter(timeout);
+        let mut handled = 0;
+        while let Some(notification) = iter.next()? {
+            evict(&mut self.cache, &self.channel, &notification);
+            handled += 1;
+        }
+        Ok(handled)
+    }
+
+    /// Evicts the keys of notifications as they arrive. Only returns when the
+    /// connection fails.
+    pub fn run(&mut self) -> Result<(), postgres::Error> {
+        let mut notifications = self.client.notifications();
+        let mut iter = notifications.blocking_iter();
+        while let Some(notification) = iter.next()? {
+            evict(&mut self.cache, &self.channel, &notification);
+        }
+        Ok(())
+    }
+}
+
+fn evict<C: CacheHandle>(cache: &mut C, channel: &str, notification: &Notification) {
+    if notification.channel() != channel {
+        return;
+    }
+    let key = notification.payload().to_string();
+    if key.is_empty() {
+        warn!("Ignoring notification without a key on channel {}", channel);
+        return;
+    }
+    debug!("Invalidating key from notification: {}", key);
+    if let Err(e) = cache.delete(&key) {
+        error!("Error invalidating key {} from notification: {}", key, e);
+    }
+}
+
+/// Quotes a channel name, which `LISTEN` takes as an identifier rather than
+/// a bind parameter.
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
@@ -12,6 +12,12 @@ use std::time::Duration;
 
 pub struct PostgresTestUtil {}
 
+impl Default for PostgresTestUtil {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl PostgresTestUtil {
     pub fn new() -> Self {
         PostgresTestUtil {}
@@ -43,11 +49,11 @@ impl PostgresTestUtil {
     }
 
     async fn wait_until_postgres_online(
-        url: &String,
+        url: &str,
         retries: usize,
     ) -> Result<(), Box<dyn Error>> {
         for i in 0..retries {
-            let con_res = PgConnection::establish(&url).map_err(|e| Box::new(e));
+            let con_res = PgConnection::establish(url).map_err(Box::new);
             if let Ok(mut con) = con_res {
                 let res = diesel::select(dsl::sql::<Integer>("1")).execute(&mut con);
                 match res {
@@ -0,0 +1,416 @@
+use crate::cacher::{
+    CacheError, CacheHandle, CacheListing, EntryInfo, HashmapCache, HashmapCacheHandle,
+};
+use crate::null_cacher::NullCache;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+
+/// A cache operation recorded by `RecordingCache`, with the key (or pattern,
+/// or tag) it was called with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CacheOperation {
+    Get(String),
+    Exists(String),
+    Put(String),
+    Delete(String),
+    DeletePattern(String),
+    Scan(String),
+    InvalidateTag(String),
+    Clear,
+}
+
+/// What the statement wrappers did, or would have done, to the cache,
+/// grouped by intent. Built from the operations of a `RecordingCache` by
+/// `report`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CacheReport {
+    /// Keys looked up, with `get` or `exists`, in the order they were.
+    pub lookups: Vec<String>,
+    /// Keys written.
+    pub populated: Vec<String>,
+    /// Keys deleted.
+    pub invalidated: Vec<String>,
+    /// Operations on several keys at once: scans, pattern and tag
+    /// invalidations, and clears.
+    pub other: Vec<CacheOperation>,
+}
+
+impl std::fmt::Display for CacheReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "lookups: {:?}, populated: {:?}, invalidated: {:?}",
+            self.lookups, self.populated, self.invalidated
+        )?;
+        if !self.other.is_empty() {
+            write!(f, ", other: {:?}", self.other)?;
+        }
+        Ok(())
+    }
+}
+
+/// Cache handle for tests that records every operation before passing it on
+/// to an inner handle.
+///
+/// Batch operations are recorded once per key, so a `get_multi` of two keys
+/// shows up as two `Get`s. Clones share the same log, which makes it possible
+/// to hand a clone to the statement wrappers and assert on the operations
+/// they performed:
+///
+/// ```ignore
+/// let cache = RecordingCache::new();
+/// students::table
+///     .select(Student::as_select())
+///     .filter(students::id.eq(2))
+///     .try_from_cache_and_populate::<Student>(cache.clone(), "student:2")
+///     .load::<Student>(connection)?;
+/// assert_eq!(
+///     cache.operations(),
+///     vec![
+///         CacheOperation::Get("student:2".to_string()),
+///         CacheOperation::Put("student:2".to_string()),
+///     ]
+/// );
+/// ```
+#[derive(Clone, Debug)]
+pub struct RecordingCache<C: CacheHandle = HashmapCacheHandle> {
+    inner: C,
+    operations: Arc<Mutex<Vec<CacheOperation>>>,
+}
+
+impl RecordingCache {
+    /// Records operations on a fresh in-memory cache.
+    pub fn new() -> Self {
+        Self::wrap(HashmapCache::new().handle())
+    }
+}
+
+impl Default for RecordingCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RecordingCache<NullCache> {
+    /// Records operations without touching any cache, to explain what the
+    /// statement wrappers would do, e.g. the order of the keys looked up by
+    /// `try_from_cache_multi`, before running them against Redis:
+    ///
+    /// ```ignore
+    /// let cache = RecordingCache::dry_run();
+    /// students::table
+    ///     .select((Student::as_select(), cache_key_expr("student", students::id)))
+    ///     .filter(students::id.eq_any(vec![1, 3]))
+    ///     .try_from_cache_multi::<Student, _>(cache.clone(), keys.into_iter())
+    ///     .load_iter::<Student, DefaultLoadingMode>(connection)?;
+    /// info!("{}", cache.report());
+    /// ```
+    ///
+    /// Every lookup misses, as with `NullCache`, so the report shows the
+    /// path of a cold cache: the keys looked up, then those populated from
+    /// the database. The statements themselves still run.
+    pub fn dry_run() -> Self {
+        Self::wrap(NullCache)
+    }
+}
+
+impl<C: CacheHandle> RecordingCache<C> {
+    /// Records operations on `inner`.
+    pub fn wrap(inner: C) -> Self {
+        RecordingCache {
+            inner,
+            operations: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Returns the operations recorded so far, oldest first.
+    pub fn operations(&self) -> Vec<CacheOperation> {
+        self.log().clone()
+    }
+
+    /// Returns the operations recorded so far and clears the log.
+    pub fn take_operations(&self) -> Vec<CacheOperation> {
+        std::mem::take(&mut *self.log())
+    }
+
+    /// Groups the operations recorded so far by intent.
+    pub fn report(&self) -> CacheReport {
+        let mut report = CacheReport::default();
+        for operation in self.operations() {
+            match operation {
+                CacheOperation::Get(key) | CacheOperation::Exists(key) => report.lookups.push(key),
+                CacheOperation::Put(key) => report.populated.push(key),
+                CacheOperation::Delete(key) => report.invalidated.push(key),
+                other => report.other.push(other),
+            }
+        }
+        report
+    }
+
+    fn record(&self, operation: CacheOperation) {
+        self.log().push(operation);
+    }
+
+    fn record_each(&self, keys: &[String], operation: fn(String) -> CacheOperation) {
+        self.log()
+            .extend(keys.iter().map(|key| operation(key.clone())));
+    }
+
+    fn log(&self) -> MutexGuard<'_, Vec<CacheOperation>> {
+        // A test that panicked while recording should not hide the log from
+        // the assertions of another.
+        self.operations
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl<C: CacheHandle> CacheHandle for RecordingCache<C> {
+    fn get<V: Serialize + DeserializeOwned>(&self, key: &String) -> Result<Option<V>, CacheError> {
+        self.record(CacheOperation::Get(key.clone()));
+        self.inner.get(key)
+    }
+
+    fn get_multi<V: Serialize + DeserializeOwned>(
+        &self,
+        keys: &[String],
+    ) -> Result<Vec<Option<V>>, CacheError> {
+        self.record_each(keys, CacheOperation::Get);
+        self.inner.get_multi(keys)
+    }
+
+    fn get_with_ttl<V: Serialize + DeserializeOwned>(
+        &self,
+        key: &String,
+    ) -> Result<Option<(V, Option<Duration>)>, CacheError> {
+        self.record(CacheOperation::Get(key.clone()));
+        self.inner.get_with_ttl(key)
+    }
+
+    fn exists(&self, key: &String) -> Result<bool, CacheError> {
+        self.record(CacheOperation::Exists(key.clone()));
+        self.inner.exists(key)
+    }
+
+    /// Records an `Exists` lookup, since the value itself is not read.
+    fn entry_info(&self, key: &String) -> Result<Option<EntryInfo>, CacheError> {
+        self.record(CacheOperation::Exists(key.clone()));
+        self.inner.entry_info(key)
+    }
+
+    fn put<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+    ) -> Result<(), CacheError> {
+        self.record(CacheOperation::Put(key.clone()));
+        self.inner.put(key, value)
+    }
+
+    fn get_raw(&self, key: &String) -> Result<Option<Vec<u8>>, CacheError> {
+        self.record(CacheOperation::Get(key.clone()));
+        self.inner.get_raw(key)
+    }
+
+    fn put_raw(&mut self, key: &String, bytes: &[u8]) -> Result<(), CacheError> {
+        self.record(CacheOperation::Put(key.clone()));
+        self.inner.put_raw(key, bytes)
+    }
+
+    fn put_with_ttl<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+        ttl: Duration,
+    ) -> Result<(), CacheError> {
+        self.record(CacheOperation::Put(key.clone()));
+        self.inner.put_with_ttl(key, value, ttl)
+    }
+
+    fn put_multi<V: Serialize + DeserializeOwned>(
+        &mut self,
+        entries: &[(String, V)],
+    ) -> Result<(), CacheError> {
+        self.log().extend(
+            entries
+                .iter()
+                .map(|(key, _)| CacheOperation::Put(key.clone())),
+        );
+        self.inner.put_multi(entries)
+    }
+
+    fn put_if_absent<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+    ) -> Result<bool, CacheError> {
+        self.record(CacheOperation::Put(key.clone()));
+        self.inner.put_if_absent(key, value)
+    }
+
+    fn compare_and_set<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        expected_version: u64,
+        value: &V,
+    ) -> Result<bool, CacheError> {
+        self.record(CacheOperation::Put(key.clone()));
+        self.inner.compare_and_set(key, expected_version, value)
+    }
+
+    fn put_read_at<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+        ttl: Option<Duration>,
+        read_at: SystemTime,
+    ) -> Result<bool, CacheError> {
+        self.record(CacheOperation::Put(key.clone()));
+        self.inner.put_read_at(key, value, ttl, read_at)
+    }
+
+    fn put_multi_read_at<V: Serialize + DeserializeOwned>(
+        &mut self,
+        entries: &[(String, V)],
+        read_at: SystemTime,
+    ) -> Result<usize, CacheError> {
+        self.log().extend(
+            entries
+                .iter()
+                .map(|(key, _)| CacheOperation::Put(key.clone())),
+        );
+        self.inner.put_multi_read_at(entries, read_at)
+    }
+
+    fn delete(&mut self, key: &String) -> Result<(), CacheError> {
+        self.record(CacheOperation::Delete(key.clone()));
+        self.inner.delete(key)
+    }
+
+    fn delete_multi(&mut self, keys: &[String]) -> Result<(), CacheError> {
+        self.record_each(keys, CacheOperation::Delete);
+        self.inner.delete_multi(keys)
+    }
+
+    fn delete_multi_counted(&mut self, keys: &[String]) -> Result<usize, CacheError> {
+        self.record_each(keys, CacheOperation::Delete);
+        self.inner.delete_multi_counted(keys)
+    }
+
+    fn delete_pattern(&mut self, pattern: &str) -> Result<usize, CacheError> {
+        self.record(CacheOperation::DeletePattern(pattern.to_string()));
+        self.inner.delete_pattern(pattern)
+    }
+
+    fn clear(&mut self) -> Result<(), CacheError> {
+        self.record(CacheOperation::Clear);
+        self.inner.clear()
+    }
+
+    fn scan_keys(&self, pattern: &str) -> Result<HashMap<String, String>, CacheError> {
+        self.record(CacheOperation::Scan(pattern.to_string()));
+        self.inner.scan_keys(pattern)
+    }
+
+    fn scan_typed<V: Serialize + DeserializeOwned>(
+        &self,
+        pattern: &str,
+    ) -> Result<HashMap<String, V>, CacheError> {
+        self.record(CacheOperation::Scan(pattern.to_string()));
+        self.inner.scan_typed(pattern)
+    }
+
+    /// Records the scan once; the reads of its values are not recorded.
+    fn list<V: Serialize + DeserializeOwned + 'static>(
+        &self,
+        pattern: &str,
+    ) -> Result<CacheListing<'_, V>, CacheError> {
+        self.record(CacheOperation::Scan(pattern.to_string()));
+        self.inner.list(pattern)
+    }
+
+    fn list_keys(&self, pattern: &str) -> Result<Vec<String>, CacheError> {
+        self.record(CacheOperation::Scan(pattern.to_string()));
+        self.inner.list_keys(pattern)
+    }
+
+    fn count_keys(&self, pattern: &str) -> Result<usize, CacheError> {
+        self.record(CacheOperation::Scan(pattern.to_string()));
+        self.inner.count_keys(pattern)
+    }
+
+    fn put_tagged<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+        tags: &[&str],
+    ) -> Result<(), CacheError> {
+        self.record(CacheOperation::Put(key.clone()));
+        self.inner.put_tagged(key, value, tags)
+    }
+
+    fn invalidate_tag(&mut self, tag: &str) -> Result<Vec<String>, CacheError> {
+        self.record(CacheOperation::InvalidateTag(tag.to_string()));
+        self.inner.invalidate_tag(tag)
+    }
+
+    fn health_check(&self) -> Result<(), CacheError> {
+        self.inner.health_check()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use CacheOperation::*;
+
+    #[test]
+    fn test_clones_share_the_operation_log() {
+        let cache = RecordingCache::new();
+        let mut handle = cache.clone();
+        let key = "student:2".to_string();
+
+        assert_eq!(handle.get::<String>(&key).unwrap(), None);
+        handle.put(&key, &"Ori".to_string()).unwrap();
+        assert_eq!(cache.get::<String>(&key).unwrap(), Some("Ori".to_string()));
+        assert_eq!(cache.scan_keys("student:*").unwrap().len(), 1);
+        handle.delete_multi(std::slice::from_ref(&key)).unwrap();
+
+        assert_eq!(
+            cache.take_operations(),
+            vec![
+                Get(key.clone()),
+                Put(key.clone()),
+                Get(key.clone()),
+                Scan("student:*".to_string()),
+                Delete(key.clone()),
+            ]
+        );
+        assert!(handle.operations().is_empty());
+    }
+
+    #[test]
+    fn test_dry_run_reports_intent_without_storing() {
+        let mut cache = RecordingCache::dry_run();
+        let keys = ["student:3".to_string(), "student:1".to_string()];
+
+        assert_eq!(cache.get_multi::<String>(&keys).unwrap(), vec![None, None]);
+        cache.put(&keys[0], &"Ori".to_string()).unwrap();
+        assert_eq!(cache.get::<String>(&keys[0]).unwrap(), None);
+        cache.delete(&keys[1]).unwrap();
+        cache.invalidate_tag("students").unwrap();
+
+        assert_eq!(
+            cache.report(),
+            CacheReport {
+                lookups: vec![keys[0].clone(), keys[1].clone(), keys[0].clone()],
+                populated: vec![keys[0].clone()],
+                invalidated: vec![keys[1].clone()],
+                other: vec![InvalidateTag("students".to_string())],
+            }
+        );
+    }
+}
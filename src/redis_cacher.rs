@@ -1,38 +1,713 @@
-use crate::cacher::CacheError;
+use crate::async_redis_cacher::AsyncRedisCacheHandle;
+use crate::cache_key::KeyFormat;
 use crate::cacher::CacheHandle;
+use crate::cacher::{CacheError, CacheErrorKind, CacheListing, EntryInfo, VersionedValue};
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+use crate::compression::{CompressingSerializer, CompressionCodec};
+use crate::instrumentation;
+use crate::serializer::{
+    ChecksumSerializer, JsonConfig, JsonSerializer, SchemaVersion, Serializer, VersionedSerializer,
+};
 use async_std::task;
-use log::{debug, info};
+use log::{debug, error, info, warn};
 use redis;
 use redis::Commands;
+use redis::ConnectionLike;
 use redis::RedisError;
 use serde::de::DeserializeOwned;
 use serde::ser::Serialize;
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::JoinHandle;
 use std::time::Duration;
+use std::time::Instant;
 use std::time::SystemTime;
 
-pub struct RedisCache {
+/// Number of keys removed per `DEL` command by `clear`.
+const CLEAR_BATCH_SIZE: usize = 500;
+
+/// Lua library loaded by `ensure_functions_loaded`.
+const REDIS_FUNCTIONS: &str = include_str!("../lua/functions.lua");
+
+/// Name of the Lua library, as declared on the first line of its script.
+const REDIS_FUNCTIONS_LIBRARY: &str = "turbodiesel";
+
+/// Default `COUNT` hint passed to each `SCAN` call.
+const DEFAULT_SCAN_COUNT: usize = 1000;
+
+/// How long the lock taken by `get_or_insert_with` is held at most.
+const COMPUTE_LOCK_TTL: Duration = Duration::from_secs(5);
+
+/// Prefix of the keys holding the locks taken by `get_or_insert_with`,
+/// which scans skip.
+const COMPUTE_LOCK_PREFIX: &str = "td-lock:";
+
+/// Interval at which `get_or_insert_with` polls for a value computed by
+/// another caller holding the lock.
+const COMPUTE_LOCK_POLL: Duration = Duration::from_millis(50);
+
+/// How long an invalidation keeps out writes stamped before it, unless set
+/// with `RedisCache::with_tombstone_ttl`.
+pub(crate) const DEFAULT_TOMBSTONE_TTL: Duration = Duration::from_secs(120);
+
+/// How often the invalidation listener checks whether it has been stopped.
+const LISTENER_POLL: Duration = Duration::from_millis(200);
+
+/// Wraps a Redis error, classifying I/O failures, timeouts and refused
+/// connections as `Connection` errors and anything else as `Backend`.
+pub(crate) fn redis_error(message: &str, e: RedisError) -> CacheError {
+    let kind = if e.is_io_error()
+        || e.is_timeout()
+        || e.is_connection_refusal()
+        || e.is_connection_dropped()
+    {
+        CacheErrorKind::Connection
+    } else {
+        CacheErrorKind::Backend
+    };
+    CacheError::with_cause(message, e).with_kind(kind)
+}
+
+/// Reports a reply of a type the calling code does not expect, e.g. from
+/// Lua functions of another version, as a `Backend` error rather than a
+/// panic.
+fn unexpected_response(function: &str, value: &redis::Value) -> CacheError {
+    let kind = match value {
+        redis::Value::Int(_) => "integer",
+        redis::Value::Array(_) => "array",
+        redis::Value::Map(_) => "map",
+        redis::Value::Set(_) => "set",
+        redis::Value::Okay => "status",
+        redis::Value::ServerError(_) => "error",
+        _ => "other",
+    };
+    CacheError::new(&format!(
+        "Unexpected {} response from Redis {} function call: {:?}",
+        kind, function, value
+    ))
+    .with_kind(CacheErrorKind::Backend)
+}
+
+/// Reads the version a Lua library script declares as `local TD_VERSION = n`.
+fn functions_version(script: &str) -> Option<u64> {
+    script.lines().find_map(|line| {
+        line.trim()
+            .strip_prefix("local TD_VERSION =")
+            .and_then(|version| version.trim().parse().ok())
+    })
+}
+
+/// Reads `library_name` from an entry of `FUNCTION LIST`, which is a map in
+/// RESP3 and a flat list of names and values in RESP2.
+fn library_name(library: &redis::Value) -> Option<String> {
+    let is_name = |field: &redis::Value| {
+        redis::from_redis_value::<String>(field).is_ok_and(|field| field == "library_name")
+    };
+    let value = match library {
+        redis::Value::Map(fields) => fields
+            .iter()
+            .find(|(field, _)| is_name(field))
+            .map(|(_, value)| value),
+        redis::Value::Array(fields) => fields
+            .chunks(2)
+            .find(|pair| is_name(&pair[0]))
+            .and_then(|pair| pair.get(1)),
+        _ => None,
+    }?;
+    redis::from_redis_value(value).ok()
+}
+
+/// How often, and how patiently, reads, writes and deletes are retried after
+/// a failure.
+#[derive(Clone, Copy, Debug)]
+struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Delay before retrying after the failed `attempt`, counted from 1.
+    fn delay(&self, attempt: u32) -> Duration {
+        self.base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            base_delay: Duration::ZERO,
+        }
+    }
+}
+
+/// Name of the Redis set holding the keys tagged with `tag`.
+fn tag_key(tag: &str) -> String {
+    format!("tag:{}", tag)
+}
+
+/// Replica a handle sends its reads to; see `RedisCache::with_read_replica`.
+struct ReadReplica {
     client: redis::Client,
+    /// Connection reused across reads, opened on first use.
+    con: RefCell<Option<redis::Connection>>,
+}
+
+impl ReadReplica {
+    fn new(client: redis::Client) -> Self {
+        ReadReplica {
+            client,
+            con: RefCell::new(None),
+        }
+    }
+}
+
+/// Keys recently written through the handles of one cache, shared by all of
+/// them; see `RedisCache::with_read_your_writes`.
+#[derive(Clone)]
+struct RecentWrites {
+    window: Duration,
+    written: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl RecentWrites {
+    fn new(window: Duration) -> Self {
+        RecentWrites {
+            window,
+            written: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Notes that `keys` are being written now, forgetting the keys whose
+    /// window has passed.
+    fn record<'a>(&self, keys: impl IntoIterator<Item = &'a str>) {
+        let now = Instant::now();
+        let mut written = self.lock();
+        written.retain(|_, at| now.duration_since(*at) < self.window);
+        for key in keys {
+            written.insert(key.to_string(), now);
+        }
+    }
+
+    /// Returns whether `key` was written within the window.
+    fn contains(&self, key: &str) -> bool {
+        self.lock()
+            .get(key)
+            .is_some_and(|at| at.elapsed() < self.window)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Instant>> {
+        // Only timestamps are kept, so the map is still usable after a panic
+        // elsewhere while the lock was held.
+        self.written
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+pub struct RedisCache<S: Serializer = JsonSerializer> {
+    client: redis::Client,
+    serializer: S,
+    namespace: Option<String>,
+    key_format: KeyFormat,
+    scan_count: usize,
+    invalidation_channel: Option<String>,
+    retry: RetryPolicy,
+    timeout: Option<Duration>,
+    replica: Option<redis::Client>,
+    recent_writes: Option<RecentWrites>,
+    track_entries: bool,
+    tombstone_ttl: Duration,
 }
 
 impl RedisCache {
     pub fn new(redis_url: &str) -> Result<Self, RedisError> {
         let client = redis::Client::open(redis_url)?;
-        Ok(RedisCache { client })
+        Ok(RedisCache {
+            client,
+            serializer: JsonSerializer,
+            namespace: None,
+            key_format: KeyFormat::default(),
+            scan_count: DEFAULT_SCAN_COUNT,
+            invalidation_channel: None,
+            retry: RetryPolicy::default(),
+            timeout: None,
+            replica: None,
+            recent_writes: None,
+            track_entries: false,
+            tombstone_ttl: DEFAULT_TOMBSTONE_TTL,
+        })
+    }
+
+    /// Creates a cache whose keys are all stored under `prefix:`.
+    ///
+    /// Handles take logical key names (e.g. `student:2`) and transparently
+    /// read and write `prefix:student:2`, so several applications can share
+    /// one Redis instance without key collisions.
+    pub fn with_namespace(redis_url: &str, prefix: &str) -> Result<Self, RedisError> {
+        let mut cache = Self::new(redis_url)?;
+        cache.namespace = Some(prefix.to_string());
+        Ok(cache)
+    }
+
+    /// Creates a cache writing to the Redis primary at `primary_url` and
+    /// reading from its replica at `replica_url`.
+    ///
+    /// `get`, `get_multi`, `get_with_ttl`, `get_raw`, `exists` and the scans,
+    /// e.g. `scan_keys` and `list`, go to the replica. Writes, deletes,
+    /// tags, `compare_and_set` and `get_or_insert_with`'s lock go to the
+    /// primary, as do async handles. The Lua functions only need loading on
+    /// the primary, which replicates them.
+    ///
+    /// Replication is asynchronous, so a read right after a write may still
+    /// see the replica's previous value, or miss a key just written. Use
+    /// `with_read_your_writes` to send reads of recently written keys to the
+    /// primary instead.
+    pub fn with_read_replica(primary_url: &str, replica_url: &str) -> Result<Self, RedisError> {
+        let mut cache = Self::new(primary_url)?;
+        cache.replica = Some(redis::Client::open(replica_url)?);
+        Ok(cache)
+    }
+}
+
+impl<S: Serializer> RedisCache<ChecksumSerializer<S>> {
+    /// Treats values failing their checksum as misses instead of
+    /// `CacheErrorKind::Corruption` errors.
+    pub fn corruption_as_miss(mut self) -> Self {
+        self.serializer = self.serializer.corruption_as_miss();
+        self
     }
+}
 
-    pub fn handle(&self) -> RedisCacheHandle {
-        RedisCacheHandle::new(self.client.clone())
+impl<S: Serializer> RedisCache<VersionedSerializer<S>> {
+    /// Tags values of type `T` with `T::SCHEMA_VERSION` rather than the
+    /// version given to `with_schema_version`.
+    pub fn with_type_version<T: SchemaVersion>(mut self) -> Self {
+        self.serializer = self.serializer.with_type_version::<T>();
+        self
     }
 }
 
-pub struct RedisCacheHandle {
+impl<S: Serializer> RedisCache<S> {
+    /// Replaces the serializer used to encode stored values. All handles
+    /// reading the same keys must use the same serializer.
+    pub fn with_serializer<S2: Serializer>(self, serializer: S2) -> RedisCache<S2> {
+        RedisCache {
+            client: self.client,
+            serializer,
+            namespace: self.namespace,
+            key_format: self.key_format,
+            scan_count: self.scan_count,
+            invalidation_channel: self.invalidation_channel,
+            retry: self.retry,
+            timeout: self.timeout,
+            replica: self.replica,
+            recent_writes: self.recent_writes,
+            track_entries: self.track_entries,
+            tombstone_ttl: self.tombstone_ttl,
+        }
+    }
+
+    /// Compresses stored values with `codec` once they reach the default size
+    /// threshold. Use `with_serializer` and a `CompressingSerializer` to pick
+    /// a different threshold.
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    pub fn with_compression(self, codec: CompressionCodec) -> RedisCache<CompressingSerializer<S>> {
+        let serializer = CompressingSerializer::new(self.serializer.clone(), codec);
+        self.with_serializer(serializer)
+    }
+
+    /// Stores values as JSON formatted by `config`, e.g. with sorted keys so
+    /// that equal values are stored as the same bytes. Replaces the current
+    /// serializer.
+    pub fn with_json_config(self, config: JsonConfig) -> RedisCache<JsonConfig> {
+        self.with_serializer(config)
+    }
+
+    /// Tags stored values with schema `version` and treats values tagged
+    /// with another version as misses, so that a deployment changing the
+    /// shape of a model does not read the entries cached by the previous
+    /// one. See `VersionedSerializer` for when to bump it.
+    pub fn with_schema_version(self, version: u32) -> RedisCache<VersionedSerializer<S>> {
+        let serializer = VersionedSerializer::new(self.serializer.clone(), version);
+        self.with_serializer(serializer)
+    }
+
+    /// Stores a checksum with every value and verifies it on read, so that
+    /// values damaged by flaky infrastructure fail with a
+    /// `CacheErrorKind::Corruption` error rather than a deserialization one.
+    /// See `ChecksumSerializer`.
+    pub fn with_integrity_check(self) -> RedisCache<ChecksumSerializer<S>> {
+        let serializer = ChecksumSerializer::new(self.serializer.clone());
+        self.with_serializer(serializer)
+    }
+
+    /// Stores keys in `key_format`, e.g. with a version prefix or a
+    /// separator other than `:`. All handles reading the same keys must use
+    /// the same format.
+    pub fn with_key_format(mut self, key_format: KeyFormat) -> Self {
+        self.key_format = key_format;
+        self
+    }
+
+    /// Sets the `COUNT` hint used by each `SCAN` call when handles iterate
+    /// over keys, e.g. in `scan_keys` and `clear`.
+    pub fn with_scan_count(mut self, scan_count: usize) -> Self {
+        self.scan_count = scan_count;
+        self
+    }
+
+    /// Retries `get`, `get_multi`, `get_with_ttl`, `put`, `put_with_ttl`,
+    /// `delete` and `delete_multi` calls that fail with a
+    /// `CacheErrorKind::Connection` error, making up to `max_attempts`
+    /// attempts in all.
+    /// Retry `n` waits `base_delay * 2^(n - 1)` and reconnects first.
+    ///
+    /// These operations are safe to repeat: reads and deletes are idempotent
+    /// and a put overwrites. Other operations, e.g. `put_if_absent`, are
+    /// not retried.
+    pub fn with_retry(mut self, max_attempts: u32, base_delay: Duration) -> Self {
+        self.retry = RetryPolicy {
+            max_attempts: max_attempts.max(1),
+            base_delay,
+        };
+        self
+    }
+
+    /// Bounds how long a handle waits to connect to Redis and for each read
+    /// and write on its connection, so that a slow or hung backend fails
+    /// the call with a `CacheErrorKind::Connection` error instead of
+    /// blocking it indefinitely. Applies to every operation going through
+    /// the handle's connection, e.g. `get`, `put`, `delete` and `scan_keys`.
+    ///
+    /// The connection is discarded after a timeout, since its reply may
+    /// still arrive, and the next call opens a new one. Combine it with
+    /// `with_retry` to retry timed out calls.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// With a read replica, sends the reads of a key to the primary for
+    /// `window` after it was written or deleted through any handle of this
+    /// cache, so that they see the write despite replication lag. Pick a
+    /// window above the replica's usual lag.
+    ///
+    /// Only writes made through this cache are tracked: a key written by
+    /// another process may still be read stale from the replica. Scans
+    /// always go to the replica.
+    pub fn with_read_your_writes(mut self, window: Duration) -> Self {
+        self.recent_writes = Some(RecentWrites::new(window));
+        self
+    }
+
+    /// Counts the reads of each value, and stamps the last one, for
+    /// `entry_info`. Reads through `get`, `get_raw`, `get_multi` and
+    /// `get_with_ttl` count; scans and `exists` do not.
+    ///
+    /// Every counted read writes to Redis, so with a read replica, reads go
+    /// to the primary instead. Without tracking, `entry_info` still reports
+    /// when each value was stored.
+    pub fn with_entry_tracking(mut self) -> Self {
+        self.track_entries = true;
+        self
+    }
+
+    /// Sets how long an invalidated key keeps its tombstone, two minutes by
+    /// default.
+    ///
+    /// Invalidations leave a tombstone that rejects writes stamped before
+    /// it, such as `put_as_of` and `populate_cache` rows read before a
+    /// concurrent update. Keep the window above the slowest such query; a
+    /// zero window leaves no tombstone.
+    pub fn with_tombstone_ttl(mut self, ttl: Duration) -> Self {
+        self.tombstone_ttl = ttl;
+        self
+    }
+
+    /// Publishes every key invalidated through `delete` or `delete_multi`
+    /// (and therefore `invalidate_key`/`invalidate_keys`) on the Redis pub/sub
+    /// `channel`, from both `handle` and `async_handle`. See
+    /// `RedisCacheHandle::subscribe_invalidations`.
+    pub fn with_invalidation_channel(mut self, channel: &str) -> Self {
+        self.invalidation_channel = Some(channel.to_string());
+        self
+    }
+
+    pub fn handle(&self) -> RedisCacheHandle<S> {
+        let handle =
+            RedisCacheHandle::with_serializer(self.client.clone(), self.serializer.clone())
+                .with_namespace(self.namespace.clone(), self.key_format.clone())
+                .with_scan_count(self.scan_count)
+                .with_invalidation_channel(self.invalidation_channel.clone())
+                .with_retry_policy(self.retry)
+                .with_read_replica(self.replica.clone(), self.recent_writes.clone())
+                .with_entry_tracking(self.track_entries)
+                .with_tombstone_ttl(self.tombstone_ttl);
+        match self.timeout {
+            Some(timeout) => handle.with_timeout(timeout),
+            None => handle,
+        }
+    }
+
+    /// Opens a multiplexed async connection and returns a handle using it.
+    ///
+    /// The connection is shared by all clones of the returned handle.
+    pub async fn async_handle(&self) -> Result<AsyncRedisCacheHandle<S>, RedisError> {
+        let con = self.client.get_multiplexed_async_connection().await?;
+        Ok(
+            AsyncRedisCacheHandle::with_serializer(con, self.serializer.clone())
+                .with_namespace(self.namespace.clone(), self.key_format.clone())
+                .with_tombstone_ttl(self.tombstone_ttl)
+                .with_invalidation_channel(self.invalidation_channel.clone()),
+        )
+    }
+}
+
+/// Handle to a Redis cache.
+///
+/// A handle keeps one connection open and reuses it for every call,
+/// reconnecting if it breaks, until it is closed with `close` or dropped.
+/// It can be sent to another thread but not shared
+/// between threads; clone it instead, each clone opening its own connection.
+pub struct RedisCacheHandle<S: Serializer = JsonSerializer> {
     client: redis::Client,
+    serializer: S,
+    namespace: Option<String>,
+    key_format: KeyFormat,
+    scan_count: usize,
+    invalidation_channel: Option<String>,
+    retry: RetryPolicy,
+    timeout: Option<Duration>,
+    /// Connection reused across calls, opened on first use.
+    con: RefCell<Option<redis::Connection>>,
+    replica: Option<ReadReplica>,
+    recent_writes: Option<RecentWrites>,
+    track_entries: bool,
+    tombstone_ttl: Duration,
 }
 
 impl RedisCacheHandle {
     pub fn new(client: redis::Client) -> Self {
-        RedisCacheHandle { client }
+        Self::with_serializer(client, JsonSerializer)
+    }
+}
+
+impl<S: Serializer> RedisCacheHandle<S> {
+    pub fn with_serializer(client: redis::Client, serializer: S) -> Self {
+        RedisCacheHandle {
+            client,
+            serializer,
+            namespace: None,
+            key_format: KeyFormat::default(),
+            scan_count: DEFAULT_SCAN_COUNT,
+            invalidation_channel: None,
+            retry: RetryPolicy::default(),
+            timeout: None,
+            con: RefCell::new(None),
+            replica: None,
+            recent_writes: None,
+            track_entries: false,
+            tombstone_ttl: DEFAULT_TOMBSTONE_TTL,
+        }
+    }
+
+    /// Sets the pub/sub channel on which invalidated keys are published.
+    pub(crate) fn with_invalidation_channel(mut self, channel: Option<String>) -> Self {
+        self.invalidation_channel = channel;
+        self
+    }
+
+    /// Spawns a thread that listens for keys published on `channel` by
+    /// handles configured with `with_invalidation_channel`, and deletes each
+    /// of them from `local`, typically an in-memory cache sitting in front of
+    /// Redis on this node.
+    ///
+    /// Each message is the logical (non-namespaced) key as a plain string.
+    /// Redis delivers messages from one publisher in the order they were
+    /// published, and a key is only published once its invalidation has been
+    /// applied in Redis. Delivery is at most once: messages published while
+    /// the listener is disconnected are lost, and until a message arrives
+    /// `local` may still serve the old value.
+    ///
+    /// The listener runs until the returned `InvalidationListener` is stopped
+    /// or dropped.
+    pub fn subscribe_invalidations<L>(
+        &self,
+        channel: &str,
+        local: L,
+    ) -> Result<InvalidationListener, CacheError>
+    where
+        L: CacheHandle + Send + 'static,
+    {
+        let mut con = self
+            .client
+            .get_connection()
+            .map_err(|e| redis_error("Failed to connect to Redis", e))?;
+        let channel = channel.to_string();
+        let stop = Arc::new(AtomicBool::new(false));
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let thread = {
+            let stop = Arc::clone(&stop);
+            std::thread::spawn(move || {
+                let mut local = local;
+                let mut pubsub = con.as_pubsub();
+                let subscribed = pubsub
+                    .subscribe(&channel)
+                    .and_then(|_| pubsub.set_read_timeout(Some(LISTENER_POLL)));
+                let failed = subscribed.is_err();
+                _ = ready_tx.send(subscribed);
+                if failed {
+                    return;
+                }
+                while !stop.load(Ordering::Relaxed) {
+                    let msg = match pubsub.get_message() {
+                        Ok(msg) => msg,
+                        Err(e) if e.is_timeout() => continue,
+                        Err(e) => {
+                            error!("Invalidation listener on {} stopped: {}", channel, e);
+                            return;
+                        }
+                    };
+                    match msg.get_payload::<String>() {
+                        Ok(key) => {
+                            debug!("Evicting key {} on invalidation message", key);
+                            if let Err(e) = local.delete(&key) {
+                                warn!("Error evicting key {} from local cache: {}", key, e);
+                            }
+                        }
+                        Err(e) => warn!("Ignoring malformed invalidation message: {}", e),
+                    }
+                }
+            })
+        };
+        ready_rx
+            .recv()
+            .map_err(|e| CacheError::with_cause("Invalidation listener exited early", e))?
+            .map_err(|e| redis_error("Failed to subscribe to channel", e))?;
+        Ok(InvalidationListener {
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Publishes each invalidated key on the invalidation channel, if one is
+    /// configured.
+    fn publish_invalidations(
+        &self,
+        con: &mut redis::Connection,
+        keys: &[String],
+    ) -> Result<(), CacheError> {
+        let Some(channel) = &self.invalidation_channel else {
+            return Ok(());
+        };
+        let mut pipe = redis::pipe();
+        for key in keys {
+            pipe.publish(channel, key).ignore();
+        }
+        pipe.query::<()>(con)
+            .map_err(|e| redis_error("Failed to publish invalidations", e))
+    }
+
+    /// Sets the `COUNT` hint used by each `SCAN` call.
+    pub fn with_scan_count(mut self, scan_count: usize) -> Self {
+        self.scan_count = scan_count.max(1);
+        self
+    }
+
+    /// Retries failed reads, writes and deletes; see `RedisCache::with_retry`.
+    pub fn with_retry(self, max_attempts: u32, base_delay: Duration) -> Self {
+        self.with_retry_policy(RetryPolicy {
+            max_attempts: max_attempts.max(1),
+            base_delay,
+        })
+    }
+
+    fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Sends reads to `replica`, if any, except for the keys in
+    /// `recent_writes`; see `RedisCache::with_read_replica`.
+    fn with_read_replica(
+        mut self,
+        replica: Option<redis::Client>,
+        recent_writes: Option<RecentWrites>,
+    ) -> Self {
+        self.replica = replica.map(ReadReplica::new);
+        self.recent_writes = recent_writes;
+        self
+    }
+
+    /// Counts reads for `entry_info`; see `RedisCache::with_entry_tracking`.
+    pub(crate) fn with_entry_tracking(mut self, track_entries: bool) -> Self {
+        self.track_entries = track_entries;
+        self
+    }
+
+    /// Sets how long invalidations keep out older writes; see
+    /// `RedisCache::with_tombstone_ttl`.
+    pub fn with_tombstone_ttl(mut self, ttl: Duration) -> Self {
+        self.tombstone_ttl = ttl;
+        self
+    }
+
+    /// Bounds connecting and each read and write; see
+    /// `RedisCache::with_timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        *self.con.get_mut() = None;
+        if let Some(replica) = &mut self.replica {
+            *replica.con.get_mut() = None;
+        }
+        self
+    }
+
+    pub(crate) fn with_namespace(
+        mut self,
+        namespace: Option<String>,
+        key_format: KeyFormat,
+    ) -> Self {
+        self.namespace = namespace;
+        self.key_format = key_format;
+        self
+    }
+
+    fn qualify(&self, key: &str) -> String {
+        self.key_format.qualify(self.namespace.as_deref(), key)
+    }
+
+    /// Strips the namespace and key format from a fully-qualified key.
+    fn unqualify(&self, key: &str) -> String {
+        self.key_format
+            .unqualify(self.namespace.as_deref(), key)
+            .to_string()
+    }
+
+    /// Closes the handle's connection, e.g. on shutdown. Every operation
+    /// completes before returning, so there is nothing left to flush.
+    ///
+    /// Safe to call more than once. The handle stays usable: the next call
+    /// opens a new connection. Dropping a handle closes it too.
+    pub fn close(&self) {
+        if self.con.borrow_mut().take().is_some() {
+            debug!("Closed Redis connection");
+        }
+        if let Some(replica) = &self.replica
+            && replica.con.borrow_mut().take().is_some()
+        {
+            debug!("Closed Redis replica connection");
+        }
+    }
+
+    /// Returns whether the handle currently holds an open connection.
+    pub fn is_connected(&self) -> bool {
+        self.con.borrow().as_ref().is_some_and(|con| con.is_open())
     }
 
     pub fn check_online(&self) -> Result<(), RedisError> {
@@ -54,167 +729,1198 @@ impl RedisCacheHandle {
         )))
     }
 
+    /// Loads the Lua functions the cache relies on, unless the same or a
+    /// newer version is already loaded. Same as `ensure_functions_loaded`.
     pub fn load_redis_functions(&self) -> Result<(), RedisError> {
-        let script = include_str!("../lua/functions.lua");
+        self.ensure_functions_loaded().map(|_| ())
+    }
+
+    /// Loads the Lua functions the cache relies on if they are absent or
+    /// older than the version bundled with this crate, and returns whether
+    /// they were loaded. Run it once at startup.
+    ///
+    /// The library carries a version, returned by `td_version`, so during a
+    /// rolling upgrade the instances still running the previous release do
+    /// not replace the functions loaded by the new one. A library loaded
+    /// before versioning was introduced counts as version 0. Two instances
+    /// starting at the same moment can still both load their version, as
+    /// the check and the load are separate commands; running this again
+    /// after the rollout settles on the newest one.
+    pub fn ensure_functions_loaded(&self) -> Result<bool, RedisError> {
+        let bundled = functions_version(REDIS_FUNCTIONS).ok_or_else(|| {
+            RedisError::from((
+                redis::ErrorKind::ClientError,
+                "Bundled Redis functions carry no version",
+            ))
+        })?;
         let mut con = self.client.get_connection()?;
-        con.send_packed_command(
-            redis::cmd("FUNCTION")
-                .arg("LOAD")
-                .arg("REPLACE")
-                .arg(script)
-                .get_packed_command()
-                .as_slice(),
-        )?;
-        let response = con.recv_response()?;
-        info!("Loaded Redis functions for module: {:?}", response);
-        Ok(())
+        if let Some(loaded) = Self::loaded_functions_version(&mut con)? {
+            if loaded >= bundled {
+                debug!(
+                    "Redis functions version {} already loaded, bundled version is {}",
+                    loaded, bundled
+                );
+                return Ok(false);
+            }
+            info!(
+                "Replacing Redis functions version {} with version {}",
+                loaded, bundled
+            );
+        }
+        let library: String = redis::cmd("FUNCTION")
+            .arg("LOAD")
+            .arg("REPLACE")
+            .arg(REDIS_FUNCTIONS)
+            .query(&mut con)?;
+        info!(
+            "Loaded Redis functions version {} for library: {}",
+            bundled, library
+        );
+        Ok(true)
     }
 
-    fn raw_get(&self, key: &String) -> Option<redis::Value> {
-        let mut con = self
-            .client
-            .get_connection()
-            .expect("Failed to connect to Redis");
-        con.send_packed_command(
-            redis::cmd("FCALL")
-                .arg("td_get")
+    /// Returns the version of the loaded library, or `None` if it is not
+    /// loaded.
+    fn loaded_functions_version(con: &mut redis::Connection) -> Result<Option<u64>, RedisError> {
+        let libraries: Vec<redis::Value> = redis::cmd("FUNCTION")
+            .arg("LIST")
+            .arg("LIBRARYNAME")
+            .arg(REDIS_FUNCTIONS_LIBRARY)
+            .query(con)?;
+        // LIBRARYNAME matches by pattern, so also check the exact name.
+        let loaded = libraries.iter().any(|library| {
+            library_name(library).is_some_and(|name| name == REDIS_FUNCTIONS_LIBRARY)
+        });
+        if !loaded {
+            return Ok(None);
+        }
+        match redis::cmd("FCALL_RO")
+            .arg("td_version")
+            .arg(0)
+            .query::<u64>(con)
+        {
+            Ok(version) => Ok(Some(version)),
+            // Loaded before the library was versioned.
+            Err(e) if e.kind() == redis::ErrorKind::ResponseError => Ok(Some(0)),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Collects all keys matching `pattern` by iterating `SCAN` with a cursor,
+    /// so the server is never blocked the way `KEYS` would block it.
+    fn scan(&self, con: &mut redis::Connection, pattern: &str) -> Result<Vec<String>, CacheError> {
+        let mut keys = Vec::new();
+        let mut cursor: u64 = 0;
+        loop {
+            let (next_cursor, batch): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(pattern)
+                .arg("COUNT")
+                .arg(self.scan_count)
+                .query(con)
+                .map_err(|e| redis_error("Failed to scan keys", e))?;
+            keys.extend(batch);
+            if next_cursor == 0 {
+                break;
+            }
+            cursor = next_cursor;
+        }
+        keys.retain(|key| !self.unqualify(key).starts_with(COMPUTE_LOCK_PREFIX));
+        // SCAN may return a key more than once while the keyspace is rehashed.
+        keys.sort_unstable();
+        keys.dedup();
+        Ok(keys)
+    }
+
+    /// Scans the keys matching `pattern` and reads their live values with
+    /// `td_get`, in pipelined batches of `scan_count` keys. Keys are returned
+    /// without the namespace.
+    fn scan_values(&self, pattern: &str) -> Result<Vec<(String, redis::Value)>, CacheError> {
+        self.with_read_connection(&[], |con| {
+            let keys = self.scan(con, &self.qualify(pattern))?;
+
+            let mut result = Vec::with_capacity(keys.len());
+            for batch in keys.chunks(self.scan_count) {
+                let mut pipe = redis::pipe();
+                for key in batch {
+                    pipe.cmd("FCALL").arg("td_get").arg(1).arg(key);
+                }
+                let values: Vec<redis::Value> = pipe
+                    .query(con)
+                    .map_err(|e| redis_error("Failed to call Redis td_get function", e))?;
+                for (key, value) in batch.iter().zip(values) {
+                    if value != redis::Value::Nil {
+                        result.push((self.unqualify(key), value));
+                    }
+                }
+            }
+            Ok(result)
+        })
+    }
+
+    /// Runs a single `SCAN` call from `cursor` and reads the live values of
+    /// the keys it returned with pipelined `td_get` calls. Returns the next
+    /// cursor, which is 0 once the scan is complete, and the keys without
+    /// the namespace.
+    fn scan_page(
+        &self,
+        cursor: u64,
+        pattern: &str,
+    ) -> Result<(u64, Vec<(String, redis::Value)>), CacheError> {
+        self.with_read_connection(&[], |con| {
+            let (next_cursor, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(pattern)
+                .arg("COUNT")
+                .arg(self.scan_count)
+                .query(con)
+                .map_err(|e| redis_error("Failed to scan keys", e))?;
+            if keys.is_empty() {
+                return Ok((next_cursor, Vec::new()));
+            }
+            let mut pipe = redis::pipe();
+            for key in &keys {
+                pipe.cmd("FCALL").arg("td_get").arg(1).arg(key);
+            }
+            let values: Vec<redis::Value> = pipe
+                .query(con)
+                .map_err(|e| redis_error("Failed to call Redis td_get function", e))?;
+            let entries = keys
+                .iter()
+                .zip(values)
+                .filter(|(_, value)| *value != redis::Value::Nil)
+                .map(|(key, value)| (self.unqualify(key), value))
+                .collect();
+            Ok((next_cursor, entries))
+        })
+    }
+
+    /// Runs `f` on the handle's connection, opening it on first use.
+    ///
+    /// If `f` fails in a way that closed the connection, e.g. because Redis
+    /// restarted or dropped an idle client, the connection is reopened and
+    /// `f` is retried once before the error is returned.
+    fn with_connection<T>(
+        &self,
+        f: impl FnMut(&mut redis::Connection) -> Result<T, CacheError>,
+    ) -> Result<T, CacheError> {
+        self.with_connection_to(&self.client, &self.con, f)
+    }
+
+    /// Like `with_connection`, but on the server `read_target` picks for
+    /// `keys`.
+    fn with_read_connection<T>(
+        &self,
+        keys: &[String],
+        f: impl FnMut(&mut redis::Connection) -> Result<T, CacheError>,
+    ) -> Result<T, CacheError> {
+        let (client, con) = self.read_target(keys);
+        self.with_connection_to(client, con, f)
+    }
+
+    /// Returns the server to read `keys` from, with the slot holding the
+    /// handle's connection to it: the read replica, if one is configured,
+    /// reads are not tracked and none of `keys` was written within the
+    /// read-your-writes window, or else the primary.
+    fn read_target(
+        &self,
+        keys: &[String],
+    ) -> (&redis::Client, &RefCell<Option<redis::Connection>>) {
+        match &self.replica {
+            Some(replica)
+                if !self.track_entries
+                    && !self
+                        .recent_writes
+                        .as_ref()
+                        .is_some_and(|recent| keys.iter().any(|key| recent.contains(key))) =>
+            {
+                (&replica.client, &replica.con)
+            }
+            _ => (&self.client, &self.con),
+        }
+    }
+
+    /// Notes that `keys` are being written, so that the reads of them go to
+    /// the primary during the read-your-writes window, if one is set.
+    fn note_written<'a>(&self, keys: impl IntoIterator<Item = &'a str>) {
+        if let Some(recent_writes) = &self.recent_writes {
+            recent_writes.record(keys);
+        }
+    }
+
+    fn with_connection_to<T>(
+        &self,
+        client: &redis::Client,
+        con: &RefCell<Option<redis::Connection>>,
+        mut f: impl FnMut(&mut redis::Connection) -> Result<T, CacheError>,
+    ) -> Result<T, CacheError> {
+        let mut slot = con.borrow_mut();
+        let res = match f(self.open_connection(client, &mut slot)?) {
+            Err(e) if slot.as_ref().is_some_and(|con| !con.is_open()) => {
+                warn!("Redis connection lost, reconnecting: {}", e);
+                f(self.open_connection(client, &mut slot)?)
+            }
+            res => res,
+        };
+        // A command that timed out may still get its reply, which the next
+        // command would read as its own; never reuse such a connection.
+        if res
+            .as_ref()
+            .is_err_and(|e| e.kind() == CacheErrorKind::Connection)
+        {
+            *slot = None;
+        }
+        res
+    }
+
+    /// Like `with_connection`, but retries connection failures according to
+    /// the handle's retry policy, dropping the connection before each retry
+    /// so a fresh one is opened.
+    fn with_retrying_connection<T>(
+        &self,
+        f: impl FnMut(&mut redis::Connection) -> Result<T, CacheError>,
+    ) -> Result<T, CacheError> {
+        self.with_retrying_connection_to(&self.client, &self.con, f)
+    }
+
+    /// Like `with_retrying_connection`, but on the server `read_target`
+    /// picks for `keys`.
+    fn with_retrying_read_connection<T>(
+        &self,
+        keys: &[String],
+        f: impl FnMut(&mut redis::Connection) -> Result<T, CacheError>,
+    ) -> Result<T, CacheError> {
+        let (client, con) = self.read_target(keys);
+        self.with_retrying_connection_to(client, con, f)
+    }
+
+    fn with_retrying_connection_to<T>(
+        &self,
+        client: &redis::Client,
+        con: &RefCell<Option<redis::Connection>>,
+        mut f: impl FnMut(&mut redis::Connection) -> Result<T, CacheError>,
+    ) -> Result<T, CacheError> {
+        let mut attempt = 1;
+        loop {
+            match self.with_connection_to(client, con, &mut f) {
+                Err(e)
+                    if e.kind() == CacheErrorKind::Connection
+                        && attempt < self.retry.max_attempts =>
+                {
+                    let delay = self.retry.delay(attempt);
+                    warn!(
+                        "Redis operation failed (attempt {} of {}), retrying in {:?}: {}",
+                        attempt, self.retry.max_attempts, delay, e
+                    );
+                    *con.borrow_mut() = None;
+                    std::thread::sleep(delay);
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Returns the connection held in `slot`, replacing it with a new one if
+    /// it is missing or closed.
+    fn open_connection<'a>(
+        &self,
+        client: &redis::Client,
+        slot: &'a mut Option<redis::Connection>,
+    ) -> Result<&'a mut redis::Connection, CacheError> {
+        let con = match slot.take() {
+            Some(con) if con.is_open() => con,
+            _ => self
+                .connect(client)
+                .map_err(|e| redis_error("Failed to connect to Redis", e))?,
+        };
+        Ok(slot.insert(con))
+    }
+
+    fn connect(&self, client: &redis::Client) -> Result<redis::Connection, RedisError> {
+        let Some(timeout) = self.timeout else {
+            return client.get_connection();
+        };
+        let con = client.get_connection_with_timeout(timeout)?;
+        con.set_read_timeout(Some(timeout))?;
+        con.set_write_timeout(Some(timeout))?;
+        Ok(con)
+    }
+
+    /// Builds the call of the read `function`, e.g. `td_get`, on `key`, or
+    /// of its `_tracked` variant counting the read when entries are tracked.
+    fn read_call(&self, function: &str, key: &str) -> Result<redis::Cmd, CacheError> {
+        let mut cmd = redis::cmd("FCALL");
+        if self.track_entries {
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_err(|e| CacheError::with_cause("Failed to get current time", e))?;
+            cmd.arg(format!("{}_tracked", function))
                 .arg(1)
-                .arg(key)
-                .get_packed_command()
-                .as_slice(),
-        )
-        .expect("Failed to call Redis function");
-        let response = con
-            .recv_response()
-            .expect("Failed to receive response from Redis function call");
-        debug!("Response from Redis td_get function call: {:?}", response);
-        match response {
-            redis::Value::Nil => None,
-            _ => Some(response),
+                .arg(self.qualify(key))
+                .arg(now.as_secs())
+                .arg(now.subsec_nanos());
+        } else {
+            cmd.arg(function).arg(1).arg(self.qualify(key));
+        }
+        Ok(cmd)
+    }
+
+    fn raw_get(&self, key: &String) -> Result<Option<redis::Value>, CacheError> {
+        let cmd = self.read_call("td_get", key)?;
+        self.with_retrying_read_connection(std::slice::from_ref(key), |con| {
+            con.send_packed_command(cmd.get_packed_command().as_slice())
+                .map_err(|e| redis_error("Failed to call Redis td_get function", e))?;
+            let response = con.recv_response().map_err(|e| {
+                redis_error("Failed to receive response from Redis function call", e)
+            })?;
+            debug!("Response from Redis td_get function call: {:?}", response);
+            match response {
+                redis::Value::Nil => Ok(None),
+                _ => Ok(Some(response)),
+            }
+        })
+    }
+
+    pub(crate) fn decode_value<V: DeserializeOwned>(
+        serializer: &S,
+        value: redis::Value,
+    ) -> Result<Option<V>, CacheError> {
+        let Some(bytes) = Self::value_bytes(value)? else {
+            return Ok(None);
+        };
+        match serializer.deserialize(&bytes) {
+            Ok(value) => Ok(Some(value)),
+            // E.g. written by a deployment with another shape of the model.
+            Err(e) if serializer.is_miss(&e) => {
+                debug!("Ignoring cached value: {}", e);
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn value_bytes(value: redis::Value) -> Result<Option<Vec<u8>>, CacheError> {
+        match value {
+            redis::Value::SimpleString(str_value) => Ok(Some(str_value.into_bytes())),
+            redis::Value::BulkString(data) => Ok(Some(data)),
+            redis::Value::Nil => Ok(None),
+            other => Err(unexpected_response("td_get", &other)),
+        }
+    }
+
+    fn set<V: Serialize>(
+        &mut self,
+        key: &str,
+        value: &V,
+        ttl: Option<Duration>,
+    ) -> Result<(), CacheError> {
+        let serialized = self.serializer.serialize(value)?;
+        self.set_raw(key, &serialized, ttl)
+    }
+
+    fn set_raw(
+        &mut self,
+        key: &str,
+        serialized: &[u8],
+        ttl: Option<Duration>,
+    ) -> Result<(), CacheError> {
+        self.set_raw_at(key, serialized, ttl, None).map(|_| ())
+    }
+
+    /// Calls `td_set`, stamping the write with `at`, or with the current time
+    /// if `None`, and returns whether it was written rather than rejected by
+    /// a newer invalidation.
+    fn set_raw_at(
+        &mut self,
+        key: &str,
+        serialized: &[u8],
+        ttl: Option<Duration>,
+        at: Option<SystemTime>,
+    ) -> Result<bool, CacheError> {
+        instrumentation::stored(key, serialized.len());
+        self.note_written([key]);
+        self.with_retrying_connection(|con| {
+            let stamp = at
+                .unwrap_or_else(SystemTime::now)
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_err(|e| CacheError::with_cause("Failed to get current time", e))?;
+            con.send_packed_command(
+                redis::cmd("FCALL")
+                    .arg("td_set")
+                    .arg(1)
+                    .arg(self.qualify(key))
+                    .arg(serialized)
+                    .arg(stamp.as_secs())
+                    .arg(stamp.subsec_nanos())
+                    .arg(ttl.map_or(0, |ttl| ttl.as_millis()))
+                    .get_packed_command()
+                    .as_slice(),
+            )
+            .map_err(|e| redis_error("Failed to call Redis td_set function", e))?;
+            let response = con.recv_response().map_err(|e| {
+                redis_error("Failed to receive response from Redis function call", e)
+            })?;
+            debug!("Response from Redis td_set function call: {:?}", response);
+            Ok(response == redis::Value::Int(1))
+        })
+    }
+
+    /// Stores several values in one pipeline of `td_set` calls, stamped with
+    /// `at` or the current time, and returns how many were written.
+    fn set_multi_at<V: Serialize>(
+        &mut self,
+        entries: &[(String, V)],
+        at: Option<SystemTime>,
+    ) -> Result<usize, CacheError> {
+        if entries.is_empty() {
+            return Ok(0);
         }
+        let serialized = entries
+            .iter()
+            .map(|(key, value)| {
+                let serialized = self.serializer.serialize(value)?;
+                instrumentation::stored(key, serialized.len());
+                Ok((key, serialized))
+            })
+            .collect::<Result<Vec<_>, CacheError>>()?;
+        self.note_written(entries.iter().map(|(key, _)| key.as_str()));
+        self.with_retrying_connection(|con| {
+            let stamp = at
+                .unwrap_or_else(SystemTime::now)
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_err(|e| CacheError::with_cause("Failed to get current time", e))?;
+            let mut pipe = redis::pipe();
+            for (key, value) in &serialized {
+                pipe.cmd("FCALL")
+                    .arg("td_set")
+                    .arg(1)
+                    .arg(self.qualify(key))
+                    .arg(value)
+                    .arg(stamp.as_secs())
+                    .arg(stamp.subsec_nanos())
+                    .arg(0);
+            }
+            let responses: Vec<redis::Value> = pipe
+                .query(con)
+                .map_err(|e| redis_error("Failed to call Redis td_set function", e))?;
+            debug!(
+                "Responses from pipelined Redis td_set function calls: {:?}",
+                responses
+            );
+            Ok(responses
+                .iter()
+                .filter(|response| **response == redis::Value::Int(1))
+                .count())
+        })
+    }
+
+    /// Stores a value stamped with `read_at`, the time it was read from the
+    /// database, rather than the current time. Returns whether it was
+    /// written: a write is rejected if the key was invalidated after
+    /// `read_at`, as long as its tombstone lives, so a row read before a
+    /// concurrent update does not replace the invalidation made by that
+    /// update. See `RedisCache::with_tombstone_ttl`.
+    ///
+    /// ```ignore
+    /// let read_at = SystemTime::now();
+    /// let student = students::table.find(2).first::<Student>(connection)?;
+    /// if !handle.put_as_of("student:2", &student, read_at)? {
+    ///     // Updated in the meantime; the next read loads the new row.
+    /// }
+    /// ```
+    ///
+    /// The stamps of writes and invalidations come from the clocks of the
+    /// handles making them, so those clocks must be kept in sync.
+    pub fn put_as_of<V: Serialize>(
+        &mut self,
+        key: &str,
+        value: &V,
+        read_at: SystemTime,
+    ) -> Result<bool, CacheError> {
+        let serialized = self.serializer.serialize(value)?;
+        self.set_raw_at(key, &serialized, None, Some(read_at))
     }
 
-    pub fn raw_delete(&mut self, key: &String) {
+    /// Replaces the field at `pointer`, a JSON Pointer such as `/name` or
+    /// `/courses/0/grade`, in the cached value of `key` with `value`, without
+    /// reading the value back or rewriting the rest of it, e.g. after an
+    /// update that set a single column of a large row.
+    ///
+    /// Returns whether the value was patched: a key that is not cached,
+    /// invalidated, or invalidated by a concurrent update stamped after this
+    /// patch (see `RedisCache::with_tombstone_ttl`) is left alone, so the
+    /// next read loads the row from the database. The key keeps its TTL. It
+    /// is an error if the cached value has no field at `pointer`.
+    ///
+    /// ```ignore
+    /// diesel::update(students::table.find(2))
+    ///     .set(students::name.eq("Ori2"))
+    ///     .execute(connection)?;
+    /// handle.patch_field("student:2", "/name", &"Ori2")?;
+    /// ```
+    ///
+    /// The stored value must be JSON text, so the cache must use
+    /// `JsonSerializer` or `JsonConfig`, possibly wrapped in a
+    /// `MeasuringSerializer`; other serializers fail with a `Serialization`
+    /// error without touching Redis.
+    pub fn patch_field<V: Serialize>(
+        &mut self,
+        key: &str,
+        pointer: &str,
+        value: &V,
+    ) -> Result<bool, CacheError> {
+        if !self.serializer.writes_json() {
+            return Err(
+                CacheError::new("patch_field requires values to be stored as JSON")
+                    .with_kind(CacheErrorKind::Serialization),
+            );
+        }
+        let patch = JsonSerializer.serialize(value)?;
+        instrumentation::stored(key, patch.len());
+        self.note_written([key]);
+        self.with_retrying_connection(|con| {
+            let stamp = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_err(|e| CacheError::with_cause("Failed to get current time", e))?;
+            let patched: i32 = redis::cmd("FCALL")
+                .arg("td_patch")
+                .arg(1)
+                .arg(self.qualify(key))
+                .arg(pointer)
+                .arg(&patch)
+                .arg(stamp.as_secs())
+                .arg(stamp.subsec_nanos())
+                .query(con)
+                .map_err(|e| redis_error("Failed to call Redis td_patch function", e))?;
+            Ok(patched == 1)
+        })
+    }
+
+    /// Tries to take the short-lived lock guarding the computation of a key,
+    /// owned by `token`.
+    fn try_lock(
+        &self,
+        con: &mut redis::Connection,
+        lock_key: &str,
+        token: &str,
+    ) -> Result<bool, CacheError> {
+        redis::cmd("FCALL")
+            .arg("td_lock")
+            .arg(1)
+            .arg(lock_key)
+            .arg(COMPUTE_LOCK_TTL.as_millis())
+            .arg(token)
+            .query(con)
+            .map_err(|e| redis_error("Failed to call Redis td_lock function", e))
+    }
+
+    /// Releases the lock taken by `try_lock`, unless it expired and another
+    /// caller has taken it since.
+    fn unlock(
+        &self,
+        con: &mut redis::Connection,
+        lock_key: &str,
+        token: &str,
+    ) -> Result<(), CacheError> {
+        redis::cmd("FCALL")
+            .arg("td_unlock")
+            .arg(1)
+            .arg(lock_key)
+            .arg(token)
+            .query::<()>(con)
+            .map_err(|e| redis_error("Failed to call Redis td_unlock function", e))
+    }
+
+    pub fn raw_delete(&mut self, key: &str) {
         let mut con = self
             .client
             .get_connection()
             .expect("Failed to connect to Redis");
-        _ = con.del::<_, ()>(key);
+        _ = con.del::<_, ()>(self.qualify(key));
     }
 }
 
-impl CacheHandle for RedisCacheHandle {
+impl<S: Serializer> CacheHandle for RedisCacheHandle<S> {
     fn get<V: Serialize + DeserializeOwned>(&self, key: &String) -> Result<Option<V>, CacheError> {
-        match self.raw_get(key) {
-            Some(value) => match value {
-                redis::Value::SimpleString(str_value) => {
-                    let deserialized: V = serde_json::from_str(str_value.as_str())
-                        .map_err(|e| CacheError::with_cause("Failed to deserialize value", e))?;
-                    Ok(Some(deserialized))
-                }
-                redis::Value::BulkString(data) => {
-                    let str_value = String::from_utf8(data).map_err(|e| {
-                        CacheError::with_cause("Failed to convert bulk string to UTF-8", e)
-                    })?;
-                    let deserialized: V = serde_json::from_str(&str_value)
-                        .map_err(|e| CacheError::with_cause("Failed to deserialize value", e))?;
-                    Ok(Some(deserialized))
-                }
-                redis::Value::Nil => Ok(None),
-                _ => panic!("Unexpected response type from Redis function call"),
-            },
+        match self.raw_get(key)? {
+            Some(value) => Self::decode_value(&self.serializer, value),
             None => Ok(None),
         }
     }
 
+    fn get_with_ttl<V: Serialize + DeserializeOwned>(
+        &self,
+        key: &String,
+    ) -> Result<Option<(V, Option<Duration>)>, CacheError> {
+        let cmd = self.read_call("td_get_with_ttl", key)?;
+        let response = self.with_retrying_read_connection(std::slice::from_ref(key), |con| {
+            cmd.query::<redis::Value>(con)
+                .map_err(|e| redis_error("Failed to call Redis td_get_with_ttl function", e))
+        })?;
+        debug!(
+            "Response from Redis td_get_with_ttl function call: {:?}",
+            response
+        );
+        match response {
+            redis::Value::Nil => Ok(None),
+            redis::Value::Array(mut fields) if fields.len() == 2 => {
+                // PTTL is -1 for a key without an expiry.
+                let remaining = match fields.pop() {
+                    Some(redis::Value::Int(ms)) if ms >= 0 => {
+                        Some(Duration::from_millis(ms as u64))
+                    }
+                    _ => None,
+                };
+                let value = fields.pop().unwrap_or(redis::Value::Nil);
+                Ok(Self::decode_value(&self.serializer, value)?.map(|value| (value, remaining)))
+            }
+            other => Err(unexpected_response("td_get_with_ttl", &other)),
+        }
+    }
+
+    fn get_multi<V: Serialize + DeserializeOwned>(
+        &self,
+        keys: &[String],
+    ) -> Result<Vec<Option<V>>, CacheError> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut pipe = redis::pipe();
+        for key in keys {
+            pipe.add_command(self.read_call("td_get", key)?);
+        }
+        let responses: Vec<redis::Value> = self.with_retrying_read_connection(keys, |con| {
+            pipe.query(con)
+                .map_err(|e| redis_error("Failed to call Redis td_get function", e))
+        })?;
+        debug!(
+            "Responses from pipelined Redis td_get function calls: {:?}",
+            responses
+        );
+        responses
+            .into_iter()
+            .map(|value| Self::decode_value(&self.serializer, value))
+            .collect()
+    }
+
+    fn exists(&self, key: &String) -> Result<bool, CacheError> {
+        self.with_read_connection(std::slice::from_ref(key), |con| {
+            let exists: bool = redis::cmd("FCALL")
+                .arg("td_exists")
+                .arg(1)
+                .arg(self.qualify(key))
+                .query(con)
+                .map_err(|e| redis_error("Failed to call Redis td_exists function", e))?;
+            Ok(exists)
+        })
+    }
+
+    /// Reads the time `td_set` stamped on the value, along with the read
+    /// count and last read kept by `RedisCache::with_entry_tracking`. The
+    /// times are those of the clocks of the handles that wrote and read it.
+    fn entry_info(&self, key: &String) -> Result<Option<EntryInfo>, CacheError> {
+        type Fields = (u64, u32, Option<u64>, Option<u32>, Option<u64>);
+        let fields = self.with_read_connection(std::slice::from_ref(key), |con| {
+            redis::cmd("FCALL")
+                .arg("td_entry_info")
+                .arg(1)
+                .arg(self.qualify(key))
+                .query::<Option<Fields>>(con)
+                .map_err(|e| redis_error("Failed to call Redis td_entry_info function", e))
+        })?;
+        Ok(fields.map(|(sec, nsec, at_sec, at_nsec, hits)| EntryInfo {
+            created_at: SystemTime::UNIX_EPOCH + Duration::new(sec, nsec),
+            last_accessed: at_sec
+                .map(|at_sec| SystemTime::UNIX_EPOCH + Duration::new(at_sec, at_nsec.unwrap_or(0))),
+            hit_count: hits.unwrap_or(0),
+        }))
+    }
+
     fn put<V: Serialize + DeserializeOwned>(
         &mut self,
         key: &String,
         value: &V,
     ) -> Result<(), CacheError> {
-        let mut con = self
-            .client
-            .get_connection()
-            .map_err(|e| CacheError::with_cause("Failed to connect to Redis", e))?;
-        let now = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .map_err(|e| CacheError::with_cause("Failed to get current time", e))?;
-        con.send_packed_command(
+        self.set(key, value, None)
+    }
+
+    fn get_raw(&self, key: &String) -> Result<Option<Vec<u8>>, CacheError> {
+        match self.raw_get(key)? {
+            Some(value) => Self::value_bytes(value),
+            None => Ok(None),
+        }
+    }
+
+    fn put_raw(&mut self, key: &String, bytes: &[u8]) -> Result<(), CacheError> {
+        self.set_raw(key, bytes, None)
+    }
+
+    fn put_with_ttl<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+        ttl: Duration,
+    ) -> Result<(), CacheError> {
+        self.set(key, value, Some(ttl))
+    }
+
+    /// Pipelines one `td_set` call per entry.
+    fn put_multi<V: Serialize + DeserializeOwned>(
+        &mut self,
+        entries: &[(String, V)],
+    ) -> Result<(), CacheError> {
+        self.set_multi_at(entries, None).map(|_| ())
+    }
+
+    /// Stamps every write with `read_at`, in one pipeline; see
+    /// `RedisCacheHandle::put_as_of`.
+    fn put_multi_read_at<V: Serialize + DeserializeOwned>(
+        &mut self,
+        entries: &[(String, V)],
+        read_at: SystemTime,
+    ) -> Result<usize, CacheError> {
+        self.set_multi_at(entries, Some(read_at))
+    }
+
+    /// Uses the `td_setnx` function, which treats an invalidated key as
+    /// absent.
+    fn put_if_absent<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+    ) -> Result<bool, CacheError> {
+        let serialized = self.serializer.serialize(value)?;
+        instrumentation::stored(key, serialized.len());
+        self.note_written([key.as_str()]);
+        self.with_connection(|con| {
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_err(|e| CacheError::with_cause("Failed to get current time", e))?;
             redis::cmd("FCALL")
-                .arg("td_set")
+                .arg("td_setnx")
                 .arg(1)
-                .arg(key)
-                .arg(serde_json::to_string(value).unwrap())
+                .arg(self.qualify(key))
+                .arg(&serialized)
                 .arg(now.as_secs())
                 .arg(now.subsec_nanos())
-                .get_packed_command()
-                .as_slice(),
-        )
-        .map_err(|e| CacheError::with_cause("Failed to call Redis td_set function", e))?;
-        let response = con.recv_response().map_err(|e| {
-            CacheError::with_cause("Failed to receive response from Redis function call", e)
-        })?;
-        debug!("Response from Redis td_set function call: {:?}", response);
-        Ok(())
+                .arg(0)
+                .query(con)
+                .map_err(|e| redis_error("Failed to call Redis td_setnx function", e))
+        })
     }
 
-    fn delete(&mut self, key: &String) -> Result<(), CacheError> {
-        let mut con = self
-            .client
-            .get_connection()
-            .map_err(|e| CacheError::with_cause("Failed to connect to Redis", e))?;
-        let now = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .map_err(|e| CacheError::with_cause("Failed to get current time", e))?;
-        con.send_packed_command(
-            redis::cmd("FCALL")
-                .arg("td_invalidate")
+    /// `WATCH`es the key, checks the stored version, and writes the new
+    /// value in a `MULTI`/`EXEC` transaction, which Redis aborts if the key
+    /// changed in between.
+    fn compare_and_set<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        expected_version: u64,
+        value: &V,
+    ) -> Result<bool, CacheError> {
+        let serialized = self.serializer.serialize(&VersionedValue {
+            version: expected_version + 1,
+            value,
+        })?;
+        let qualified = self.qualify(key);
+        self.note_written([key.as_str()]);
+        self.with_connection(|con| {
+            redis::cmd("WATCH")
+                .arg(&qualified)
+                .exec(con)
+                .map_err(|e| redis_error("Failed to watch Redis key", e))?;
+            let current = redis::cmd("FCALL")
+                .arg("td_get")
+                .arg(1)
+                .arg(&qualified)
+                .query::<redis::Value>(con)
+                .map_err(|e| redis_error("Failed to call Redis td_get function", e))?;
+            let version = Self::decode_value::<VersionedValue<V>>(&self.serializer, current)
+                .map(|current| current.map_or(0, |current| current.version));
+            if !matches!(version, Ok(version) if version == expected_version) {
+                redis::cmd("UNWATCH")
+                    .exec(con)
+                    .map_err(|e| redis_error("Failed to unwatch Redis key", e))?;
+                return version.map(|_| false);
+            }
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_err(|e| CacheError::with_cause("Failed to get current time", e))?;
+            // `EXEC` replies nil when the transaction was aborted.
+            let response: Option<(i64,)> = redis::pipe()
+                .atomic()
+                .cmd("FCALL")
+                .arg("td_set")
                 .arg(1)
-                .arg(key)
+                .arg(&qualified)
+                .arg(&serialized)
                 .arg(now.as_secs())
                 .arg(now.subsec_nanos())
-                .get_packed_command()
-                .as_slice(),
-        )
-        .map_err(|e| CacheError::with_cause("Failed to call Redis td_invalidate function", e))?;
-        let response = con.recv_response().map_err(|e| {
-            CacheError::with_cause("Failed to receive response from Redis function call", e)
+                .arg(0)
+                .query(con)
+                .map_err(|e| redis_error("Failed to call Redis td_set function", e))?;
+            debug!("Response from Redis td_set transaction: {:?}", response);
+            let written = response == Some((1,));
+            if written {
+                instrumentation::stored(key, serialized.len());
+            }
+            Ok(written)
+        })
+    }
+
+    fn delete(&mut self, key: &String) -> Result<(), CacheError> {
+        self.note_written([key.as_str()]);
+        self.with_retrying_connection(|con| {
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_err(|e| CacheError::with_cause("Failed to get current time", e))?;
+            con.send_packed_command(
+                redis::cmd("FCALL")
+                    .arg("td_invalidate")
+                    .arg(1)
+                    .arg(self.qualify(key))
+                    .arg(now.as_secs())
+                    .arg(now.subsec_nanos())
+                    .arg(self.tombstone_ttl.as_millis())
+                    .get_packed_command()
+                    .as_slice(),
+            )
+            .map_err(|e| redis_error("Failed to call Redis td_invalidate function", e))?;
+            let response = con.recv_response().map_err(|e| {
+                redis_error("Failed to receive response from Redis function call", e)
+            })?;
+            debug!(
+                "Response from Redis td_invalidate function call: {:?}",
+                response
+            );
+            self.publish_invalidations(con, std::slice::from_ref(key))
+        })
+    }
+
+    fn delete_multi(&mut self, keys: &[String]) -> Result<(), CacheError> {
+        self.delete_multi_counted(keys).map(|_| ())
+    }
+
+    /// Counts the keys whose `td_invalidate` call found a live value.
+    fn delete_multi_counted(&mut self, keys: &[String]) -> Result<usize, CacheError> {
+        if keys.is_empty() {
+            return Ok(0);
+        }
+        self.note_written(keys.iter().map(String::as_str));
+        self.with_retrying_connection(|con| {
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_err(|e| CacheError::with_cause("Failed to get current time", e))?;
+            let mut pipe = redis::pipe();
+            for key in keys {
+                pipe.cmd("FCALL")
+                    .arg("td_invalidate")
+                    .arg(1)
+                    .arg(self.qualify(key))
+                    .arg(now.as_secs())
+                    .arg(now.subsec_nanos())
+                    .arg(self.tombstone_ttl.as_millis());
+            }
+            let responses: Vec<i64> = pipe
+                .query(con)
+                .map_err(|e| redis_error("Failed to call Redis td_invalidate function", e))?;
+            debug!(
+                "Responses from pipelined Redis td_invalidate function calls: {:?}",
+                responses
+            );
+            self.publish_invalidations(con, keys)?;
+            Ok(responses
+                .into_iter()
+                .filter(|&existed| existed == 1)
+                .count())
+        })
+    }
+
+    /// Finds the matching keys with `SCAN` rather than `KEYS`, so Redis is
+    /// not blocked, and invalidates them with pipelined `td_invalidate` calls
+    /// in batches of `scan_count` keys. Like `delete_multi`, this leaves the
+    /// invalidation markers that keep stale writes out and publishes the keys
+    /// on the invalidation channel, which a plain `DEL` would not.
+    fn delete_pattern(&mut self, pattern: &str) -> Result<usize, CacheError> {
+        let keys = self.with_connection(|con| self.scan(con, &self.qualify(pattern)))?;
+        let keys = keys
+            .iter()
+            .map(|key| self.unqualify(key))
+            .collect::<Vec<_>>();
+        let mut deleted = 0;
+        for batch in keys.chunks(self.scan_count) {
+            deleted += self.delete_multi_counted(batch)?;
+        }
+        debug!("Invalidated {} keys matching {}", deleted, pattern);
+        Ok(deleted)
+    }
+
+    /// Deletes every key under the configured namespace using `SCAN`, in
+    /// batches, rather than `FLUSHDB`. Without a namespace this falls back to
+    /// scanning `*` and removes all keys in the selected database, so use a
+    /// dedicated database for the cache in that case.
+    fn clear(&mut self) -> Result<(), CacheError> {
+        self.with_connection(|con| {
+            let keys = self.scan(con, &self.qualify("*"))?;
+            for batch in keys.chunks(CLEAR_BATCH_SIZE) {
+                con.del::<_, ()>(batch)
+                    .map_err(|e| redis_error("Failed to delete keys", e))?;
+            }
+            debug!("Cleared {} keys from Redis", keys.len());
+            Ok(())
+        })
+    }
+
+    /// Counts matching keys with `SCAN`. Keys holding only an invalidation
+    /// marker are counted until the marker expires.
+    fn count_keys(&self, pattern: &str) -> Result<usize, CacheError> {
+        self.with_read_connection(&[], |con| Ok(self.scan(con, &self.qualify(pattern))?.len()))
+    }
+
+    /// Lists matching keys with `SCAN` alone, without reading any value.
+    /// Like `count_keys`, keys holding only an invalidation marker are
+    /// listed until the marker expires.
+    fn list_keys(&self, pattern: &str) -> Result<Vec<String>, CacheError> {
+        let keys = self.with_read_connection(&[], |con| self.scan(con, &self.qualify(pattern)))?;
+        Ok(keys.iter().map(|key| self.unqualify(key)).collect())
+    }
+
+    /// Adds `key` to the Redis set `tag:{tag}` of each tag before storing the
+    /// value, so a concurrent `invalidate_tag` can't miss a visible value.
+    fn put_tagged<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+        tags: &[&str],
+    ) -> Result<(), CacheError> {
+        if !tags.is_empty() {
+            let mut pipe = redis::pipe();
+            for tag in tags {
+                pipe.sadd(self.qualify(&tag_key(tag)), key).ignore();
+            }
+            self.with_connection(|con| {
+                pipe.query::<()>(con)
+                    .map_err(|e| redis_error("Failed to tag key", e))
+            })?;
+        }
+        self.set(key, value, None)
+    }
+
+    /// Reads and removes the tag's set in one `MULTI` transaction, then
+    /// invalidates its keys like `delete_multi`.
+    fn invalidate_tag(&mut self, tag: &str) -> Result<Vec<String>, CacheError> {
+        let tag_key = self.qualify(&tag_key(tag));
+        let (keys,): (Vec<String>,) = self.with_connection(|con| {
+            redis::pipe()
+                .atomic()
+                .smembers(&tag_key)
+                .del(&tag_key)
+                .ignore()
+                .query(con)
+                .map_err(|e| redis_error("Failed to read tagged keys", e))
         })?;
-        debug!(
-            "Response from Redis td_invalidate function call: {:?}",
-            response
-        );
-        Ok(())
+        debug!("Invalidating {} keys tagged {}", keys.len(), tag);
+        self.delete_multi(&keys)?;
+        Ok(keys)
+    }
+
+    fn health_check(&self) -> Result<(), CacheError> {
+        self.with_connection(|con| {
+            con.ping::<String>()
+                .map(|_| ())
+                .map_err(|e| redis_error("Failed to ping Redis", e))
+        })
+    }
+
+    /// Stamps the write with `read_at`; see `RedisCacheHandle::put_as_of`.
+    fn put_read_at<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+        ttl: Option<Duration>,
+        read_at: SystemTime,
+    ) -> Result<bool, CacheError> {
+        let serialized = self.serializer.serialize(value)?;
+        self.set_raw_at(key, &serialized, ttl, Some(read_at))
+    }
+
+    /// Only one caller runs `f` for a missing key at a time: the others wait
+    /// for its result, for up to a few seconds, before computing it
+    /// themselves.
+    fn get_or_insert_with<V, F>(&mut self, key: &String, f: F) -> Result<V, CacheError>
+    where
+        V: Serialize + DeserializeOwned,
+        F: FnOnce() -> Result<V, CacheError>,
+    {
+        if let Some(value) = self.get(key)? {
+            return Ok(value);
+        }
+        let lock_key = self.qualify(&format!("{}{}", COMPUTE_LOCK_PREFIX, key));
+        let token = rand::random::<u64>().to_string();
+        let started = std::time::Instant::now();
+        let locked = loop {
+            if self.with_connection(|con| self.try_lock(con, &lock_key, &token))? {
+                break true;
+            }
+            if started.elapsed() >= COMPUTE_LOCK_TTL {
+                debug!("Timed out waiting for lock on key {}, computing value", key);
+                break false;
+            }
+            std::thread::sleep(COMPUTE_LOCK_POLL);
+            if let Some(value) = self.get(key)? {
+                return Ok(value);
+            }
+        };
+        let res = f().and_then(|value| self.put(key, &value).map(|_| value));
+        if locked && let Err(e) = self.with_connection(|con| self.unlock(con, &lock_key, &token)) {
+            warn!("Error releasing lock on key {}: {}", key, e);
+        }
+        res
     }
 
     fn scan_keys(&self, pattern: &str) -> Result<HashMap<String, String>, CacheError> {
-        let mut con = self
-            .client
-            .get_connection()
-            .map_err(|e| CacheError::with_cause("Failed to connect to Redis", e))?;
-        let keys: Vec<String> = con
-            .keys(pattern)
-            .map_err(|e| CacheError::with_cause("Failed to scan keys", e))?;
+        Ok(self
+            .scan_values(pattern)?
+            .into_iter()
+            .map(|(key, value)| (key, format!("{:?}", value)))
+            .collect())
+    }
+
+    /// Reads the values found by `SCAN` through `td_get`, in pipelined
+    /// batches, and deserializes them.
+    fn scan_typed<V: Serialize + DeserializeOwned>(
+        &self,
+        pattern: &str,
+    ) -> Result<HashMap<String, V>, CacheError> {
+        let mut result = HashMap::new();
+        for (key, value) in self.scan_values(pattern)? {
+            if let Some(value) = Self::decode_value(&self.serializer, value)? {
+                result.insert(key, value);
+            }
+        }
+        Ok(result)
+    }
+
+    /// Streams the matching keys one `SCAN` page at a time, reading each
+    /// page's values with pipelined `td_get` calls, so only a page of
+    /// values is held in memory. Keys come in no particular order, and a key
+    /// may be returned twice if Redis rehashes its keyspace during the scan.
+    fn list<V: Serialize + DeserializeOwned + 'static>(
+        &self,
+        pattern: &str,
+    ) -> Result<CacheListing<'_, V>, CacheError> {
+        Ok(Box::new(RedisListing {
+            handle: self,
+            pattern: self.qualify(pattern),
+            cursor: Some(0),
+            page: VecDeque::new(),
+            _value: std::marker::PhantomData::<V>,
+        }))
+    }
+}
 
-        Ok(keys
-            .iter()
-            .map(|k| (k.clone(), self.raw_get(&k)))
-            .filter_map(|x| match x {
-                (k, Some(v)) => Some((k, format!("{:?}", v))),
-                _ => None,
-            })
-            .collect())
+/// Iterator returned by `RedisCacheHandle::list`.
+struct RedisListing<'a, S: Serializer, V> {
+    handle: &'a RedisCacheHandle<S>,
+    pattern: String,
+    /// Cursor of the next `SCAN` call, or `None` once the scan is complete
+    /// or has failed.
+    cursor: Option<u64>,
+    page: VecDeque<(String, redis::Value)>,
+    _value: std::marker::PhantomData<V>,
+}
+
+impl<S: Serializer, V: DeserializeOwned> Iterator for RedisListing<'_, S, V> {
+    type Item = Result<(String, V), CacheError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((key, value)) = self.page.pop_front() {
+                match RedisCacheHandle::decode_value(&self.handle.serializer, value) {
+                    Ok(Some(value)) => return Some(Ok((key, value))),
+                    Ok(None) => continue,
+                    Err(e) => {
+                        self.cursor = None;
+                        self.page.clear();
+                        return Some(Err(e));
+                    }
+                }
+            }
+            let cursor = self.cursor?;
+            match self.handle.scan_page(cursor, &self.pattern) {
+                Ok((next_cursor, entries)) => {
+                    self.cursor = (next_cursor != 0).then_some(next_cursor);
+                    self.page.extend(entries);
+                }
+                Err(e) => {
+                    self.cursor = None;
+                    return Some(Err(e));
+                }
+            }
+        }
     }
 }
 
-impl Clone for RedisCacheHandle {
+impl<S: Serializer> Clone for RedisCacheHandle<S> {
     fn clone(&self) -> Self {
         RedisCacheHandle {
             client: self.client.clone(),
+            serializer: self.serializer.clone(),
+            namespace: self.namespace.clone(),
+            key_format: self.key_format.clone(),
+            scan_count: self.scan_count,
+            invalidation_channel: self.invalidation_channel.clone(),
+            retry: self.retry,
+            timeout: self.timeout,
+            con: RefCell::new(None),
+            replica: self
+                .replica
+                .as_ref()
+                .map(|replica| ReadReplica::new(replica.client.clone())),
+            recent_writes: self.recent_writes.clone(),
+            track_entries: self.track_entries,
+            tombstone_ttl: self.tombstone_ttl,
+        }
+    }
+}
+
+impl<S: Serializer> Drop for RedisCacheHandle<S> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// Background listener started by `RedisCacheHandle::subscribe_invalidations`.
+///
+/// Stops listening when dropped.
+pub struct InvalidationListener {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl InvalidationListener {
+    /// Stops the listener and waits for its thread to exit.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            _ = thread.join();
         }
     }
 }
 
+impl Drop for InvalidationListener {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::redis_test_util::RedisTestUtil;
@@ -227,6 +1933,188 @@ mod tests {
         crate::test_utils::init_logging_for_tests();
     }
 
+    #[test]
+    fn test_namespace_qualifies_keys() {
+        let cache = RedisCache::with_namespace("redis://127.0.0.1/", "app")
+            .expect("Failed to create RedisCache");
+        let handle = cache.handle();
+        assert_eq!(handle.qualify("student:2"), "app:student:2");
+        assert_eq!(handle.unqualify("app:student:2"), "student:2");
+
+        let plain = RedisCache::new("redis://127.0.0.1/").expect("Failed to create RedisCache");
+        assert_eq!(plain.handle().qualify("student:2"), "student:2");
+
+        let formatted =
+            cache.with_key_format(KeyFormat::new().with_separator('/').with_prefix("v2"));
+        let handle = formatted.handle();
+        assert_eq!(handle.qualify("student/2"), "app/v2/student/2");
+        assert_eq!(handle.unqualify("app/v2/student/2"), "student/2");
+    }
+
+    #[test]
+    fn test_stale_schema_decodes_as_miss() {
+        let current = VersionedSerializer::new(JsonSerializer, 2);
+        let stale = VersionedSerializer::new(JsonSerializer, 1)
+            .serialize(&"Ori".to_string())
+            .unwrap();
+        let value =
+            RedisCacheHandle::decode_value::<String>(&current, redis::Value::BulkString(stale));
+        assert_eq!(value.unwrap(), None);
+
+        let broken = RedisCacheHandle::decode_value::<String>(
+            &JsonSerializer,
+            redis::Value::BulkString(b"not json".to_vec()),
+        );
+        assert!(broken.is_err());
+    }
+
+    #[test]
+    fn test_corrupted_value_decodes_as_error_or_miss() {
+        let checked = ChecksumSerializer::new(JsonSerializer);
+        let mut stored = checked.serialize(&"Ori".to_string()).unwrap();
+        stored.truncate(stored.len() - 1);
+
+        let error = RedisCacheHandle::decode_value::<String>(
+            &checked,
+            redis::Value::BulkString(stored.clone()),
+        )
+        .unwrap_err();
+        assert_eq!(error.kind(), CacheErrorKind::Corruption);
+
+        let value = RedisCacheHandle::decode_value::<String>(
+            &checked.corruption_as_miss(),
+            redis::Value::BulkString(stored),
+        );
+        assert_eq!(value.unwrap(), None);
+    }
+
+    #[test]
+    fn test_unexpected_response_type_is_an_error() {
+        for value in [
+            redis::Value::Int(1),
+            redis::Value::Array(vec![redis::Value::Nil]),
+        ] {
+            let error =
+                RedisCacheHandle::decode_value::<String>(&JsonSerializer, value).unwrap_err();
+            assert_eq!(error.kind(), CacheErrorKind::Backend);
+            assert!(error.to_string().contains("td_get"));
+        }
+    }
+
+    #[test]
+    fn test_read_replica_serves_reads_outside_read_your_writes_window() {
+        let cache = RedisCache::with_read_replica("redis://primary:6379", "redis://replica:6379")
+            .unwrap()
+            .with_read_your_writes(Duration::from_millis(50));
+        let handle = cache.handle();
+        let other = cache.handle();
+        let host = |keys: &[&str]| {
+            let keys = keys.iter().map(|key| key.to_string()).collect::<Vec<_>>();
+            let (client, _) = handle.read_target(&keys);
+            client.get_connection_info().addr.to_string()
+        };
+        assert_eq!(host(&["student:2"]), "replica:6379");
+
+        // Writes through any handle of the cache count.
+        other.note_written(["student:2"]);
+        assert_eq!(host(&["student:2"]), "primary:6379");
+        assert_eq!(host(&["student:1", "student:2"]), "primary:6379");
+        assert_eq!(host(&["student:1"]), "replica:6379");
+        assert_eq!(host(&[]), "replica:6379");
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(host(&["student:2"]), "replica:6379");
+    }
+
+    #[test]
+    fn test_functions_version_and_library_name() {
+        assert!(functions_version(REDIS_FUNCTIONS).is_some());
+        assert_eq!(functions_version("local TD_VERSION = 12\n"), Some(12));
+        assert_eq!(functions_version("#!lua name=turbodiesel\n"), None);
+
+        let bulk = |s: &str| redis::Value::BulkString(s.as_bytes().to_vec());
+        let resp2 = redis::Value::Array(vec![
+            bulk("library_name"),
+            bulk("turbodiesel"),
+            bulk("engine"),
+            bulk("LUA"),
+        ]);
+        assert_eq!(library_name(&resp2), Some("turbodiesel".to_string()));
+        let resp3 = redis::Value::Map(vec![(bulk("library_name"), bulk("turbodiesel"))]);
+        assert_eq!(library_name(&resp3), Some("turbodiesel".to_string()));
+        assert_eq!(library_name(&redis::Value::Nil), None);
+    }
+
+    #[test]
+    fn test_hung_redis_times_out() {
+        // Accepts connections but never replies.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("redis://{}/", listener.local_addr().unwrap());
+        let _server = std::thread::spawn(move || {
+            let _streams: Vec<_> = listener.incoming().collect();
+        });
+        let cache = RedisCache::new(&url)
+            .expect("Failed to create RedisCache")
+            .with_timeout(Duration::from_millis(100));
+        let handle = cache.handle();
+        let started = std::time::Instant::now();
+        let err = handle.get::<String>(&"student:2".to_string()).unwrap_err();
+        assert_eq!(err.kind(), CacheErrorKind::Connection);
+        assert!(handle.scan_keys("student:*").is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(!handle.is_connected());
+    }
+
+    #[test]
+    fn test_unreachable_redis_returns_error() {
+        // Nothing listens on port 1, so every connection attempt is refused.
+        let cache = RedisCache::new("redis://127.0.0.1:1/").expect("Failed to create RedisCache");
+        let handle = cache.handle();
+        let key = "student:2".to_string();
+        let err = handle.get::<String>(&key).unwrap_err();
+        assert_eq!(err.kind(), CacheErrorKind::Connection);
+        assert!(handle.scan_keys("student:*").is_err());
+        let err = handle.health_check().unwrap_err();
+        assert_eq!(err.kind(), CacheErrorKind::Connection);
+
+        // Closing a handle that never connected does nothing, however often.
+        handle.close();
+        handle.close();
+        assert!(!handle.is_connected());
+    }
+
+    #[test]
+    fn test_unreachable_redis_retries_with_backoff() {
+        let cache = RedisCache::new("redis://127.0.0.1:1/")
+            .expect("Failed to create RedisCache")
+            .with_retry(3, Duration::from_millis(20));
+        let mut handle = cache.handle();
+        let key = "student:2".to_string();
+
+        // Two retries, waiting 20ms and then 40ms.
+        let start = std::time::Instant::now();
+        assert!(handle.put(&key, &"Ori".to_string()).is_err());
+        assert!(start.elapsed() >= Duration::from_millis(60));
+
+        // Operations that are unsafe to repeat fail on the first attempt.
+        let start = std::time::Instant::now();
+        assert!(handle.put_if_absent(&key, &"Ori".to_string()).is_err());
+        assert!(start.elapsed() < Duration::from_millis(20));
+    }
+
+    #[test]
+    fn test_patch_field_requires_json_values() {
+        let cache = RedisCache::new("redis://127.0.0.1:1/")
+            .expect("Failed to create RedisCache")
+            .with_serializer(ChecksumSerializer::new(JsonSerializer));
+        // Rejected before connecting, so nothing listening on port 1 is fine.
+        let err = cache
+            .handle()
+            .patch_field("student:2", "/name", &"Ori2")
+            .unwrap_err();
+        assert_eq!(err.kind(), CacheErrorKind::Serialization);
+    }
+
     #[tokio::test]
     async fn test_redis_get_and_set() {
         let redis_test = RedisTestUtil::new();
@@ -235,6 +2123,23 @@ mod tests {
                 let cache =
                     RedisCache::new(redis_url.as_str()).expect("Failed to create RedisCache");
                 let mut handle = cache.handle();
+                handle.health_check().expect("Redis is not healthy");
+                assert!(handle.is_connected());
+                handle.close();
+                assert!(!handle.is_connected());
+
+                let raw_key = "raw_key".to_string();
+                handle
+                    .put_raw(&raw_key, br#""raw_value""#)
+                    .expect("Failed to put raw bytes into cache");
+                assert_eq!(
+                    handle.get::<String>(&raw_key).expect("Failed to get value"),
+                    Some("raw_value".to_string())
+                );
+                assert_eq!(
+                    handle.get_raw(&raw_key).expect("Failed to get raw bytes"),
+                    Some(br#""raw_value""#.to_vec())
+                );
 
                 let key = "test_key".to_string();
                 let value = "test_value".to_string();
@@ -249,19 +2154,122 @@ mod tests {
                     handle.get(&key).expect("Failed to get value from cache");
                 assert_eq!(
                     retrieved_value,
-                    Some(value),
+                    Some(value.clone()),
                     "Retrieved value does not match set value"
                 );
 
+                // Test get_multi
+                let multi_values: Vec<Option<String>> = handle
+                    .get_multi(&[key.clone(), "missing_key".to_string()])
+                    .expect("Failed to get values from cache");
+                assert_eq!(multi_values, vec![Some(value.clone()), None]);
+
+                // Test exists
+                assert!(handle.exists(&key).expect("Failed to check key"));
+                assert!(
+                    !handle
+                        .exists(&"missing_key".to_string())
+                        .expect("Failed to check key")
+                );
+
+                // Test put_if_absent
+                let absent_key = "absent_key".to_string();
+                assert!(
+                    handle
+                        .put_if_absent(&absent_key, &value)
+                        .expect("Failed to put value into cache")
+                );
+                assert!(
+                    !handle
+                        .put_if_absent(&absent_key, &"other".to_string())
+                        .expect("Failed to put value into cache")
+                );
+                handle
+                    .delete(&absent_key)
+                    .expect("Failed to delete key from cache");
+                assert!(
+                    handle
+                        .put_if_absent(&absent_key, &value)
+                        .expect("Failed to put value into cache"),
+                    "Invalidated key expected to count as absent"
+                );
+
+                // Test compare_and_set
+                let versioned_key = "versioned_key".to_string();
+                assert!(
+                    handle
+                        .compare_and_set(&versioned_key, 0, &value)
+                        .expect("Failed to compare and set value")
+                );
+                assert!(
+                    !handle
+                        .compare_and_set(&versioned_key, 0, &"other".to_string())
+                        .expect("Failed to compare and set value"),
+                    "Outdated version expected to be rejected"
+                );
+                assert!(
+                    handle
+                        .compare_and_set(&versioned_key, 1, &"other".to_string())
+                        .expect("Failed to compare and set value")
+                );
+                assert_eq!(
+                    handle
+                        .get_versioned::<String>(&versioned_key)
+                        .expect("Failed to get versioned value"),
+                    Some(VersionedValue {
+                        version: 2,
+                        value: "other".to_string()
+                    })
+                );
+
+                // Test get_or_insert_with
+                let computed_key = "computed_key".to_string();
+                let computed: String = handle
+                    .get_or_insert_with(&computed_key, || Ok("computed".to_string()))
+                    .expect("Failed to get or insert value");
+                assert_eq!(computed, "computed");
+                let cached: String = handle
+                    .get_or_insert_with(&computed_key, || {
+                        Err(CacheError::new("value should come from the cache"))
+                    })
+                    .expect("Failed to get or insert value");
+                assert_eq!(cached, "computed");
+                // The lock held while computing is never listed as a cached key.
+                let observer = handle.clone();
+                let locked_key = "locked_key".to_string();
+                handle
+                    .get_or_insert_with(&locked_key, || {
+                        assert_eq!(observer.list_keys("*locked_key*")?, Vec::<String>::new());
+                        Ok(1)
+                    })
+                    .expect("Failed to get or insert value");
+                assert_eq!(handle.count_keys("*locked_key*").unwrap(), 1);
+
                 // Test scan keys
                 let scan_result = handle.scan_keys("test_key*").expect("Failed to scan keys");
                 assert_eq!(scan_result.len(), 1, "Expected one key in scan result");
                 let expected_raw_value = "bulk-string('\"\\\"test_value\\\"\"')".to_string();
                 assert_eq!(
-                    scan_result.get(&"test_key".to_string()),
+                    scan_result.get("test_key"),
                     Some(&expected_raw_value),
                     "Scan result does not match expected value"
                 );
+                assert_eq!(handle.count_keys("test_key*").unwrap(), 1);
+
+                // Test clear
+                handle
+                    .put(&"other_key".to_string(), &value)
+                    .expect("Failed to put value into cache");
+                handle.clear().expect("Failed to clear cache");
+                assert!(
+                    handle
+                        .scan_keys("*")
+                        .expect("Failed to scan keys")
+                        .is_empty()
+                );
+                handle
+                    .put(&key, &value)
+                    .expect("Failed to put value into cache");
 
                 // Test delete
                 handle
@@ -275,6 +2283,418 @@ mod tests {
                     empty, None,
                     "Retrieved value expected to be None after deletion"
                 );
+                assert!(
+                    !handle.exists(&key).expect("Failed to check key"),
+                    "Invalidated key expected not to exist"
+                );
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_redis_reconnects_after_connection_is_killed() {
+        let redis_test = RedisTestUtil::new();
+        redis_test
+            .run_test_with_redis(async move |redis_url, _| {
+                let cache =
+                    RedisCache::new(redis_url.as_str()).expect("Failed to create RedisCache");
+                let mut handle = cache.handle();
+                let key = "student:1".to_string();
+                handle
+                    .put(&key, &"John".to_string())
+                    .expect("Failed to put value into cache");
+
+                // Drop every other client connection, including the handle's.
+                let mut admin = redis::Client::open(redis_url.as_str())
+                    .and_then(|client| client.get_connection())
+                    .expect("Failed to connect to Redis");
+                redis::cmd("CLIENT")
+                    .arg("KILL")
+                    .arg("TYPE")
+                    .arg("normal")
+                    .exec(&mut admin)
+                    .expect("Failed to kill clients");
+
+                assert_eq!(
+                    handle.get::<String>(&key).expect("Failed to reconnect"),
+                    Some("John".to_string())
+                );
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_redis_scan_keys_iterates_cursor() {
+        let redis_test = RedisTestUtil::new();
+        redis_test
+            .run_test_with_redis(async move |redis_url, _| {
+                let cache = RedisCache::new(redis_url.as_str())
+                    .expect("Failed to create RedisCache")
+                    .with_scan_count(100);
+                let mut handle = cache.handle();
+
+                for i in 0..3000 {
+                    handle
+                        .put(&format!("scan:{}", i), &i)
+                        .expect("Failed to put value into cache");
+                }
+
+                let scan_result = handle.scan_keys("scan:*").expect("Failed to scan keys");
+                assert_eq!(scan_result.len(), 3000);
+                assert_eq!(
+                    scan_result.get("scan:2999"),
+                    Some(&"bulk-string('\"2999\"')".to_string())
+                );
+                assert_eq!(handle.count_keys("scan:*").unwrap(), 3000);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_redis_invalidate_tag() {
+        let redis_test = RedisTestUtil::new();
+        redis_test
+            .run_test_with_redis(async move |redis_url, _| {
+                let cache = RedisCache::with_namespace(redis_url.as_str(), "app")
+                    .expect("Failed to create RedisCache");
+                let mut handle = cache.handle();
+
+                let student = "student:2".to_string();
+                let roster = "class:roster:5".to_string();
+                handle
+                    .put_tagged(&student, &"Ori".to_string(), &["student:2"])
+                    .expect("Failed to put tagged value");
+                handle
+                    .put_tagged(&roster, &vec![2], &["student:2", "class:5"])
+                    .expect("Failed to put tagged value");
+
+                let mut deleted = handle
+                    .invalidate_tag("student:2")
+                    .expect("Failed to invalidate tag");
+                deleted.sort();
+                assert_eq!(deleted, vec![roster.clone(), student.clone()]);
+                assert!(!handle.exists(&student).expect("Failed to check key"));
+                assert!(!handle.exists(&roster).expect("Failed to check key"));
+                assert!(
+                    handle
+                        .invalidate_tag("student:2")
+                        .expect("Failed to invalidate tag")
+                        .is_empty()
+                );
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_redis_read_replica_reads_own_writes_from_primary() {
+        let redis_test = RedisTestUtil::new();
+        redis_test
+            .run_test_with_redis(async move |redis_url, _| {
+                // Nothing listens on the replica's port, so any read sent to
+                // it fails.
+                let cache =
+                    RedisCache::with_read_replica(redis_url.as_str(), "redis://127.0.0.1:1")
+                        .expect("Failed to create RedisCache")
+                        .with_read_your_writes(Duration::from_secs(60));
+                let mut handle = cache.handle();
+
+                handle
+                    .put(&"student:2".to_string(), &"Ori".to_string())
+                    .expect("Failed to put value on the primary");
+                assert_eq!(
+                    handle.get::<String>(&"student:2".to_string()).unwrap(),
+                    Some("Ori".to_string())
+                );
+                let error = handle.get::<String>(&"student:1".to_string()).unwrap_err();
+                assert_eq!(error.kind(), CacheErrorKind::Connection);
+                assert!(handle.scan_keys("student:*").is_err());
+
+                handle
+                    .delete(&"student:2".to_string())
+                    .expect("Failed to delete value on the primary");
+                assert!(!handle.exists(&"student:2".to_string()).unwrap());
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_redis_entry_info_counts_tracked_reads() {
+        let redis_test = RedisTestUtil::new();
+        redis_test
+            .run_test_with_redis(async move |redis_url, _| {
+                let cache = RedisCache::new(redis_url.as_str())
+                    .expect("Failed to create RedisCache")
+                    .with_entry_tracking();
+                let mut handle = cache.handle();
+                let key = "student:2".to_string();
+                assert_eq!(handle.entry_info(&key).unwrap(), None);
+
+                let before = SystemTime::now() - Duration::from_secs(1);
+                handle.put(&key, &2).expect("Failed to put value");
+                let info = handle
+                    .entry_info(&key)
+                    .unwrap()
+                    .expect("Missing entry info");
+                assert!(info.created_at >= before);
+                assert_eq!(info.last_accessed, None);
+                assert_eq!(info.hit_count, 0);
+
+                handle.get::<i32>(&key).unwrap();
+                handle.get_multi::<i32>(std::slice::from_ref(&key)).unwrap();
+                handle.get_with_ttl::<i32>(&key).unwrap();
+                // A miss is not counted, and does not create the key.
+                handle.get::<i32>(&"student:1".to_string()).unwrap();
+                let info = handle
+                    .entry_info(&key)
+                    .unwrap()
+                    .expect("Missing entry info");
+                assert_eq!(info.hit_count, 3);
+                assert!(info.last_accessed.is_some_and(|at| at >= info.created_at));
+                assert_eq!(handle.count_keys("student:*").unwrap(), 1);
+
+                handle.put(&key, &3).expect("Failed to put value");
+                assert_eq!(handle.entry_info(&key).unwrap().unwrap().hit_count, 0);
+                handle.delete(&key).expect("Failed to delete value");
+                assert_eq!(handle.entry_info(&key).unwrap(), None);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_redis_invalidation_rejects_writes_read_before_it() {
+        let redis_test = RedisTestUtil::new();
+        redis_test
+            .run_test_with_redis(async move |redis_url, _| {
+                let cache = RedisCache::new(redis_url.as_str())
+                    .expect("Failed to create RedisCache")
+                    .with_tombstone_ttl(Duration::from_millis(500));
+                let mut reader = cache.handle();
+                let mut writer = cache.handle();
+                let key = "student:2".to_string();
+
+                // The reader loads the row, the writer updates it and
+                // invalidates the key, then the reader caches what it read.
+                let read_at = SystemTime::now();
+                writer.delete(&key).expect("Failed to invalidate key");
+                assert!(!reader.put_as_of(&key, &"old".to_string(), read_at).unwrap());
+                assert_eq!(reader.get::<String>(&key).unwrap(), None);
+                let batch = [(key.clone(), "old".to_string()), ("student:3".to_string(), "x".to_string())];
+                assert_eq!(reader.put_multi_read_at(&batch, read_at).unwrap(), 1);
+                assert_eq!(reader.get::<String>(&key).unwrap(), None);
+
+                // A row read after the invalidation is cached.
+                assert!(
+                    reader
+                        .put_as_of(&key, &"new".to_string(), SystemTime::now())
+                        .unwrap()
+                );
+                assert_eq!(reader.get::<String>(&key).unwrap(), Some("new".to_string()));
+
+                // Once the tombstone expires, nothing keeps the late write out.
+                writer.delete(&key).expect("Failed to invalidate key");
+                std::thread::sleep(Duration::from_millis(600));
+                assert!(reader.put_as_of(&key, &"old".to_string(), read_at).unwrap());
+                assert_eq!(reader.get::<String>(&key).unwrap(), Some("old".to_string()));
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_redis_put_without_ttl_clears_expiry() {
+        let redis_test = RedisTestUtil::new();
+        redis_test
+            .run_test_with_redis(async move |redis_url, _| {
+                let cache = RedisCache::new(redis_url.as_str())
+                    .expect("Failed to create RedisCache")
+                    .with_tombstone_ttl(Duration::from_secs(60));
+                let mut handle = cache.handle();
+                let key = "student:2".to_string();
+
+                handle
+                    .put_with_ttl(&key, &1, Duration::from_secs(60))
+                    .expect("Failed to put value");
+                handle.put(&key, &2).expect("Failed to put value");
+                assert_eq!(handle.get_with_ttl::<i32>(&key).unwrap(), Some((2, None)));
+
+                // A value cached over a tombstone does not keep its expiry either.
+                handle.delete(&key).expect("Failed to invalidate key");
+                handle.put(&key, &3).expect("Failed to put value");
+                assert_eq!(handle.get_with_ttl::<i32>(&key).unwrap(), Some((3, None)));
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_redis_patch_field_replaces_one_field() {
+        let redis_test = RedisTestUtil::new();
+        redis_test
+            .run_test_with_redis(async move |redis_url, _| {
+                let cache =
+                    RedisCache::new(redis_url.as_str()).expect("Failed to create RedisCache");
+                let mut handle = cache.handle();
+                let key = "student:2".to_string();
+                let student = serde_json::json!({
+                    "id": 2,
+                    "name": "Ori",
+                    "courses": [{"name": "math", "grade": null}],
+                });
+                handle
+                    .put_with_ttl(&key, &student, Duration::from_secs(60))
+                    .expect("Failed to put value");
+
+                assert!(handle.patch_field(&key, "/name", &"Ori2").unwrap());
+                assert!(handle.patch_field(&key, "/courses/0/grade", &90).unwrap());
+                assert_eq!(
+                    handle.get::<serde_json::Value>(&key).unwrap(),
+                    Some(serde_json::json!({
+                        "id": 2,
+                        "name": "Ori2",
+                        "courses": [{"name": "math", "grade": 90}],
+                    }))
+                );
+                let (_, ttl) = handle
+                    .get_with_ttl::<serde_json::Value>(&key)
+                    .unwrap()
+                    .unwrap();
+                assert!(ttl.is_some());
+
+                assert!(handle.patch_field(&key, "/dob", &"2000-01-01").is_err());
+                // Nothing to patch once the key is gone.
+                handle.delete(&key).expect("Failed to invalidate key");
+                assert!(!handle.patch_field(&key, "/name", &"Ori3").unwrap());
+                assert!(!handle.patch_field("student:3", "/name", &"Ori3").unwrap());
+                assert_eq!(handle.get::<serde_json::Value>(&key).unwrap(), None);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_redis_scan_typed_deserializes_values() {
+        let redis_test = RedisTestUtil::new();
+        redis_test
+            .run_test_with_redis(async move |redis_url, _| {
+                let cache = RedisCache::with_namespace(redis_url.as_str(), "app")
+                    .expect("Failed to create RedisCache");
+                let mut handle = cache.handle();
+                for id in 1..=3 {
+                    handle
+                        .put(&format!("student:{}", id), &id)
+                        .expect("Failed to put key");
+                }
+                handle
+                    .put(&"course:1".to_string(), &10)
+                    .expect("Failed to put key");
+
+                let values = handle
+                    .scan_typed::<i32>("student:*")
+                    .expect("Failed to scan keys");
+                assert_eq!(
+                    values,
+                    HashMap::from([
+                        ("student:1".to_string(), 1),
+                        ("student:2".to_string(), 2),
+                        ("student:3".to_string(), 3),
+                    ])
+                );
+
+                let mut keys = handle.list_keys("student:*").expect("Failed to list keys");
+                keys.sort();
+                assert_eq!(keys, vec!["student:1", "student:2", "student:3"]);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_redis_warm_writes_all_pairs() {
+        let redis_test = RedisTestUtil::new();
+        redis_test
+            .run_test_with_redis(async move |redis_url, _| {
+                let cache = RedisCache::with_namespace(redis_url.as_str(), "app")
+                    .expect("Failed to create RedisCache");
+                let mut handle = cache.handle();
+
+                let pairs = (1..=3)
+                    .map(|id| (format!("student:{}", id), id))
+                    .collect::<Vec<_>>();
+                assert_eq!(handle.warm(pairs).expect("Failed to warm cache"), 3);
+                let keys = ["student:1", "student:2", "student:3", "student:4"].map(String::from);
+                assert_eq!(
+                    handle.get_multi::<i32>(&keys).expect("Failed to get keys"),
+                    vec![Some(1), Some(2), Some(3), None]
+                );
+
+                // Only the warmed keys held a live value.
+                assert_eq!(
+                    handle
+                        .delete_multi_counted(&keys)
+                        .expect("Failed to delete keys"),
+                    3
+                );
+                assert_eq!(
+                    handle
+                        .delete_multi_counted(&keys)
+                        .expect("Failed to delete keys"),
+                    0
+                );
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_redis_invalidation_evicts_local_cache() {
+        let redis_test = RedisTestUtil::new();
+        redis_test
+            .run_test_with_redis(async move |redis_url, _| {
+                let cache = RedisCache::new(redis_url.as_str())
+                    .expect("Failed to create RedisCache")
+                    .with_invalidation_channel("td:invalidations");
+                let mut handle = cache.handle();
+
+                let local_cache = crate::cacher::HashmapCache::new();
+                let mut local = local_cache.handle();
+                let key = "student:1".to_string();
+                local
+                    .put(&key, &"Alice".to_string())
+                    .expect("Failed to put value into local cache");
+                let listener = handle
+                    .subscribe_invalidations("td:invalidations", local.clone())
+                    .expect("Failed to subscribe to invalidations");
+
+                handle
+                    .delete(&key)
+                    .expect("Failed to delete key from cache");
+
+                let mut evicted = false;
+                for _ in 0..50 {
+                    if !local.exists(&key).expect("Failed to check key") {
+                        evicted = true;
+                        break;
+                    }
+                    std::thread::sleep(Duration::from_millis(20));
+                }
+                assert!(evicted, "Key expected to be evicted from the local cache");
+
+                // Async handles publish their invalidations too.
+                local
+                    .put(&key, &"Alice".to_string())
+                    .expect("Failed to put value into local cache");
+                let mut async_handle = cache
+                    .async_handle()
+                    .await
+                    .expect("Failed to create async handle");
+                crate::async_cacher::AsyncCacheHandle::delete(&mut async_handle, &key)
+                    .await
+                    .expect("Failed to delete key from cache");
+                let mut evicted = false;
+                for _ in 0..50 {
+                    if !local.exists(&key).expect("Failed to check key") {
+                        evicted = true;
+                        break;
+                    }
+                    std::thread::sleep(Duration::from_millis(20));
+                }
+                assert!(evicted, "Key expected to be evicted by an async delete");
+                listener.stop();
             })
             .await;
     }
@@ -14,6 +14,12 @@ pub struct RedisTestUtil {
     port: u16,
 }
 
+impl Default for RedisTestUtil {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl RedisTestUtil {
     pub fn new() -> Self {
         let port = free_local_ipv4_port().unwrap();
@@ -0,0 +1,617 @@
+use crate::cache_stats::CacheStats;
+use crate::cacher::{CacheError, CacheErrorKind};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::any::type_name;
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Converts cached values to and from the bytes stored by a cache backend.
+///
+/// Cache handles are generic over a `Serializer`, with `JsonSerializer` as the
+/// default. Compact binary formats are available behind the `bincode` and
+/// `msgpack` features.
+pub trait Serializer: Clone + Send + Sync + 'static {
+    fn serialize<V: Serialize>(&self, value: &V) -> Result<Vec<u8>, CacheError>;
+    fn deserialize<V: DeserializeOwned>(&self, bytes: &[u8]) -> Result<V, CacheError>;
+
+    /// Whether handles of shared caches should read a value that failed to
+    /// deserialize with `error` as a miss rather than fail, e.g. one written
+    /// under another schema version. Decorators also ask the serializer
+    /// they wrap.
+    fn is_miss(&self, error: &CacheError) -> bool {
+        error.kind() == CacheErrorKind::StaleSchema
+    }
+
+    /// Whether the stored bytes are the JSON text of the value, as
+    /// `RedisCacheHandle::patch_field` requires. Decorators that only
+    /// observe the bytes ask the serializer they wrap.
+    fn writes_json(&self) -> bool {
+        false
+    }
+}
+
+/// Stores values as JSON text.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonSerializer;
+
+impl Serializer for JsonSerializer {
+    fn serialize<V: Serialize>(&self, value: &V) -> Result<Vec<u8>, CacheError> {
+        serde_json::to_vec(value).map_err(|e| {
+            CacheError::with_cause("Failed to serialize value", e)
+                .with_kind(CacheErrorKind::Serialization)
+        })
+    }
+
+    fn deserialize<V: DeserializeOwned>(&self, bytes: &[u8]) -> Result<V, CacheError> {
+        serde_json::from_slice(bytes).map_err(|e| {
+            CacheError::with_cause("Failed to deserialize value", e)
+                .with_kind(CacheErrorKind::Deserialization)
+        })
+    }
+
+    fn writes_json(&self) -> bool {
+        true
+    }
+}
+
+/// Stores values as JSON with the chosen formatting options. The default
+/// configuration writes the same bytes as `JsonSerializer`.
+///
+/// Values written with any configuration can be read with any other, and
+/// with `JsonSerializer`, so the options can be changed without flushing
+/// the cache.
+///
+/// ```ignore
+/// let cache = HashmapCache::new().with_json_config(JsonConfig::new().sort_keys(true));
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct JsonConfig {
+    pretty: bool,
+    sort_keys: bool,
+}
+
+impl JsonConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Indents stored values over several lines, so they are readable in
+    /// `scan_keys` output or `redis-cli`, at the cost of larger values.
+    pub fn pretty(mut self, pretty: bool) -> Self {
+        self.pretty = pretty;
+        self
+    }
+
+    /// Writes the keys of every JSON object in sorted order, so that equal
+    /// values always serialize to the same bytes, e.g. to hash them into a
+    /// fingerprint. Without it, a `HashMap` field is written in its own,
+    /// random, iteration order. Costs an intermediate `serde_json::Value`.
+    pub fn sort_keys(mut self, sort_keys: bool) -> Self {
+        self.sort_keys = sort_keys;
+        self
+    }
+
+    fn write<V: Serialize + ?Sized>(&self, value: &V) -> serde_json::Result<Vec<u8>> {
+        if self.pretty {
+            serde_json::to_vec_pretty(value)
+        } else {
+            serde_json::to_vec(value)
+        }
+    }
+}
+
+impl Serializer for JsonConfig {
+    fn serialize<V: Serialize>(&self, value: &V) -> Result<Vec<u8>, CacheError> {
+        let bytes = if self.sort_keys {
+            serde_json::to_value(value).and_then(|mut value| {
+                value.sort_all_objects();
+                self.write(&value)
+            })
+        } else {
+            self.write(value)
+        };
+        bytes.map_err(|e| {
+            CacheError::with_cause("Failed to serialize value", e)
+                .with_kind(CacheErrorKind::Serialization)
+        })
+    }
+
+    fn deserialize<V: DeserializeOwned>(&self, bytes: &[u8]) -> Result<V, CacheError> {
+        JsonSerializer.deserialize(bytes)
+    }
+
+    fn writes_json(&self) -> bool {
+        true
+    }
+}
+
+/// Stores values in the compact `bincode` binary format.
+#[cfg(feature = "bincode")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BincodeSerializer;
+
+#[cfg(feature = "bincode")]
+impl Serializer for BincodeSerializer {
+    fn serialize<V: Serialize>(&self, value: &V) -> Result<Vec<u8>, CacheError> {
+        bincode::serialize(value).map_err(|e| {
+            CacheError::with_cause("Failed to serialize value", e)
+                .with_kind(CacheErrorKind::Serialization)
+        })
+    }
+
+    fn deserialize<V: DeserializeOwned>(&self, bytes: &[u8]) -> Result<V, CacheError> {
+        bincode::deserialize(bytes).map_err(|e| {
+            CacheError::with_cause("Failed to deserialize value", e)
+                .with_kind(CacheErrorKind::Deserialization)
+        })
+    }
+}
+
+/// Stores values in the MessagePack binary format.
+#[cfg(feature = "msgpack")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MessagePackSerializer;
+
+#[cfg(feature = "msgpack")]
+impl Serializer for MessagePackSerializer {
+    fn serialize<V: Serialize>(&self, value: &V) -> Result<Vec<u8>, CacheError> {
+        rmp_serde::to_vec(value).map_err(|e| {
+            CacheError::with_cause("Failed to serialize value", e)
+                .with_kind(CacheErrorKind::Serialization)
+        })
+    }
+
+    fn deserialize<V: DeserializeOwned>(&self, bytes: &[u8]) -> Result<V, CacheError> {
+        rmp_serde::from_slice(bytes).map_err(|e| {
+            CacheError::with_cause("Failed to deserialize value", e)
+                .with_kind(CacheErrorKind::Deserialization)
+        })
+    }
+}
+
+/// Serializer decorator that records the payload size of every value, and
+/// the time spent encoding or decoding it, into a shared `CacheStats`.
+///
+/// Handles built with a plain serializer are not measured, so the timing
+/// only costs those that opt in. Comparing the time and bytes against the
+/// hit counters shows whether serialization eats into the cache savings,
+/// e.g. before switching to a binary serializer or enabling compression.
+/// Only successful calls are recorded.
+///
+/// ```ignore
+/// let stats = Arc::new(CacheStats::new());
+/// let cache = HashmapCache::new()
+///     .with_serializer(MeasuringSerializer::new(JsonSerializer, stats.clone()));
+/// ```
+#[derive(Clone, Debug)]
+pub struct MeasuringSerializer<S: Serializer> {
+    inner: S,
+    stats: Arc<CacheStats>,
+}
+
+impl<S: Serializer> MeasuringSerializer<S> {
+    pub fn new(inner: S, stats: Arc<CacheStats>) -> Self {
+        MeasuringSerializer { inner, stats }
+    }
+}
+
+impl<S: Serializer> Serializer for MeasuringSerializer<S> {
+    fn serialize<V: Serialize>(&self, value: &V) -> Result<Vec<u8>, CacheError> {
+        let start = Instant::now();
+        let bytes = self.inner.serialize(value)?;
+        self.stats.record_serialized(bytes.len(), start.elapsed());
+        Ok(bytes)
+    }
+
+    fn deserialize<V: DeserializeOwned>(&self, bytes: &[u8]) -> Result<V, CacheError> {
+        let start = Instant::now();
+        let value = self.inner.deserialize(bytes)?;
+        self.stats.record_deserialized(bytes.len(), start.elapsed());
+        Ok(value)
+    }
+
+    fn is_miss(&self, error: &CacheError) -> bool {
+        self.inner.is_miss(error)
+    }
+
+    fn writes_json(&self) -> bool {
+        self.inner.writes_json()
+    }
+}
+
+/// Header byte of a value tagged by `VersionedSerializer`. It can start
+/// neither JSON text nor a `CompressingSerializer` payload.
+const SCHEMA_VERSION_HEADER: u8 = 0xFF;
+
+/// Schema version of a cached type, for `VersionedSerializer::with_type_version`.
+///
+/// Bump it whenever the type's serialized shape changes, e.g. a field is
+/// added, removed, renamed or changes type:
+///
+/// ```ignore
+/// impl SchemaVersion for Student {
+///     // 2: added `email`.
+///     const SCHEMA_VERSION: u32 = 2;
+/// }
+/// ```
+pub trait SchemaVersion {
+    const SCHEMA_VERSION: u32;
+}
+
+/// Serializer decorator that tags every value with a schema version, so
+/// that values written by a deployment with a different model shape are
+/// not deserialized into the current one.
+///
+/// Reading a value tagged with another version, or written without a tag,
+/// fails with `CacheErrorKind::StaleSchema`, which the Redis and memcached
+/// handles turn into a miss: the row is read from the database again and
+/// cached under the current version. Caches in process memory do not
+/// outlive a deployment, so they do not need versioning.
+///
+/// The version applies to every type, unless a type registers its own with
+/// `with_type_version`. When a model changes shape, bump its
+/// `SchemaVersion` (or the global version) in the same change; the stale
+/// entries are then ignored and eventually expire or are overwritten,
+/// without flushing the cache. During a rolling upgrade the old and new
+/// deployments each see the other's entries as misses.
+///
+/// Per-type versions are looked up by the type passed to `get` and `put`,
+/// so they do not apply through `DynCacheHandle`, which stores JSON values.
+///
+/// ```ignore
+/// let cache = RedisCache::new(url)?
+///     .with_schema_version(1)
+///     .with_type_version::<Student>();
+/// ```
+#[derive(Clone, Debug)]
+pub struct VersionedSerializer<S: Serializer> {
+    inner: S,
+    version: u32,
+    type_versions: HashMap<&'static str, u32>,
+}
+
+impl<S: Serializer> VersionedSerializer<S> {
+    /// Tags every value with `version`.
+    pub fn new(inner: S, version: u32) -> Self {
+        VersionedSerializer {
+            inner,
+            version,
+            type_versions: HashMap::new(),
+        }
+    }
+
+    /// Tags values of type `T` with `T::SCHEMA_VERSION` instead.
+    pub fn with_type_version<T: SchemaVersion>(mut self) -> Self {
+        self.type_versions
+            .insert(type_name::<T>(), T::SCHEMA_VERSION);
+        self
+    }
+
+    fn version_of<V>(&self) -> u32 {
+        self.type_versions
+            .get(type_name::<V>())
+            .copied()
+            .unwrap_or(self.version)
+    }
+}
+
+impl<S: Serializer> Serializer for VersionedSerializer<S> {
+    fn serialize<V: Serialize>(&self, value: &V) -> Result<Vec<u8>, CacheError> {
+        let payload = self.inner.serialize(value)?;
+        let mut out = Vec::with_capacity(payload.len() + 5);
+        out.push(SCHEMA_VERSION_HEADER);
+        out.extend_from_slice(&self.version_of::<V>().to_be_bytes());
+        out.extend_from_slice(&payload);
+        Ok(out)
+    }
+
+    fn deserialize<V: DeserializeOwned>(&self, bytes: &[u8]) -> Result<V, CacheError> {
+        let expected = self.version_of::<V>();
+        match bytes.split_first() {
+            Some((&SCHEMA_VERSION_HEADER, rest)) if rest.len() >= 4 => {
+                let (version, payload) = rest.split_at(4);
+                let version = u32::from_be_bytes(version.try_into().unwrap());
+                if version != expected {
+                    return Err(CacheError::new(&format!(
+                        "Cached value has schema version {}, expected {}",
+                        version, expected
+                    ))
+                    .with_kind(CacheErrorKind::StaleSchema));
+                }
+                self.inner.deserialize(payload)
+            }
+            _ => Err(CacheError::new("Cached value has no schema version")
+                .with_kind(CacheErrorKind::StaleSchema)),
+        }
+    }
+
+    fn is_miss(&self, error: &CacheError) -> bool {
+        error.kind() == CacheErrorKind::StaleSchema || self.inner.is_miss(error)
+    }
+}
+
+/// Header byte of a value checksummed by `ChecksumSerializer`, distinct from
+/// `SCHEMA_VERSION_HEADER` and the `CompressingSerializer` headers.
+const CHECKSUM_HEADER: u8 = 0xFE;
+
+/// Serializer decorator that stores a CRC32 checksum with every value and
+/// verifies it on read, so that a value damaged in storage or in transit,
+/// e.g. by a truncated write, fails with a clear
+/// `CacheErrorKind::Corruption` error instead of a confusing one from the
+/// inner format.
+///
+/// Values written without a checksum, e.g. before the check was enabled,
+/// fail the same way. With `corruption_as_miss`, handles of shared caches
+/// read such values as misses instead, so the row is read from the
+/// database and cached again; the error is still logged at debug level.
+///
+/// ```ignore
+/// let cache = RedisCache::new(url)?
+///     .with_integrity_check()
+///     .corruption_as_miss();
+/// ```
+#[derive(Clone, Debug)]
+pub struct ChecksumSerializer<S: Serializer> {
+    inner: S,
+    corruption_as_miss: bool,
+}
+
+impl<S: Serializer> ChecksumSerializer<S> {
+    pub fn new(inner: S) -> Self {
+        ChecksumSerializer {
+            inner,
+            corruption_as_miss: false,
+        }
+    }
+
+    /// Reads values that fail the checksum as misses rather than errors.
+    pub fn corruption_as_miss(mut self) -> Self {
+        self.corruption_as_miss = true;
+        self
+    }
+}
+
+impl<S: Serializer> Serializer for ChecksumSerializer<S> {
+    fn serialize<V: Serialize>(&self, value: &V) -> Result<Vec<u8>, CacheError> {
+        let payload = self.inner.serialize(value)?;
+        let mut out = Vec::with_capacity(payload.len() + 5);
+        out.push(CHECKSUM_HEADER);
+        out.extend_from_slice(&crc32fast::hash(&payload).to_be_bytes());
+        out.extend_from_slice(&payload);
+        Ok(out)
+    }
+
+    fn deserialize<V: DeserializeOwned>(&self, bytes: &[u8]) -> Result<V, CacheError> {
+        match bytes.split_first() {
+            Some((&CHECKSUM_HEADER, rest)) if rest.len() >= 4 => {
+                let (checksum, payload) = rest.split_at(4);
+                let checksum = u32::from_be_bytes(checksum.try_into().unwrap());
+                let actual = crc32fast::hash(payload);
+                if checksum != actual {
+                    return Err(CacheError::new(&format!(
+                        "Cached value of {} bytes has checksum {:08x}, expected {:08x}",
+                        payload.len(),
+                        actual,
+                        checksum
+                    ))
+                    .with_kind(CacheErrorKind::Corruption));
+                }
+                self.inner.deserialize(payload)
+            }
+            _ => Err(CacheError::new("Cached value has no checksum")
+                .with_kind(CacheErrorKind::Corruption)),
+        }
+    }
+
+    fn is_miss(&self, error: &CacheError) -> bool {
+        (self.corruption_as_miss && error.kind() == CacheErrorKind::Corruption)
+            || self.inner.is_miss(error)
+    }
+}
+
+/// Converts rows of type `U` to and from the value stored in the cache.
+///
+/// The statement wrappers store rows through their own `Serialize` /
+/// `DeserializeOwned` impls, with `SerdeCodec`. A wrapper given another codec
+/// with `with_codec` stores `Cached` instead, which lets a row type leave out
+/// large derived fields and rebuild them on read without changing the
+/// model's serde attributes. The cached value is still encoded by the
+/// handle's `Serializer`.
+///
+/// ```ignore
+/// #[derive(Clone)]
+/// struct ReportCodec;
+///
+/// impl CacheCodec<Report> for ReportCodec {
+///     type Cached = (i32, String);
+///     type Encoded<'a> = (i32, String);
+///
+///     fn encode<'a>(&self, report: &'a Report) -> Result<Self::Encoded<'a>, CacheError> {
+///         Ok((report.id, report.body.clone()))
+///     }
+///
+///     fn decode(&self, (id, body): Self::Cached) -> Result<Report, CacheError> {
+///         Ok(Report::new(id, body))
+///     }
+/// }
+/// ```
+pub trait CacheCodec<U>: Clone {
+    /// The value stored in the cache for a row.
+    type Cached: Serialize + DeserializeOwned;
+    /// What `encode` returns: usually `Cached` itself, or a reference to the
+    /// row when it is stored as-is.
+    type Encoded<'a>: Borrow<Self::Cached>
+    where
+        Self: 'a,
+        U: 'a;
+
+    fn encode<'a>(&'a self, row: &'a U) -> Result<Self::Encoded<'a>, CacheError>;
+    fn decode(&self, cached: Self::Cached) -> Result<U, CacheError>;
+}
+
+/// Stores rows as-is through their serde impls, which is JSON with the
+/// default `JsonSerializer`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SerdeCodec;
+
+impl<U: Serialize + DeserializeOwned> CacheCodec<U> for SerdeCodec {
+    type Cached = U;
+    type Encoded<'a>
+        = &'a U
+    where
+        U: 'a;
+
+    fn encode<'a>(&'a self, row: &'a U) -> Result<&'a U, CacheError> {
+        Ok(row)
+    }
+
+    fn decode(&self, cached: U) -> Result<U, CacheError> {
+        Ok(cached)
+    }
+}
+
+/// Renders a stored value for debugging output such as `scan_keys`. Text
+/// payloads are returned as-is, binary payloads are hex encoded.
+pub(crate) fn printable(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => text.to_string(),
+        Err(_) => bytes.iter().map(|b| format!("{:02x}", b)).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_printable_hex_encodes_binary() {
+        assert_eq!(printable(b"\"text\""), "\"text\"");
+        assert_eq!(printable(&[0xff, 0x00, 0x10]), "ff0010");
+    }
+
+    #[test]
+    fn test_deserialize_error_has_kind_and_source() {
+        use std::error::Error;
+
+        let err = JsonSerializer.deserialize::<i32>(b"not json").unwrap_err();
+        assert_eq!(err.kind(), CacheErrorKind::Deserialization);
+        let source = err.source().expect("Cause should be exposed as the source");
+        assert!(source.is::<serde_json::Error>());
+    }
+
+    #[test]
+    fn test_only_plain_json_serializers_write_json() {
+        assert!(JsonSerializer.writes_json());
+        assert!(JsonConfig::new().pretty(true).writes_json());
+        let stats = Arc::new(CacheStats::new());
+        assert!(MeasuringSerializer::new(JsonSerializer, stats).writes_json());
+        assert!(!ChecksumSerializer::new(JsonSerializer).writes_json());
+        assert!(!VersionedSerializer::new(JsonSerializer, 1).writes_json());
+    }
+
+    #[test]
+    fn test_measuring_serializer_records_sizes() {
+        let stats = Arc::new(CacheStats::new());
+        let serializer = MeasuringSerializer::new(JsonSerializer, stats.clone());
+
+        let bytes = serializer.serialize(&"Ori".to_string()).unwrap();
+        assert_eq!(serializer.deserialize::<String>(&bytes).unwrap(), "Ori");
+        assert!(serializer.deserialize::<i32>(b"not json").is_err());
+
+        assert_eq!(stats.serializations(), 1);
+        assert_eq!(stats.serialized_bytes(), 5);
+        assert_eq!(stats.deserializations(), 1);
+        assert_eq!(stats.deserialized_bytes(), 5);
+
+        stats.reset();
+        assert_eq!(stats.serialized_bytes() + stats.deserialized_bytes(), 0);
+        assert_eq!(stats.serialization_time(), std::time::Duration::ZERO);
+    }
+
+    #[test]
+    fn test_versioned_serializer_rejects_other_versions() {
+        #[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+        struct Student(i32, String);
+
+        impl SchemaVersion for Student {
+            const SCHEMA_VERSION: u32 = 2;
+        }
+
+        let v1 = VersionedSerializer::new(JsonSerializer, 1);
+        let v2 = VersionedSerializer::new(JsonSerializer, 1).with_type_version::<Student>();
+        let student = Student(2, "Ori".to_string());
+
+        let bytes = v2.serialize(&student).unwrap();
+        assert_eq!(v2.deserialize::<Student>(&bytes).unwrap(), student);
+        let err = v1.deserialize::<Student>(&bytes).unwrap_err();
+        assert_eq!(err.kind(), CacheErrorKind::StaleSchema);
+
+        // Other types keep the global version.
+        let bytes = v1.serialize(&"Ori".to_string()).unwrap();
+        assert_eq!(v2.deserialize::<String>(&bytes).unwrap(), "Ori");
+
+        // Values written before versioning was enabled are stale too.
+        let legacy = JsonSerializer.serialize(&student).unwrap();
+        let err = v2.deserialize::<Student>(&legacy).unwrap_err();
+        assert_eq!(err.kind(), CacheErrorKind::StaleSchema);
+    }
+
+    #[test]
+    fn test_checksum_serializer_detects_corruption() {
+        let checked = ChecksumSerializer::new(JsonSerializer);
+        let bytes = checked.serialize(&"Ori".to_string()).unwrap();
+        assert_eq!(checked.deserialize::<String>(&bytes).unwrap(), "Ori");
+
+        // A flipped bit fails the checksum rather than the JSON parser.
+        let mut flipped = bytes.clone();
+        *flipped.last_mut().unwrap() ^= 0x01;
+        let err = checked.deserialize::<String>(&flipped).unwrap_err();
+        assert_eq!(err.kind(), CacheErrorKind::Corruption);
+        assert!(!checked.is_miss(&err));
+
+        // So does a value written without a checksum.
+        let legacy = JsonSerializer.serialize(&"Ori".to_string()).unwrap();
+        let err = checked.deserialize::<String>(&legacy).unwrap_err();
+        assert_eq!(err.kind(), CacheErrorKind::Corruption);
+
+        // Decorators wrapping it pass on whether corruption reads as a miss.
+        let versioned = VersionedSerializer::new(checked.corruption_as_miss(), 1);
+        assert!(versioned.is_miss(&err));
+    }
+
+    #[test]
+    fn test_json_config_sorts_keys_and_pretty_prints() {
+        use std::collections::HashMap;
+
+        let forward = (0..20)
+            .map(|i| (format!("k{}", i), i))
+            .collect::<HashMap<_, _>>();
+        let backward = (0..20)
+            .rev()
+            .map(|i| (format!("k{}", i), i))
+            .collect::<HashMap<_, _>>();
+        let sorted = JsonConfig::new().sort_keys(true);
+        let bytes = sorted.serialize(&forward).unwrap();
+        assert_eq!(bytes, sorted.serialize(&backward).unwrap());
+        assert!(bytes.starts_with(br#"{"k0":0,"k1":1,"k10":10"#));
+
+        let pretty = JsonConfig::new().pretty(true);
+        let bytes = pretty.serialize(&forward).unwrap();
+        assert!(bytes.contains(&b'\n'));
+        assert_eq!(
+            JsonSerializer
+                .deserialize::<HashMap<String, i32>>(&bytes)
+                .unwrap(),
+            forward
+        );
+
+        let plain = "Ori".to_string();
+        assert_eq!(
+            JsonConfig::default().serialize(&plain).unwrap(),
+            JsonSerializer.serialize(&plain).unwrap()
+        );
+    }
+}
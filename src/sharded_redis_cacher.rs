@@ -0,0 +1,450 @@
+use crate::cacher::{CacheError, CacheHandle, EntryInfo};
+use crate::redis_cacher::{RedisCache, RedisCacheHandle};
+use crate::serializer::{JsonSerializer, Serializer};
+use redis::RedisError;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// Number of points each shard occupies on the hash ring. More points spread
+/// the keys more evenly between shards.
+const POINTS_PER_SHARD: usize = 160;
+
+/// Hashes `data` to a position on the ring. SHA-256 is used rather than
+/// `DefaultHasher`, whose output may change between Rust releases, so keys
+/// keep their shard across restarts and upgrades.
+fn ring_hash(data: &[u8]) -> u64 {
+    let digest = Sha256::digest(data);
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    u64::from_be_bytes(bytes)
+}
+
+/// Consistent hash ring mapping keys to shard indexes.
+///
+/// The points of a shard are derived from its URL, not its position in the
+/// list, so reordering the URLs moves no keys, and adding or removing a shard
+/// only moves the keys of the ring segments it gains or loses.
+#[derive(Debug)]
+struct HashRing {
+    points: Vec<(u64, usize)>,
+}
+
+impl HashRing {
+    fn new(names: &[&str]) -> Self {
+        let mut points = names
+            .iter()
+            .enumerate()
+            .flat_map(|(shard, name)| {
+                (0..POINTS_PER_SHARD)
+                    .map(move |i| (ring_hash(format!("{}#{}", name, i).as_bytes()), shard))
+            })
+            .collect::<Vec<_>>();
+        points.sort_unstable();
+        HashRing { points }
+    }
+
+    /// Returns the shard owning the first point at or after the key's hash,
+    /// wrapping around the ring.
+    fn shard(&self, key: &str) -> usize {
+        let hash = ring_hash(key.as_bytes());
+        let i = self.points.partition_point(|&(point, _)| point < hash);
+        self.points[i % self.points.len()].1
+    }
+}
+
+/// Redis cache whose keys are spread over several independent Redis
+/// instances by consistent hashing.
+///
+/// Unlike Redis Cluster, the servers know nothing of each other: each key is
+/// routed to one of them by the client. Every process must list the same
+/// shard URLs for them to agree on where a key lives; their order does not
+/// matter.
+///
+/// ```ignore
+/// let cache = ShardedRedisCache::new(&["redis://cache-1/", "redis://cache-2/"])?;
+/// let results = students::table
+///     .select((Student::as_select(), cache_key_expr("student", students::id)))
+///     .populate_cache(cache.handle())
+///     .load::<Student>(connection)?;
+/// ```
+pub struct ShardedRedisCache<S: Serializer = JsonSerializer> {
+    shards: Vec<RedisCache<S>>,
+    ring: Arc<HashRing>,
+}
+
+impl ShardedRedisCache {
+    pub fn new(redis_urls: &[&str]) -> Result<Self, RedisError> {
+        if redis_urls.is_empty() {
+            return Err(RedisError::from((
+                redis::ErrorKind::InvalidClientConfig,
+                "At least one shard URL is required",
+            )));
+        }
+        let shards = redis_urls
+            .iter()
+            .map(|url| RedisCache::new(url))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(ShardedRedisCache {
+            shards,
+            ring: Arc::new(HashRing::new(redis_urls)),
+        })
+    }
+}
+
+impl<S: Serializer> ShardedRedisCache<S> {
+    /// Replaces the serializer used to encode stored values on every shard.
+    pub fn with_serializer<S2: Serializer>(self, serializer: S2) -> ShardedRedisCache<S2> {
+        ShardedRedisCache {
+            shards: self
+                .shards
+                .into_iter()
+                .map(|shard| shard.with_serializer(serializer.clone()))
+                .collect(),
+            ring: self.ring,
+        }
+    }
+
+    /// Sets how long invalidated keys keep their tombstone on every shard;
+    /// see `RedisCache::with_tombstone_ttl`.
+    pub fn with_tombstone_ttl(self, ttl: Duration) -> Self {
+        ShardedRedisCache {
+            shards: self
+                .shards
+                .into_iter()
+                .map(|shard| shard.with_tombstone_ttl(ttl))
+                .collect(),
+            ring: self.ring,
+        }
+    }
+
+    pub fn handle(&self) -> ShardedRedisCacheHandle<S> {
+        ShardedRedisCacheHandle {
+            shards: self.shards.iter().map(RedisCache::handle).collect(),
+            ring: Arc::clone(&self.ring),
+        }
+    }
+}
+
+/// Handle to a `ShardedRedisCache`.
+///
+/// Single-key operations go to the key's shard. `get_multi` and
+/// `delete_multi` are split by shard, with one pipelined call per shard,
+/// while `put_multi` writes its entries one at a time. `clear`, `scan_keys`,
+/// `count_keys`, `delete_pattern` and `invalidate_tag` fan out to every
+/// shard and merge the results. A tag's keys are tracked on the shard of
+/// each key, so tags may span shards.
+///
+/// Like `RedisCacheHandle`, it holds one connection per shard and can be
+/// sent to another thread but not shared between threads.
+#[derive(Clone)]
+pub struct ShardedRedisCacheHandle<S: Serializer = JsonSerializer> {
+    shards: Vec<RedisCacheHandle<S>>,
+    ring: Arc<HashRing>,
+}
+
+impl<S: Serializer> ShardedRedisCacheHandle<S> {
+    /// Returns the index, in the list of URLs, of the shard storing `key`.
+    pub fn shard_index(&self, key: &str) -> usize {
+        self.ring.shard(key)
+    }
+
+    /// Stores a value stamped with the time it was read on the key's shard;
+    /// see `RedisCacheHandle::put_as_of`.
+    pub fn put_as_of<V: Serialize>(
+        &mut self,
+        key: &str,
+        value: &V,
+        read_at: SystemTime,
+    ) -> Result<bool, CacheError> {
+        self.shard_mut(key).put_as_of(key, value, read_at)
+    }
+
+    /// Replaces one field of the JSON value cached under `key` on its shard;
+    /// see `RedisCacheHandle::patch_field`.
+    pub fn patch_field<V: Serialize>(
+        &mut self,
+        key: &str,
+        pointer: &str,
+        value: &V,
+    ) -> Result<bool, CacheError> {
+        self.shard_mut(key).patch_field(key, pointer, value)
+    }
+
+    fn shard(&self, key: &str) -> &RedisCacheHandle<S> {
+        &self.shards[self.ring.shard(key)]
+    }
+
+    fn shard_mut(&mut self, key: &str) -> &mut RedisCacheHandle<S> {
+        &mut self.shards[self.ring.shard(key)]
+    }
+
+    /// Splits `items` by the shard of their key, remembering the position of
+    /// each item in `items`.
+    fn group<'a, T>(
+        &self,
+        items: &'a [T],
+        key: impl Fn(&T) -> &String,
+    ) -> Vec<Vec<(usize, &'a T)>> {
+        let mut groups = vec![Vec::new(); self.shards.len()];
+        for (i, item) in items.iter().enumerate() {
+            groups[self.ring.shard(key(item))].push((i, item));
+        }
+        groups
+    }
+}
+
+impl<S: Serializer> CacheHandle for ShardedRedisCacheHandle<S> {
+    fn get<V: Serialize + DeserializeOwned>(&self, key: &String) -> Result<Option<V>, CacheError> {
+        self.shard(key).get(key)
+    }
+
+    fn get_with_ttl<V: Serialize + DeserializeOwned>(
+        &self,
+        key: &String,
+    ) -> Result<Option<(V, Option<Duration>)>, CacheError> {
+        self.shard(key).get_with_ttl(key)
+    }
+
+    fn get_multi<V: Serialize + DeserializeOwned>(
+        &self,
+        keys: &[String],
+    ) -> Result<Vec<Option<V>>, CacheError> {
+        let mut values = keys.iter().map(|_| None).collect::<Vec<_>>();
+        for (shard, group) in self.group(keys, |key| key).into_iter().enumerate() {
+            if group.is_empty() {
+                continue;
+            }
+            let shard_keys = group
+                .iter()
+                .map(|(_, key)| (*key).clone())
+                .collect::<Vec<_>>();
+            let shard_values = self.shards[shard].get_multi::<V>(&shard_keys)?;
+            for ((i, _), value) in group.into_iter().zip(shard_values) {
+                values[i] = value;
+            }
+        }
+        Ok(values)
+    }
+
+    fn exists(&self, key: &String) -> Result<bool, CacheError> {
+        self.shard(key).exists(key)
+    }
+
+    fn entry_info(&self, key: &String) -> Result<Option<EntryInfo>, CacheError> {
+        self.shard(key).entry_info(key)
+    }
+
+    fn put<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+    ) -> Result<(), CacheError> {
+        self.shard_mut(key).put(key, value)
+    }
+
+    fn get_raw(&self, key: &String) -> Result<Option<Vec<u8>>, CacheError> {
+        self.shard(key).get_raw(key)
+    }
+
+    fn put_raw(&mut self, key: &String, bytes: &[u8]) -> Result<(), CacheError> {
+        self.shard_mut(key).put_raw(key, bytes)
+    }
+
+    fn put_with_ttl<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+        ttl: Duration,
+    ) -> Result<(), CacheError> {
+        self.shard_mut(key).put_with_ttl(key, value, ttl)
+    }
+
+    fn put_if_absent<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+    ) -> Result<bool, CacheError> {
+        self.shard_mut(key).put_if_absent(key, value)
+    }
+
+    fn compare_and_set<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        expected_version: u64,
+        value: &V,
+    ) -> Result<bool, CacheError> {
+        self.shard_mut(key)
+            .compare_and_set(key, expected_version, value)
+    }
+
+    fn put_read_at<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+        ttl: Option<Duration>,
+        read_at: SystemTime,
+    ) -> Result<bool, CacheError> {
+        self.shard_mut(key).put_read_at(key, value, ttl, read_at)
+    }
+
+    fn delete(&mut self, key: &String) -> Result<(), CacheError> {
+        self.shard_mut(key).delete(key)
+    }
+
+    fn delete_multi(&mut self, keys: &[String]) -> Result<(), CacheError> {
+        self.delete_multi_counted(keys).map(|_| ())
+    }
+
+    fn delete_multi_counted(&mut self, keys: &[String]) -> Result<usize, CacheError> {
+        let groups = self.group(keys, |key| key);
+        let mut deleted = 0;
+        for (shard, group) in groups.into_iter().enumerate() {
+            if group.is_empty() {
+                continue;
+            }
+            let shard_keys = group
+                .into_iter()
+                .map(|(_, key)| key.clone())
+                .collect::<Vec<_>>();
+            deleted += self.shards[shard].delete_multi_counted(&shard_keys)?;
+        }
+        Ok(deleted)
+    }
+
+    fn delete_pattern(&mut self, pattern: &str) -> Result<usize, CacheError> {
+        self.shards
+            .iter_mut()
+            .map(|shard| shard.delete_pattern(pattern))
+            .sum()
+    }
+
+    fn clear(&mut self) -> Result<(), CacheError> {
+        self.shards.iter_mut().try_for_each(|shard| shard.clear())
+    }
+
+    fn scan_keys(&self, pattern: &str) -> Result<HashMap<String, String>, CacheError> {
+        let mut result = HashMap::new();
+        for shard in &self.shards {
+            result.extend(shard.scan_keys(pattern)?);
+        }
+        Ok(result)
+    }
+
+    fn scan_typed<V: Serialize + DeserializeOwned>(
+        &self,
+        pattern: &str,
+    ) -> Result<HashMap<String, V>, CacheError> {
+        let mut result = HashMap::new();
+        for shard in &self.shards {
+            result.extend(shard.scan_typed(pattern)?);
+        }
+        Ok(result)
+    }
+
+    fn list_keys(&self, pattern: &str) -> Result<Vec<String>, CacheError> {
+        let mut result = Vec::new();
+        for shard in &self.shards {
+            result.extend(shard.list_keys(pattern)?);
+        }
+        Ok(result)
+    }
+
+    fn count_keys(&self, pattern: &str) -> Result<usize, CacheError> {
+        self.shards
+            .iter()
+            .map(|shard| shard.count_keys(pattern))
+            .sum()
+    }
+
+    fn put_tagged<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+        tags: &[&str],
+    ) -> Result<(), CacheError> {
+        self.shard_mut(key).put_tagged(key, value, tags)
+    }
+
+    fn invalidate_tag(&mut self, tag: &str) -> Result<Vec<String>, CacheError> {
+        let mut keys = Vec::new();
+        for shard in &mut self.shards {
+            keys.extend(shard.invalidate_tag(tag)?);
+        }
+        Ok(keys)
+    }
+
+    fn health_check(&self) -> Result<(), CacheError> {
+        self.shards
+            .iter()
+            .try_for_each(|shard| shard.health_check())
+    }
+
+    fn get_or_insert_with<V, F>(&mut self, key: &String, f: F) -> Result<V, CacheError>
+    where
+        V: Serialize + DeserializeOwned,
+        F: FnOnce() -> Result<V, CacheError>,
+    {
+        self.shard_mut(key).get_or_insert_with(key, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URLS: [&str; 3] = [
+        "redis://127.0.0.1:6380/",
+        "redis://127.0.0.1:6381/",
+        "redis://127.0.0.1:6382/",
+    ];
+
+    fn keys() -> Vec<String> {
+        (0..3000).map(|i| format!("student:{}", i)).collect()
+    }
+
+    #[test]
+    fn test_ring_is_stable_and_balanced() {
+        let ring = HashRing::new(&URLS);
+        // The same URLs in another order place every key on the same shard.
+        let reordered = HashRing::new(&[URLS[2], URLS[0], URLS[1]]);
+        let remap = [2, 0, 1];
+
+        let mut counts = [0; 3];
+        for key in keys() {
+            let shard = ring.shard(&key);
+            assert_eq!(remap[reordered.shard(&key)], shard);
+            counts[shard] += 1;
+        }
+        assert!(counts.iter().all(|&count| count > 700), "{:?}", counts);
+    }
+
+    #[test]
+    fn test_adding_a_shard_moves_only_its_keys() {
+        let ring = HashRing::new(&URLS);
+        let grown = HashRing::new(&[URLS[0], URLS[1], URLS[2], "redis://127.0.0.1:6383/"]);
+
+        let mut moved = 0;
+        for key in keys() {
+            let shard = grown.shard(&key);
+            if shard != ring.shard(&key) {
+                assert_eq!(shard, 3);
+                moved += 1;
+            }
+        }
+        assert!(moved > 400 && moved < 1200, "{} keys moved", moved);
+    }
+
+    #[test]
+    fn test_unreachable_shard_returns_error() {
+        let cache = ShardedRedisCache::new(&["redis://127.0.0.1:1/", "redis://127.0.0.1:2/"])
+            .expect("Failed to create ShardedRedisCache");
+        let handle = cache.handle();
+        assert!(handle.health_check().is_err());
+        assert!(handle.get::<String>(&"student:2".to_string()).is_err());
+        assert!(ShardedRedisCache::new(&[]).is_err());
+    }
+}
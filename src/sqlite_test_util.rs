@@ -0,0 +1,70 @@
+use log::info;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Distinguishes the databases of tests running concurrently in one process.
+static NEXT_DATABASE: AtomicUsize = AtomicUsize::new(0);
+
+pub struct SqliteTestUtil {}
+
+impl Default for SqliteTestUtil {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SqliteTestUtil {
+    pub fn new() -> Self {
+        SqliteTestUtil {}
+    }
+
+    /// Runs `f` against a fresh SQLite database file, passing its path as the
+    /// connection URL, and removes the file afterwards.
+    ///
+    /// SQLite needs no container, so unlike `run_test_with_postgres` the
+    /// closure receives no `DockerOperations`.
+    pub async fn run_test_with_sqlite<Fun, Fut>(&self, f: Fun)
+    where
+        Fut: Future<Output = ()> + Send + 'static,
+        Fun: FnOnce(String) -> Fut + Send + 'static,
+    {
+        let path = Self::database_path();
+        let url = path.to_string_lossy().into_owned();
+        info!("Running inside SQLite: {}", url);
+        f(url).await;
+        _ = std::fs::remove_file(&path);
+        info!("Finished running inside SQLite.");
+    }
+
+    fn database_path() -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "turbodiesel-test-{}-{}.db",
+            std::process::id(),
+            NEXT_DATABASE.fetch_add(1, Ordering::Relaxed)
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use diesel::dsl;
+    use diesel::prelude::SqliteConnection;
+    use diesel::sql_types::Integer;
+    use diesel::{Connection, RunQueryDsl};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_basic_connect() {
+        let util = SqliteTestUtil::new();
+
+        util.run_test_with_sqlite(async move |url| {
+            let mut con = SqliteConnection::establish(&url).expect("Error connecting to sqlite");
+            let result = diesel::select(dsl::sql::<Integer>("1"))
+                .get_result::<i32>(&mut con)
+                .unwrap();
+            assert_eq!(result, 1);
+        })
+        .await;
+    }
+}
@@ -1,82 +1,411 @@
-use crate::cacher::CacheHandle;
+use crate::cache_key::{
+    IntoCacheKey, KeyExtractor, RowWithCacheKey, SelectedCacheKey, query_cache_key,
+};
+use crate::cache_stats::CacheStats;
+use crate::cacher::{CacheError, CacheHandle};
+use crate::deferred_invalidation::DeferredInvalidations;
+use crate::instrumentation::{self, Span};
+use crate::serializer::{CacheCodec, SerdeCodec};
+use diesel::backend::Backend;
 use diesel::connection::Connection;
+use diesel::deserialize::{self, FromSql, Queryable};
+use diesel::expression::array_comparison::AsInExpression;
+use diesel::expression_methods::ExpressionMethods;
+use diesel::query_builder::{
+    BoxedSqlQuery, DeleteStatement, InsertStatement, Query, QueryFragment, SelectStatement,
+    SqlQuery, UpdateStatement,
+};
 use diesel::query_dsl::load_dsl::ExecuteDsl;
+use diesel::query_dsl::methods::{FilterDsl, LimitDsl};
 use diesel::query_dsl::{LoadQuery, RunQueryDsl};
 use diesel::result::QueryResult;
+use diesel::sql_types::{BigInt, Nullable};
+use diesel::{QuerySource, Table};
 use log::{debug, error, warn};
-use serde::Serialize;
-use serde::de::DeserializeOwned;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::de::{self, DeserializeOwned};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Borrow;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::iter::FusedIterator;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, SystemTime};
+
+static BEST_EFFORT: AtomicBool = AtomicBool::new(false);
+
+/// Turns the process-wide best-effort mode on or off; it is off by default.
+///
+/// Normally a cache failure while invalidating keys (`invalidate_key`,
+/// `invalidate_keys`, `invalidate_tag`) or writing an updated row
+/// (`write_through_update`) fails the update with
+/// `Error::RollbackTransaction`, so the database is never changed while the
+/// cache may still hold the old row. In best-effort mode the failure is only
+/// logged and the update goes ahead, so an unavailable cache never fails a
+/// request. Reads and `populate_cache` already fall back to the database and
+/// log cache failures in either mode.
+///
+/// The price is consistency: a key that could not be invalidated keeps
+/// serving the old row after the update commits, until it expires or is
+/// invalidated again. Give keys a TTL to bound how long that lasts.
+pub fn set_best_effort(enabled: bool) {
+    BEST_EFFORT.store(enabled, Ordering::Relaxed);
+}
+
+/// Returns whether the best-effort mode set by `set_best_effort` is on.
+pub fn best_effort() -> bool {
+    BEST_EFFORT.load(Ordering::Relaxed)
+}
+
+/// Decides the outcome of an update whose cache invalidation or write has
+/// just failed and been logged: the update proceeds in best-effort mode,
+/// and is rolled back otherwise.
+fn fail_unless_best_effort(best_effort_wrapper: bool) -> QueryResult<()> {
+    if best_effort_wrapper || best_effort() {
+        warn!("Proceeding with the database update despite the cache failure");
+        Ok(())
+    } else {
+        Err(diesel::result::Error::RollbackTransaction)
+    }
+}
 
 /// Iterator that populates the cache as rows are streamed from a query.
 ///
 /// Used internally by `populate_cache` to transparently insert each
-/// record into the cache while reading rows from the database.
-pub struct ResultCachingIterator<I, U, C>
+/// record into the cache while reading rows from the database. Rows whose
+/// key is NULL are passed through without being cached. Rows are written
+/// with `put_read_at`, stamped with the time the query started, so a backend
+/// that keeps tombstones rejects rows read before a concurrent invalidation.
+/// Once the inner query is exhausted, the number of rows read and cached is
+/// logged.
+pub struct ResultCachingIterator<I, U, C, X = SerdeCodec>
 where
-    I: Iterator<Item = QueryResult<(U, String)>>,
+    I: Iterator<Item = QueryResult<(U, SelectedCacheKey)>>,
     C: CacheHandle,
     U: Serialize,
 {
-    inner: I,
+    inner: std::iter::Fuse<I>,
     cache: C,
+    codec: X,
+    ttl: Option<Duration>,
+    jitter: Option<TtlJitter>,
+    stats: Option<Arc<CacheStats>>,
+    span: Span,
+    verbosity: LogVerbosity,
+    /// When the inner query started.
+    read_at: SystemTime,
+    /// `None` once the totals have been logged.
+    counts: Option<PopulateCounts>,
+}
+
+/// How much a populating wrapper logs about the rows it caches.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogVerbosity {
+    /// Log every row read and cached at debug level, as well as the totals.
+    #[default]
+    PerRow,
+    /// Log only the totals once the rows have been read, for bulk loads
+    /// where a line per row would flood the logs. Failed writes and NULL
+    /// keys are still logged as they happen.
+    Quiet,
+}
+
+/// Rows read and cached so far by a `ResultCachingIterator`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct PopulateCounts {
+    read: usize,
+    cached: usize,
 }
 
-impl<I, U, C> Iterator for ResultCachingIterator<I, U, C>
+impl<I, U, C, X> Iterator for ResultCachingIterator<I, U, C, X>
 where
-    I: Iterator<Item = QueryResult<(U, String)>>,
+    I: Iterator<Item = QueryResult<(U, SelectedCacheKey)>>,
     C: CacheHandle,
     U: Serialize + DeserializeOwned + std::fmt::Debug,
+    X: CacheCodec<U>,
 {
     type Item = QueryResult<U>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let item = self.inner.next();
-        if let Some(ref it_res) = item {
-            debug!("Item result is {:?}", it_res);
-            if let Ok(it) = it_res {
-                let res = self.cache.put::<U>(&it.1, &it.0);
-                if let Err(e) = res {
-                    warn!("Error caching value for key {}: {}", it.1, e);
-                } else {
+        let span = self.span.clone();
+        span.in_scope(|| {
+            let item = self.inner.next();
+            if let Some(ref it_res) = item {
+                if self.verbosity == LogVerbosity::PerRow {
+                    debug!("Item result is {:?}", it_res);
+                }
+                if let (Ok(_), Some(counts)) = (it_res, &mut self.counts) {
+                    counts.read += 1;
+                }
+                match it_res {
+                    Ok((row, SelectedCacheKey(Some(key)))) => self.cache_row(row, key),
+                    Ok((row, SelectedCacheKey(None))) => {
+                        warn!("Cache key of row {:?} is NULL, not caching it", row);
+                    }
+                    Err(_) => {}
+                }
+            } else if let Some(counts) = self.counts.take() {
+                debug!("Cached {} of {} rows read", counts.cached, counts.read);
+            }
+            item.map(|r| r.map(|pair| pair.0))
+        })
+    }
+
+    /// Exactly one row per row of the inner query.
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+/// The inner query is fused, so no row is cached after the end.
+impl<I, U, C, X> FusedIterator for ResultCachingIterator<I, U, C, X>
+where
+    I: Iterator<Item = QueryResult<(U, SelectedCacheKey)>>,
+    C: CacheHandle,
+    U: Serialize + DeserializeOwned + std::fmt::Debug,
+    X: CacheCodec<U>,
+{
+}
+
+impl<I, U, C, X> ResultCachingIterator<I, U, C, X>
+where
+    I: Iterator<Item = QueryResult<(U, SelectedCacheKey)>>,
+    C: CacheHandle,
+    U: Serialize + DeserializeOwned,
+    X: CacheCodec<U>,
+{
+    fn cache_row(&mut self, row: &U, key: &String) {
+        let ttl = match (self.ttl, &mut self.jitter) {
+            (Some(ttl), Some(jitter)) => Some(jitter.apply(ttl)),
+            (ttl, _) => ttl,
+        };
+        let res = instrumentation::cache_op("put", key, || {
+            let encoded = self.codec.encode(row)?;
+            self.cache.put_read_at(key, encoded.borrow(), ttl, self.read_at)
+        });
+        match res {
+            Ok(false) => {
+                debug!("Key {} was invalidated after the query started, not caching it", key);
+            }
+            Ok(true) => {
+                if self.verbosity == LogVerbosity::PerRow {
                     debug!("Item cached");
                 }
+                if let Some(counts) = &mut self.counts {
+                    counts.cached += 1;
+                }
+                self.record(CacheStats::record_populated);
             }
+            Err(e) => {
+                instrumentation::write_failed(key, &e);
+                if let Some(stats) = &self.stats {
+                    stats.record_write_error(&e);
+                }
+            }
+        }
+    }
+
+    fn record(&self, f: fn(&CacheStats)) {
+        if let Some(stats) = &self.stats {
+            f(stats);
+        }
+    }
+}
+
+/// Random offset added to the TTL of each row cached by a
+/// `SelectCachingWrapper`, so rows loaded together do not all expire at once.
+#[derive(Clone, Debug)]
+struct TtlJitter {
+    max: Duration,
+    rng: StdRng,
+}
+
+impl TtlJitter {
+    fn new(max: Duration, seed: Option<u64>) -> Self {
+        let rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_os_rng(),
+        };
+        TtlJitter { max, rng }
+    }
+
+    /// Returns `base` plus a uniformly drawn offset of at most `max`.
+    fn apply(&mut self, base: Duration) -> Duration {
+        let max_nanos = u64::try_from(self.max.as_nanos()).unwrap_or(u64::MAX);
+        base.saturating_add(Duration::from_nanos(self.rng.random_range(0..=max_nanos)))
+    }
+}
+
+/// Reserved value stored by `try_from_cache_negative` to remember that the
+/// database returned no row for a key.
+const NOT_FOUND_SENTINEL: &str = "\u{0}turbodiesel:not-found";
+
+/// Marker that (de)serializes only as `NOT_FOUND_SENTINEL`.
+struct NotFound;
+
+impl Serialize for NotFound {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(NOT_FOUND_SENTINEL)
+    }
+}
+
+impl<'de> Deserialize<'de> for NotFound {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        if value == NOT_FOUND_SENTINEL {
+            Ok(NotFound)
+        } else {
+            Err(de::Error::custom("not a not-found marker"))
+        }
+    }
+}
+
+/// Cache entry read by negative lookups: either the not-found marker or a row
+/// stored exactly as `put` would store it, so both kinds of entries can share
+/// a key. Telling them apart requires a self-describing serializer such as
+/// JSON or MessagePack.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum NegativeCacheEntry<U> {
+    NotFound(NotFound),
+    Row(U),
+}
+
+/// Outcome of the up-front cache lookup for one key.
+enum CachedValue<U> {
+    Hit(U),
+    Miss,
+    NotFound,
+}
+
+/// Looks up all keys with `get_multi`, falling back to one `get` per key if
+/// the batch fails.
+fn lookup_all<T, C>(cache: &C, keys: &[String]) -> Vec<Result<Option<T>, CacheError>>
+where
+    T: Serialize + DeserializeOwned,
+    C: CacheHandle,
+{
+    match instrumentation::cache_batch_op("get_multi", keys.len(), || cache.get_multi::<T>(keys)) {
+        Ok(values) => values.into_iter().map(Ok).collect(),
+        Err(e) => {
+            // Retry key by key so that a single failing key doesn't turn
+            // every other lookup into a database read.
+            warn!("Error retrieving keys from cache in batch; error {}", e);
+            keys.iter()
+                .map(|key| instrumentation::cache_op("get", key, || cache.get::<T>(key)))
+                .collect()
         }
-        item.map(|r| r.map(|pair| pair.0))
     }
 }
 
 /// Iterator that attempts to look up each row from the cache first,
 /// falling back to the database if missing, with optional population.
 ///
-/// Used internally by `try_from_cache`, `try_from_cache_multi`, and
-/// `try_from_cache_and_populate`.
-pub struct ResultCacheLookupIterator<I, U, C, K>
+/// All keys are fetched from the cache up front with a single `get_multi`
+/// call, so only the misses fall through to the inner query.
+///
+/// Used internally by `try_from_cache`, `try_from_cache_and_populate`,
+/// `try_from_cache_negative`, and `try_from_cache_once`. Each miss is filled with the next row of the
+/// inner query, which is only correct for a single key; see
+/// `ResultCacheMultiLookupIterator` for the multi-key case.
+pub struct ResultCacheLookupIterator<I, U, C, X = SerdeCodec>
 where
     I: Iterator<Item = QueryResult<U>>,
     C: CacheHandle,
     U: Serialize + DeserializeOwned,
-    K: Iterator<Item = String>,
 {
     inner: I,
-    keys: K,
+    keys: std::vec::IntoIter<String>,
+    cached: std::vec::IntoIter<Result<CachedValue<U>, CacheError>>,
     cache: C,
+    codec: X,
     populate: bool,
+    negative_ttl: Option<Duration>,
+    stats: Option<Arc<CacheStats>>,
+    span: Span,
+    evict_on_hit: bool,
 }
 
-impl<I, U, C, K> ResultCacheLookupIterator<I, U, C, K>
+impl<I, U, C, X> ResultCacheLookupIterator<I, U, C, X>
 where
     I: Iterator<Item = QueryResult<U>>,
     C: CacheHandle,
     U: Serialize + DeserializeOwned,
-    K: Iterator<Item = String>,
+    X: CacheCodec<U>,
 {
-    fn new(inner: I, cache: C, keys: K, populate: bool) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    fn new<K>(
+        inner: I,
+        cache: C,
+        codec: X,
+        keys: K,
+        populate: bool,
+        negative_ttl: Option<Duration>,
+        stats: Option<Arc<CacheStats>>,
+        span: Span,
+    ) -> Self
+    where
+        K: Iterator<Item = String>,
+    {
+        let keys = keys.collect::<Vec<_>>();
+        let cached = if negative_ttl.is_some() {
+            lookup_all::<NegativeCacheEntry<X::Cached>, C>(&cache, &keys)
+                .into_iter()
+                .map(|res| {
+                    res.and_then(|entry| match entry {
+                        Some(NegativeCacheEntry::Row(val)) => {
+                            codec.decode(val).map(CachedValue::Hit)
+                        }
+                        Some(NegativeCacheEntry::NotFound(_)) => Ok(CachedValue::NotFound),
+                        None => Ok(CachedValue::Miss),
+                    })
+                })
+                .collect::<Vec<_>>()
+        } else {
+            lookup_all::<X::Cached, C>(&cache, &keys)
+                .into_iter()
+                .map(|res| {
+                    res.and_then(|val| match val {
+                        Some(val) => codec.decode(val).map(CachedValue::Hit),
+                        None => Ok(CachedValue::Miss),
+                    })
+                })
+                .collect::<Vec<_>>()
+        };
         Self {
             inner,
-            keys,
+            keys: keys.into_iter(),
+            cached: cached.into_iter(),
             cache,
+            codec,
             populate,
+            negative_ttl,
+            stats,
+            span,
+            evict_on_hit: false,
+        }
+    }
+
+    /// Deletes each key after a cache hit, so a cached value is read at most
+    /// once.
+    fn evicting_hits(mut self) -> Self {
+        self.evict_on_hit = true;
+        self
+    }
+
+    fn evict(&mut self, key: &String) {
+        if let Err(e) = instrumentation::cache_op("delete", key, || self.cache.delete(key)) {
+            warn!("Error evicting key {} after read: {}", key, e);
+            self.record(CacheStats::record_error);
+        }
+    }
+
+    fn record(&self, f: fn(&CacheStats)) {
+        if let Some(stats) = &self.stats {
+            f(stats);
         }
     }
 
@@ -84,72 +413,322 @@ where
         match self.inner.next() {
             Some(Ok(val)) => {
                 if self.populate {
-                    let res = self.cache.put::<U>(key, &val);
-                    if let Err(e) = res {
-                        warn!("Error caching value for key {}: {}", key, e);
+                    let res = instrumentation::cache_op("put", key, || {
+                        let encoded = self.codec.encode(&val)?;
+                        self.cache.put(key, encoded.borrow())
+                    });
+                    match res {
+                        Ok(()) => self.record(CacheStats::record_populated),
+                        Err(e) => {
+                            instrumentation::write_failed(key, &e);
+                            if let Some(stats) = &self.stats {
+                                stats.record_write_error(&e);
+                            }
+                        }
                     }
                 }
                 Some(Ok(val))
             }
             Some(Err(e)) => Some(Err(e)),
-            None => None,
+            None => {
+                if let Some(ttl) = self.negative_ttl {
+                    debug!("No row found for key: {}, caching not-found marker", key);
+                    let res = instrumentation::cache_op("put_with_ttl", key, || {
+                        self.cache.put_with_ttl::<NegativeCacheEntry<X::Cached>>(
+                            key,
+                            &NegativeCacheEntry::NotFound(NotFound),
+                            ttl,
+                        )
+                    });
+                    if let Err(e) = res {
+                        instrumentation::write_failed(key, &e);
+                        self.record(CacheStats::record_error);
+                    }
+                }
+                // The inner query has run out; end here for good rather than
+                // resume with the next key.
+                self.keys = Vec::new().into_iter();
+                None
+            }
         }
     }
 }
 
-impl<I, U, C, K> Iterator for ResultCacheLookupIterator<I, U, C, K>
+impl<I, U, C, X> Iterator for ResultCacheLookupIterator<I, U, C, X>
 where
     I: Iterator<Item = QueryResult<U>>,
     C: CacheHandle,
     U: Serialize + DeserializeOwned + std::fmt::Debug,
-    K: Iterator<Item = String>,
+    X: CacheCodec<U>,
 {
     type Item = QueryResult<U>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let key = self.keys.next()?;
-        match self.cache.get::<U>(&key) {
-            Ok(Some(cached_val)) => {
-                debug!("Cache hit for key: {}", key);
-                Some(Ok(cached_val))
-            }
-            Ok(None) => {
-                debug!("Cache miss for key: {}, reading from inner", key);
-                self.call_inner_and_cache(&key)
+        let span = self.span.clone();
+        span.in_scope(|| {
+            loop {
+                let key = self.keys.next()?;
+                return match self.cached.next() {
+                    Some(Ok(CachedValue::Hit(cached_val))) => {
+                        instrumentation::cache_hit(&key);
+                        self.record(CacheStats::record_hit);
+                        if self.evict_on_hit {
+                            self.evict(&key);
+                        }
+                        Some(Ok(cached_val))
+                    }
+                    Some(Ok(CachedValue::NotFound)) => {
+                        // The row is known not to exist; yield nothing for this key.
+                        instrumentation::cache_not_found(&key);
+                        self.record(CacheStats::record_hit);
+                        continue;
+                    }
+                    Some(Ok(CachedValue::Miss)) | None => {
+                        instrumentation::cache_miss(&key);
+                        self.record(CacheStats::record_miss);
+                        self.call_inner_and_cache(&key)
+                    }
+                    Some(Err(e)) => {
+                        instrumentation::lookup_failed(&key, &e);
+                        self.record(CacheStats::record_error);
+                        self.call_inner_and_cache(&key)
+                    }
+                };
             }
-            Err(e) => {
-                warn!("Error retrieving from cache for key: {}; error {}", key, e);
-                self.call_inner_and_cache(&key);
-                None
+        })
+    }
+
+    /// At most one row per remaining key: a key known not to exist yields
+    /// none, and a miss the inner query has no row for ends the iteration.
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.keys.len()))
+    }
+}
+
+impl<I, U, C, X> FusedIterator for ResultCacheLookupIterator<I, U, C, X>
+where
+    I: Iterator<Item = QueryResult<U>>,
+    C: CacheHandle,
+    U: Serialize + DeserializeOwned + std::fmt::Debug,
+    X: CacheCodec<U>,
+{
+}
+
+/// Iterator that looks up many keys in the cache first, filling the misses
+/// from rows of the inner query matched up by their cache key.
+///
+/// The inner query selects `(row, cache key)` pairs, as for `populate_cache`.
+/// On the first miss it is read to the end and its rows are indexed by key,
+/// so the database may return them in any order. Inner rows for keys that
+/// were cache hits are discarded, and a missed key without a row yields
+/// nothing.
+///
+/// Used internally by `try_from_cache_multi`.
+pub struct ResultCacheMultiLookupIterator<I, U>
+where
+    I: Iterator<Item = QueryResult<(U, String)>>,
+    U: Serialize + DeserializeOwned,
+{
+    inner: Option<I>,
+    fetched: HashMap<String, U>,
+    keys: std::vec::IntoIter<String>,
+    cached: std::vec::IntoIter<Result<Option<U>, CacheError>>,
+    stats: Option<Arc<CacheStats>>,
+    span: Span,
+}
+
+impl<I, U> ResultCacheMultiLookupIterator<I, U>
+where
+    I: Iterator<Item = QueryResult<(U, String)>>,
+    U: Serialize + DeserializeOwned,
+{
+    fn new<C, K>(inner: I, cache: &C, keys: K, stats: Option<Arc<CacheStats>>, span: Span) -> Self
+    where
+        C: CacheHandle,
+        K: Iterator<Item = String>,
+    {
+        let keys = keys.collect::<Vec<_>>();
+        let cached = lookup_all::<U, C>(cache, &keys);
+        Self {
+            inner: Some(inner),
+            fetched: HashMap::new(),
+            keys: keys.into_iter(),
+            cached: cached.into_iter(),
+            stats,
+            span,
+        }
+    }
+
+    fn record(&self, f: fn(&CacheStats)) {
+        if let Some(stats) = &self.stats {
+            f(stats);
+        }
+    }
+
+    /// Takes the row fetched for `key`, reading the whole inner query on the
+    /// first call.
+    fn fetch(&mut self, key: &str) -> Option<QueryResult<U>> {
+        if let Some(inner) = self.inner.take() {
+            for row in inner {
+                match row {
+                    Ok((val, row_key)) => {
+                        self.fetched.insert(row_key, val);
+                    }
+                    Err(e) => return Some(Err(e)),
+                }
             }
         }
+        self.fetched.remove(key).map(Ok)
+    }
+}
+
+impl<I, U> Iterator for ResultCacheMultiLookupIterator<I, U>
+where
+    I: Iterator<Item = QueryResult<(U, String)>>,
+    U: Serialize + DeserializeOwned + std::fmt::Debug,
+{
+    type Item = QueryResult<U>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let span = self.span.clone();
+        span.in_scope(|| {
+            loop {
+                let key = self.keys.next()?;
+                let fetched = match self.cached.next() {
+                    Some(Ok(Some(cached_val))) => {
+                        instrumentation::cache_hit(&key);
+                        self.record(CacheStats::record_hit);
+                        return Some(Ok(cached_val));
+                    }
+                    Some(Ok(None)) | None => {
+                        instrumentation::cache_miss(&key);
+                        self.record(CacheStats::record_miss);
+                        self.fetch(&key)
+                    }
+                    Some(Err(e)) => {
+                        instrumentation::lookup_failed(&key, &e);
+                        self.record(CacheStats::record_error);
+                        self.fetch(&key)
+                    }
+                };
+                if fetched.is_some() {
+                    return fetched;
+                }
+                debug!("No row found for key: {}", key);
+            }
+        })
     }
 }
 
 /// Wrapper for a Diesel select query that populates the cache as results are loaded.
 ///
-/// Returned by `populate_cache`.
-pub struct SelectCachingWrapper<T, C>
+/// Returned by `populate_cache` and `populate_cache_with_ttl`.
+pub struct SelectCachingWrapper<T, C, X = SerdeCodec>
 where
     C: CacheHandle,
 {
     inner_select: T,
     cache: C,
+    codec: X,
+    ttl: Option<Duration>,
+    jitter: Option<TtlJitter>,
+    stats: Option<Arc<CacheStats>>,
+    verbosity: LogVerbosity,
 }
 
 impl<T, C> SelectCachingWrapper<T, C>
 where
     C: CacheHandle,
 {
-    fn new(inner_select: T, cache: C) -> Self {
+    fn new(inner_select: T, cache: C, ttl: Option<Duration>) -> Self {
         Self {
             inner_select,
             cache,
+            codec: SerdeCodec,
+            ttl,
+            jitter: None,
+            stats: None,
+            verbosity: LogVerbosity::PerRow,
+        }
+    }
+}
+
+impl<T, C, X> SelectCachingWrapper<T, C, X>
+where
+    C: CacheHandle,
+{
+    /// Records the rows written to the cache and the failed writes into
+    /// `stats`. The rows are streamed lazily, so the counts are final only
+    /// once the results have been fully read.
+    pub fn with_stats(mut self, stats: Arc<CacheStats>) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    /// Stores each row through `codec` instead of its own serde impls.
+    pub fn with_codec<X2>(self, codec: X2) -> SelectCachingWrapper<T, C, X2> {
+        SelectCachingWrapper {
+            inner_select: self.inner_select,
+            cache: self.cache,
+            codec,
+            ttl: self.ttl,
+            jitter: self.jitter,
+            stats: self.stats,
+            verbosity: self.verbosity,
         }
     }
+
+    /// Logs only the number of rows read and cached once the results have
+    /// been fully read, instead of a line per row. Same as
+    /// `with_log_verbosity(LogVerbosity::Quiet)`.
+    ///
+    /// ```ignore
+    /// let results = students::table
+    ///     .select(row_with_cache_key)
+    ///     .populate_cache::<Student>(handle.clone())
+    ///     .quiet()
+    ///     .load::<Student>(connection)?;
+    /// ```
+    pub fn quiet(self) -> Self {
+        self.with_log_verbosity(LogVerbosity::Quiet)
+    }
+
+    /// Sets how much is logged about the cached rows; see `LogVerbosity`.
+    pub fn with_log_verbosity(mut self, verbosity: LogVerbosity) -> Self {
+        self.verbosity = verbosity;
+        self
+    }
+
+    /// Caches each row for `base` plus a random offset of up to `jitter`,
+    /// drawn separately per row.
+    ///
+    /// Rows loaded together with a plain TTL all expire at the same moment,
+    /// and the reads that miss on them then hit the database at once.
+    /// Spreading the expiries over `jitter` avoids that stampede.
+    ///
+    /// ```ignore
+    /// let results = students::table
+    ///     .select(row_with_cache_key)
+    ///     .populate_cache::<Student>(handle.clone())
+    ///     .with_ttl_jitter(Duration::from_secs(600), Duration::from_secs(60))
+    ///     .load::<Student>(connection)?;
+    /// ```
+    pub fn with_ttl_jitter(mut self, base: Duration, jitter: Duration) -> Self {
+        self.ttl = Some(base);
+        self.jitter = Some(TtlJitter::new(jitter, None));
+        self
+    }
+
+    /// Same as `with_ttl_jitter`, but draws the offsets from a generator
+    /// seeded with `seed`, so tests get the same TTLs on every run.
+    pub fn with_seeded_ttl_jitter(mut self, base: Duration, jitter: Duration, seed: u64) -> Self {
+        self.ttl = Some(base);
+        self.jitter = Some(TtlJitter::new(jitter, Some(seed)));
+        self
+    }
 }
 
-impl<T, Conn, C> ExecuteDsl<Conn, Conn::Backend> for SelectCachingWrapper<T, C>
+impl<T, Conn, C, X> ExecuteDsl<Conn, Conn::Backend> for SelectCachingWrapper<T, C, X>
 where
     T: ExecuteDsl<Conn>,
     Conn: Connection,
@@ -160,301 +739,3794 @@ where
     }
 }
 
-impl<T, Conn, C> RunQueryDsl<Conn> for SelectCachingWrapper<T, C> where C: CacheHandle {}
+impl<T, Conn, C, X> RunQueryDsl<Conn> for SelectCachingWrapper<T, C, X> where C: CacheHandle {}
 
-impl<'query, T, Conn, U, B, C> LoadQuery<'query, Conn, U, B> for SelectCachingWrapper<T, C>
+/// Limits the inner query, so that `first` caches the one row it returns.
+impl<T, C, X> LimitDsl for SelectCachingWrapper<T, C, X>
 where
-    T: LoadQuery<'query, Conn, (U, String), B>,
+    T: LimitDsl,
+    C: CacheHandle,
+{
+    type Output = SelectCachingWrapper<T::Output, C, X>;
+
+    fn limit(self, limit: i64) -> Self::Output {
+        SelectCachingWrapper {
+            inner_select: self.inner_select.limit(limit),
+            cache: self.cache,
+            codec: self.codec,
+            ttl: self.ttl,
+            jitter: self.jitter,
+            stats: self.stats,
+            verbosity: self.verbosity,
+        }
+    }
+}
+
+impl<'query, T, Conn, U, B, C, X> LoadQuery<'query, Conn, U, B> for SelectCachingWrapper<T, C, X>
+where
+    T: LoadQuery<'query, Conn, (U, SelectedCacheKey), B>,
     Conn: 'query,
     U: Serialize + DeserializeOwned + std::fmt::Debug,
     C: CacheHandle,
+    X: CacheCodec<U>,
 {
     type RowIter<'a>
-        = ResultCachingIterator<T::RowIter<'a>, U, C>
+        = ResultCachingIterator<T::RowIter<'a>, U, C, X>
     where
         Conn: 'a;
 
     fn internal_load(self, conn: &mut Conn) -> QueryResult<Self::RowIter<'_>> {
-        debug!("In SelectCachingWrapper internal_load");
-
-        let load_iter = self.inner_select.internal_load(conn)?;
+        let span = instrumentation::query_span("SelectCachingWrapper");
+        let read_at = SystemTime::now();
+        let load_iter = span.in_scope(|| self.inner_select.internal_load(conn))?;
         let caching_iter = ResultCachingIterator {
-            inner: load_iter,
+            inner: load_iter.fuse(),
             cache: self.cache,
+            codec: self.codec,
+            ttl: self.ttl,
+            jitter: self.jitter,
+            stats: self.stats,
+            span,
+            verbosity: self.verbosity,
+            read_at,
+            counts: Some(PopulateCounts::default()),
         };
         Ok(caching_iter)
     }
 }
 
-/// Wrapper for a Diesel select query that attempts to read results from the cache
-/// before falling back to the database, optionally populating the cache on misses.
-///
-/// Returned by `try_from_cache`, `try_from_cache_multi`, and
-/// `try_from_cache_and_populate`.
-pub struct SelectCacheReadWrapper<T, C, K>
-where
-    C: CacheHandle,
-    K: Iterator<Item = String>,
-{
-    inner_select: T,
-    keys: K,
-    cache: C,
-    populate: bool,
-}
+/// TTL in seconds selected alongside each row for
+/// `populate_cache_with_row_ttl`, from a `BIGINT` expression. It is NULL for
+/// rows that should never expire.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SelectedTtl(pub Option<i64>);
 
-impl<T, C, K> SelectCacheReadWrapper<T, C, K>
+impl<DB> FromSql<BigInt, DB> for SelectedTtl
 where
-    C: CacheHandle,
-    K: Iterator<Item = String>,
+    DB: Backend,
+    i64: FromSql<BigInt, DB>,
 {
-    fn new(inner_select: T, keys: K, cache: C, populate: bool) -> Self {
-        Self {
-            inner_select,
-            keys,
-            cache,
-            populate,
+    fn from_sql(bytes: DB::RawValue<'_>) -> deserialize::Result<Self> {
+        i64::from_sql(bytes).map(|seconds| SelectedTtl(Some(seconds)))
+    }
+
+    fn from_nullable_sql(bytes: Option<DB::RawValue<'_>>) -> deserialize::Result<Self> {
+        match bytes {
+            Some(bytes) => <Self as FromSql<BigInt, DB>>::from_sql(bytes),
+            None => Ok(SelectedTtl(None)),
         }
     }
 }
 
-impl<T, Conn, C, K> ExecuteDsl<Conn, Conn::Backend> for SelectCacheReadWrapper<T, C, K>
+impl<DB> FromSql<Nullable<BigInt>, DB> for SelectedTtl
 where
-    T: ExecuteDsl<Conn>,
-    Conn: Connection,
-    C: CacheHandle,
-    K: Iterator<Item = String>,
+    DB: Backend,
+    i64: FromSql<BigInt, DB>,
 {
-    fn execute(query: Self, conn: &mut Conn) -> QueryResult<usize> {
-        ExecuteDsl::<Conn, Conn::Backend>::execute(query.inner_select, conn)
+    fn from_sql(bytes: DB::RawValue<'_>) -> deserialize::Result<Self> {
+        <Self as FromSql<BigInt, DB>>::from_sql(bytes)
+    }
+
+    fn from_nullable_sql(bytes: Option<DB::RawValue<'_>>) -> deserialize::Result<Self> {
+        <Self as FromSql<BigInt, DB>>::from_nullable_sql(bytes)
     }
 }
 
-impl<T, Conn, C, K> RunQueryDsl<Conn> for SelectCacheReadWrapper<T, C, K>
+impl<DB> Queryable<BigInt, DB> for SelectedTtl
 where
-    C: CacheHandle,
-    K: Iterator<Item = String>,
+    DB: Backend,
+    Self: FromSql<BigInt, DB>,
 {
+    type Row = Self;
+
+    fn build(row: Self) -> deserialize::Result<Self> {
+        Ok(row)
+    }
 }
 
-impl<'query, T, Conn, U, B, C, K> LoadQuery<'query, Conn, U, B> for SelectCacheReadWrapper<T, C, K>
+impl<DB> Queryable<Nullable<BigInt>, DB> for SelectedTtl
 where
-    T: LoadQuery<'query, Conn, U, B>,
-    Conn: 'query,
-    U: Serialize + DeserializeOwned + std::fmt::Debug,
-    C: CacheHandle,
-    K: Iterator<Item = String>,
+    DB: Backend,
+    Self: FromSql<Nullable<BigInt>, DB>,
 {
-    type RowIter<'a>
-        = ResultCacheLookupIterator<T::RowIter<'a>, U, C, K>
-    where
-        Conn: 'a;
-
-    fn internal_load(self, conn: &mut Conn) -> QueryResult<Self::RowIter<'_>> {
-        debug!("In SelectCacheReadWrapper internal_load");
+    type Row = Self;
 
-        let load_iter = self.inner_select.internal_load(conn)?;
-        let lookup_iter =
-            ResultCacheLookupIterator::new(load_iter, self.cache, self.keys, self.populate);
-        Ok(lookup_iter)
+    fn build(row: Self) -> deserialize::Result<Self> {
+        Ok(row)
     }
 }
 
-/// Wrapper for a Diesel update statement that invalidates specified cache keys
-/// after a successful database update.
+/// Iterator that populates the cache as rows are streamed from a query,
+/// caching each row for the TTL selected with it.
 ///
-/// Returned by `invalidate_key` and `invalidate_keys`.
-pub struct UpdateWrapper<T, K, C>
+/// Used internally by `populate_cache_with_row_ttl`. Rows whose key is NULL
+/// are passed through without being cached, as are rows whose TTL is zero
+/// or negative, since they would expire right away. Rows are stamped with
+/// the time the query started, as for `populate_cache`.
+pub struct ResultRowTtlCachingIterator<I, U, C>
 where
-    K: Iterator<Item = String>,
+    I: Iterator<Item = QueryResult<(U, SelectedCacheKey, SelectedTtl)>>,
     C: CacheHandle,
 {
-    inner_update: T,
-    keys: K,
+    inner: I,
     cache: C,
+    stats: Option<Arc<CacheStats>>,
+    span: Span,
+    /// When the inner query started.
+    read_at: SystemTime,
 }
 
-impl<T, K, C> UpdateWrapper<T, K, C>
+impl<I, U, C> Iterator for ResultRowTtlCachingIterator<I, U, C>
 where
-    K: Iterator<Item = String>,
+    I: Iterator<Item = QueryResult<(U, SelectedCacheKey, SelectedTtl)>>,
     C: CacheHandle,
+    U: Serialize + DeserializeOwned + std::fmt::Debug,
 {
-    fn new(inner_update: T, keys: K, cache: C) -> Self {
-        Self {
-            inner_update,
-            keys,
-            cache,
+    type Item = QueryResult<U>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let span = self.span.clone();
+        span.in_scope(|| {
+            let item = self.inner.next();
+            match &item {
+                Some(Ok((row, SelectedCacheKey(Some(key)), SelectedTtl(ttl)))) => {
+                    self.cache_row(row, key, *ttl)
+                }
+                Some(Ok((row, SelectedCacheKey(None), _))) => {
+                    warn!("Cache key of row {:?} is NULL, not caching it", row);
+                }
+                _ => {}
+            }
+            item.map(|r| r.map(|(row, _, _)| row))
+        })
+    }
+}
+
+impl<I, U, C> ResultRowTtlCachingIterator<I, U, C>
+where
+    I: Iterator<Item = QueryResult<(U, SelectedCacheKey, SelectedTtl)>>,
+    C: CacheHandle,
+    U: Serialize + DeserializeOwned,
+{
+    fn cache_row(&mut self, row: &U, key: &String, ttl_seconds: Option<i64>) {
+        let ttl = match ttl_seconds {
+            Some(seconds) if seconds <= 0 => {
+                debug!("TTL of key {} is {} seconds, not caching it", key, seconds);
+                return;
+            }
+            Some(seconds) => Some(Duration::from_secs(seconds as u64)),
+            None => None,
+        };
+        let res = instrumentation::cache_op("put", key, || {
+            self.cache.put_read_at(key, row, ttl, self.read_at)
+        });
+        match res {
+            Ok(false) => {
+                debug!("Key {} was invalidated after the query started, not caching it", key);
+            }
+            Ok(true) => {
+                if let Some(stats) = &self.stats {
+                    stats.record_populated();
+                }
+            }
+            Err(e) => {
+                instrumentation::write_failed(key, &e);
+                if let Some(stats) = &self.stats {
+                    stats.record_write_error(&e);
+                }
+            }
         }
     }
 }
 
-impl<T, Conn, K, C> ExecuteDsl<Conn, Conn::Backend> for UpdateWrapper<T, K, C>
+/// Wrapper for a Diesel select of `(row, cache key, TTL)` that populates the
+/// cache with each row for its own TTL.
+///
+/// Returned by `populate_cache_with_row_ttl`.
+pub struct SelectRowTtlCachingWrapper<T, C>
+where
+    C: CacheHandle,
+{
+    inner_select: T,
+    cache: C,
+    stats: Option<Arc<CacheStats>>,
+}
+
+impl<T, C> SelectRowTtlCachingWrapper<T, C>
+where
+    C: CacheHandle,
+{
+    /// Records the rows written to the cache and the failed writes into
+    /// `stats`, as for `populate_cache`.
+    pub fn with_stats(mut self, stats: Arc<CacheStats>) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+}
+
+impl<T, Conn, C> ExecuteDsl<Conn, Conn::Backend> for SelectRowTtlCachingWrapper<T, C>
 where
     T: ExecuteDsl<Conn>,
     Conn: Connection,
-    K: Iterator<Item = String>,
     C: CacheHandle,
 {
     fn execute(query: Self, conn: &mut Conn) -> QueryResult<usize> {
-        for key in query.keys {
-            debug!("Invalidating cache for key: {}", key);
-            if let Err(e) = query.cache.clone().delete(&key) {
-                error!("Error deleting key {} from cache: {}", key, e);
-                return Err(diesel::result::Error::RollbackTransaction);
+        ExecuteDsl::<Conn, Conn::Backend>::execute(query.inner_select, conn)
+    }
+}
+
+impl<T, Conn, C> RunQueryDsl<Conn> for SelectRowTtlCachingWrapper<T, C> where C: CacheHandle {}
+
+impl<'query, T, Conn, U, B, C> LoadQuery<'query, Conn, U, B> for SelectRowTtlCachingWrapper<T, C>
+where
+    T: LoadQuery<'query, Conn, (U, SelectedCacheKey, SelectedTtl), B>,
+    Conn: 'query,
+    U: Serialize + DeserializeOwned + std::fmt::Debug,
+    C: CacheHandle,
+{
+    type RowIter<'a>
+        = ResultRowTtlCachingIterator<T::RowIter<'a>, U, C>
+    where
+        Conn: 'a;
+
+    fn internal_load(self, conn: &mut Conn) -> QueryResult<Self::RowIter<'_>> {
+        let span = instrumentation::query_span("SelectRowTtlCachingWrapper");
+        let read_at = SystemTime::now();
+        let load_iter = span.in_scope(|| self.inner_select.internal_load(conn))?;
+        Ok(ResultRowTtlCachingIterator {
+            inner: load_iter,
+            cache: self.cache,
+            stats: self.stats,
+            span,
+            read_at,
+        })
+    }
+}
+
+/// Iterator that populates the cache in batches as rows are streamed from a
+/// query.
+///
+/// Used internally by `populate_cache_batched`. Rows are yielded as soon as
+/// they are read and buffered until `batch_size` of them can be written with
+/// a single `put_multi_read_at` call, stamped with the time the query
+/// started; the last partial batch is written once the inner query is
+/// exhausted. A failed write is returned as an
+/// `Error::SerializationError` wrapping the `CacheError` from the following
+/// call to `next`, unless `set_best_effort` is on, in which case it is only
+/// logged. Rows still buffered when the iterator is dropped early,
+/// e.g. after `first` or a `load_iter` that is not read to the end, are
+/// written on drop, where a failure can only be logged.
+pub struct BatchCachingIterator<I, U, C>
+where
+    I: Iterator<Item = QueryResult<(U, SelectedCacheKey)>>,
+    C: CacheHandle,
+    U: Serialize + DeserializeOwned,
+{
+    inner: I,
+    cache: C,
+    batch_size: usize,
+    pending: Vec<(String, U)>,
+    failed: Option<CacheError>,
+    span: Span,
+    /// When the inner query started.
+    read_at: SystemTime,
+}
+
+impl<I, U, C> Iterator for BatchCachingIterator<I, U, C>
+where
+    I: Iterator<Item = QueryResult<(U, SelectedCacheKey)>>,
+    C: CacheHandle,
+    U: Serialize + DeserializeOwned + Clone + std::fmt::Debug,
+{
+    type Item = QueryResult<U>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let span = self.span.clone();
+        span.in_scope(|| {
+            if let Some(e) = self.failed.take() {
+                return Some(Err(batch_write_error(e)));
+            }
+            match self.inner.next() {
+                Some(Ok((row, SelectedCacheKey(Some(key))))) => {
+                    self.pending.push((key, row.clone()));
+                    if self.pending.len() >= self.batch_size {
+                        self.failed = self.flush_or_fail();
+                    }
+                    Some(Ok(row))
+                }
+                Some(Ok((row, SelectedCacheKey(None)))) => {
+                    warn!("Cache key of row {:?} is NULL, not caching it", row);
+                    Some(Ok(row))
+                }
+                Some(Err(e)) => Some(Err(e)),
+                None => self.flush_or_fail().map(|e| Err(batch_write_error(e))),
             }
+        })
+    }
+}
+
+impl<I, U, C> BatchCachingIterator<I, U, C>
+where
+    I: Iterator<Item = QueryResult<(U, SelectedCacheKey)>>,
+    C: CacheHandle,
+    U: Serialize + DeserializeOwned,
+{
+    /// Writes the buffered rows to the cache. They are dropped from the
+    /// buffer whether or not the write succeeds.
+    fn flush(&mut self) -> Result<(), CacheError> {
+        if self.pending.is_empty() {
+            return Ok(());
         }
-        ExecuteDsl::<Conn, Conn::Backend>::execute(query.inner_update, conn)
+        let batch = std::mem::take(&mut self.pending);
+        debug!("Flushing a batch of {} cached rows", batch.len());
+        let written = instrumentation::cache_batch_op("put_multi_read_at", batch.len(), || {
+            self.cache.put_multi_read_at(&batch, self.read_at)
+        })?;
+        if written < batch.len() {
+            debug!(
+                "{} of {} keys were invalidated after the query started, not caching them",
+                batch.len() - written,
+                batch.len()
+            );
+        }
+        Ok(())
+    }
+
+    /// Flushes the buffered rows, returning the error to fail the query with
+    /// if the write failed outside best-effort mode.
+    fn flush_or_fail(&mut self) -> Option<CacheError> {
+        let e = self.flush().err()?;
+        error!("Error writing a batch of cached rows: {}", e);
+        fail_unless_best_effort(false).err().map(|_| e)
     }
 }
 
-impl<T, Conn, K, C> RunQueryDsl<Conn> for UpdateWrapper<T, K, C>
+impl<I, U, C> Drop for BatchCachingIterator<I, U, C>
 where
-    K: Iterator<Item = String>,
+    I: Iterator<Item = QueryResult<(U, SelectedCacheKey)>>,
     C: CacheHandle,
+    U: Serialize + DeserializeOwned,
 {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            error!("Error writing the last batch of cached rows on drop: {}", e);
+        }
+    }
 }
 
-/// Provides extension methods for Diesel select statements that integrate caching behavior.
+fn batch_write_error(e: CacheError) -> diesel::result::Error {
+    diesel::result::Error::SerializationError(Box::new(e))
+}
+
+/// Wrapper for a Diesel select query that populates the cache with its
+/// results, a batch of rows at a time.
 ///
-/// This trait allows wrapping a Diesel select with cache population, cache lookup,
-/// and hybrid read-through patterns, seamlessly woven into Diesel’s query DSL.
+/// Returned by `populate_cache_batched`.
+pub struct SelectBatchCachingWrapper<T, C>
+where
+    C: CacheHandle,
+{
+    inner_select: T,
+    cache: C,
+    batch_size: usize,
+}
+
+impl<T, Conn, C> ExecuteDsl<Conn, Conn::Backend> for SelectBatchCachingWrapper<T, C>
+where
+    T: ExecuteDsl<Conn>,
+    Conn: Connection,
+    C: CacheHandle,
+{
+    fn execute(query: Self, conn: &mut Conn) -> QueryResult<usize> {
+        ExecuteDsl::<Conn, Conn::Backend>::execute(query.inner_select, conn)
+    }
+}
+
+impl<T, Conn, C> RunQueryDsl<Conn> for SelectBatchCachingWrapper<T, C> where C: CacheHandle {}
+
+/// Limits the inner query, so that `first` caches the one row it returns.
+impl<T, C> LimitDsl for SelectBatchCachingWrapper<T, C>
+where
+    T: LimitDsl,
+    C: CacheHandle,
+{
+    type Output = SelectBatchCachingWrapper<T::Output, C>;
+
+    fn limit(self, limit: i64) -> Self::Output {
+        SelectBatchCachingWrapper {
+            inner_select: self.inner_select.limit(limit),
+            cache: self.cache,
+            batch_size: self.batch_size,
+        }
+    }
+}
+
+impl<'query, T, Conn, U, B, C> LoadQuery<'query, Conn, U, B> for SelectBatchCachingWrapper<T, C>
+where
+    T: LoadQuery<'query, Conn, (U, SelectedCacheKey), B>,
+    Conn: 'query,
+    U: Serialize + DeserializeOwned + Clone + std::fmt::Debug,
+    C: CacheHandle,
+{
+    type RowIter<'a>
+        = BatchCachingIterator<T::RowIter<'a>, U, C>
+    where
+        Conn: 'a;
+
+    fn internal_load(self, conn: &mut Conn) -> QueryResult<Self::RowIter<'_>> {
+        let span = instrumentation::query_span("SelectBatchCachingWrapper");
+        let read_at = SystemTime::now();
+        let load_iter = span.in_scope(|| self.inner_select.internal_load(conn))?;
+        Ok(BatchCachingIterator {
+            inner: load_iter,
+            cache: self.cache,
+            batch_size: self.batch_size.max(1),
+            pending: Vec::with_capacity(self.batch_size.clamp(1, 1024)),
+            failed: None,
+            span,
+            read_at,
+        })
+    }
+}
+
+/// Query that loads `(row, id)` pairs and turns each id into the row's cache
+/// key, for `SelectCachingWrapper`.
+///
+/// Returned inside the wrapper by `populate_cache_by_ids`.
+pub struct IdKeyedQuery<T, Id, F> {
+    inner_select: T,
+    expected_ids: Vec<Id>,
+    key_fn: F,
+}
+
+impl<T, Conn, Id, F> RunQueryDsl<Conn> for IdKeyedQuery<T, Id, F> {}
+
+impl<'query, T, Conn, U, B, Id, F> LoadQuery<'query, Conn, (U, SelectedCacheKey), B>
+    for IdKeyedQuery<T, Id, F>
+where
+    T: LoadQuery<'query, Conn, (U, Id), B>,
+    Conn: 'query,
+    Id: Eq + Hash + std::fmt::Debug,
+    F: Fn(&Id) -> String,
+{
+    type RowIter<'a>
+        = IdKeyIterator<T::RowIter<'a>, Id, F>
+    where
+        Conn: 'a;
+
+    fn internal_load(self, conn: &mut Conn) -> QueryResult<Self::RowIter<'_>> {
+        Ok(IdKeyIterator {
+            inner: self.inner_select.internal_load(conn)?,
+            missing_ids: self.expected_ids.into_iter().collect(),
+            key_fn: self.key_fn,
+        })
+    }
+}
+
+/// Iterator that maps the id of each row to its cache key and, once
+/// exhausted, warns about the expected ids that returned no row.
+pub struct IdKeyIterator<I, Id, F> {
+    inner: I,
+    missing_ids: HashSet<Id>,
+    key_fn: F,
+}
+
+impl<I, U, Id, F> Iterator for IdKeyIterator<I, Id, F>
+where
+    I: Iterator<Item = QueryResult<(U, Id)>>,
+    Id: Eq + Hash + std::fmt::Debug,
+    F: Fn(&Id) -> String,
+{
+    type Item = QueryResult<(U, SelectedCacheKey)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.inner.next() {
+            Some(Ok((row, id))) => {
+                let key = (self.key_fn)(&id);
+                self.missing_ids.remove(&id);
+                Some(Ok((row, SelectedCacheKey(Some(key)))))
+            }
+            Some(Err(e)) => Some(Err(e)),
+            None => {
+                if !self.missing_ids.is_empty() {
+                    warn!(
+                        "No row found to cache for ids: {:?}",
+                        self.missing_ids.drain().collect::<Vec<_>>()
+                    );
+                }
+                None
+            }
+        }
+    }
+}
+
+/// Query that loads rows and derives each row's cache key from the row
+/// itself, for `SelectCachingWrapper`.
+///
+/// Returned inside the wrapper by `populate_cache_with_key_fn`.
+pub struct RowKeyedQuery<T, F> {
+    inner_select: T,
+    key_fn: F,
+}
+
+impl<T, Conn, F> RunQueryDsl<Conn> for RowKeyedQuery<T, F> {}
+
+impl<'query, T, Conn, U, B, F> LoadQuery<'query, Conn, (U, SelectedCacheKey), B>
+    for RowKeyedQuery<T, F>
+where
+    T: LoadQuery<'query, Conn, U, B>,
+    Conn: 'query,
+    F: KeyExtractor<U>,
+{
+    type RowIter<'a>
+        = RowKeyIterator<T::RowIter<'a>, F>
+    where
+        Conn: 'a;
+
+    fn internal_load(self, conn: &mut Conn) -> QueryResult<Self::RowIter<'_>> {
+        Ok(RowKeyIterator {
+            inner: self.inner_select.internal_load(conn)?,
+            key_fn: self.key_fn,
+        })
+    }
+}
+
+/// Iterator that pairs each row with the cache key computed from it.
+pub struct RowKeyIterator<I, F> {
+    inner: I,
+    key_fn: F,
+}
+
+impl<I, U, F> Iterator for RowKeyIterator<I, F>
+where
+    I: Iterator<Item = QueryResult<U>>,
+    F: KeyExtractor<U>,
+{
+    type Item = QueryResult<(U, SelectedCacheKey)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|res| {
+            res.map(|row| {
+                let key = self.key_fn.extract_key(&row);
+                (row, SelectedCacheKey(Some(key)))
+            })
+        })
+    }
+}
+
+/// Wrapper for a Diesel select query that attempts to read results from the cache
+/// before falling back to the database, optionally populating the cache on misses.
 ///
-/// Implemented for all Diesel select queries.
-pub trait WrappableQuery {
-    type Cache: CacheHandle;
+/// Returned by `try_from_cache`, `try_from_cache_and_populate`,
+/// `try_from_cache_negative`, `try_from_cache_with_stats`, and
+/// `try_from_cache_once`.
+pub struct SelectCacheReadWrapper<T, C, K, X = SerdeCodec>
+where
+    C: CacheHandle,
+    K: Iterator<Item = String>,
+{
+    inner_select: T,
+    keys: K,
+    cache: C,
+    codec: X,
+    populate: bool,
+    negative_ttl: Option<Duration>,
+    stats: Option<Arc<CacheStats>>,
+    evict_on_hit: bool,
+}
+
+impl<T, C, K> SelectCacheReadWrapper<T, C, K>
+where
+    C: CacheHandle,
+    K: Iterator<Item = String>,
+{
+    fn new(inner_select: T, keys: K, cache: C, populate: bool) -> Self {
+        Self {
+            inner_select,
+            keys,
+            cache,
+            codec: SerdeCodec,
+            populate,
+            negative_ttl: None,
+            stats: None,
+            evict_on_hit: false,
+        }
+    }
+}
+
+impl<T, C, K, X> SelectCacheReadWrapper<T, C, K, X>
+where
+    C: CacheHandle,
+    K: Iterator<Item = String>,
+{
+    fn evicting_hits(mut self) -> Self {
+        self.evict_on_hit = true;
+        self
+    }
+
+    fn with_negative_ttl(mut self, negative_ttl: Duration) -> Self {
+        self.negative_ttl = Some(negative_ttl);
+        self
+    }
+
+    /// Records cache hits, misses and errors into `stats` as rows stream through.
+    pub fn with_stats(mut self, stats: Arc<CacheStats>) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    /// Reads and populates cached rows through `codec` instead of their own
+    /// serde impls. A cached value that `codec` fails to decode is treated
+    /// like a failed lookup and read from the database.
+    pub fn with_codec<X2>(self, codec: X2) -> SelectCacheReadWrapper<T, C, K, X2> {
+        SelectCacheReadWrapper {
+            inner_select: self.inner_select,
+            keys: self.keys,
+            cache: self.cache,
+            codec,
+            populate: self.populate,
+            negative_ttl: self.negative_ttl,
+            stats: self.stats,
+            evict_on_hit: self.evict_on_hit,
+        }
+    }
+}
+
+impl<T, Conn, C, K, X> ExecuteDsl<Conn, Conn::Backend> for SelectCacheReadWrapper<T, C, K, X>
+where
+    T: ExecuteDsl<Conn>,
+    Conn: Connection,
+    C: CacheHandle,
+    K: Iterator<Item = String>,
+{
+    fn execute(query: Self, conn: &mut Conn) -> QueryResult<usize> {
+        ExecuteDsl::<Conn, Conn::Backend>::execute(query.inner_select, conn)
+    }
+}
+
+impl<T, Conn, C, K, X> RunQueryDsl<Conn> for SelectCacheReadWrapper<T, C, K, X>
+where
+    C: CacheHandle,
+    K: Iterator<Item = String>,
+{
+}
+
+/// Limits both the keys and the inner query, so that `first` looks up only
+/// the first key and yields a cached value for it when there is one.
+impl<T, C, K, X> LimitDsl for SelectCacheReadWrapper<T, C, K, X>
+where
+    T: LimitDsl,
+    C: CacheHandle,
+    K: Iterator<Item = String>,
+{
+    type Output = SelectCacheReadWrapper<T::Output, C, std::iter::Take<K>, X>;
+
+    fn limit(self, limit: i64) -> Self::Output {
+        SelectCacheReadWrapper {
+            inner_select: self.inner_select.limit(limit),
+            keys: self.keys.take(usize::try_from(limit).unwrap_or(0)),
+            cache: self.cache,
+            codec: self.codec,
+            populate: self.populate,
+            negative_ttl: self.negative_ttl,
+            stats: self.stats,
+            evict_on_hit: self.evict_on_hit,
+        }
+    }
+}
+
+impl<'query, T, Conn, U, B, C, K, X> LoadQuery<'query, Conn, U, B>
+    for SelectCacheReadWrapper<T, C, K, X>
+where
+    T: LoadQuery<'query, Conn, U, B>,
+    Conn: 'query,
+    U: Serialize + DeserializeOwned + std::fmt::Debug,
+    C: CacheHandle,
+    K: Iterator<Item = String>,
+    X: CacheCodec<U>,
+{
+    type RowIter<'a>
+        = ResultCacheLookupIterator<T::RowIter<'a>, U, C, X>
+    where
+        Conn: 'a;
+
+    fn internal_load(self, conn: &mut Conn) -> QueryResult<Self::RowIter<'_>> {
+        let span = instrumentation::query_span("SelectCacheReadWrapper");
+        let load_iter = span.in_scope(|| self.inner_select.internal_load(conn))?;
+        let lookup_iter = span.clone().in_scope(|| {
+            ResultCacheLookupIterator::new(
+                load_iter,
+                self.cache,
+                self.codec,
+                self.keys,
+                self.populate,
+                self.negative_ttl,
+                self.stats,
+                span,
+            )
+        });
+        if self.evict_on_hit {
+            return Ok(lookup_iter.evicting_hits());
+        }
+        Ok(lookup_iter)
+    }
+}
+
+/// Wrapper for a Diesel select query that reads a single row from the cache
+/// under a key derived from the query itself, populating it on a miss.
+///
+/// Returned by `try_from_cache_auto`. The key is computed with
+/// `query_cache_key` for the connection's backend when the query runs.
+pub struct SelectCacheAutoKeyWrapper<T, C>
+where
+    C: CacheHandle,
+{
+    inner_select: T,
+    cache: C,
+}
+
+impl<T, Conn, C> ExecuteDsl<Conn, Conn::Backend> for SelectCacheAutoKeyWrapper<T, C>
+where
+    T: ExecuteDsl<Conn>,
+    Conn: Connection,
+    C: CacheHandle,
+{
+    fn execute(query: Self, conn: &mut Conn) -> QueryResult<usize> {
+        ExecuteDsl::<Conn, Conn::Backend>::execute(query.inner_select, conn)
+    }
+}
+
+impl<T, Conn, C> RunQueryDsl<Conn> for SelectCacheAutoKeyWrapper<T, C> where C: CacheHandle {}
+
+/// Limits the inner query, which also changes the derived key.
+impl<T, C> LimitDsl for SelectCacheAutoKeyWrapper<T, C>
+where
+    T: LimitDsl,
+    C: CacheHandle,
+{
+    type Output = SelectCacheAutoKeyWrapper<T::Output, C>;
+
+    fn limit(self, limit: i64) -> Self::Output {
+        SelectCacheAutoKeyWrapper {
+            inner_select: self.inner_select.limit(limit),
+            cache: self.cache,
+        }
+    }
+}
+
+impl<'query, T, Conn, U, B, C> LoadQuery<'query, Conn, U, B> for SelectCacheAutoKeyWrapper<T, C>
+where
+    T: LoadQuery<'query, Conn, U, B> + QueryFragment<Conn::Backend>,
+    Conn: Connection + 'query,
+    Conn::Backend: Default,
+    <Conn::Backend as Backend>::QueryBuilder: Default,
+    U: Serialize + DeserializeOwned + std::fmt::Debug,
+    C: CacheHandle,
+{
+    type RowIter<'a>
+        = ResultCacheLookupIterator<T::RowIter<'a>, U, C>
+    where
+        Conn: 'a;
+
+    fn internal_load(self, conn: &mut Conn) -> QueryResult<Self::RowIter<'_>> {
+        let key = query_cache_key::<Conn::Backend, _>(&self.inner_select);
+        debug!("Derived cache key {} from query", key);
+        SelectCacheReadWrapper::new(self.inner_select, std::iter::once(key), self.cache, true)
+            .internal_load(conn)
+    }
+}
+
+/// Wrapper for a Diesel select query that reads many keys from the cache,
+/// falling back to the database for the misses.
+///
+/// Returned by `try_from_cache_multi`.
+pub struct SelectCacheMultiReadWrapper<T, C, K>
+where
+    C: CacheHandle,
+    K: Iterator<Item = String>,
+{
+    inner_select: T,
+    keys: K,
+    cache: C,
+    stats: Option<Arc<CacheStats>>,
+}
+
+impl<T, C, K> SelectCacheMultiReadWrapper<T, C, K>
+where
+    C: CacheHandle,
+    K: Iterator<Item = String>,
+{
+    fn new(inner_select: T, keys: K, cache: C) -> Self {
+        Self {
+            inner_select,
+            keys,
+            cache,
+            stats: None,
+        }
+    }
+
+    /// Records cache hits, misses and errors into `stats` as rows stream through.
+    pub fn with_stats(mut self, stats: Arc<CacheStats>) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+}
+
+impl<T, Conn, C, K> ExecuteDsl<Conn, Conn::Backend> for SelectCacheMultiReadWrapper<T, C, K>
+where
+    T: ExecuteDsl<Conn>,
+    Conn: Connection,
+    C: CacheHandle,
+    K: Iterator<Item = String>,
+{
+    fn execute(query: Self, conn: &mut Conn) -> QueryResult<usize> {
+        ExecuteDsl::<Conn, Conn::Backend>::execute(query.inner_select, conn)
+    }
+}
+
+impl<T, Conn, C, K> RunQueryDsl<Conn> for SelectCacheMultiReadWrapper<T, C, K>
+where
+    C: CacheHandle,
+    K: Iterator<Item = String>,
+{
+}
+
+impl<'query, T, Conn, U, B, C, K> LoadQuery<'query, Conn, U, B>
+    for SelectCacheMultiReadWrapper<T, C, K>
+where
+    T: LoadQuery<'query, Conn, (U, String), B>,
+    Conn: 'query,
+    U: Serialize + DeserializeOwned + std::fmt::Debug,
+    C: CacheHandle,
+    K: Iterator<Item = String>,
+{
+    type RowIter<'a>
+        = ResultCacheMultiLookupIterator<T::RowIter<'a>, U>
+    where
+        Conn: 'a;
+
+    fn internal_load(self, conn: &mut Conn) -> QueryResult<Self::RowIter<'_>> {
+        let span = instrumentation::query_span("SelectCacheMultiReadWrapper");
+        let load_iter = span.in_scope(|| self.inner_select.internal_load(conn))?;
+        let lookup_iter = span.clone().in_scope(|| {
+            ResultCacheMultiLookupIterator::new(load_iter, &self.cache, self.keys, self.stats, span)
+        });
+        Ok(lookup_iter)
+    }
+}
+
+/// Wrapper for a Diesel select query whose rows are cached together, as one
+/// `Vec`, under a single key.
+///
+/// Returned by `populate_cache_list` and `try_from_cache_list`.
+pub struct SelectListCacheWrapper<T, C>
+where
+    C: CacheHandle,
+{
+    inner_select: T,
+    key: String,
+    cache: C,
+    read: bool,
+    populate: bool,
+}
+
+impl<T, Conn, C> ExecuteDsl<Conn, Conn::Backend> for SelectListCacheWrapper<T, C>
+where
+    T: ExecuteDsl<Conn>,
+    Conn: Connection,
+    C: CacheHandle,
+{
+    fn execute(query: Self, conn: &mut Conn) -> QueryResult<usize> {
+        ExecuteDsl::<Conn, Conn::Backend>::execute(query.inner_select, conn)
+    }
+}
+
+impl<T, Conn, C> RunQueryDsl<Conn> for SelectListCacheWrapper<T, C> where C: CacheHandle {}
+
+impl<'query, T, Conn, U, B, C> LoadQuery<'query, Conn, U, B> for SelectListCacheWrapper<T, C>
+where
+    T: LoadQuery<'query, Conn, U, B>,
+    Conn: 'query,
+    U: Serialize + DeserializeOwned + std::fmt::Debug,
+    C: CacheHandle,
+{
+    type RowIter<'a>
+        = ResultListIterator<U>
+    where
+        Conn: 'a;
+
+    fn internal_load(self, conn: &mut Conn) -> QueryResult<Self::RowIter<'_>> {
+        let span = instrumentation::query_span("SelectListCacheWrapper");
+        let _entered = span.enter();
+        let mut cache = self.cache;
+        let key = self.key;
+
+        if self.read {
+            match cache.get::<Vec<U>>(&key) {
+                Ok(Some(rows)) => {
+                    instrumentation::cache_hit(&key);
+                    return Ok(ResultListIterator(rows.into_iter()));
+                }
+                Ok(None) => instrumentation::cache_miss(&key),
+                Err(e) => instrumentation::lookup_failed(&key, &e),
+            }
+        }
+        // The list is cached whole, so every row is read before any is returned.
+        let rows = self
+            .inner_select
+            .internal_load(conn)?
+            .collect::<QueryResult<Vec<U>>>()?;
+        if self.populate {
+            debug!("Caching {} rows under key {}", rows.len(), key);
+            if let Err(e) = instrumentation::cache_op("put", &key, || cache.put(&key, &rows)) {
+                instrumentation::write_failed(&key, &e);
+            }
+        }
+        Ok(ResultListIterator(rows.into_iter()))
+    }
+}
+
+/// Iterator over the rows of a list read or cached by `SelectListCacheWrapper`.
+pub struct ResultListIterator<U>(std::vec::IntoIter<U>);
+
+impl<U> Iterator for ResultListIterator<U> {
+    type Item = QueryResult<U>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(Ok)
+    }
+}
+
+/// Wrapper for a Diesel select query that serves a key from the cache and
+/// refreshes it in the background shortly before it expires.
+///
+/// Returned by `try_from_cache_refresh_ahead`.
+pub struct SelectCacheRefreshAheadWrapper<T, C, F>
+where
+    C: CacheHandle,
+{
+    inner_select: T,
+    key: String,
+    cache: C,
+    ttl: Duration,
+    threshold: Duration,
+    refresh: F,
+}
+
+impl<T, Conn, C, F> ExecuteDsl<Conn, Conn::Backend> for SelectCacheRefreshAheadWrapper<T, C, F>
+where
+    T: ExecuteDsl<Conn>,
+    Conn: Connection,
+    C: CacheHandle,
+{
+    fn execute(query: Self, conn: &mut Conn) -> QueryResult<usize> {
+        ExecuteDsl::<Conn, Conn::Backend>::execute(query.inner_select, conn)
+    }
+}
+
+impl<T, Conn, C, F> RunQueryDsl<Conn> for SelectCacheRefreshAheadWrapper<T, C, F> where
+    C: CacheHandle
+{
+}
+
+impl<'query, T, Conn, U, B, C, F> LoadQuery<'query, Conn, U, B>
+    for SelectCacheRefreshAheadWrapper<T, C, F>
+where
+    T: LoadQuery<'query, Conn, U, B>,
+    Conn: 'query,
+    U: Serialize + DeserializeOwned + std::fmt::Debug + Send + 'static,
+    C: CacheHandle + Send + 'static,
+    F: FnOnce() -> QueryResult<Option<U>> + Send + 'static,
+{
+    type RowIter<'a>
+        = ResultRefreshAheadIterator<T::RowIter<'a>, U, C>
+    where
+        Conn: 'a;
+
+    fn internal_load(self, conn: &mut Conn) -> QueryResult<Self::RowIter<'_>> {
+        let span = instrumentation::query_span("SelectCacheRefreshAheadWrapper");
+        let _entered = span.enter();
+
+        match self.cache.get_with_ttl::<U>(&self.key) {
+            Ok(Some((value, remaining))) => {
+                instrumentation::cache_hit(&self.key);
+                if remaining.is_some_and(|remaining| remaining < self.threshold) {
+                    debug!("Refreshing key {} ahead of expiry", self.key);
+                    spawn_refresh(self.cache, self.key, self.ttl, self.refresh);
+                }
+                // Served from the cache, so the query is never sent.
+                return Ok(ResultRefreshAheadIterator::Cached(Some(value)));
+            }
+            Ok(None) => instrumentation::cache_miss(&self.key),
+            Err(e) => instrumentation::lookup_failed(&self.key, &e),
+        }
+        let read_at = SystemTime::now();
+        let load_iter = self.inner_select.internal_load(conn)?;
+        Ok(ResultRefreshAheadIterator::Loaded {
+            inner: load_iter,
+            key: Some(self.key),
+            cache: self.cache,
+            ttl: self.ttl,
+            read_at,
+        })
+    }
+}
+
+/// Iterator returned by `SelectCacheRefreshAheadWrapper`: either the cached
+/// value, or the first row of the query, which is cached for the next read,
+/// stamped with the time the query started.
+pub enum ResultRefreshAheadIterator<I, U, C> {
+    Cached(Option<U>),
+    Loaded {
+        inner: I,
+        key: Option<String>,
+        cache: C,
+        ttl: Duration,
+        read_at: SystemTime,
+    },
+}
+
+impl<I, U, C> Iterator for ResultRefreshAheadIterator<I, U, C>
+where
+    I: Iterator<Item = QueryResult<U>>,
+    U: Serialize + DeserializeOwned,
+    C: CacheHandle,
+{
+    type Item = QueryResult<U>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Self::Cached(value) => value.take().map(Ok),
+            Self::Loaded {
+                inner,
+                key,
+                cache,
+                ttl,
+                read_at,
+            } => {
+                // Like the other single-key lookups, only one row is yielded.
+                let key = key.take()?;
+                let item = inner.next();
+                if let Some(Ok(val)) = &item {
+                    let res = instrumentation::cache_op("put_read_at", &key, || {
+                        cache.put_read_at::<U>(&key, val, Some(*ttl), *read_at)
+                    });
+                    match res {
+                        Ok(true) => {}
+                        Ok(false) => debug!(
+                            "Key {} was invalidated after the query started, not caching it",
+                            key
+                        ),
+                        Err(e) => instrumentation::write_failed(&key, &e),
+                    }
+                }
+                item
+            }
+        }
+    }
+}
+
+/// Keys with a background refresh in flight in this process, so that the
+/// hits on a hot key start a single refresh rather than one each.
+static REFRESHING: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(Default::default);
+
+/// Marks a key as being refreshed until dropped, even if the refresh panics.
+struct RefreshGuard(String);
+
+impl RefreshGuard {
+    /// Returns `None` if the key is already being refreshed.
+    fn acquire(key: &str) -> Option<Self> {
+        let mut refreshing = REFRESHING
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        refreshing
+            .insert(key.to_string())
+            .then(|| RefreshGuard(key.to_string()))
+    }
+}
+
+impl Drop for RefreshGuard {
+    fn drop(&mut self) {
+        REFRESHING
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .remove(&self.0);
+    }
+}
+
+/// Runs `refresh` on a new thread and stores its row under `key` for `ttl`,
+/// or deletes `key` if the row is gone. Errors are logged and dropped.
+///
+/// Returns `None`, starting nothing, if a refresh of `key` is already in
+/// flight. The row is stored with `put_read_at`, stamped with the time the
+/// refresh started, so it does not overwrite the invalidation of an update
+/// committed after it was read.
+fn spawn_refresh<U, C, F>(
+    mut cache: C,
+    key: String,
+    ttl: Duration,
+    refresh: F,
+) -> Option<std::thread::JoinHandle<()>>
+where
+    U: Serialize + DeserializeOwned + Send + 'static,
+    C: CacheHandle + Send + 'static,
+    F: FnOnce() -> QueryResult<Option<U>> + Send + 'static,
+{
+    let Some(guard) = RefreshGuard::acquire(&key) else {
+        debug!("Key {} is already being refreshed", key);
+        return None;
+    };
+    Some(std::thread::spawn(move || {
+        let _guard = guard;
+        let read_at = SystemTime::now();
+        let res = match refresh() {
+            Ok(Some(value)) => cache
+                .put_read_at(&key, &value, Some(ttl), read_at)
+                .map(|_| ()),
+            Ok(None) => cache.delete(&key),
+            Err(e) => {
+                warn!("Error refreshing key {} from the database: {}", key, e);
+                return;
+            }
+        };
+        if let Err(e) = res {
+            instrumentation::write_failed(&key, &e);
+        }
+    }))
+}
+
+/// Cached form of a row read by `try_from_cache_stale_while_revalidate`: the
+/// row along with the Unix times, in milliseconds, at which it turns stale
+/// and at which it expires.
+#[derive(Serialize, Deserialize)]
+struct StaleWhileRevalidateEntry<U> {
+    value: U,
+    stale_at: u64,
+    expires_at: u64,
+}
+
+#[derive(Debug, PartialEq)]
+enum Freshness {
+    Fresh,
+    Stale,
+    Expired,
+}
+
+impl<U> StaleWhileRevalidateEntry<U> {
+    fn new(value: U, soft_ttl: Duration, hard_ttl: Duration) -> Self {
+        let now = unix_millis();
+        Self {
+            value,
+            stale_at: now.saturating_add(soft_ttl.as_millis() as u64),
+            expires_at: now.saturating_add(hard_ttl.as_millis() as u64),
+        }
+    }
+
+    fn freshness(&self, now: u64) -> Freshness {
+        if now >= self.expires_at {
+            Freshness::Expired
+        } else if now >= self.stale_at {
+            Freshness::Stale
+        } else {
+            Freshness::Fresh
+        }
+    }
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+/// Wrapper for a Diesel select query that serves a cached row, stale or not,
+/// until it hard-expires, refreshing stale rows in the background.
+///
+/// Returned by `try_from_cache_stale_while_revalidate`.
+pub struct SelectCacheStaleWhileRevalidateWrapper<T, C, F>
+where
+    C: CacheHandle,
+{
+    inner_select: T,
+    key: String,
+    cache: C,
+    soft_ttl: Duration,
+    hard_ttl: Duration,
+    refresh: F,
+}
+
+impl<T, Conn, C, F> ExecuteDsl<Conn, Conn::Backend>
+    for SelectCacheStaleWhileRevalidateWrapper<T, C, F>
+where
+    T: ExecuteDsl<Conn>,
+    Conn: Connection,
+    C: CacheHandle,
+{
+    fn execute(query: Self, conn: &mut Conn) -> QueryResult<usize> {
+        ExecuteDsl::<Conn, Conn::Backend>::execute(query.inner_select, conn)
+    }
+}
+
+impl<T, Conn, C, F> RunQueryDsl<Conn> for SelectCacheStaleWhileRevalidateWrapper<T, C, F> where
+    C: CacheHandle
+{
+}
+
+impl<'query, T, Conn, U, B, C, F> LoadQuery<'query, Conn, U, B>
+    for SelectCacheStaleWhileRevalidateWrapper<T, C, F>
+where
+    T: LoadQuery<'query, Conn, U, B>,
+    Conn: 'query,
+    U: Serialize + DeserializeOwned + std::fmt::Debug + Send + 'static,
+    C: CacheHandle + Send + 'static,
+    F: FnOnce() -> QueryResult<Option<U>> + Send + 'static,
+{
+    type RowIter<'a>
+        = ResultStaleWhileRevalidateIterator<T::RowIter<'a>, U, C>
+    where
+        Conn: 'a;
+
+    fn internal_load(self, conn: &mut Conn) -> QueryResult<Self::RowIter<'_>> {
+        let span = instrumentation::query_span("SelectCacheStaleWhileRevalidateWrapper");
+        let _entered = span.enter();
+
+        let now = unix_millis();
+        match self.cache.get::<StaleWhileRevalidateEntry<U>>(&self.key) {
+            // An expired entry can be left behind by a backend that does not
+            // expire entries, and counts as a miss.
+            Ok(Some(entry)) if entry.freshness(now) != Freshness::Expired => {
+                instrumentation::cache_hit(&self.key);
+                if entry.freshness(now) == Freshness::Stale {
+                    debug!("Serving stale key {} while revalidating", self.key);
+                    let (soft_ttl, hard_ttl) = (self.soft_ttl, self.hard_ttl);
+                    let refresh = self.refresh;
+                    spawn_refresh(self.cache, self.key, hard_ttl, move || {
+                        let row = refresh()?;
+                        Ok(row.map(|row| StaleWhileRevalidateEntry::new(row, soft_ttl, hard_ttl)))
+                    });
+                }
+                return Ok(ResultStaleWhileRevalidateIterator::Cached(Some(
+                    entry.value,
+                )));
+            }
+            Ok(_) => instrumentation::cache_miss(&self.key),
+            Err(e) => instrumentation::lookup_failed(&self.key, &e),
+        }
+        let read_at = SystemTime::now();
+        let load_iter = self.inner_select.internal_load(conn)?;
+        Ok(ResultStaleWhileRevalidateIterator::Loaded {
+            inner: load_iter,
+            key: Some(self.key),
+            cache: self.cache,
+            soft_ttl: self.soft_ttl,
+            hard_ttl: self.hard_ttl,
+            read_at,
+        })
+    }
+}
+
+/// Iterator returned by `SelectCacheStaleWhileRevalidateWrapper`: either the
+/// cached value, or the first row of the query, which is cached for the next
+/// read, stamped with the time the query started.
+pub enum ResultStaleWhileRevalidateIterator<I, U, C> {
+    Cached(Option<U>),
+    Loaded {
+        inner: I,
+        key: Option<String>,
+        cache: C,
+        soft_ttl: Duration,
+        hard_ttl: Duration,
+        read_at: SystemTime,
+    },
+}
+
+impl<I, U, C> Iterator for ResultStaleWhileRevalidateIterator<I, U, C>
+where
+    I: Iterator<Item = QueryResult<U>>,
+    U: Serialize + DeserializeOwned,
+    C: CacheHandle,
+{
+    type Item = QueryResult<U>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Self::Cached(value) => value.take().map(Ok),
+            Self::Loaded {
+                inner,
+                key,
+                cache,
+                soft_ttl,
+                hard_ttl,
+                read_at,
+            } => {
+                let key = key.take()?;
+                let val = match inner.next()? {
+                    Ok(val) => val,
+                    Err(e) => return Some(Err(e)),
+                };
+                let entry = StaleWhileRevalidateEntry::new(val, *soft_ttl, *hard_ttl);
+                let res = instrumentation::cache_op("put_read_at", &key, || {
+                    cache.put_read_at(&key, &entry, Some(*hard_ttl), *read_at)
+                });
+                match res {
+                    Ok(true) => {}
+                    Ok(false) => debug!(
+                        "Key {} was invalidated after the query started, not caching it",
+                        key
+                    ),
+                    Err(e) => instrumentation::write_failed(&key, &e),
+                }
+                Some(Ok(entry.value))
+            }
+        }
+    }
+}
+
+/// Wrapper for a Diesel select query that reads through the cache, and falls
+/// back to an expired cached row if the database read fails.
+///
+/// Returned by `try_from_cache_serve_stale_on_error`.
+pub struct SelectCacheServeStaleWrapper<T, C>
+where
+    C: CacheHandle,
+{
+    inner_select: T,
+    key: String,
+    cache: C,
+    ttl: Duration,
+    grace: Duration,
+}
+
+impl<T, Conn, C> ExecuteDsl<Conn, Conn::Backend> for SelectCacheServeStaleWrapper<T, C>
+where
+    T: ExecuteDsl<Conn>,
+    Conn: Connection,
+    C: CacheHandle,
+{
+    fn execute(query: Self, conn: &mut Conn) -> QueryResult<usize> {
+        ExecuteDsl::<Conn, Conn::Backend>::execute(query.inner_select, conn)
+    }
+}
+
+impl<T, Conn, C> RunQueryDsl<Conn> for SelectCacheServeStaleWrapper<T, C> where C: CacheHandle {}
+
+impl<'query, T, Conn, U, B, C> LoadQuery<'query, Conn, U, B> for SelectCacheServeStaleWrapper<T, C>
+where
+    T: LoadQuery<'query, Conn, U, B>,
+    Conn: 'query,
+    U: Serialize + DeserializeOwned + std::fmt::Debug,
+    C: CacheHandle,
+{
+    type RowIter<'a>
+        = ResultServeStaleIterator<T::RowIter<'a>, U, C>
+    where
+        Conn: 'a;
+
+    fn internal_load(self, conn: &mut Conn) -> QueryResult<Self::RowIter<'_>> {
+        let span = instrumentation::query_span("SelectCacheServeStaleWrapper");
+        let _entered = span.enter();
+
+        let now = unix_millis();
+        let mut fallback = None;
+        match self.cache.get::<StaleWhileRevalidateEntry<U>>(&self.key) {
+            Ok(Some(entry)) => match entry.freshness(now) {
+                Freshness::Fresh => {
+                    instrumentation::cache_hit(&self.key);
+                    return Ok(ResultServeStaleIterator::Cached(Some(entry.value)));
+                }
+                // Past its TTL but within the grace window: only served if
+                // the database cannot be read.
+                Freshness::Stale => {
+                    instrumentation::cache_miss(&self.key);
+                    fallback = Some(entry.value);
+                }
+                Freshness::Expired => instrumentation::cache_miss(&self.key),
+            },
+            Ok(None) => instrumentation::cache_miss(&self.key),
+            Err(e) => instrumentation::lookup_failed(&self.key, &e),
+        }
+        let read_at = SystemTime::now();
+        match self.inner_select.internal_load(conn) {
+            Ok(load_iter) => Ok(ResultServeStaleIterator::Loaded {
+                inner: load_iter,
+                key: Some(self.key),
+                cache: self.cache,
+                ttl: self.ttl,
+                grace: self.grace,
+                fallback,
+                read_at,
+            }),
+            Err(e) => match fallback {
+                Some(value) => {
+                    warn!("Serving stale key {} after database error: {}", self.key, e);
+                    Ok(ResultServeStaleIterator::Cached(Some(value)))
+                }
+                None => Err(e),
+            },
+        }
+    }
+}
+
+/// Iterator returned by `SelectCacheServeStaleWrapper`: either a cached value,
+/// fresh or served after a database error, or the first row of the query,
+/// which is cached for the next read, stamped with the time the query
+/// started.
+pub enum ResultServeStaleIterator<I, U, C> {
+    Cached(Option<U>),
+    Loaded {
+        inner: I,
+        key: Option<String>,
+        cache: C,
+        ttl: Duration,
+        grace: Duration,
+        fallback: Option<U>,
+        read_at: SystemTime,
+    },
+}
+
+impl<I, U, C> Iterator for ResultServeStaleIterator<I, U, C>
+where
+    I: Iterator<Item = QueryResult<U>>,
+    U: Serialize + DeserializeOwned,
+    C: CacheHandle,
+{
+    type Item = QueryResult<U>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Self::Cached(value) => value.take().map(Ok),
+            Self::Loaded {
+                inner,
+                key,
+                cache,
+                ttl,
+                grace,
+                fallback,
+                read_at,
+            } => {
+                let key = key.take()?;
+                let val = match inner.next()? {
+                    Ok(val) => val,
+                    Err(e) => {
+                        return match fallback.take() {
+                            Some(value) => {
+                                warn!("Serving stale key {} after database error: {}", key, e);
+                                Some(Ok(value))
+                            }
+                            None => Some(Err(e)),
+                        };
+                    }
+                };
+                let hard_ttl = ttl.saturating_add(*grace);
+                let entry = StaleWhileRevalidateEntry::new(val, *ttl, hard_ttl);
+                let res = instrumentation::cache_op("put_read_at", &key, || {
+                    cache.put_read_at(&key, &entry, Some(hard_ttl), *read_at)
+                });
+                match res {
+                    Ok(true) => {}
+                    Ok(false) => debug!(
+                        "Key {} was invalidated after the query started, not caching it",
+                        key
+                    ),
+                    Err(e) => instrumentation::write_failed(&key, &e),
+                }
+                Some(Ok(entry.value))
+            }
+        }
+    }
+}
+
+/// When `invalidate_key` and `invalidate_keys` delete their keys, relative
+/// to running the update.
+///
+/// With `Before`, a reader that misses between the delete and the commit of
+/// the update still sees the old row in the database and caches it again,
+/// where it stays until it expires. With `After`, readers keep being served
+/// the old cached value until the update has run, and a cache failure is
+/// only noticed once the update is applied. `Both` deletes before and again
+/// after the update, which removes whatever a reader cached in between and
+/// is the safest against this race, at the cost of a second round trip.
+///
+/// The second delete runs right after the statement, which is after it
+/// commits only when no transaction surrounds it. Inside a transaction,
+/// `defer_invalidate_key` deletes keys once the transaction commits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InvalidationOrder {
+    /// Delete the keys, then run the update.
+    #[default]
+    Before,
+    /// Run the update, then delete the keys. If the delete fails, the update
+    /// has already run and is only undone by rolling back a surrounding
+    /// transaction.
+    After,
+    /// Delete the keys, run the update, then delete them again.
+    Both,
+}
+
+/// Wrapper for a Diesel update statement that invalidates specified cache keys
+/// along with a database update, in the given `InvalidationOrder`.
+///
+/// Returned by `invalidate_key`, `invalidate_keys` and
+/// `invalidate_key_best_effort`.
+pub struct UpdateWrapper<T, K, C>
+where
+    K: Iterator<Item = String>,
+    C: CacheHandle,
+{
+    inner_update: T,
+    keys: K,
+    cache: C,
+    best_effort: bool,
+    order: InvalidationOrder,
+}
+
+impl<T, K, C> UpdateWrapper<T, K, C>
+where
+    K: Iterator<Item = String>,
+    C: CacheHandle,
+{
+    fn new(
+        inner_update: T,
+        keys: K,
+        cache: C,
+        best_effort: bool,
+        order: InvalidationOrder,
+    ) -> Self {
+        Self {
+            inner_update,
+            keys,
+            cache,
+            best_effort,
+            order,
+        }
+    }
+
+    /// Like `execute`, but also reports how many of the keys held a cached
+    /// value, as `(rows_updated, keys_invalidated)`. A low count relative to
+    /// the number of keys points at invalidating keys that were never cached.
+    /// With `InvalidationOrder::Both`, the count is that of the first delete.
+    pub fn execute_counted<Conn>(self, conn: &mut Conn) -> QueryResult<(usize, usize)>
+    where
+        T: ExecuteDsl<Conn>,
+        Conn: Connection,
+    {
+        self.run(conn, true)
+    }
+
+    fn run<Conn>(self, conn: &mut Conn, counted: bool) -> QueryResult<(usize, usize)>
+    where
+        T: ExecuteDsl<Conn>,
+        Conn: Connection,
+    {
+        let span = instrumentation::query_span("UpdateWrapper");
+        let _entered = span.enter();
+        let keys = self.keys.collect::<Vec<_>>();
+        let mut invalidated = 0;
+        if self.order != InvalidationOrder::After {
+            invalidated = delete_keys(&self.cache, &keys, self.best_effort, counted)?;
+        }
+        let rows = ExecuteDsl::<Conn, Conn::Backend>::execute(self.inner_update, conn)?;
+        if self.order != InvalidationOrder::Before {
+            let deleted = delete_keys(&self.cache, &keys, self.best_effort, counted)?;
+            if self.order == InvalidationOrder::After {
+                invalidated = deleted;
+            }
+        }
+        Ok((rows, invalidated))
+    }
+}
+
+/// Deletes `keys` for `UpdateWrapper`, returning how many were cached if
+/// `counted`, or 0 otherwise. A failure is only logged in best-effort mode.
+fn delete_keys<C: CacheHandle>(
+    cache: &C,
+    keys: &[String],
+    best_effort: bool,
+    counted: bool,
+) -> QueryResult<usize> {
+    debug!("Invalidating cache for keys: {:?}", keys);
+    let res = if counted {
+        instrumentation::cache_batch_op("delete_multi_counted", keys.len(), || {
+            cache.clone().delete_multi_counted(keys)
+        })
+    } else {
+        instrumentation::cache_batch_op("delete_multi", keys.len(), || {
+            cache.clone().delete_multi(keys).map(|_| 0)
+        })
+    };
+    match res {
+        Ok(invalidated) => {
+            if counted {
+                debug!("{} of {} keys were cached", invalidated, keys.len());
+            }
+            Ok(invalidated)
+        }
+        Err(e) => {
+            error!("Error deleting keys {:?} from cache: {}", keys, e);
+            fail_unless_best_effort(best_effort)?;
+            Ok(0)
+        }
+    }
+}
+
+impl<T, Conn, K, C> ExecuteDsl<Conn, Conn::Backend> for UpdateWrapper<T, K, C>
+where
+    T: ExecuteDsl<Conn>,
+    Conn: Connection,
+    K: Iterator<Item = String>,
+    C: CacheHandle,
+{
+    fn execute(query: Self, conn: &mut Conn) -> QueryResult<usize> {
+        query.run(conn, false).map(|(rows, _)| rows)
+    }
+}
+
+impl<T, Conn, K, C> RunQueryDsl<Conn> for UpdateWrapper<T, K, C>
+where
+    K: Iterator<Item = String>,
+    C: CacheHandle,
+{
+}
+
+/// Wrapper for a Diesel update statement that queues cache keys in a
+/// `DeferredInvalidations` once the update succeeds, to be deleted after
+/// the surrounding transaction commits.
+///
+/// Returned by `defer_invalidate_key` and `defer_invalidate_keys`.
+pub struct DeferredUpdateWrapper<T, K, C>
+where
+    K: Iterator<Item = String>,
+    C: CacheHandle,
+{
+    inner_update: T,
+    keys: K,
+    invalidations: Arc<DeferredInvalidations<C>>,
+}
+
+impl<T, Conn, K, C> ExecuteDsl<Conn, Conn::Backend> for DeferredUpdateWrapper<T, K, C>
+where
+    T: ExecuteDsl<Conn>,
+    Conn: Connection,
+    K: Iterator<Item = String>,
+    C: CacheHandle,
+{
+    fn execute(query: Self, conn: &mut Conn) -> QueryResult<usize> {
+        let span = instrumentation::query_span("DeferredUpdateWrapper");
+        let _entered = span.enter();
+        let rows = ExecuteDsl::<Conn, Conn::Backend>::execute(query.inner_update, conn)?;
+        query.invalidations.add(query.keys);
+        Ok(rows)
+    }
+}
+
+impl<T, Conn, K, C> RunQueryDsl<Conn> for DeferredUpdateWrapper<T, K, C>
+where
+    K: Iterator<Item = String>,
+    C: CacheHandle,
+{
+}
+
+/// Wrapper for a Diesel update statement that invalidates every cache key
+/// carrying a tag along with the database update.
+///
+/// Returned by `invalidate_tag`.
+pub struct TagInvalidationWrapper<T, C>
+where
+    C: CacheHandle,
+{
+    inner_update: T,
+    tag: String,
+    cache: C,
+}
+
+impl<T, C> TagInvalidationWrapper<T, C>
+where
+    C: CacheHandle,
+{
+    fn new(inner_update: T, tag: String, cache: C) -> Self {
+        Self {
+            inner_update,
+            tag,
+            cache,
+        }
+    }
+}
+
+impl<T, Conn, C> ExecuteDsl<Conn, Conn::Backend> for TagInvalidationWrapper<T, C>
+where
+    T: ExecuteDsl<Conn>,
+    Conn: Connection,
+    C: CacheHandle,
+{
+    fn execute(query: Self, conn: &mut Conn) -> QueryResult<usize> {
+        let span = instrumentation::query_span("TagInvalidationWrapper");
+        let _entered = span.enter();
+        match query.cache.clone().invalidate_tag(&query.tag) {
+            Ok(keys) => debug!("Invalidated keys {:?} tagged {}", keys, query.tag),
+            Err(e) => {
+                error!("Error invalidating tag {} in cache: {}", query.tag, e);
+                fail_unless_best_effort(false)?;
+            }
+        }
+        ExecuteDsl::<Conn, Conn::Backend>::execute(query.inner_update, conn)
+    }
+}
+
+impl<T, Conn, C> RunQueryDsl<Conn> for TagInvalidationWrapper<T, C> where C: CacheHandle {}
+
+/// Wrapper for a Diesel update statement that invalidates every cache key
+/// matching a pattern along with the database update.
+///
+/// Returned by `invalidate_pattern`.
+pub struct PatternInvalidationWrapper<T, C>
+where
+    C: CacheHandle,
+{
+    inner_update: T,
+    pattern: String,
+    cache: C,
+}
+
+impl<T, C> PatternInvalidationWrapper<T, C>
+where
+    C: CacheHandle,
+{
+    fn new(inner_update: T, pattern: String, cache: C) -> Self {
+        Self {
+            inner_update,
+            pattern,
+            cache,
+        }
+    }
+}
+
+impl<T, Conn, C> ExecuteDsl<Conn, Conn::Backend> for PatternInvalidationWrapper<T, C>
+where
+    T: ExecuteDsl<Conn>,
+    Conn: Connection,
+    C: CacheHandle,
+{
+    fn execute(query: Self, conn: &mut Conn) -> QueryResult<usize> {
+        let span = instrumentation::query_span("PatternInvalidationWrapper");
+        let _entered = span.enter();
+        match query.cache.clone().delete_pattern(&query.pattern) {
+            Ok(deleted) => debug!("Invalidated {} keys matching {}", deleted, query.pattern),
+            Err(e) => {
+                error!(
+                    "Error invalidating pattern {} in cache: {}",
+                    query.pattern, e
+                );
+                fail_unless_best_effort(false)?;
+            }
+        }
+        ExecuteDsl::<Conn, Conn::Backend>::execute(query.inner_update, conn)
+    }
+}
+
+impl<T, Conn, C> RunQueryDsl<Conn> for PatternInvalidationWrapper<T, C> where C: CacheHandle {}
+
+/// Wrapper for a Diesel update statement with a `RETURNING` clause that writes
+/// the updated row back into the cache under a given key.
+///
+/// Returned by `write_through_update`.
+pub struct WriteThroughUpdateWrapper<T, C, U>
+where
+    C: CacheHandle,
+{
+    inner_update: T,
+    key: String,
+    cache: C,
+    row: PhantomData<U>,
+}
+
+impl<T, C, U> WriteThroughUpdateWrapper<T, C, U>
+where
+    C: CacheHandle,
+{
+    fn new(inner_update: T, key: String, cache: C) -> Self {
+        Self {
+            inner_update,
+            key,
+            cache,
+            row: PhantomData,
+        }
+    }
+}
+
+impl<T, Conn, C, U> RunQueryDsl<Conn> for WriteThroughUpdateWrapper<T, C, U> where C: CacheHandle {}
+
+impl<'query, T, Conn, U, B, C> LoadQuery<'query, Conn, U, B> for WriteThroughUpdateWrapper<T, C, U>
+where
+    T: LoadQuery<'query, Conn, U, B>,
+    Conn: 'query,
+    U: Serialize + DeserializeOwned + std::fmt::Debug,
+    C: CacheHandle,
+{
+    type RowIter<'a>
+        = std::vec::IntoIter<QueryResult<U>>
+    where
+        Conn: 'a;
+
+    fn internal_load(mut self, conn: &mut Conn) -> QueryResult<Self::RowIter<'_>> {
+        let span = instrumentation::query_span("WriteThroughUpdateWrapper");
+        let _entered = span.enter();
+
+        let rows = self
+            .inner_update
+            .internal_load(conn)?
+            .collect::<QueryResult<Vec<U>>>()?;
+        if let [row] = &rows[..] {
+            debug!("Writing updated row to cache for key: {}", self.key);
+            let res =
+                instrumentation::cache_op("put", &self.key, || self.cache.put::<U>(&self.key, row));
+            if let Err(e) = res {
+                error!("Error writing key {} to cache: {}", self.key, e);
+                fail_unless_best_effort(false)?;
+            }
+        } else {
+            warn!(
+                "Update returned {} rows for cache key {}, invalidating it",
+                rows.len(),
+                self.key
+            );
+            let res = instrumentation::cache_op("delete", &self.key, || self.cache.delete(&self.key));
+            if let Err(e) = res {
+                error!("Error invalidating key {} in cache: {}", self.key, e);
+                fail_unless_best_effort(false)?;
+            }
+        }
+        Ok(rows.into_iter().map(Ok).collect::<Vec<_>>().into_iter())
+    }
+}
+
+/// Wrapper for a Diesel insert statement with a `RETURNING` clause that writes
+/// the inserted rows into the cache under keys derived from each row.
+///
+/// Returned by `populate_on_insert`.
+pub struct InsertCachingWrapper<T, C, U, F>
+where
+    C: CacheHandle,
+{
+    inner_insert: T,
+    cache: C,
+    key_fn: F,
+    row: PhantomData<U>,
+}
+
+impl<T, Conn, C, U, F> RunQueryDsl<Conn> for InsertCachingWrapper<T, C, U, F> where C: CacheHandle {}
+
+impl<'query, T, Conn, U, B, C, F> LoadQuery<'query, Conn, U, B> for InsertCachingWrapper<T, C, U, F>
+where
+    T: LoadQuery<'query, Conn, U, B>,
+    Conn: 'query,
+    U: Serialize + DeserializeOwned + std::fmt::Debug,
+    C: CacheHandle,
+    F: KeyExtractor<U>,
+{
+    type RowIter<'a>
+        = std::vec::IntoIter<QueryResult<U>>
+    where
+        Conn: 'a;
+
+    fn internal_load(mut self, conn: &mut Conn) -> QueryResult<Self::RowIter<'_>> {
+        let span = instrumentation::query_span("InsertCachingWrapper");
+        let _entered = span.enter();
+
+        let rows = self.inner_insert.internal_load(conn)?;
+        cache_returned_rows(rows, &mut self.cache, &self.key_fn, "inserted")
+    }
+}
+
+/// Pairs each row returned by a statement with its cache key.
+fn key_returned_rows<U, F>(
+    rows: impl Iterator<Item = QueryResult<U>>,
+    key_fn: &F,
+) -> QueryResult<Vec<(String, U)>>
+where
+    F: KeyExtractor<U>,
+{
+    rows.map(|row| row.map(|row| (key_fn.extract_key(&row), row)))
+        .collect()
+}
+
+/// Writes the rows returned by a statement into `cache` in one `put_multi`,
+/// each under the key computed by `key_fn`, and hands the rows back.
+fn cache_returned_rows<U, C, F>(
+    rows: impl Iterator<Item = QueryResult<U>>,
+    cache: &mut C,
+    key_fn: &F,
+    what: &str,
+) -> QueryResult<std::vec::IntoIter<QueryResult<U>>>
+where
+    U: Serialize + DeserializeOwned,
+    C: CacheHandle,
+    F: KeyExtractor<U>,
+{
+    let entries = key_returned_rows(rows, key_fn)?;
+    if !entries.is_empty() {
+        debug!("Writing {} {} rows to cache", entries.len(), what);
+        let res = instrumentation::cache_batch_op("put_multi", entries.len(), || {
+            cache.put_multi(&entries)
+        });
+        if let Err(e) = res {
+            error!("Error writing {} rows to cache: {}", what, e);
+            fail_unless_best_effort(false)?;
+        }
+    }
+    Ok(entries
+        .into_iter()
+        .map(|(_, row)| Ok(row))
+        .collect::<Vec<_>>()
+        .into_iter())
+}
+
+/// Wrapper for a Diesel update statement with a `RETURNING` clause that
+/// replaces the cached copies of the updated rows with the returned ones,
+/// under keys derived from each row.
+///
+/// Returned by `refresh_from_returning`.
+pub struct RefreshFromReturningWrapper<T, C, U, F>
+where
+    C: CacheHandle,
+{
+    inner_update: T,
+    cache: C,
+    key_fn: F,
+    row: PhantomData<U>,
+}
+
+impl<T, Conn, C, U, F> RunQueryDsl<Conn> for RefreshFromReturningWrapper<T, C, U, F> where
+    C: CacheHandle
+{
+}
+
+impl<'query, T, Conn, U, B, C, F> LoadQuery<'query, Conn, U, B>
+    for RefreshFromReturningWrapper<T, C, U, F>
+where
+    T: LoadQuery<'query, Conn, U, B>,
+    Conn: 'query,
+    U: Serialize + DeserializeOwned + std::fmt::Debug,
+    C: CacheHandle,
+    F: KeyExtractor<U>,
+{
+    type RowIter<'a>
+        = std::vec::IntoIter<QueryResult<U>>
+    where
+        Conn: 'a;
+
+    fn internal_load(mut self, conn: &mut Conn) -> QueryResult<Self::RowIter<'_>> {
+        let span = instrumentation::query_span("RefreshFromReturningWrapper");
+        let _entered = span.enter();
+
+        let rows = self.inner_update.internal_load(conn)?;
+        cache_returned_rows(rows, &mut self.cache, &self.key_fn, "updated")
+    }
+}
+
+/// Wrapper for a Diesel delete statement with a `RETURNING` clause that
+/// deletes the cache keys of exactly the deleted rows, derived from each
+/// returned row.
+///
+/// Returned by `invalidate_from_returning`.
+pub struct InvalidateFromReturningWrapper<T, C, U, F>
+where
+    C: CacheHandle,
+{
+    inner_delete: T,
+    cache: C,
+    key_fn: F,
+    row: PhantomData<U>,
+}
+
+impl<T, Conn, C, U, F> RunQueryDsl<Conn> for InvalidateFromReturningWrapper<T, C, U, F> where
+    C: CacheHandle
+{
+}
+
+impl<'query, T, Conn, U, B, C, F> LoadQuery<'query, Conn, U, B>
+    for InvalidateFromReturningWrapper<T, C, U, F>
+where
+    T: LoadQuery<'query, Conn, U, B>,
+    Conn: 'query,
+    C: CacheHandle,
+    F: KeyExtractor<U>,
+{
+    type RowIter<'a>
+        = std::vec::IntoIter<QueryResult<U>>
+    where
+        Conn: 'a;
+
+    fn internal_load(mut self, conn: &mut Conn) -> QueryResult<Self::RowIter<'_>> {
+        let span = instrumentation::query_span("InvalidateFromReturningWrapper");
+        let _entered = span.enter();
+
+        let rows = self.inner_delete.internal_load(conn)?;
+        let (keys, rows): (Vec<String>, Vec<U>) =
+            key_returned_rows(rows, &self.key_fn)?.into_iter().unzip();
+        if !keys.is_empty() {
+            debug!("Invalidating {} deleted rows in cache", keys.len());
+            let res = instrumentation::cache_batch_op("delete_multi", keys.len(), || {
+                self.cache.delete_multi(&keys)
+            });
+            if let Err(e) = res {
+                error!("Error invalidating deleted rows in cache: {}", e);
+                fail_unless_best_effort(false)?;
+            }
+        }
+        Ok(rows.into_iter().map(Ok).collect::<Vec<_>>().into_iter())
+    }
+}
+
+/// Provides extension methods for Diesel select statements that integrate caching behavior.
+///
+/// This trait allows wrapping a Diesel select with cache population, cache lookup,
+/// and hybrid read-through patterns, seamlessly woven into Diesel’s query DSL.
+///
+/// Implemented for all Diesel select queries and every cache handle type `C`,
+/// so the backend is inferred from the handle passed at the call site.
+pub trait WrappableQuery<C: CacheHandle> {
+    /// Populates the cache with results returned from the database query.
+    ///
+    /// After executing the query, each record is inserted into the cache
+    /// using its provided cache key, allowing subsequent lookups to
+    /// reuse cached results.
+    ///
+    /// The SQL query consumed by `populate_cache` should be a pair consisting
+    /// of the actual data row and a SQL expression that produces the cache key
+    /// for each row. The key expression may be nullable; rows whose key is
+    /// NULL are returned without being cached, with a warning. A query that
+    /// selects only the row, or a key that is not `Text`, e.g.
+    /// `sql::<Integer>("id")`, is rejected at compile time. For example, you can
+    /// select a student row and concatenate a cache prefix:
+    ///
+    /// ```ignore
+    /// let row_with_cache_key = (Student::as_select(), cache_key_expr("student", students::id));
+    /// let results = students::dsl::students
+    ///     .select(row_with_cache_key)
+    ///     .populate_cache::<Student>(handle.clone())
+    ///     .load_iter::<Student, DefaultLoadingMode>(connection)?;
+    /// ```
+    ///
+    /// You can also combine `populate_cache` with `try_from_cache` to benefit
+    /// from cache reads while still populating the cache if missing:
+    ///
+    /// ```ignore
+    /// let results = students::dsl::students
+    ///     .select(row_with_cache_key)
+    ///     .populate_cache::<Student>(handle.clone())
+    ///     .try_from_cache::<Student>(handle.clone(), "student:2")
+    ///     .load_iter::<Student, DefaultLoadingMode>(connection)?;
+    /// ```
+    fn populate_cache<U>(self, cache: C) -> SelectCachingWrapper<Self, C>
+    where
+        Self: Sized + Query,
+        Self::SqlType: RowWithCacheKey,
+        U: Serialize + DeserializeOwned,
+    {
+        SelectCachingWrapper::new(self, cache, None)
+    }
+
+    /// Populates the cache like `populate_cache`, but every inserted record
+    /// expires after the given `ttl`.
+    ///
+    /// This is useful for bulk loads where all entries should share a
+    /// uniform expiry instead of living until explicitly invalidated. Use
+    /// `with_ttl_jitter` on the result to spread the expiries out instead.
+    fn populate_cache_with_ttl<U>(self, cache: C, ttl: Duration) -> SelectCachingWrapper<Self, C>
+    where
+        Self: Sized + Query,
+        Self::SqlType: RowWithCacheKey,
+        U: Serialize + DeserializeOwned,
+    {
+        SelectCachingWrapper::new(self, cache, Some(ttl))
+    }
+
+    /// Populates the cache like `populate_cache`, but caches each row for a
+    /// TTL computed by the database alongside it, e.g. longer for recent
+    /// records than for archived ones.
+    ///
+    /// The query selects `(row, cache key, TTL in seconds)`, where the TTL
+    /// is a `BIGINT` expression. Rows whose TTL is NULL never expire, and
+    /// rows whose TTL is zero or negative are returned without being cached.
+    ///
+    /// ```ignore
+    /// let ttl = sql::<Nullable<BigInt>>(
+    ///     "CASE WHEN dob > now() - interval '1 year' THEN 3600 ELSE 60 END",
+    /// );
+    /// let results = students::table
+    ///     .select((Student::as_select(), cache_key_expr("student", students::id), ttl))
+    ///     .populate_cache_with_row_ttl::<Student>(handle.clone())
+    ///     .load::<Student>(connection)?;
+    /// ```
+    fn populate_cache_with_row_ttl<U>(self, cache: C) -> SelectRowTtlCachingWrapper<Self, C>
+    where
+        Self: Sized,
+        U: Serialize + DeserializeOwned,
+    {
+        SelectRowTtlCachingWrapper {
+            inner_select: self,
+            cache,
+            stats: None,
+        }
+    }
+
+    /// Populates the cache like `populate_cache`, but writes the rows with
+    /// one `put_multi_read_at` call per `batch_size` rows instead of one
+    /// write per row, which saves round trips on large loads.
+    ///
+    /// Rows are cloned into the batch as they are yielded. Unlike
+    /// `populate_cache`, which only logs failed writes, a batch that cannot
+    /// be written fails the query unless `set_best_effort` is on; see
+    /// `BatchCachingIterator`.
+    fn populate_cache_batched<U>(
+        self,
+        cache: C,
+        batch_size: usize,
+    ) -> SelectBatchCachingWrapper<Self, C>
+    where
+        Self: Sized + Query,
+        Self::SqlType: RowWithCacheKey,
+        U: Serialize + DeserializeOwned + Clone,
+    {
+        SelectBatchCachingWrapper {
+            inner_select: self,
+            cache,
+            batch_size,
+        }
+    }
+
+    /// Populates the cache like `populate_cache`, for the rows whose `column`
+    /// is one of `ids`, each cached under the key `key_fn` builds from its id.
+    ///
+    /// The query is filtered with `column.eq_any(ids)` and should select the
+    /// row together with `column`, so the filter and the keys always come
+    /// from the same ids. In debug builds, ids that return no row are logged
+    /// as a warning once the results have been read.
+    ///
+    /// ```ignore
+    /// let results = students::table
+    ///     .select((Student::as_select(), students::id))
+    ///     .populate_cache_by_ids::<Student, _, _, _>(handle.clone(), students::id, vec![1, 2], |id| {
+    ///         format!("student:{}", id)
+    ///     })
+    ///     .load::<Student>(connection)?;
+    /// ```
+    #[allow(clippy::type_complexity)]
+    fn populate_cache_by_ids<U, Col, Id, F>(
+        self,
+        cache: C,
+        column: Col,
+        ids: Vec<Id>,
+        key_fn: F,
+    ) -> SelectCachingWrapper<
+        IdKeyedQuery<diesel::dsl::Filter<Self, diesel::dsl::EqAny<Col, Vec<Id>>>, Id, F>,
+        C,
+    >
+    where
+        Self: Sized + FilterDsl<diesel::dsl::EqAny<Col, Vec<Id>>>,
+        U: Serialize + DeserializeOwned,
+        Col: ExpressionMethods,
+        Col::SqlType: diesel::sql_types::SqlType,
+        Vec<Id>: AsInExpression<Col::SqlType>,
+        Id: Clone + Eq + Hash + std::fmt::Debug,
+        F: Fn(&Id) -> String,
+    {
+        let expected_ids = if cfg!(debug_assertions) {
+            ids.clone()
+        } else {
+            Vec::new()
+        };
+        let query = IdKeyedQuery {
+            inner_select: self.filter(column.eq_any(ids)),
+            expected_ids,
+            key_fn,
+        };
+        SelectCachingWrapper::new(query, cache, None)
+    }
+
+    /// Populates the cache like `populate_cache`, but computes each row's
+    /// key in Rust with `key_fn` rather than selecting it in SQL.
+    ///
+    /// The query selects only the rows, and the key logic stays next to the
+    /// model, type-checked against it. `key_fn` is a closure, a function
+    /// such as `Student::cache_key`, or any other `KeyExtractor`:
+    ///
+    /// ```ignore
+    /// let results = students::table
+    ///     .select(Student::as_select())
+    ///     .populate_cache_with_key_fn(handle.clone(), |student: &Student| {
+    ///         format!("student:{}", student.id)
+    ///     })
+    ///     .load::<Student>(connection)?;
+    /// ```
+    fn populate_cache_with_key_fn<U, F>(
+        self,
+        cache: C,
+        key_fn: F,
+    ) -> SelectCachingWrapper<RowKeyedQuery<Self, F>, C>
+    where
+        Self: Sized,
+        U: Serialize + DeserializeOwned,
+        F: KeyExtractor<U>,
+    {
+        let query = RowKeyedQuery {
+            inner_select: self,
+            key_fn,
+        };
+        SelectCachingWrapper::new(query, cache, None)
+    }
+
+    /// Attempts to load results from the cache by the specified key.
+    ///
+    /// If the cache contains a value under the given key, that value is returned
+    /// without querying the database. Otherwise, the query is executed against
+    /// the database. The retrieved result is **not** inserted into the cache
+    /// after fallback to the database.
+    ///
+    /// Use this for read-through caching when you do not need to refresh
+    /// the cache if the key is missing.
+    fn try_from_cache<U>(
+        self,
+        cache: C,
+        key: impl IntoCacheKey<U>,
+    ) -> SelectCacheReadWrapper<Self, C, <Vec<String> as IntoIterator>::IntoIter>
+    where
+        Self: Sized,
+        U: Serialize + DeserializeOwned,
+    {
+        SelectCacheReadWrapper::new(self, vec![key.into_cache_key()].into_iter(), cache, false)
+    }
+
+    /// Attempts to load results from the cache by the specified key, and
+    /// if missing, loads from the database and populates the cache.
+    ///
+    /// This combines a cache lookup with automatic population: when the key
+    /// is missing, the fallback query runs against the database and then
+    /// inserts the result back into the cache for next time.
+    ///
+    /// This is helpful for classic read-through caching behavior.
+    fn try_from_cache_and_populate<U>(
+        self,
+        cache: C,
+        key: impl IntoCacheKey<U>,
+    ) -> SelectCacheReadWrapper<Self, C, <Vec<String> as IntoIterator>::IntoIter>
+    where
+        Self: Sized,
+        U: Serialize + DeserializeOwned,
+    {
+        SelectCacheReadWrapper::new(self, vec![key.into_cache_key()].into_iter(), cache, true)
+    }
+
+    /// Like `try_from_cache_and_populate`, but also remembers rows that do not
+    /// exist.
+    ///
+    /// When the database returns no row for the key, a not-found marker is
+    /// cached for `negative_ttl`. Until it expires (or the key is invalidated),
+    /// lookups return no rows without reaching the database. The marker is a
+    /// reserved value that a serialized row cannot be mistaken for, but it
+    /// requires a self-describing serializer such as JSON or MessagePack.
+    fn try_from_cache_negative<U>(
+        self,
+        cache: C,
+        key: impl IntoCacheKey<U>,
+        negative_ttl: Duration,
+    ) -> SelectCacheReadWrapper<Self, C, <Vec<String> as IntoIterator>::IntoIter>
+    where
+        Self: Sized,
+        U: Serialize + DeserializeOwned,
+    {
+        SelectCacheReadWrapper::new(self, vec![key.into_cache_key()].into_iter(), cache, true)
+            .with_negative_ttl(negative_ttl)
+    }
+
+    /// Same as `try_from_cache`, but records cache hits, misses and errors
+    /// into the shared `stats` as rows stream through.
+    ///
+    /// ```ignore
+    /// let stats = Arc::new(CacheStats::new());
+    /// let student = students::dsl::students
+    ///     .filter(students::dsl::id.eq(2))
+    ///     .try_from_cache_with_stats::<Student>(handle.clone(), "student:2", stats.clone())
+    ///     .get_result::<Student>(connection)?;
+    /// println!("{}", stats);
+    /// ```
+    fn try_from_cache_with_stats<U>(
+        self,
+        cache: C,
+        key: impl IntoCacheKey<U>,
+        stats: Arc<CacheStats>,
+    ) -> SelectCacheReadWrapper<Self, C, <Vec<String> as IntoIterator>::IntoIter>
+    where
+        Self: Sized,
+        U: Serialize + DeserializeOwned,
+    {
+        SelectCacheReadWrapper::new(self, vec![key.into_cache_key()].into_iter(), cache, false)
+            .with_stats(stats)
+    }
+
+    /// Same as `try_from_cache`, but a cache hit is deleted as soon as it is
+    /// read, so the value can be used only once, e.g. a single-use token.
+    ///
+    /// Only genuine hits are evicted; a row read from the database after a
+    /// miss leaves the cache untouched. A failed delete is logged and the
+    /// cached row is still returned.
+    fn try_from_cache_once<U>(
+        self,
+        cache: C,
+        key: impl IntoCacheKey<U>,
+    ) -> SelectCacheReadWrapper<Self, C, <Vec<String> as IntoIterator>::IntoIter>
+    where
+        Self: Sized,
+        U: Serialize + DeserializeOwned,
+    {
+        SelectCacheReadWrapper::new(self, vec![key.into_cache_key()].into_iter(), cache, false)
+            .evicting_hits()
+    }
+
+    /// Same as `try_from_cache_and_populate`, but the key is derived from the
+    /// query's SQL and bind parameters with `query_cache_key`, so none has
+    /// to be passed.
+    ///
+    /// Only use this for deterministic queries: SQL calling `now()`,
+    /// `random()` or similar gets the same key on every run, so the first
+    /// result would be served forever. Like `try_from_cache`, it yields at
+    /// most one row. To invalidate the entry, compute its key with
+    /// `query_cache_key` on the same query.
+    ///
+    /// ```ignore
+    /// let student = students::dsl::students
+    ///     .filter(students::dsl::id.eq(2))
+    ///     .select(Student::as_select())
+    ///     .try_from_cache_auto::<Student>(handle.clone())
+    ///     .get_result::<Student>(connection)?;
+    /// ```
+    fn try_from_cache_auto<U>(self, cache: C) -> SelectCacheAutoKeyWrapper<Self, C>
+    where
+        Self: Sized,
+        U: Serialize + DeserializeOwned,
+    {
+        SelectCacheAutoKeyWrapper {
+            inner_select: self,
+            cache,
+        }
+    }
+
+    /// Attempts to load results from the cache by multiple keys.
+    ///
+    /// Each provided key is checked against the cache. On cache misses,
+    /// the query is executed against the database and each miss is filled
+    /// with the row carrying its key, so the query may return rows in any
+    /// order. Missing results are **not** populated back into the cache.
+    ///
+    /// Like `populate_cache`, the query selects each row paired with its
+    /// cache key:
+    ///
+    /// ```ignore
+    /// let results = students::dsl::students
+    ///     .select((Student::as_select(), cache_key_expr("student", students::id)))
+    ///     .filter(students::dsl::id.eq_any(vec![1, 3]))
+    ///     .try_from_cache_multi::<Student, _>(handle.clone(), keys.into_iter())
+    ///     .load_iter::<Student, DefaultLoadingMode>(connection)?;
+    /// ```
+    ///
+    /// This is useful for batched reads where you want to check multiple
+    /// keys in a single pass.
+    fn try_from_cache_multi<U, K>(
+        self,
+        cache: C,
+        keys: K,
+    ) -> SelectCacheMultiReadWrapper<Self, C, K>
+    where
+        Self: Sized,
+        U: Serialize + DeserializeOwned,
+        K: Iterator<Item = String>,
+    {
+        SelectCacheMultiReadWrapper::new(self, keys, cache)
+    }
+
+    /// Executes the query and caches all of its rows together, as one
+    /// `Vec<U>`, under `key`.
+    ///
+    /// Use this for keys that stand for a list of rows rather than a single
+    /// one, e.g. the students of a class. Unlike `populate_cache`, the query
+    /// selects only the rows, and a query that returns no rows caches an
+    /// empty list.
+    ///
+    /// The list is only cached once all of it has been read, so every row is
+    /// loaded from the database before the first one is returned. With
+    /// `load_iter`, the rows then stream out of memory rather than from the
+    /// database cursor.
+    ///
+    /// ```ignore
+    /// let students = students::table
+    ///     .filter(students::class_id.eq(5))
+    ///     .select(Student::as_select())
+    ///     .populate_cache_list::<Student>(handle.clone(), "class:5:students")
+    ///     .load::<Student>(connection)?;
+    /// ```
+    fn populate_cache_list<U>(
+        self,
+        cache: C,
+        key: impl IntoCacheKey<Vec<U>>,
+    ) -> SelectListCacheWrapper<Self, C>
+    where
+        Self: Sized,
+        U: Serialize + DeserializeOwned,
+    {
+        SelectListCacheWrapper {
+            inner_select: self,
+            key: key.into_cache_key(),
+            cache,
+            read: false,
+            populate: true,
+        }
+    }
+
+    /// Attempts to load a list of rows cached by `populate_cache_list` under
+    /// `key`, falling back to the database if the key is missing.
+    ///
+    /// On a hit the whole cached list is returned without querying the
+    /// database. As with `try_from_cache`, rows read on a miss are not
+    /// cached, unless the query is itself wrapped by `populate_cache_list`.
+    /// Either way the list is read in full before the first row is returned.
+    ///
+    /// ```ignore
+    /// let students = students::table
+    ///     .filter(students::class_id.eq(5))
+    ///     .select(Student::as_select())
+    ///     .populate_cache_list::<Student>(handle.clone(), "class:5:students")
+    ///     .try_from_cache_list::<Student>(handle.clone(), "class:5:students")
+    ///     .load::<Student>(connection)?;
+    /// ```
+    fn try_from_cache_list<U>(
+        self,
+        cache: C,
+        key: impl IntoCacheKey<Vec<U>>,
+    ) -> SelectListCacheWrapper<Self, C>
+    where
+        Self: Sized,
+        U: Serialize + DeserializeOwned,
+    {
+        SelectListCacheWrapper {
+            inner_select: self,
+            key: key.into_cache_key(),
+            cache,
+            read: true,
+            populate: false,
+        }
+    }
+
+    /// Attempts to load results from the cache by the specified key, and
+    /// refreshes hot keys before they expire.
+    ///
+    /// On a miss the query runs and its row is cached for `ttl`. On a hit
+    /// the cached value is returned without querying the database; if less
+    /// than `threshold` of its TTL remains, `refresh` is also run on a
+    /// background thread and its row re-cached for a fresh `ttl`, so a
+    /// frequently read key never expires under load. The caller never waits
+    /// for the refresh, and a failed refresh is only logged. A key is
+    /// refreshed once at a time per process: hits while its refresh is in
+    /// flight do not start another.
+    ///
+    /// `refresh` must re-run the query on its own connection, e.g. one
+    /// checked out of a pool, and return `None` when the row no longer
+    /// exists, which deletes the key. A `threshold` of around a tenth of the
+    /// `ttl` suits most hot keys; a higher one refreshes earlier and more
+    /// often. Backends that do not track expiry never refresh ahead.
+    ///
+    /// ```ignore
+    /// let student = students::dsl::students
+    ///     .filter(students::dsl::id.eq(2))
+    ///     .select(Student::as_select())
+    ///     .try_from_cache_refresh_ahead::<Student, _>(
+    ///         handle.clone(),
+    ///         "student:2",
+    ///         Duration::from_secs(300),
+    ///         Duration::from_secs(30),
+    ///         move || {
+    ///             let mut con = pool.get().expect("no connection");
+    ///             students::dsl::students
+    ///                 .find(2)
+    ///                 .select(Student::as_select())
+    ///                 .first(&mut con)
+    ///                 .optional()
+    ///         },
+    ///     )
+    ///     .get_result::<Student>(connection)?;
+    /// ```
+    fn try_from_cache_refresh_ahead<U, F>(
+        self,
+        cache: C,
+        key: impl IntoCacheKey<U>,
+        ttl: Duration,
+        threshold: Duration,
+        refresh: F,
+    ) -> SelectCacheRefreshAheadWrapper<Self, C, F>
+    where
+        Self: Sized,
+        U: Serialize + DeserializeOwned,
+        F: FnOnce() -> QueryResult<Option<U>> + Send + 'static,
+    {
+        SelectCacheRefreshAheadWrapper {
+            inner_select: self,
+            key: key.into_cache_key(),
+            cache,
+            ttl,
+            threshold,
+            refresh,
+        }
+    }
+
+    /// Serves `key` from the cache until it is `hard_ttl` old, even once it
+    /// is past `soft_ttl` and stale. A stale read returns immediately and
+    /// re-reads the row on a background thread with `refresh`, caching it
+    /// afresh; a hard-expired or missing entry is read from the database like
+    /// a normal miss and cached. As with `try_from_cache_refresh_ahead`, the
+    /// stale reads of a key start one refresh at a time per process.
+    ///
+    /// `refresh` must re-run the query on its own connection, as for
+    /// `try_from_cache_refresh_ahead`, and return `None` when the row no
+    /// longer exists, which deletes the key. Entries are stored with both
+    /// expiry times alongside the row, so they can only be read back through
+    /// this method.
+    ///
+    /// ```ignore
+    /// let student = students::dsl::students
+    ///     .filter(students::dsl::id.eq(2))
+    ///     .select(Student::as_select())
+    ///     .try_from_cache_stale_while_revalidate::<Student, _>(
+    ///         handle.clone(),
+    ///         "student:2",
+    ///         Duration::from_secs(60),
+    ///         Duration::from_secs(600),
+    ///         move || {
+    ///             let mut con = pool.get().expect("no connection");
+    ///             students::dsl::students
+    ///                 .find(2)
+    ///                 .select(Student::as_select())
+    ///                 .first(&mut con)
+    ///                 .optional()
+    ///         },
+    ///     )
+    ///     .get_result::<Student>(connection)?;
+    /// ```
+    fn try_from_cache_stale_while_revalidate<U, F>(
+        self,
+        cache: C,
+        key: impl IntoCacheKey<U>,
+        soft_ttl: Duration,
+        hard_ttl: Duration,
+        refresh: F,
+    ) -> SelectCacheStaleWhileRevalidateWrapper<Self, C, F>
+    where
+        Self: Sized,
+        U: Serialize + DeserializeOwned,
+        F: FnOnce() -> QueryResult<Option<U>> + Send + 'static,
+    {
+        SelectCacheStaleWhileRevalidateWrapper {
+            inner_select: self,
+            key: key.into_cache_key(),
+            cache,
+            soft_ttl,
+            hard_ttl,
+            refresh,
+        }
+    }
+
+    /// Reads `key` through the cache like `try_from_cache_and_populate`,
+    /// caching rows for `ttl`, but keeps each entry for a `grace` window past
+    /// its `ttl`, as a fallback for when the database cannot be read. An
+    /// entry past its `ttl` is a miss and the row is read from the database;
+    /// only if that read fails, e.g. because the connection dropped, is the
+    /// expired row returned instead of the error.
+    ///
+    /// This trades freshness for availability: during an outage, callers get
+    /// rows up to `ttl + grace` old without noticing, and an update made
+    /// just before it may not be visible. The error is logged. Entries carry
+    /// their expiry times alongside the row, in the same format as
+    /// `try_from_cache_stale_while_revalidate`, so they can only be read
+    /// back through these two methods.
+    ///
+    /// ```ignore
+    /// let student = students::dsl::students
+    ///     .filter(students::dsl::id.eq(2))
+    ///     .select(Student::as_select())
+    ///     .try_from_cache_serve_stale_on_error::<Student>(
+    ///         handle.clone(),
+    ///         "student:2",
+    ///         Duration::from_secs(60),
+    ///         Duration::from_secs(3600),
+    ///     )
+    ///     .get_result::<Student>(connection)?;
+    /// ```
+    fn try_from_cache_serve_stale_on_error<U>(
+        self,
+        cache: C,
+        key: impl IntoCacheKey<U>,
+        ttl: Duration,
+        grace: Duration,
+    ) -> SelectCacheServeStaleWrapper<Self, C>
+    where
+        Self: Sized,
+        U: Serialize + DeserializeOwned,
+    {
+        SelectCacheServeStaleWrapper {
+            inner_select: self,
+            key: key.into_cache_key(),
+            cache,
+            ttl,
+            grace,
+        }
+    }
+}
+
+/// Provides extension methods for Diesel update statements that allow automatic
+/// cache key invalidation after the update executes.
+///
+/// Implemented for all Diesel update queries and every cache handle type `C`.
+pub trait WrappableUpdate<C: CacheHandle> {
+    /// Invalidates a single cache key along with a database update.
+    ///
+    /// This ensures consistency by deleting the given key from the cache
+    /// before the update, after it, or both, as chosen by `order`. Any
+    /// subsequent lookups will be forced to refetch fresh data from the
+    /// database. See `InvalidationOrder` for the races each order leaves.
+    ///
+    /// ```ignore
+    /// diesel::update(students::table)
+    ///     .set(students::dsl::name.eq("Ori2"))
+    ///     .filter(students::dsl::id.eq(2))
+    ///     .invalidate_key(handle.clone(), "student:2", InvalidationOrder::Both)
+    ///     .execute(connection)?;
+    /// ```
+    fn invalidate_key(
+        self,
+        cache: C,
+        key: &str,
+        order: InvalidationOrder,
+    ) -> UpdateWrapper<Self, <Vec<String> as IntoIterator>::IntoIter, C>
+    where
+        Self: Sized,
+    {
+        UpdateWrapper::new(self, vec![key.to_string()].into_iter(), cache, false, order)
+    }
+
+    /// Invalidates a single cache key before a database update, like
+    /// `invalidate_key` with `InvalidationOrder::Before`, but runs the update
+    /// even if the cache cannot be reached, as in the global mode set by
+    /// `set_best_effort`.
+    ///
+    /// The failure is logged, and the key may keep serving the old row until
+    /// it expires; see `set_best_effort` for the tradeoff.
+    fn invalidate_key_best_effort(
+        self,
+        cache: C,
+        key: &str,
+    ) -> UpdateWrapper<Self, <Vec<String> as IntoIterator>::IntoIter, C>
+    where
+        Self: Sized,
+    {
+        UpdateWrapper::new(
+            self,
+            vec![key.to_string()].into_iter(),
+            cache,
+            true,
+            InvalidationOrder::Before,
+        )
+    }
+
+    /// Invalidates multiple cache keys along with a database update, in the
+    /// given `order`.
+    ///
+    /// This removes all specified keys from the cache to maintain
+    /// consistency with the updated data in the database. Useful when
+    /// an update potentially affects multiple cached rows.
+    fn invalidate_keys<K>(
+        self,
+        cache: C,
+        keys: K,
+        order: InvalidationOrder,
+    ) -> UpdateWrapper<Self, K, C>
+    where
+        Self: Sized,
+        K: Iterator<Item = String>,
+    {
+        UpdateWrapper::new(self, keys, cache, false, order)
+    }
+
+    /// Queues a single cache key in `invalidations` once the update succeeds,
+    /// instead of deleting it right away, so it is only invalidated if the
+    /// surrounding transaction commits. See `DeferredInvalidations`.
+    fn defer_invalidate_key(
+        self,
+        invalidations: Arc<DeferredInvalidations<C>>,
+        key: &str,
+    ) -> DeferredUpdateWrapper<Self, <Vec<String> as IntoIterator>::IntoIter, C>
+    where
+        Self: Sized,
+    {
+        self.defer_invalidate_keys(invalidations, vec![key.to_string()].into_iter())
+    }
 
-    /// Populates the cache with results returned from the database query.
-    ///
-    /// After executing the query, each record is inserted into the cache
-    /// using its provided cache key, allowing subsequent lookups to
-    /// reuse cached results.
+    /// Queues multiple cache keys in `invalidations` once the update
+    /// succeeds; see `defer_invalidate_key`.
+    fn defer_invalidate_keys<K>(
+        self,
+        invalidations: Arc<DeferredInvalidations<C>>,
+        keys: K,
+    ) -> DeferredUpdateWrapper<Self, K, C>
+    where
+        Self: Sized,
+        K: Iterator<Item = String>,
+    {
+        DeferredUpdateWrapper {
+            inner_update: self,
+            keys,
+            invalidations,
+        }
+    }
+
+    /// Invalidates every cache key stored with `put_tagged` under `tag`
+    /// along with a database update.
     ///
-    /// The SQL query consumed by `populate_cache` should be a pair consisting
-    /// of the actual data row and a SQL expression that produces the cache key
-    /// for each row. For example, you can select a student row and
-    /// concatenate a cache prefix:
+    /// Like `invalidate_keys`, the keys are removed before the update runs,
+    /// and a cache failure returns `Error::RollbackTransaction`, so the
+    /// update is not applied when the cache could not be invalidated, unless
+    /// `set_best_effort` is on.
     ///
     /// ```ignore
-    /// let row_with_cache_key = (Student::as_select(), sql::<Text>("'student:' || id"));
-    /// let results = students::dsl::students
-    ///     .select(row_with_cache_key)
-    ///     .populate_cache::<Student>(handle.clone())
-    ///     .load_iter::<Student, DefaultLoadingMode>(connection)?;
+    /// diesel::update(students::table)
+    ///     .set(students::dsl::name.eq("Ori2"))
+    ///     .filter(students::dsl::id.eq(2))
+    ///     .invalidate_tag(handle.clone(), "student:2")
+    ///     .execute(connection)?;
     /// ```
+    fn invalidate_tag(self, cache: C, tag: &str) -> TagInvalidationWrapper<Self, C>
+    where
+        Self: Sized,
+    {
+        TagInvalidationWrapper::new(self, tag.to_string(), cache)
+    }
+
+    /// Invalidates every cache key matching `pattern`, with the wildcards of
+    /// `CacheHandle::scan_keys`, before running the update. Failures are
+    /// handled as in `invalidate_tag`.
     ///
-    /// You can also combine `populate_cache` with `try_from_cache` to benefit
-    /// from cache reads while still populating the cache if missing:
+    /// Use this sparingly: finding the matching keys visits the whole cache,
+    /// which on Redis means a `SCAN` of the entire keyspace on every update.
+    /// Known keys or tags are much cheaper when the update touches few rows.
     ///
     /// ```ignore
-    /// let results = students::dsl::students
-    ///     .select(row_with_cache_key)
-    ///     .populate_cache::<Student>(handle.clone())
-    ///     .try_from_cache::<Student>(handle.clone(), "student:2")
-    ///     .load_iter::<Student, DefaultLoadingMode>(connection)?;
+    /// diesel::update(students::table)
+    ///     .set(students::dsl::name.eq("Ori2"))
+    ///     .invalidate_pattern(handle.clone(), "student:*")
+    ///     .execute(connection)?;
     /// ```
-    fn populate_cache<U>(self, cache: Self::Cache) -> SelectCachingWrapper<Self, Self::Cache>
+    fn invalidate_pattern(self, cache: C, pattern: &str) -> PatternInvalidationWrapper<Self, C>
     where
         Self: Sized,
-        U: Serialize + DeserializeOwned,
     {
-        SelectCachingWrapper::new(self, cache)
+        PatternInvalidationWrapper::new(self, pattern.to_string(), cache)
     }
 
-    /// Attempts to load results from the cache by the specified key.
+    /// Writes the updated row into the cache under the given key, instead of
+    /// invalidating it.
     ///
-    /// If the cache contains a value under the given key, that value is returned
-    /// without querying the database. Otherwise, the query is executed against
-    /// the database. The retrieved result is **not** inserted into the cache
-    /// after fallback to the database.
+    /// The update must carry a `RETURNING` clause producing the new row, and is
+    /// run with `get_result` (or `load`/`get_results`). Once the database returns
+    /// the row, it is stored in the cache right away, so there is no window in
+    /// which a concurrent reader can repopulate the key with a stale value. An
+    /// update returning no row, or several, cannot tell which value belongs
+    /// under the key, so the key is invalidated instead. If the cache write
+    /// fails, the query returns `Error::RollbackTransaction`, unless
+    /// `set_best_effort` is on.
     ///
-    /// Use this for read-through caching when you do not need to refresh
-    /// the cache if the key is missing.
-    fn try_from_cache<'a, U>(
+    /// ```ignore
+    /// let student = diesel::update(students::table)
+    ///     .set(students::dsl::name.eq("Ori2"))
+    ///     .filter(students::dsl::id.eq(2))
+    ///     .returning(Student::as_returning())
+    ///     .write_through_update::<Student>(handle.clone(), "student:2")
+    ///     .get_result::<Student>(connection)?;
+    /// ```
+    fn write_through_update<U>(
         self,
-        cache: Self::Cache,
-        key: &'a str,
-    ) -> SelectCacheReadWrapper<Self, Self::Cache, <Vec<String> as IntoIterator>::IntoIter>
+        cache: C,
+        key: impl IntoCacheKey<U>,
+    ) -> WriteThroughUpdateWrapper<Self, C, U>
     where
         Self: Sized,
         U: Serialize + DeserializeOwned,
     {
-        SelectCacheReadWrapper::new(self, vec![key.to_string()].into_iter(), cache, false)
+        WriteThroughUpdateWrapper::new(self, key.into_cache_key(), cache)
     }
 
-    /// Attempts to load results from the cache by the specified key, and
-    /// if missing, loads from the database and populates the cache.
+    /// Writes every row returned by the update into the cache, each under
+    /// the key computed by `key_fn`, replacing the cached copies of the old
+    /// rows with a single `put_multi`.
     ///
-    /// This combines a cache lookup with automatic population: when the key
-    /// is missing, the fallback query runs against the database and then
-    /// inserts the result back into the cache for next time.
+    /// This is `write_through_update` for updates touching any number of
+    /// rows: unlike `invalidate_key`, the keys never go cold, so the next
+    /// reads are hits. The update must carry a `RETURNING` clause producing
+    /// the rows, and is run with `load`/`get_results` (or `get_result`).
+    /// Inside a transaction, the rows are cached before it commits; if it
+    /// rolls back, the cache holds rows that were never committed until they
+    /// are invalidated or expire. If the cache write fails, the query returns
+    /// `Error::RollbackTransaction`, unless `set_best_effort` is on.
     ///
-    /// This is helpful for classic read-through caching behavior.
-    fn try_from_cache_and_populate<'a, U>(
+    /// ```ignore
+    /// let students = diesel::update(students::table)
+    ///     .set(students::dsl::name.eq("Ori2"))
+    ///     .filter(students::dsl::id.eq_any([2, 3]))
+    ///     .returning(Student::as_returning())
+    ///     .refresh_from_returning(handle.clone(), |s: &Student| format!("student:{}", s.id))
+    ///     .get_results::<Student>(connection)?;
+    /// ```
+    fn refresh_from_returning<U, F>(
         self,
-        cache: Self::Cache,
-        key: &'a str,
-    ) -> SelectCacheReadWrapper<Self, Self::Cache, <Vec<String> as IntoIterator>::IntoIter>
+        cache: C,
+        key_fn: F,
+    ) -> RefreshFromReturningWrapper<Self, C, U, F>
     where
         Self: Sized,
         U: Serialize + DeserializeOwned,
+        F: KeyExtractor<U>,
     {
-        SelectCacheReadWrapper::new(self, vec![key.to_string()].into_iter(), cache, true)
+        RefreshFromReturningWrapper {
+            inner_update: self,
+            cache,
+            key_fn,
+            row: PhantomData,
+        }
     }
+}
 
-    /// Attempts to load results from the cache by multiple keys.
+/// Provides extension methods for Diesel insert statements that write the
+/// inserted rows into the cache.
+///
+/// Implemented for all Diesel insert queries and every cache handle type `C`.
+pub trait WrappableInsert<C: CacheHandle> {
+    /// Writes the rows returned by an insert into the cache, each under the
+    /// key computed by `key_fn`, saving the round trip of warming the cache
+    /// with a separate query.
     ///
-    /// Each provided key is checked against the cache. On cache misses,
-    /// the query is executed against the database for those rows only.
-    /// Missing results are **not** populated back into the cache.
+    /// The insert must carry a `RETURNING` clause producing the rows, and is
+    /// run with `get_result` (or `load`/`get_results`). An upsert with
+    /// `on_conflict(...).do_update()` returns the row as it is after the
+    /// update, which replaces any cached copy of the old row. As with
+    /// `write_through_update`, if the cache write fails the query returns
+    /// `Error::RollbackTransaction`, unless `set_best_effort` is on.
     ///
-    /// This is useful for batched reads where you want to check multiple
-    /// keys in a single pass.
-    fn try_from_cache_multi<U, K>(
-        self,
-        cache: Self::Cache,
-        keys: K,
-    ) -> SelectCacheReadWrapper<Self, Self::Cache, K>
+    /// ```ignore
+    /// let student = diesel::insert_into(students::table)
+    ///     .values(&new_student)
+    ///     .on_conflict(students::id)
+    ///     .do_update()
+    ///     .set(students::name.eq(excluded(students::name)))
+    ///     .returning(Student::as_returning())
+    ///     .populate_on_insert(handle.clone(), |s: &Student| format!("student:{}", s.id))
+    ///     .get_result::<Student>(connection)?;
+    /// ```
+    fn populate_on_insert<U, F>(self, cache: C, key_fn: F) -> InsertCachingWrapper<Self, C, U, F>
     where
         Self: Sized,
         U: Serialize + DeserializeOwned,
-        K: Iterator<Item = String>,
+        F: KeyExtractor<U>,
     {
-        SelectCacheReadWrapper::new(self, keys, cache, false)
+        InsertCachingWrapper {
+            inner_insert: self,
+            cache,
+            key_fn,
+            row: PhantomData,
+        }
     }
 }
 
-/// Provides extension methods for Diesel update statements that allow automatic
-/// cache key invalidation after the update executes.
+/// Provides extension methods for Diesel delete statements that invalidate
+/// the cache keys of the deleted rows.
 ///
-/// Implemented for all Diesel update queries.
-pub trait WrappableUpdate {
-    type Cache: CacheHandle;
-
-    /// Invalidates a single cache key after a database update.
+/// Implemented for all Diesel delete queries and every cache handle type `C`.
+pub trait WrappableDelete<C: CacheHandle> {
+    /// Deletes the cache key of every row returned by the delete, each
+    /// computed by `key_fn`, with a single `delete_multi`.
+    ///
+    /// Unlike `invalidate_key` on an update, the keys need not be known up
+    /// front: they come from the rows the database actually deleted, so a
+    /// delete filtered on anything but the key invalidates exactly the
+    /// affected entries. The delete must carry a `RETURNING` clause, which
+    /// may return just the columns the key is built from, and is run with
+    /// `load`/`get_results` (or `get_result`). The keys are deleted before
+    /// the transaction commits, so a concurrent reader may re-cache a row
+    /// the transaction is about to delete. If the cache delete fails, the
+    /// query returns `Error::RollbackTransaction`, unless `set_best_effort`
+    /// is on.
     ///
-    /// This ensures consistency by deleting the given key from the
-    /// cache after the update is executed. Any subsequent lookups will
-    /// be forced to refetch fresh data from the database.
-    fn invalidate_key<'a>(
+    /// ```ignore
+    /// let deleted = diesel::delete(students::table)
+    ///     .filter(students::dsl::dob.lt(cutoff))
+    ///     .returning(students::dsl::id)
+    ///     .invalidate_from_returning(handle.clone(), |id: &i32| Student::cache_key_for(*id))
+    ///     .get_results::<i32>(connection)?;
+    /// ```
+    fn invalidate_from_returning<U, F>(
         self,
-        cache: Self::Cache,
-        key: &'a str,
-    ) -> UpdateWrapper<Self, <Vec<String> as IntoIterator>::IntoIter, Self::Cache>
+        cache: C,
+        key_fn: F,
+    ) -> InvalidateFromReturningWrapper<Self, C, U, F>
     where
         Self: Sized,
+        F: KeyExtractor<U>,
     {
-        UpdateWrapper::new(self, vec![key.to_string()].into_iter(), cache)
+        InvalidateFromReturningWrapper {
+            inner_delete: self,
+            cache,
+            key_fn,
+            row: PhantomData,
+        }
     }
+}
 
-    /// Invalidates multiple cache keys after a database update.
-    ///
-    /// This removes all specified keys from the cache to maintain
-    /// consistency with the updated data in the database. Useful when
-    /// an update potentially affects multiple cached rows.
-    fn invalidate_keys<K>(self, cache: Self::Cache, keys: K) -> UpdateWrapper<Self, K, Self::Cache>
-    where
-        Self: Sized,
-        K: Iterator<Item = String>,
-    {
-        UpdateWrapper::new(self, keys, cache)
+impl<From, Select, Distinct, Where, Order, LimitOffset, GroupBy, Having, Locking, C>
+    WrappableQuery<C>
+    for SelectStatement<From, Select, Distinct, Where, Order, LimitOffset, GroupBy, Having, Locking>
+where
+    C: CacheHandle,
+{
+}
+
+/// Raw SQL from `diesel::sql_query`, whose rows are read by name with
+/// `QueryableByName`. There is no DSL expression to select a key alongside
+/// each row, so the key is given explicitly, e.g. to `try_from_cache_list`,
+/// or computed from the row by `populate_cache_with_key_fn`.
+///
+/// The type returned by `bind` cannot be named outside Diesel, so a query
+/// with parameters is boxed before it is wrapped:
+///
+/// ```ignore
+/// let report = diesel::sql_query("SELECT ... WHERE class_id = $1")
+///     .bind::<Integer, _>(5)
+///     .into_boxed()
+///     .try_from_cache_list::<ClassReport>(handle.clone(), "report:class:5")
+///     .load::<ClassReport>(connection)?;
+/// ```
+impl<Inner, C> WrappableQuery<C> for SqlQuery<Inner> where C: CacheHandle {}
+
+/// A boxed `diesel::sql_query`, e.g. with bound parameters, as for `SqlQuery`.
+impl<DB, Query, C> WrappableQuery<C> for BoxedSqlQuery<'_, DB, Query>
+where
+    DB: Backend,
+    C: CacheHandle,
+{
+}
+
+impl<T, C, C2, X> WrappableQuery<C2> for SelectCachingWrapper<T, C, X>
+where
+    C: CacheHandle,
+    C2: CacheHandle,
+{
+}
+
+impl<T, C, C2> WrappableQuery<C2> for SelectListCacheWrapper<T, C>
+where
+    C: CacheHandle,
+    C2: CacheHandle,
+{
+}
+
+impl<T, U, V, Ret, C> WrappableUpdate<C> for UpdateStatement<T, U, V, Ret>
+where
+    T: QuerySource,
+    C: CacheHandle,
+{
+}
+
+impl<T, U, Op, Ret, C> WrappableInsert<C> for InsertStatement<T, U, Op, Ret>
+where
+    T: Table,
+    C: CacheHandle,
+{
+}
+
+impl<T, U, Ret, C> WrappableDelete<C> for DeleteStatement<T, U, Ret>
+where
+    T: QuerySource,
+    C: CacheHandle,
+{
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cacher::{HashmapCache, HashmapCacheHandle};
+    use std::collections::HashMap;
+    use std::sync::atomic::AtomicUsize;
+
+    /// Cache handle that fails every lookup of one particular key, and every
+    /// `put_multi` or `put_multi_read_at` that includes it, and rejects
+    /// `put_read_at` of it as if it had been invalidated after the read.
+    #[derive(Clone)]
+    struct FlakyCache {
+        inner: HashmapCacheHandle,
+        failing_key: String,
+    }
+
+    impl CacheHandle for FlakyCache {
+        fn get<V: Serialize + DeserializeOwned>(
+            &self,
+            key: &String,
+        ) -> Result<Option<V>, CacheError> {
+            if *key == self.failing_key {
+                return Err(CacheError::new("Simulated cache failure"));
+            }
+            self.inner.get(key)
+        }
+
+        fn get_multi<V: Serialize + DeserializeOwned>(
+            &self,
+            keys: &[String],
+        ) -> Result<Vec<Option<V>>, CacheError> {
+            keys.iter().map(|key| self.get(key)).collect()
+        }
+
+        fn exists(&self, key: &String) -> Result<bool, CacheError> {
+            if *key == self.failing_key {
+                return Err(CacheError::new("Simulated cache failure"));
+            }
+            self.inner.exists(key)
+        }
+
+        fn put<V: Serialize + DeserializeOwned>(
+            &mut self,
+            key: &String,
+            value: &V,
+        ) -> Result<(), CacheError> {
+            self.inner.put(key, value)
+        }
+
+        fn put_multi<V: Serialize + DeserializeOwned>(
+            &mut self,
+            entries: &[(String, V)],
+        ) -> Result<(), CacheError> {
+            if entries.iter().any(|(key, _)| *key == self.failing_key) {
+                return Err(CacheError::new("Simulated cache failure"));
+            }
+            self.inner.put_multi(entries)
+        }
+
+        fn get_raw(&self, key: &String) -> Result<Option<Vec<u8>>, CacheError> {
+            self.inner.get_raw(key)
+        }
+
+        fn put_raw(&mut self, key: &String, bytes: &[u8]) -> Result<(), CacheError> {
+            self.inner.put_raw(key, bytes)
+        }
+
+        fn put_with_ttl<V: Serialize + DeserializeOwned>(
+            &mut self,
+            key: &String,
+            value: &V,
+            ttl: Duration,
+        ) -> Result<(), CacheError> {
+            self.inner.put_with_ttl(key, value, ttl)
+        }
+
+        fn put_read_at<V: Serialize + DeserializeOwned>(
+            &mut self,
+            key: &String,
+            value: &V,
+            ttl: Option<Duration>,
+            read_at: SystemTime,
+        ) -> Result<bool, CacheError> {
+            if *key == self.failing_key {
+                return Ok(false);
+            }
+            self.inner.put_read_at(key, value, ttl, read_at)
+        }
+
+        fn put_multi_read_at<V: Serialize + DeserializeOwned>(
+            &mut self,
+            entries: &[(String, V)],
+            read_at: SystemTime,
+        ) -> Result<usize, CacheError> {
+            if entries.iter().any(|(key, _)| *key == self.failing_key) {
+                return Err(CacheError::new("Simulated cache failure"));
+            }
+            self.inner.put_multi_read_at(entries, read_at)
+        }
+
+        fn put_if_absent<V: Serialize + DeserializeOwned>(
+            &mut self,
+            key: &String,
+            value: &V,
+        ) -> Result<bool, CacheError> {
+            self.inner.put_if_absent(key, value)
+        }
+
+        fn delete(&mut self, key: &String) -> Result<(), CacheError> {
+            self.inner.delete(key)
+        }
+
+        fn delete_multi(&mut self, keys: &[String]) -> Result<(), CacheError> {
+            self.inner.delete_multi(keys)
+        }
+
+        fn clear(&mut self) -> Result<(), CacheError> {
+            self.inner.clear()
+        }
+
+        fn scan_keys(&self, pattern: &str) -> Result<HashMap<String, String>, CacheError> {
+            self.inner.scan_keys(pattern)
+        }
+
+        fn count_keys(&self, pattern: &str) -> Result<usize, CacheError> {
+            self.inner.count_keys(pattern)
+        }
+
+        fn put_tagged<V: Serialize + DeserializeOwned>(
+            &mut self,
+            key: &String,
+            value: &V,
+            tags: &[&str],
+        ) -> Result<(), CacheError> {
+            self.inner.put_tagged(key, value, tags)
+        }
+
+        fn invalidate_tag(&mut self, tag: &str) -> Result<Vec<String>, CacheError> {
+            self.inner.invalidate_tag(tag)
+        }
+
+        fn health_check(&self) -> Result<(), CacheError> {
+            self.inner.health_check()
+        }
+    }
+
+    #[test]
+    fn test_lookup_continues_after_cache_error() {
+        let cache = HashmapCache::new();
+        let mut flaky = FlakyCache {
+            inner: cache.handle(),
+            failing_key: "row:3".to_string(),
+        };
+        flaky.put(&"row:1".to_string(), &"one".to_string()).unwrap();
+
+        // The inner query only yields the rows that were not found in the cache.
+        let db_rows = vec![Ok("two".to_string()), Ok("three".to_string())].into_iter();
+        let keys = ["row:1", "row:2", "row:3"].map(String::from).into_iter();
+        let stats = Arc::new(CacheStats::new());
+        let rows = ResultCacheLookupIterator::new(
+            db_rows,
+            flaky,
+            SerdeCodec,
+            keys,
+            false,
+            None,
+            Some(stats.clone()),
+            Span::none(),
+        )
+        .map(|row| row.unwrap())
+        .collect::<Vec<_>>();
+
+        assert_eq!(rows, vec!["one", "two", "three"]);
+        assert_eq!((stats.hits(), stats.misses(), stats.errors()), (1, 1, 1));
+    }
+
+    #[test]
+    fn test_wrapper_iterators_hint_sizes_and_fuse() {
+        let cache = HashmapCache::new();
+        let db_rows = (1..=3).map(|i| Ok((i.to_string(), SelectedCacheKey(Some(i.to_string())))));
+        let mut rows = ResultCachingIterator {
+            inner: db_rows.fuse(),
+            cache: cache.handle(),
+            codec: SerdeCodec,
+            ttl: None,
+            jitter: None,
+            stats: None,
+            span: Span::none(),
+            verbosity: LogVerbosity::PerRow,
+            read_at: SystemTime::now(),
+            counts: None,
+        };
+        assert_eq!(rows.size_hint(), (3, Some(3)));
+        rows.next();
+        assert_eq!(rows.size_hint(), (2, Some(2)));
+
+        // An inner iterator that resumes after `None` is not read again.
+        let mut calls = 0;
+        let resuming = std::iter::from_fn(|| {
+            calls += 1;
+            (calls > 1).then(|| {
+                Ok((
+                    "late".to_string(),
+                    SelectedCacheKey(Some("late".to_string())),
+                ))
+            })
+        });
+        let mut rows = ResultCachingIterator {
+            inner: resuming.fuse(),
+            cache: cache.handle(),
+            codec: SerdeCodec,
+            ttl: None,
+            jitter: None,
+            stats: None,
+            span: Span::none(),
+            verbosity: LogVerbosity::PerRow,
+            read_at: SystemTime::now(),
+            counts: None,
+        };
+        assert!(rows.next().is_none());
+        assert!(rows.next().is_none());
+        assert!(!cache.handle().exists(&"late".to_string()).unwrap());
+
+        // Bounded by the keys left, until a miss finds no row.
+        cache
+            .handle()
+            .put(&"row:1".to_string(), &"one".to_string())
+            .unwrap();
+        let keys = ["row:1", "row:2", "row:3"].map(String::from).into_iter();
+        let mut rows = ResultCacheLookupIterator::new(
+            std::iter::empty::<QueryResult<String>>(),
+            cache.handle(),
+            SerdeCodec,
+            keys,
+            false,
+            None,
+            None,
+            Span::none(),
+        );
+        assert_eq!(rows.size_hint(), (0, Some(3)));
+        assert_eq!(rows.next().unwrap().unwrap(), "one");
+        assert_eq!(rows.size_hint(), (0, Some(2)));
+        assert!(rows.next().is_none());
+        assert_eq!(rows.size_hint(), (0, Some(0)));
+        assert!(rows.next().is_none());
+    }
+
+    #[test]
+    fn test_multi_lookup_matches_rows_to_keys() {
+        let cache = HashmapCache::new();
+        cache
+            .handle()
+            .put(&"row:2".to_string(), &"two".to_string())
+            .unwrap();
+
+        // The inner query returns the misses out of key order, plus a row for
+        // a key that was already a hit; "row:4" has no row at all.
+        let db_rows = vec![
+            Ok(("three".to_string(), "row:3".to_string())),
+            Ok(("stale".to_string(), "row:2".to_string())),
+            Ok(("one".to_string(), "row:1".to_string())),
+        ]
+        .into_iter();
+        let keys = ["row:1", "row:2", "row:4", "row:3"]
+            .map(String::from)
+            .into_iter();
+        let stats = Arc::new(CacheStats::new());
+        let rows = ResultCacheMultiLookupIterator::new(
+            db_rows,
+            &cache.handle(),
+            keys,
+            Some(stats.clone()),
+            Span::none(),
+        )
+        .map(|row| row.unwrap())
+        .collect::<Vec<_>>();
+
+        assert_eq!(rows, vec!["one", "two", "three"]);
+        assert_eq!((stats.hits(), stats.misses()), (1, 3));
+    }
+
+    #[test]
+    fn test_negative_lookup_caches_missing_row() {
+        let cache = HashmapCache::new();
+        let key = || vec!["row:1".to_string()].into_iter();
+        let ttl = Some(Duration::from_secs(60));
+
+        let db_rows = Vec::<QueryResult<String>>::new().into_iter();
+        let rows = ResultCacheLookupIterator::new(
+            db_rows,
+            cache.handle(),
+            SerdeCodec,
+            key(),
+            true,
+            ttl,
+            None,
+            Span::none(),
+        );
+        assert_eq!(rows.count(), 0);
+        assert!(cache.handle().exists(&"row:1".to_string()).unwrap());
+
+        // The marker short-circuits the lookup, so the database row is never read.
+        let db_rows = vec![Ok("unexpected".to_string())].into_iter();
+        let rows = ResultCacheLookupIterator::new(
+            db_rows,
+            cache.handle(),
+            SerdeCodec,
+            key(),
+            true,
+            ttl,
+            None,
+            Span::none(),
+        );
+        assert_eq!(rows.count(), 0);
+
+        // A cached row is still returned as is.
+        cache
+            .handle()
+            .put(&"row:1".to_string(), &"one".to_string())
+            .unwrap();
+        let db_rows = Vec::<QueryResult<String>>::new().into_iter();
+        let rows = ResultCacheLookupIterator::new(
+            db_rows,
+            cache.handle(),
+            SerdeCodec,
+            key(),
+            true,
+            ttl,
+            None,
+            Span::none(),
+        )
+        .map(|row| row.unwrap())
+        .collect::<Vec<_>>();
+        assert_eq!(rows, vec!["one"]);
+    }
+
+    /// Row with a field derived from another, left out of the cached copy.
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Document {
+        body: String,
+        words: usize,
+    }
+
+    #[derive(Clone)]
+    struct DocumentCodec;
+
+    impl CacheCodec<Document> for DocumentCodec {
+        type Cached = String;
+        type Encoded<'a> = &'a String;
+
+        fn encode<'a>(&'a self, row: &'a Document) -> Result<&'a String, CacheError> {
+            Ok(&row.body)
+        }
+
+        fn decode(&self, body: String) -> Result<Document, CacheError> {
+            let words = body.split_whitespace().count();
+            Ok(Document { body, words })
+        }
+    }
+
+    #[test]
+    fn test_lookup_with_codec_stores_encoded_row() {
+        let cache = HashmapCache::new();
+        let key = || vec!["doc:1".to_string()].into_iter();
+        let document = || Document {
+            body: "a cached document".to_string(),
+            words: 3,
+        };
+
+        let db_rows = vec![Ok(document())].into_iter();
+        let rows = ResultCacheLookupIterator::new(
+            db_rows,
+            cache.handle(),
+            DocumentCodec,
+            key(),
+            true,
+            None,
+            None,
+            Span::none(),
+        );
+        assert_eq!(
+            rows.map(|row| row.unwrap()).collect::<Vec<_>>(),
+            vec![document()]
+        );
+        assert_eq!(
+            cache.handle().get::<String>(&"doc:1".to_string()).unwrap(),
+            Some("a cached document".to_string())
+        );
+
+        // The hit is decoded by the codec, rebuilding the derived field.
+        let db_rows = Vec::<QueryResult<Document>>::new().into_iter();
+        let rows = ResultCacheLookupIterator::new(
+            db_rows,
+            cache.handle(),
+            DocumentCodec,
+            key(),
+            false,
+            None,
+            None,
+            Span::none(),
+        );
+        assert_eq!(
+            rows.map(|row| row.unwrap()).collect::<Vec<_>>(),
+            vec![document()]
+        );
+    }
+
+    #[test]
+    fn test_refresh_ahead_recaches_or_deletes_key() {
+        let cache = HashmapCache::new();
+        let key = "row:1".to_string();
+        cache
+            .handle()
+            .put_with_ttl(&key, &"old".to_string(), Duration::from_secs(1))
+            .unwrap();
+
+        let ttl = Duration::from_secs(60);
+        spawn_refresh(cache.handle(), key.clone(), ttl, || {
+            Ok(Some("new".to_string()))
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+        let (value, remaining) = cache
+            .handle()
+            .get_with_ttl::<String>(&key)
+            .unwrap()
+            .unwrap();
+        assert_eq!(value, "new");
+        assert!(remaining.is_some_and(|r| r > Duration::from_secs(50)));
+
+        // A row that no longer exists is evicted rather than served until expiry.
+        spawn_refresh(cache.handle(), key.clone(), ttl, || Ok(None::<String>))
+            .unwrap()
+            .join()
+            .unwrap();
+        assert!(!cache.handle().exists(&key).unwrap());
+    }
+
+    #[test]
+    fn test_concurrent_hits_start_one_refresh() {
+        let cache = HashmapCache::new();
+        let key = "row:2".to_string();
+        let ttl = Duration::from_secs(60);
+        let refreshes = Arc::new(AtomicUsize::new(0));
+        let (release, released) = std::sync::mpsc::channel::<()>();
+
+        let first = {
+            let refreshes = Arc::clone(&refreshes);
+            spawn_refresh(cache.handle(), key.clone(), ttl, move || {
+                refreshes.fetch_add(1, Ordering::SeqCst);
+                released.recv().unwrap();
+                Ok(Some("new".to_string()))
+            })
+            .unwrap()
+        };
+        let hits = (0..8)
+            .map(|_| {
+                let (handle, key, refreshes) =
+                    (cache.handle(), key.clone(), Arc::clone(&refreshes));
+                std::thread::spawn(move || {
+                    spawn_refresh(handle, key, ttl, move || {
+                        refreshes.fetch_add(1, Ordering::SeqCst);
+                        Ok(Some("other".to_string()))
+                    })
+                    .is_none()
+                })
+            })
+            .collect::<Vec<_>>();
+        for hit in hits {
+            assert!(hit.join().unwrap(), "A second refresh was started");
+        }
+        release.send(()).unwrap();
+        first.join().unwrap();
+        assert_eq!(refreshes.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            cache.handle().get::<String>(&key).unwrap(),
+            Some("new".to_string())
+        );
+
+        // Once the refresh is done, the next hit can start another.
+        spawn_refresh(cache.handle(), key.clone(), ttl, || Ok(None::<String>))
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_ttl_jitter_spreads_row_ttls() {
+        let base = Duration::from_secs(600);
+        let max = Duration::from_secs(60);
+        let draws = |seed| {
+            let mut jitter = TtlJitter::new(max, Some(seed));
+            (0..5).map(|_| jitter.apply(base)).collect::<Vec<_>>()
+        };
+        assert_eq!(draws(7), draws(7));
+        assert_ne!(draws(7), draws(8));
+
+        let cache = HashmapCache::new();
+        let db_rows =
+            (1..=5).map(|i| Ok((i.to_string(), SelectedCacheKey(Some(format!("row:{}", i))))));
+        let rows = ResultCachingIterator {
+            inner: db_rows.fuse(),
+            cache: cache.handle(),
+            codec: SerdeCodec,
+            ttl: Some(base),
+            jitter: Some(TtlJitter::new(max, Some(7))),
+            stats: None,
+            span: Span::none(),
+            verbosity: LogVerbosity::PerRow,
+            read_at: SystemTime::now(),
+            counts: None,
+        };
+        assert_eq!(rows.count(), 5);
+        for i in 1..=5 {
+            let (_, remaining) = cache
+                .handle()
+                .get_with_ttl::<String>(&format!("row:{}", i))
+                .unwrap()
+                .unwrap();
+            assert!(
+                remaining.is_some_and(|r| r > base - Duration::from_secs(5) && r <= base + max)
+            );
+        }
+    }
+
+    #[test]
+    fn test_row_ttl_population_uses_each_rows_ttl() {
+        let cache = HashmapCache::new();
+        let db_rows = [Some(60), None, Some(0)]
+            .into_iter()
+            .enumerate()
+            .map(|(i, ttl)| {
+                let key = SelectedCacheKey(Some(format!("row:{}", i)));
+                Ok((i.to_string(), key, SelectedTtl(ttl)))
+            });
+        let rows = ResultRowTtlCachingIterator {
+            inner: db_rows,
+            cache: cache.handle(),
+            stats: None,
+            span: Span::none(),
+            read_at: SystemTime::now(),
+        };
+        assert_eq!(
+            rows.collect::<QueryResult<Vec<_>>>().unwrap(),
+            ["0", "1", "2"]
+        );
+
+        let handle = cache.handle();
+        let (_, remaining) = handle
+            .get_with_ttl::<String>(&"row:0".to_string())
+            .unwrap()
+            .unwrap();
+        assert!(remaining.is_some_and(|r| r > Duration::from_secs(55)));
+        // A NULL TTL never expires, and a zero TTL is not cached at all.
+        assert_eq!(
+            handle.get_with_ttl::<String>(&"row:1".to_string()).unwrap(),
+            Some(("1".to_string(), None))
+        );
+        assert!(!handle.exists(&"row:2".to_string()).unwrap());
+
+        // A row invalidated after the query started is returned, not cached.
+        let stats = Arc::new(CacheStats::new());
+        let db_rows = (3..=4).map(|i| {
+            let key = SelectedCacheKey(Some(format!("row:{}", i)));
+            Ok((i.to_string(), key, SelectedTtl(Some(60))))
+        });
+        let rows = ResultRowTtlCachingIterator {
+            inner: db_rows,
+            cache: FlakyCache {
+                inner: cache.handle(),
+                failing_key: "row:4".to_string(),
+            },
+            stats: Some(stats.clone()),
+            span: Span::none(),
+            read_at: SystemTime::now(),
+        };
+        assert_eq!(rows.count(), 2);
+        assert_eq!(stats.populated(), 1);
+        assert!(handle.exists(&"row:3".to_string()).unwrap());
+        assert!(!handle.exists(&"row:4".to_string()).unwrap());
+    }
+
+    #[test]
+    fn test_quiet_population_counts_rows() {
+        let cache = HashmapCache::new();
+        let db_rows = (1..=3).map(|i| {
+            let key = (i != 2).then(|| format!("row:{}", i));
+            Ok((i.to_string(), SelectedCacheKey(key)))
+        });
+        let mut rows = ResultCachingIterator {
+            inner: db_rows.fuse(),
+            cache: cache.handle(),
+            codec: SerdeCodec,
+            ttl: None,
+            jitter: None,
+            stats: None,
+            span: Span::none(),
+            verbosity: LogVerbosity::Quiet,
+            read_at: SystemTime::now(),
+            counts: Some(PopulateCounts::default()),
+        };
+        assert_eq!(rows.by_ref().take(3).count(), 3);
+        assert_eq!(rows.counts, Some(PopulateCounts { read: 3, cached: 2 }));
+        // The totals are logged, and cleared, once the rows run out.
+        assert!(rows.next().is_none());
+        assert_eq!(rows.counts, None);
+        assert_eq!(cache.handle().count_keys("row:*").unwrap(), 2);
+    }
+
+    #[test]
+    fn test_population_skips_rows_invalidated_after_read() {
+        let cache = HashmapCache::new();
+        let db_rows = (1..=3).map(|i| Ok((i.to_string(), SelectedCacheKey(Some(format!("row:{}", i))))));
+        let mut rows = ResultCachingIterator {
+            inner: db_rows.fuse(),
+            cache: FlakyCache {
+                inner: cache.handle(),
+                failing_key: "row:2".to_string(),
+            },
+            codec: SerdeCodec,
+            ttl: None,
+            jitter: None,
+            stats: None,
+            span: Span::none(),
+            verbosity: LogVerbosity::PerRow,
+            read_at: SystemTime::now(),
+            counts: Some(PopulateCounts::default()),
+        };
+        // The rejected row is still returned, but not counted as cached.
+        assert_eq!(rows.by_ref().take(3).count(), 3);
+        assert_eq!(rows.counts, Some(PopulateCounts { read: 3, cached: 2 }));
+        assert!(!cache.handle().exists(&"row:2".to_string()).unwrap());
+    }
+
+    #[test]
+    fn test_batched_population_flushes_full_and_last_batches() {
+        let cache = HashmapCache::new();
+        let db_rows =
+            (1..=5).map(|i| Ok((i.to_string(), SelectedCacheKey(Some(format!("row:{}", i))))));
+        let mut rows = BatchCachingIterator {
+            inner: db_rows,
+            cache: cache.handle(),
+            batch_size: 2,
+            pending: Vec::new(),
+            failed: None,
+            span: Span::none(),
+            read_at: SystemTime::now(),
+        };
+        let cached = || cache.handle().count_keys("row:*").unwrap();
+
+        assert_eq!(rows.next().unwrap().unwrap(), "1");
+        assert_eq!(cached(), 0);
+        assert_eq!(rows.next().unwrap().unwrap(), "2");
+        assert_eq!(cached(), 2);
+        assert_eq!(rows.next().unwrap().unwrap(), "3");
+        assert_eq!(cached(), 2);
+        // Dropping the iterator early still writes the partial batch.
+        drop(rows);
+        assert_eq!(cached(), 3);
+
+        let flaky = FlakyCache {
+            inner: cache.handle(),
+            failing_key: "row:7".to_string(),
+        };
+        let db_rows =
+            (6..=8).map(|i| Ok((i.to_string(), SelectedCacheKey(Some(format!("row:{}", i))))));
+        let rows = BatchCachingIterator {
+            inner: db_rows,
+            cache: flaky,
+            batch_size: 2,
+            pending: Vec::new(),
+            failed: None,
+            span: Span::none(),
+            read_at: SystemTime::now(),
+        }
+        .collect::<Vec<_>>();
+        assert_eq!(rows.len(), 4);
+        assert!(matches!(
+            rows[2],
+            Err(diesel::result::Error::SerializationError(_))
+        ));
+        assert_eq!(rows[3].as_ref().unwrap(), "8");
+    }
+
+    #[test]
+    fn test_stale_while_revalidate_entry_freshness() {
+        let entry = StaleWhileRevalidateEntry::new(
+            "row".to_string(),
+            Duration::from_secs(10),
+            Duration::from_secs(20),
+        );
+        assert_eq!(entry.freshness(unix_millis()), Freshness::Fresh);
+        assert_eq!(entry.freshness(entry.stale_at), Freshness::Stale);
+        assert_eq!(entry.freshness(entry.expires_at - 1), Freshness::Stale);
+        assert_eq!(entry.freshness(entry.expires_at), Freshness::Expired);
+    }
+
+    #[test]
+    fn test_lookup_once_evicts_only_hits() {
+        let cache = HashmapCache::new();
+        let key = || vec!["row:1".to_string()].into_iter();
+        cache
+            .handle()
+            .put(&"row:1".to_string(), &"one".to_string())
+            .unwrap();
+
+        let db_rows = vec![Ok("db".to_string())].into_iter();
+        let rows = ResultCacheLookupIterator::new(
+            db_rows,
+            cache.handle(),
+            SerdeCodec,
+            key(),
+            false,
+            None,
+            None,
+            Span::none(),
+        )
+        .evicting_hits()
+        .map(|row| row.unwrap())
+        .collect::<Vec<_>>();
+        assert_eq!(rows, vec!["one"]);
+        assert!(!cache.handle().exists(&"row:1".to_string()).unwrap());
+
+        // A database fallback neither evicts nor populates.
+        let db_rows = vec![Ok("db".to_string())].into_iter();
+        let rows = ResultCacheLookupIterator::new(
+            db_rows,
+            cache.handle(),
+            SerdeCodec,
+            key(),
+            false,
+            None,
+            None,
+            Span::none(),
+        )
+        .evicting_hits()
+        .map(|row| row.unwrap())
+        .collect::<Vec<_>>();
+        assert_eq!(rows, vec!["db"]);
+        assert!(cache.handle().is_empty());
+    }
+
+    /// Key logic that needs more than the row, reused by several wrappers.
+    struct PrefixedKey(&'static str);
+
+    impl KeyExtractor<String> for PrefixedKey {
+        fn extract_key(&self, row: &String) -> String {
+            format!("{}:{}", self.0, row)
+        }
+    }
+
+    #[test]
+    fn test_custom_key_extractor_keys_returned_rows() {
+        let cache = HashmapCache::new();
+        let extractor = PrefixedKey("row");
+        let db_rows = ["1", "2"].map(|row| Ok(row.to_string())).into_iter();
+        let rows = cache_returned_rows(db_rows, &mut cache.handle(), &extractor, "inserted")
+            .unwrap()
+            .collect::<QueryResult<Vec<_>>>()
+            .unwrap();
+        assert_eq!(rows, ["1", "2"]);
+        assert_eq!(
+            cache.handle().get::<String>(&"row:2".to_string()).unwrap(),
+            Some("2".to_string())
+        );
+
+        let keyed = RowKeyIterator {
+            inner: std::iter::once(Ok("3".to_string())),
+            key_fn: extractor,
+        }
+        .collect::<QueryResult<Vec<_>>>()
+        .unwrap();
+        assert_eq!(keyed[0].1.0, Some("row:3".to_string()));
     }
 }
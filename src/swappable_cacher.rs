@@ -0,0 +1,274 @@
+use crate::cacher::{CacheError, CacheHandle, EntryInfo};
+use crate::dyn_cacher::DynCacheHandle;
+use arc_swap::ArcSwap;
+use log::info;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+
+/// Cache handle whose backend can be replaced at runtime, e.g. to migrate
+/// from the in-memory cache to Redis, or to fail over to another Redis,
+/// without restarting.
+///
+/// `set_backend` on any clone switches every clone: each one picks up the
+/// new backend at the start of its next operation. An operation reads the
+/// backend once, when it is called, and runs to completion on it, so one
+/// that is in flight during the swap still finishes on the old backend.
+/// Entries are not copied over; the new backend starts as it is, and the
+/// statement wrappers repopulate it from the database as they miss. Keep
+/// writes to both backends with a `ChainedCache` for a while before
+/// swapping if the new one must start warm.
+///
+/// ```ignore
+/// let handle = SwappableCache::new(Box::new(HashmapCache::new().handle()));
+/// let results = students::table
+///     .select((Student::as_select(), cache_key_expr("student", students::id)))
+///     .populate_cache(handle.clone())
+///     .load::<Student>(connection)?;
+///
+/// // Later, e.g. from an admin endpoint:
+/// handle.set_backend(Box::new(RedisCache::new(&redis_url)?.handle()));
+/// ```
+///
+/// Like the `DynCacheHandle` it holds, it can be sent to another thread but
+/// not shared between threads; clone it instead.
+pub struct SwappableCache {
+    backend: Arc<ArcSwap<Mutex<DynCacheHandle>>>,
+    local: RefCell<LocalBackend>,
+}
+
+/// This clone's own copy of the shared backend, and the backend it was
+/// copied from.
+struct LocalBackend {
+    source: Arc<Mutex<DynCacheHandle>>,
+    handle: DynCacheHandle,
+}
+
+impl LocalBackend {
+    fn copy(source: Arc<Mutex<DynCacheHandle>>) -> Self {
+        let handle = lock(&source).clone();
+        LocalBackend { source, handle }
+    }
+}
+
+fn lock(backend: &Mutex<DynCacheHandle>) -> MutexGuard<'_, DynCacheHandle> {
+    // The backend is only cloned under the lock, which cannot leave it
+    // half-updated.
+    backend
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl SwappableCache {
+    pub fn new(backend: DynCacheHandle) -> Self {
+        let source = Arc::new(Mutex::new(backend));
+        SwappableCache {
+            backend: Arc::new(ArcSwap::new(Arc::clone(&source))),
+            local: RefCell::new(LocalBackend::copy(source)),
+        }
+    }
+
+    /// Replaces the backend of this handle and all its clones.
+    pub fn set_backend(&self, backend: DynCacheHandle) {
+        info!("Swapping cache backend");
+        self.backend.store(Arc::new(Mutex::new(backend)));
+    }
+
+    /// Runs `f` on the current backend, first copying it if it was swapped
+    /// since the last operation of this clone.
+    fn with_backend<R>(&self, f: impl FnOnce(&mut DynCacheHandle) -> R) -> R {
+        let current = self.backend.load();
+        let mut local = self.local.borrow_mut();
+        if !Arc::ptr_eq(&*current, &local.source) {
+            *local = LocalBackend::copy(Arc::clone(&*current));
+        }
+        drop(current);
+        f(&mut local.handle)
+    }
+}
+
+impl Clone for SwappableCache {
+    fn clone(&self) -> Self {
+        SwappableCache {
+            backend: Arc::clone(&self.backend),
+            local: RefCell::new(LocalBackend::copy(self.backend.load_full())),
+        }
+    }
+}
+
+impl CacheHandle for SwappableCache {
+    fn get<V: Serialize + DeserializeOwned>(&self, key: &String) -> Result<Option<V>, CacheError> {
+        self.with_backend(|backend| backend.get(key))
+    }
+
+    fn get_multi<V: Serialize + DeserializeOwned>(
+        &self,
+        keys: &[String],
+    ) -> Result<Vec<Option<V>>, CacheError> {
+        self.with_backend(|backend| backend.get_multi(keys))
+    }
+
+    fn get_with_ttl<V: Serialize + DeserializeOwned>(
+        &self,
+        key: &String,
+    ) -> Result<Option<(V, Option<Duration>)>, CacheError> {
+        self.with_backend(|backend| backend.get_with_ttl(key))
+    }
+
+    fn exists(&self, key: &String) -> Result<bool, CacheError> {
+        self.with_backend(|backend| backend.exists(key))
+    }
+
+    fn entry_info(&self, key: &String) -> Result<Option<EntryInfo>, CacheError> {
+        self.with_backend(|backend| backend.entry_info(key))
+    }
+
+    fn put<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+    ) -> Result<(), CacheError> {
+        self.with_backend(|backend| backend.put(key, value))
+    }
+
+    fn get_raw(&self, key: &String) -> Result<Option<Vec<u8>>, CacheError> {
+        self.with_backend(|backend| backend.get_raw(key))
+    }
+
+    fn put_raw(&mut self, key: &String, bytes: &[u8]) -> Result<(), CacheError> {
+        self.with_backend(|backend| backend.put_raw(key, bytes))
+    }
+
+    fn put_with_ttl<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+        ttl: Duration,
+    ) -> Result<(), CacheError> {
+        self.with_backend(|backend| backend.put_with_ttl(key, value, ttl))
+    }
+
+    fn put_multi<V: Serialize + DeserializeOwned>(
+        &mut self,
+        entries: &[(String, V)],
+    ) -> Result<(), CacheError> {
+        self.with_backend(|backend| backend.put_multi(entries))
+    }
+
+    fn put_if_absent<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+    ) -> Result<bool, CacheError> {
+        self.with_backend(|backend| backend.put_if_absent(key, value))
+    }
+
+    fn compare_and_set<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        expected_version: u64,
+        value: &V,
+    ) -> Result<bool, CacheError> {
+        self.with_backend(|backend| backend.compare_and_set(key, expected_version, value))
+    }
+
+    fn put_read_at<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+        ttl: Option<Duration>,
+        read_at: SystemTime,
+    ) -> Result<bool, CacheError> {
+        self.with_backend(|backend| backend.put_read_at(key, value, ttl, read_at))
+    }
+
+    fn put_multi_read_at<V: Serialize + DeserializeOwned>(
+        &mut self,
+        entries: &[(String, V)],
+        read_at: SystemTime,
+    ) -> Result<usize, CacheError> {
+        self.with_backend(|backend| backend.put_multi_read_at(entries, read_at))
+    }
+
+    fn delete(&mut self, key: &String) -> Result<(), CacheError> {
+        self.with_backend(|backend| backend.delete(key))
+    }
+
+    fn delete_multi(&mut self, keys: &[String]) -> Result<(), CacheError> {
+        self.with_backend(|backend| backend.delete_multi(keys))
+    }
+
+    fn delete_multi_counted(&mut self, keys: &[String]) -> Result<usize, CacheError> {
+        self.with_backend(|backend| backend.delete_multi_counted(keys))
+    }
+
+    fn delete_pattern(&mut self, pattern: &str) -> Result<usize, CacheError> {
+        self.with_backend(|backend| backend.delete_pattern(pattern))
+    }
+
+    fn clear(&mut self) -> Result<(), CacheError> {
+        self.with_backend(|backend| backend.clear())
+    }
+
+    fn scan_keys(&self, pattern: &str) -> Result<HashMap<String, String>, CacheError> {
+        self.with_backend(|backend| backend.scan_keys(pattern))
+    }
+
+    fn list_keys(&self, pattern: &str) -> Result<Vec<String>, CacheError> {
+        self.with_backend(|backend| backend.list_keys(pattern))
+    }
+
+    fn count_keys(&self, pattern: &str) -> Result<usize, CacheError> {
+        self.with_backend(|backend| backend.count_keys(pattern))
+    }
+
+    fn put_tagged<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+        tags: &[&str],
+    ) -> Result<(), CacheError> {
+        self.with_backend(|backend| backend.put_tagged(key, value, tags))
+    }
+
+    fn invalidate_tag(&mut self, tag: &str) -> Result<Vec<String>, CacheError> {
+        self.with_backend(|backend| backend.invalidate_tag(tag))
+    }
+
+    fn health_check(&self) -> Result<(), CacheError> {
+        self.with_backend(|backend| backend.health_check())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cacher::HashmapCache;
+
+    #[test]
+    fn test_clones_pick_up_the_new_backend() {
+        let memory = HashmapCache::new();
+        let replacement = HashmapCache::new();
+        let mut handle = SwappableCache::new(Box::new(memory.handle()));
+        let other = handle.clone();
+        let key = "student:2".to_string();
+
+        handle.put(&key, &"Ori".to_string()).unwrap();
+        assert_eq!(other.get::<String>(&key).unwrap(), Some("Ori".to_string()));
+
+        // Swapped through a clone, and seen by a clone on another thread.
+        other.set_backend(Box::new(replacement.handle()));
+        let moved = handle.clone();
+        let read = std::thread::spawn(move || moved.get::<String>(&key).unwrap());
+        assert_eq!(read.join().unwrap(), None);
+
+        let key = "student:3".to_string();
+        handle.put(&key, &"Dan".to_string()).unwrap();
+        assert_eq!(other.get::<String>(&key).unwrap(), Some("Dan".to_string()));
+        assert_eq!(replacement.handle().count_keys("student:*").unwrap(), 1);
+        assert_eq!(memory.handle().count_keys("student:*").unwrap(), 1);
+    }
+}
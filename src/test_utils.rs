@@ -1,4 +1,11 @@
+use crate::cache_key::SelectedCacheKey;
+use crate::cacher::CacheHandle;
+use diesel::query_dsl::{LoadQuery, RunQueryDsl};
 use dotenvy::dotenv;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap};
 use std::sync::Once;
 
 static INIT: Once = Once::new();
@@ -14,3 +21,105 @@ pub fn init_logging_for_tests() {
             .try_init();
     });
 }
+
+/// Asserts that the rows cached under `keys` match the database.
+///
+/// `query` selects each row with its cache key, like the queries passed to
+/// `populate_cache`. A key that is not cached is coherent; a cached row that
+/// differs from its database row, or that has no database row, is stale.
+/// Rows are compared field by field through their serde representation, and
+/// the panic message lists every stale key with the fields that differ:
+///
+/// ```text
+/// Cache is not coherent with the database for 1 of 2 keys:
+///   student:100: name: cached "Noa", database "Noa2"
+/// ```
+///
+/// ```ignore
+/// assert_cache_coherent::<Student, _, _, _>(
+///     &handle,
+///     connection,
+///     students::table.select((Student::as_select(), cache_key_expr("student", students::id))),
+///     &["student:100", "student:101"],
+/// );
+/// ```
+pub fn assert_cache_coherent<'query, U, C, Conn, Q>(
+    handle: &C,
+    conn: &mut Conn,
+    query: Q,
+    keys: &[&str],
+) where
+    U: Serialize + DeserializeOwned,
+    C: CacheHandle,
+    Q: RunQueryDsl<Conn> + LoadQuery<'query, Conn, (U, SelectedCacheKey)>,
+{
+    let stored = query
+        .load::<(U, SelectedCacheKey)>(conn)
+        .expect("Failed to load rows from the database")
+        .into_iter()
+        .filter_map(|(row, SelectedCacheKey(key))| Some((key?, row)))
+        .collect::<HashMap<_, _>>();
+
+    let mut stale = Vec::new();
+    for &key in keys {
+        let cached = match handle.get::<U>(&key.to_string()) {
+            Ok(Some(cached)) => cached,
+            Ok(None) => continue,
+            Err(e) => {
+                stale.push(format!("{}: cached value could not be read: {}", key, e));
+                continue;
+            }
+        };
+        match stored.get(key) {
+            Some(row) => {
+                let diffs = field_diffs(&to_json(&cached), &to_json(row));
+                if !diffs.is_empty() {
+                    stale.push(format!("{}: {}", key, diffs.join(", ")));
+                }
+            }
+            None => stale.push(format!("{}: cached, but no row in the database", key)),
+        }
+    }
+    assert!(
+        stale.is_empty(),
+        "Cache is not coherent with the database for {} of {} keys:\n  {}",
+        stale.len(),
+        keys.len(),
+        stale.join("\n  ")
+    );
+}
+
+fn to_json<U: Serialize>(row: &U) -> Value {
+    serde_json::to_value(row).expect("Failed to convert row to JSON")
+}
+
+/// Describes each top-level field that differs between `cached` and
+/// `stored`. Fields of rows serialized as tuples are named by position, e.g.
+/// `[1]`; other values are compared whole.
+fn field_diffs(cached: &Value, stored: &Value) -> Vec<String> {
+    let diff = |field: String, cached: Option<&Value>, stored: Option<&Value>| {
+        let show = |value: Option<&Value>| value.map_or("<missing>".to_string(), Value::to_string);
+        (cached != stored).then(|| {
+            format!(
+                "{}: cached {}, database {}",
+                field,
+                show(cached),
+                show(stored)
+            )
+        })
+    };
+    match (cached, stored) {
+        (Value::Object(cached), Value::Object(stored)) => cached
+            .keys()
+            .chain(stored.keys())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .filter_map(|field| diff(field.clone(), cached.get(field), stored.get(field)))
+            .collect(),
+        (Value::Array(cached), Value::Array(stored)) => (0..cached.len().max(stored.len()))
+            .filter_map(|i| diff(format!("[{}]", i), cached.get(i), stored.get(i)))
+            .collect(),
+        _ if cached != stored => vec![format!("cached {}, database {}", cached, stored)],
+        _ => Vec::new(),
+    }
+}
@@ -0,0 +1,353 @@
+use crate::cacher::{CacheError, CacheHandle, EntryInfo};
+use log::{debug, warn};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+/// Two-level cache handle, typically an in-process cache (L1) in front of
+/// Redis (L2).
+///
+/// Reads are served from L1 when possible and fall back to L2, copying L2 hits
+/// into L1. Writes and deletes go to both tiers, L2 first, since L2 is the
+/// authoritative tier. Errors from L1 are logged and treated as misses, so a
+/// failing L1 only costs the extra round trip to L2.
+///
+/// Reads take `&self`, so L2 hits are copied into a clone of L1: L1 must be
+/// a handle whose clones share one store, as the handles of this crate do.
+/// With an L1 that copies its store on clone, reads never populate it.
+///
+/// Other nodes' L1 caches are not updated by writes made here; pair this with
+/// `RedisCacheHandle::subscribe_invalidations` on the same L1 to evict keys
+/// invalidated elsewhere.
+#[derive(Clone)]
+pub struct TieredCache<L1: CacheHandle, L2: CacheHandle> {
+    l1: L1,
+    l2: L2,
+}
+
+impl<L1: CacheHandle, L2: CacheHandle> TieredCache<L1, L2> {
+    pub fn new(l1: L1, l2: L2) -> Self {
+        TieredCache { l1, l2 }
+    }
+
+    pub fn l1(&self) -> &L1 {
+        &self.l1
+    }
+
+    pub fn l2(&self) -> &L2 {
+        &self.l2
+    }
+
+}
+
+/// Copies a value read from L2 into L1.
+fn promote<L1, V>(l1: &mut L1, key: &String, value: &V)
+where
+    L1: CacheHandle,
+    V: Serialize + DeserializeOwned,
+{
+    if let Err(e) = l1.put(key, value) {
+        warn!("Error populating L1 cache for key {}: {}", key, e);
+    }
+}
+
+impl<L1: CacheHandle, L2: CacheHandle> CacheHandle for TieredCache<L1, L2> {
+    fn get<V: Serialize + DeserializeOwned>(&self, key: &String) -> Result<Option<V>, CacheError> {
+        match self.l1.get(key) {
+            Ok(Some(value)) => return Ok(Some(value)),
+            Ok(None) => debug!("L1 miss for key {}", key),
+            Err(e) => warn!("Error reading key {} from L1 cache: {}", key, e),
+        }
+        let value = self.l2.get::<V>(key)?;
+        if let Some(value) = &value {
+            // `get` takes `&self`; promote through a clone of L1, which
+            // shares its store.
+            promote(&mut self.l1.clone(), key, value);
+        }
+        Ok(value)
+    }
+
+    fn get_multi<V: Serialize + DeserializeOwned>(
+        &self,
+        keys: &[String],
+    ) -> Result<Vec<Option<V>>, CacheError> {
+        let mut values = match self.l1.get_multi::<V>(keys) {
+            Ok(values) => values,
+            Err(e) => {
+                warn!("Error reading keys from L1 cache: {}", e);
+                keys.iter().map(|_| None).collect()
+            }
+        };
+        let missing = values
+            .iter()
+            .enumerate()
+            .filter(|(_, value)| value.is_none())
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        if missing.is_empty() {
+            return Ok(values);
+        }
+        let missing_keys = missing.iter().map(|&i| keys[i].clone()).collect::<Vec<_>>();
+        let fetched = self.l2.get_multi::<V>(&missing_keys)?;
+        let mut l1 = self.l1.clone();
+        for (i, value) in missing.into_iter().zip(fetched) {
+            if let Some(value) = &value {
+                promote(&mut l1, &keys[i], value);
+            }
+            values[i] = value;
+        }
+        Ok(values)
+    }
+
+    /// Read from L2, which holds the authoritative expiry; copies promoted
+    /// into L1 do not carry one.
+    fn get_with_ttl<V: Serialize + DeserializeOwned>(
+        &self,
+        key: &String,
+    ) -> Result<Option<(V, Option<Duration>)>, CacheError> {
+        self.l2.get_with_ttl(key)
+    }
+
+    fn exists(&self, key: &String) -> Result<bool, CacheError> {
+        if self.l1.exists(key).unwrap_or(false) {
+            return Ok(true);
+        }
+        self.l2.exists(key)
+    }
+
+    /// Reports L2's metadata, since L2 holds every key stored through this
+    /// handle. Reads served by L1 are not counted there.
+    fn entry_info(&self, key: &String) -> Result<Option<EntryInfo>, CacheError> {
+        self.l2.entry_info(key)
+    }
+
+    fn put<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+    ) -> Result<(), CacheError> {
+        self.l2.put(key, value)?;
+        self.l1.put(key, value)
+    }
+
+    /// Reads L2 only: the tiers may store values in different formats, so
+    /// raw bytes are never copied from one to the other.
+    fn get_raw(&self, key: &String) -> Result<Option<Vec<u8>>, CacheError> {
+        self.l2.get_raw(key)
+    }
+
+    /// Writes L2 and evicts the key from L1, which then reads it back from
+    /// L2 with its own serializer.
+    fn put_raw(&mut self, key: &String, bytes: &[u8]) -> Result<(), CacheError> {
+        self.l2.put_raw(key, bytes)?;
+        self.l1.delete(key)
+    }
+
+    fn put_with_ttl<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+        ttl: Duration,
+    ) -> Result<(), CacheError> {
+        self.l2.put_with_ttl(key, value, ttl)?;
+        self.l1.put_with_ttl(key, value, ttl)
+    }
+
+    fn put_multi<V: Serialize + DeserializeOwned>(
+        &mut self,
+        entries: &[(String, V)],
+    ) -> Result<(), CacheError> {
+        self.l2.put_multi(entries)?;
+        self.l1.put_multi(entries)
+    }
+
+    /// Decided by L2. If L2 already holds a value, any L1 copy is left as is.
+    fn put_if_absent<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+    ) -> Result<bool, CacheError> {
+        let written = self.l2.put_if_absent(key, value)?;
+        if written {
+            self.l1.put(key, value)?;
+        }
+        Ok(written)
+    }
+
+    /// Decided by L2. On success, the key is evicted from L1, which then
+    /// reads the new version back from L2.
+    fn compare_and_set<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        expected_version: u64,
+        value: &V,
+    ) -> Result<bool, CacheError> {
+        let written = self.l2.compare_and_set(key, expected_version, value)?;
+        if written {
+            self.l1.delete(key)?;
+        }
+        Ok(written)
+    }
+
+    /// Decided by L2, like `compare_and_set`.
+    fn put_read_at<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+        ttl: Option<Duration>,
+        read_at: SystemTime,
+    ) -> Result<bool, CacheError> {
+        let written = self.l2.put_read_at(key, value, ttl, read_at)?;
+        if written {
+            self.l1.delete(key)?;
+        }
+        Ok(written)
+    }
+
+    /// Decided by L2. Every key is evicted from L1, written or not.
+    fn put_multi_read_at<V: Serialize + DeserializeOwned>(
+        &mut self,
+        entries: &[(String, V)],
+        read_at: SystemTime,
+    ) -> Result<usize, CacheError> {
+        let written = self.l2.put_multi_read_at(entries, read_at)?;
+        let keys = entries.iter().map(|(key, _)| key.clone()).collect::<Vec<_>>();
+        self.l1.delete_multi(&keys)?;
+        Ok(written)
+    }
+
+    /// Deletes from L2 before L1. The reverse order would let a concurrent
+    /// read copy the old L2 value back into L1 after it was evicted.
+    fn delete(&mut self, key: &String) -> Result<(), CacheError> {
+        self.l2.delete(key)?;
+        self.l1.delete(key)
+    }
+
+    fn delete_multi(&mut self, keys: &[String]) -> Result<(), CacheError> {
+        self.l2.delete_multi(keys)?;
+        self.l1.delete_multi(keys)
+    }
+
+    /// Counts the keys live in L2, since it holds every key stored through
+    /// this handle.
+    fn delete_multi_counted(&mut self, keys: &[String]) -> Result<usize, CacheError> {
+        let existed = self.l2.delete_multi_counted(keys)?;
+        self.l1.delete_multi(keys)?;
+        Ok(existed)
+    }
+
+    /// Counts the keys live in L2, like `delete_multi_counted`.
+    fn delete_pattern(&mut self, pattern: &str) -> Result<usize, CacheError> {
+        let deleted = self.l2.delete_pattern(pattern)?;
+        self.l1.delete_pattern(pattern)?;
+        Ok(deleted)
+    }
+
+    fn clear(&mut self) -> Result<(), CacheError> {
+        self.l2.clear()?;
+        self.l1.clear()
+    }
+
+    /// Scans L2 only, since it holds every key stored through this handle.
+    fn scan_keys(&self, pattern: &str) -> Result<HashMap<String, String>, CacheError> {
+        self.l2.scan_keys(pattern)
+    }
+
+    fn list_keys(&self, pattern: &str) -> Result<Vec<String>, CacheError> {
+        self.l2.list_keys(pattern)
+    }
+
+    fn count_keys(&self, pattern: &str) -> Result<usize, CacheError> {
+        self.l2.count_keys(pattern)
+    }
+
+    /// Tags the key in L2 only; L1 copies are evicted by `invalidate_tag`
+    /// using the keys L2 reports.
+    fn put_tagged<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+        tags: &[&str],
+    ) -> Result<(), CacheError> {
+        self.l2.put_tagged(key, value, tags)?;
+        self.l1.put(key, value)
+    }
+
+    fn invalidate_tag(&mut self, tag: &str) -> Result<Vec<String>, CacheError> {
+        let keys = self.l2.invalidate_tag(tag)?;
+        self.l1.delete_multi(&keys)?;
+        Ok(keys)
+    }
+
+    /// Healthy as long as L2 is, since a failing L1 only costs the extra
+    /// round trip to L2.
+    fn health_check(&self) -> Result<(), CacheError> {
+        if let Err(e) = self.l1.health_check() {
+            warn!("L1 cache is unhealthy: {}", e);
+        }
+        self.l2.health_check()
+    }
+
+    /// Serves the value from L1 if present, otherwise defers to L2's
+    /// `get_or_insert_with`, so that a backend lock (e.g. Redis) still
+    /// guards the computation, and copies the result into L1.
+    fn get_or_insert_with<V, F>(&mut self, key: &String, f: F) -> Result<V, CacheError>
+    where
+        V: Serialize + DeserializeOwned,
+        F: FnOnce() -> Result<V, CacheError>,
+    {
+        match self.l1.get(key) {
+            Ok(Some(value)) => return Ok(value),
+            Ok(None) => {}
+            Err(e) => warn!("Error reading key {} from L1 cache: {}", key, e),
+        }
+        let value = self.l2.get_or_insert_with(key, f)?;
+        promote(&mut self.l1, key, &value);
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cacher::HashmapCache;
+
+    #[test]
+    fn test_tiered_reads_through_and_deletes_both_tiers() {
+        let l1 = HashmapCache::new().handle();
+        let mut l2 = HashmapCache::new().handle();
+        let mut tiered = TieredCache::new(l1.clone(), l2.clone());
+
+        let key = "student:1".to_string();
+        l2.put(&key, &"Alice".to_string()).unwrap();
+        assert!(!l1.exists(&key).unwrap());
+
+        // An L2 hit is copied into L1.
+        assert_eq!(
+            tiered.get::<String>(&key).unwrap(),
+            Some("Alice".to_string())
+        );
+        assert_eq!(l1.get::<String>(&key).unwrap(), Some("Alice".to_string()));
+
+        let other = "student:2".to_string();
+        tiered.put(&other, &"Bob".to_string()).unwrap();
+        assert!(l1.exists(&other).unwrap() && l2.exists(&other).unwrap());
+        assert_eq!(
+            tiered
+                .get_multi::<String>(&[key.clone(), other.clone(), "student:3".to_string()])
+                .unwrap(),
+            vec![Some("Alice".to_string()), Some("Bob".to_string()), None]
+        );
+
+        // Keys only present in L2 are still visible to scans.
+        l2.put(&"student:4".to_string(), &"Carol".to_string())
+            .unwrap();
+        assert_eq!(tiered.count_keys("student:*").unwrap(), 3);
+
+        tiered.delete(&key).unwrap();
+        assert!(!l1.exists(&key).unwrap());
+        assert!(!l2.exists(&key).unwrap());
+        assert_eq!(tiered.get::<String>(&key).unwrap(), None);
+    }
+}
@@ -0,0 +1,468 @@
+use crate::cacher::{CacheError, CacheErrorKind, CacheHandle, EntryInfo, VersionedValue};
+use jsonschema::Validator;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// Cache handle that checks every value written through it against a JSON
+/// Schema before passing it on to an inner handle, e.g. when other systems
+/// read the same Redis cache and rely on an agreed shape.
+///
+/// A value that does not match fails with a `CacheErrorKind::Validation`
+/// error and is not written, so a change in the shape of a model shows up at
+/// the first write rather than in another system. `put_multi` writes nothing
+/// if any of its values fails. Values are checked in their JSON form, as
+/// `serde_json::to_value` produces it, whatever serializer the inner handle
+/// stores them with. `validate_reads` checks the values read back as well,
+/// against entries written by something else: each is read as a
+/// `serde_json::Value` and checked as stored, before it is converted to the
+/// requested type, so fields the type would ignore are still caught. This
+/// needs a self-describing inner serializer, such as JSON or MessagePack;
+/// with bincode, every checked read fails. Raw bytes, from `put_raw` and
+/// `get_raw`, pass through unchecked.
+///
+/// ```ignore
+/// let schema = serde_json::json!({
+///     "type": "object",
+///     "required": ["id", "name"],
+///     "properties": {"id": {"type": "integer"}, "name": {"type": "string"}},
+/// });
+/// let handle = ValidatingCache::new(cache.handle(), &schema)?.validate_reads();
+/// students::table
+///     .select((Student::as_select(), cache_key_expr("student", students::id)))
+///     .populate_cache::<Student>(handle.clone())
+///     .load::<Student>(connection)?;
+/// ```
+#[derive(Clone, Debug)]
+pub struct ValidatingCache<C: CacheHandle> {
+    inner: C,
+    validator: Arc<Validator>,
+    validate_reads: bool,
+}
+
+impl<C: CacheHandle> ValidatingCache<C> {
+    /// Checks the values written to `inner` against `schema`. Fails with a
+    /// `Validation` error if `schema` is not a valid JSON Schema.
+    pub fn new(inner: C, schema: &serde_json::Value) -> Result<Self, CacheError> {
+        let validator = jsonschema::validator_for(schema).map_err(|e| {
+            CacheError::new(&format!("Invalid JSON schema: {}", e))
+                .with_kind(CacheErrorKind::Validation)
+        })?;
+        Ok(ValidatingCache {
+            inner,
+            validator: Arc::new(validator),
+            validate_reads: false,
+        })
+    }
+
+    /// Also checks the values read, failing the read of a value that does
+    /// not match the schema with a `Validation` error.
+    pub fn validate_reads(mut self) -> Self {
+        self.validate_reads = true;
+        self
+    }
+
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    /// Checks a value read as stored, then converts it to `V`.
+    fn check_read<V: DeserializeOwned>(
+        &self,
+        key: &str,
+        value: Option<serde_json::Value>,
+    ) -> Result<Option<V>, CacheError> {
+        value
+            .map(|value| {
+                check(&self.validator, key, &value)?;
+                from_value(value)
+            })
+            .transpose()
+    }
+}
+
+fn from_value<V: DeserializeOwned>(value: serde_json::Value) -> Result<V, CacheError> {
+    serde_json::from_value(value).map_err(|e| {
+        CacheError::with_cause("Failed to deserialize value", e)
+            .with_kind(CacheErrorKind::Deserialization)
+    })
+}
+
+/// Checks the value of `key` against the schema.
+fn check<V: Serialize>(validator: &Validator, key: &str, value: &V) -> Result<(), CacheError> {
+    let instance = serde_json::to_value(value).map_err(|e| {
+        CacheError::with_cause("Failed to serialize value", e)
+            .with_kind(CacheErrorKind::Serialization)
+    })?;
+    validator.validate(&instance).map_err(|e| {
+        CacheError::new(&format!(
+            "Value of key {} does not match the schema at '{}': {}",
+            key, e.instance_path, e
+        ))
+        .with_kind(CacheErrorKind::Validation)
+    })
+}
+
+impl<C: CacheHandle> CacheHandle for ValidatingCache<C> {
+    fn get<V: Serialize + DeserializeOwned>(&self, key: &String) -> Result<Option<V>, CacheError> {
+        if !self.validate_reads {
+            return self.inner.get(key);
+        }
+        self.check_read(key, self.inner.get(key)?)
+    }
+
+    fn get_multi<V: Serialize + DeserializeOwned>(
+        &self,
+        keys: &[String],
+    ) -> Result<Vec<Option<V>>, CacheError> {
+        if !self.validate_reads {
+            return self.inner.get_multi(keys);
+        }
+        let values = self.inner.get_multi(keys)?;
+        keys.iter()
+            .zip(values)
+            .map(|(key, value)| self.check_read(key, value))
+            .collect()
+    }
+
+    fn get_with_ttl<V: Serialize + DeserializeOwned>(
+        &self,
+        key: &String,
+    ) -> Result<Option<(V, Option<Duration>)>, CacheError> {
+        if !self.validate_reads {
+            return self.inner.get_with_ttl(key);
+        }
+        match self.inner.get_with_ttl(key)? {
+            Some((value, ttl)) => Ok(self.check_read(key, Some(value))?.map(|value| (value, ttl))),
+            None => Ok(None),
+        }
+    }
+
+    /// Checks the value itself, without its version.
+    fn get_versioned<V: Serialize + DeserializeOwned>(
+        &self,
+        key: &String,
+    ) -> Result<Option<VersionedValue<V>>, CacheError> {
+        if !self.validate_reads {
+            return self.inner.get_versioned(key);
+        }
+        match self.inner.get_versioned(key)? {
+            Some(VersionedValue { version, value }) => Ok(self
+                .check_read(key, Some(value))?
+                .map(|value| VersionedValue { version, value })),
+            None => Ok(None),
+        }
+    }
+
+    fn exists(&self, key: &String) -> Result<bool, CacheError> {
+        self.inner.exists(key)
+    }
+
+    fn entry_info(&self, key: &String) -> Result<Option<EntryInfo>, CacheError> {
+        self.inner.entry_info(key)
+    }
+
+    fn put<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+    ) -> Result<(), CacheError> {
+        check(&self.validator, key, value)?;
+        self.inner.put(key, value)
+    }
+
+    fn get_raw(&self, key: &String) -> Result<Option<Vec<u8>>, CacheError> {
+        self.inner.get_raw(key)
+    }
+
+    fn put_raw(&mut self, key: &String, bytes: &[u8]) -> Result<(), CacheError> {
+        self.inner.put_raw(key, bytes)
+    }
+
+    fn put_with_ttl<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+        ttl: Duration,
+    ) -> Result<(), CacheError> {
+        check(&self.validator, key, value)?;
+        self.inner.put_with_ttl(key, value, ttl)
+    }
+
+    fn put_multi<V: Serialize + DeserializeOwned>(
+        &mut self,
+        entries: &[(String, V)],
+    ) -> Result<(), CacheError> {
+        for (key, value) in entries {
+            check(&self.validator, key, value)?;
+        }
+        self.inner.put_multi(entries)
+    }
+
+    fn put_if_absent<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+    ) -> Result<bool, CacheError> {
+        check(&self.validator, key, value)?;
+        self.inner.put_if_absent(key, value)
+    }
+
+    /// Checks the value itself, without its version.
+    fn compare_and_set<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        expected_version: u64,
+        value: &V,
+    ) -> Result<bool, CacheError> {
+        check(&self.validator, key, value)?;
+        self.inner.compare_and_set(key, expected_version, value)
+    }
+
+    fn put_read_at<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+        ttl: Option<Duration>,
+        read_at: SystemTime,
+    ) -> Result<bool, CacheError> {
+        check(&self.validator, key, value)?;
+        self.inner.put_read_at(key, value, ttl, read_at)
+    }
+
+    fn put_multi_read_at<V: Serialize + DeserializeOwned>(
+        &mut self,
+        entries: &[(String, V)],
+        read_at: SystemTime,
+    ) -> Result<usize, CacheError> {
+        for (key, value) in entries {
+            check(&self.validator, key, value)?;
+        }
+        self.inner.put_multi_read_at(entries, read_at)
+    }
+
+    /// Checks the computed value before the inner handle caches it, so
+    /// backends that lock the computation, like Redis, still do.
+    fn get_or_insert_with<V, F>(&mut self, key: &String, f: F) -> Result<V, CacheError>
+    where
+        V: Serialize + DeserializeOwned,
+        F: FnOnce() -> Result<V, CacheError>,
+    {
+        let validator = Arc::clone(&self.validator);
+        if !self.validate_reads {
+            return self.inner.get_or_insert_with(key, || {
+                let value = f()?;
+                check(&validator, key, &value)?;
+                Ok(value)
+            });
+        }
+        let value = self.inner.get_or_insert_with(key, || {
+            let value = f()?;
+            check(&validator, key, &value)?;
+            serde_json::to_value(&value).map_err(|e| {
+                CacheError::with_cause("Failed to serialize value", e)
+                    .with_kind(CacheErrorKind::Serialization)
+            })
+        })?;
+        check(&self.validator, key, &value)?;
+        from_value(value)
+    }
+
+    fn delete(&mut self, key: &String) -> Result<(), CacheError> {
+        self.inner.delete(key)
+    }
+
+    fn delete_multi(&mut self, keys: &[String]) -> Result<(), CacheError> {
+        self.inner.delete_multi(keys)
+    }
+
+    fn delete_multi_counted(&mut self, keys: &[String]) -> Result<usize, CacheError> {
+        self.inner.delete_multi_counted(keys)
+    }
+
+    fn delete_pattern(&mut self, pattern: &str) -> Result<usize, CacheError> {
+        self.inner.delete_pattern(pattern)
+    }
+
+    fn clear(&mut self) -> Result<(), CacheError> {
+        self.inner.clear()
+    }
+
+    fn scan_keys(&self, pattern: &str) -> Result<HashMap<String, String>, CacheError> {
+        self.inner.scan_keys(pattern)
+    }
+
+    fn scan_typed<V: Serialize + DeserializeOwned>(
+        &self,
+        pattern: &str,
+    ) -> Result<HashMap<String, V>, CacheError> {
+        if !self.validate_reads {
+            return self.inner.scan_typed(pattern);
+        }
+        self.inner
+            .scan_typed::<serde_json::Value>(pattern)?
+            .into_iter()
+            .map(|(key, value)| {
+                check(&self.validator, &key, &value)?;
+                Ok((key, from_value(value)?))
+            })
+            .collect()
+    }
+
+    fn list_keys(&self, pattern: &str) -> Result<Vec<String>, CacheError> {
+        self.inner.list_keys(pattern)
+    }
+
+    fn count_keys(&self, pattern: &str) -> Result<usize, CacheError> {
+        self.inner.count_keys(pattern)
+    }
+
+    fn put_tagged<V: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &String,
+        value: &V,
+        tags: &[&str],
+    ) -> Result<(), CacheError> {
+        check(&self.validator, key, value)?;
+        self.inner.put_tagged(key, value, tags)
+    }
+
+    fn invalidate_tag(&mut self, tag: &str) -> Result<Vec<String>, CacheError> {
+        self.inner.invalidate_tag(tag)
+    }
+
+    fn health_check(&self) -> Result<(), CacheError> {
+        self.inner.health_check()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cacher::HashmapCache;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Student {
+        id: i32,
+        name: String,
+    }
+
+    fn schema() -> serde_json::Value {
+        serde_json::json!({
+            "type": "object",
+            "required": ["id", "name"],
+            "properties": {
+                "id": {"type": "integer"},
+                "name": {"type": "string", "minLength": 1},
+            },
+        })
+    }
+
+    #[test]
+    fn test_rejects_writes_not_matching_the_schema() {
+        let cache = HashmapCache::new();
+        let mut handle = ValidatingCache::new(cache.handle(), &schema()).unwrap();
+        let key = "student:2".to_string();
+
+        let ori = Student {
+            id: 2,
+            name: "Ori".to_string(),
+        };
+        handle.put(&key, &ori).unwrap();
+        assert_eq!(handle.get::<Student>(&key).unwrap(), Some(ori));
+
+        let unnamed = Student {
+            id: 3,
+            name: String::new(),
+        };
+        let err = handle.put(&key, &unnamed).unwrap_err();
+        assert_eq!(err.kind(), CacheErrorKind::Validation);
+        assert!(err.to_string().contains("/name"));
+
+        // One invalid value keeps the whole batch out.
+        let entries = [
+            (
+                "student:4".to_string(),
+                serde_json::json!({"id": 4, "name": "Dan"}),
+            ),
+            (
+                "student:5".to_string(),
+                serde_json::json!({"id": "5", "name": "Eve"}),
+            ),
+        ];
+        assert!(handle.put_multi(&entries).is_err());
+        assert_eq!(cache.handle().count_keys("student:*").unwrap(), 1);
+
+        let err = ValidatingCache::new(cache.handle(), &serde_json::json!({"type": 1}))
+            .err()
+            .expect("An invalid schema should be rejected");
+        assert_eq!(err.kind(), CacheErrorKind::Validation);
+    }
+
+    #[test]
+    fn test_validate_reads_rejects_poisoned_entries() {
+        let cache = HashmapCache::new();
+        let key = "student:2".to_string();
+        cache
+            .handle()
+            .put(&key, &serde_json::json!({"id": 2}))
+            .unwrap();
+
+        // Reads are trusted unless asked otherwise.
+        let handle = ValidatingCache::new(cache.handle(), &schema()).unwrap();
+        assert!(handle.get::<serde_json::Value>(&key).unwrap().is_some());
+
+        let handle = handle.validate_reads();
+        let err = handle.get::<serde_json::Value>(&key).unwrap_err();
+        assert_eq!(err.kind(), CacheErrorKind::Validation);
+        assert!(handle.scan_typed::<serde_json::Value>("student:*").is_err());
+        assert!(
+            handle
+                .list::<serde_json::Value>("student:*")
+                .unwrap()
+                .next()
+                .unwrap()
+                .is_err()
+        );
+        assert_eq!(
+            handle.get::<Student>(&"student:3".to_string()).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_validate_reads_checks_the_stored_value() {
+        let cache = HashmapCache::new();
+        let key = "student:2".to_string();
+        cache
+            .handle()
+            .put(&key, &serde_json::json!({"id": 2, "name": "Ori", "admin": true}))
+            .unwrap();
+
+        // `Student` drops the extra field, so only the stored value shows it.
+        let mut strict = schema();
+        strict["additionalProperties"] = serde_json::json!(false);
+        let handle = ValidatingCache::new(cache.handle(), &strict)
+            .unwrap()
+            .validate_reads();
+        let err = handle.get::<Student>(&key).unwrap_err();
+        assert_eq!(err.kind(), CacheErrorKind::Validation);
+        assert!(handle.get_multi::<Student>(std::slice::from_ref(&key)).is_err());
+        assert!(handle.scan_typed::<Student>("student:*").is_err());
+
+        cache
+            .handle()
+            .put(&key, &serde_json::json!({"id": 2, "name": "Ori"}))
+            .unwrap();
+        assert_eq!(
+            handle.get::<Student>(&key).unwrap(),
+            Some(Student {
+                id: 2,
+                name: "Ori".to_string(),
+            })
+        );
+    }
+}
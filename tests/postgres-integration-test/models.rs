@@ -2,6 +2,7 @@ use diesel::pg;
 use diesel::prelude::*;
 use serde::{Deserialize, Serialize, ser::SerializeTuple};
 use std::option::Option;
+use turbodiesel::cache_key::Cacheable;
 
 impl Serialize for Student {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
@@ -25,14 +26,17 @@ impl<'de> Deserialize<'de> for Student {
         Ok(Student {
             id,
             name,
-            dob: dob.map(|d| pg::data_types::PgDate(d)),
+            dob: dob.map(pg::data_types::PgDate),
         })
     }
 }
 
-#[derive(Queryable, Selectable, Insertable, Debug, PartialEq, Clone)]
+#[derive(
+    Queryable, QueryableByName, Selectable, Insertable, Cacheable, Debug, PartialEq, Clone,
+)]
 #[diesel(table_name = crate::schema::students)]
 #[diesel(check_for_backend(pg::Pg))]
+#[cache(prefix = "student", key = "id")]
 pub struct Student {
     pub id: i32,
     pub name: String,
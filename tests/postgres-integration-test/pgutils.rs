@@ -4,10 +4,13 @@ use diesel::Connection;
 use dotenvy::dotenv;
 use std::env;
 
-pub fn establish_connection() -> PgConnection {
+pub fn database_url() -> String {
     dotenv().ok();
+    env::var("DATABASE_URL").expect("DATABASE_URL must be set")
+}
 
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+pub fn establish_connection() -> PgConnection {
+    let database_url = database_url();
     PgConnection::establish(&database_url)
         .unwrap_or_else(|_| panic!("Error connecting to {}", database_url))
 }
\ No newline at end of file
@@ -10,6 +10,7 @@ use diesel::sql_types::Text;
 use julian::{Calendar, Month, system2jdn};
 use lazy_static::lazy_static;
 use log::info;
+use turbodiesel::cache_key::cache_key_expr;
 use turbodiesel::statement_wrappers::*;
 
 #[cfg(test)]
@@ -56,7 +57,7 @@ fn system_test_with_inmemory_cache() {
         .expect("Error updating students");
 
     students::dsl::students
-        .select(row_with_cache_key)
+        .select(row_with_cache_key.clone())
         .filter(students::dsl::id.eq(2))
         .populate_cache::<Student>(handle.clone())
         .load_iter::<Student, DefaultLoadingMode>(connection)
@@ -68,7 +69,7 @@ fn system_test_with_inmemory_cache() {
     info!("cache: {:?}", cache);
 
     students::dsl::students
-        .select(Student::as_select())
+        .select(row_with_cache_key)
         .filter(students::dsl::id.eq_any(vec![1, 2]))
         .try_from_cache_multi::<Student, _>(
             handle.clone(),
@@ -84,7 +85,7 @@ fn system_test_with_inmemory_cache() {
     diesel::update(students::table)
         .set(students::dsl::name.eq("Ori2"))
         .filter(students::dsl::id.eq(2))
-        .invalidate_key(handle.clone(), "student:2")
+        .invalidate_key(handle.clone(), "student:2", InvalidationOrder::Before)
         .execute(connection)
         .expect("Error updating students");
 
@@ -101,6 +102,1250 @@ fn system_test_with_inmemory_cache() {
         });
 }
 
+#[test]
+#[cfg(feature = "inmemory")]
+fn invalidate_keys_counts_deleted_keys_with_inmemory_cache() {
+    use turbodiesel::cacher::{CacheHandle, HashmapCache};
+
+    let handle = HashmapCache::new().handle();
+    let connection = &mut establish_connection();
+    connection.test_transaction::<_, diesel::result::Error, _>(|connection| {
+        let student = Student {
+            id: 145,
+            name: "Noa".to_string(),
+            dob: None,
+        };
+        diesel::insert_into(students::table)
+            .values(&student)
+            .execute(connection)?;
+        handle
+            .clone()
+            .put(&"student:145".to_string(), &student)
+            .unwrap();
+
+        // Student 146 was never cached, so only one of the two keys is invalidated.
+        let (rows_updated, keys_invalidated) = diesel::update(students::table)
+            .set(students::dsl::name.eq("Noa2"))
+            .filter(students::dsl::id.eq(145))
+            .invalidate_keys(
+                handle.clone(),
+                vec!["student:145".to_string(), "student:146".to_string()].into_iter(),
+                InvalidationOrder::Before,
+            )
+            .execute_counted(connection)?;
+        assert_eq!((rows_updated, keys_invalidated), (1, 1));
+        assert!(!handle.exists(&"student:145".to_string()).unwrap());
+        Ok(())
+    });
+}
+
+#[test]
+fn cache_key_expr_matches_hand_written_key() {
+    let connection = &mut establish_connection();
+    connection.test_transaction::<_, diesel::result::Error, _>(|connection| {
+        let keys = students::table
+            .select((cache_key_expr("student", students::id), sql::<Text>("'student:' || id")))
+            .order(students::id)
+            .load::<(String, String)>(connection)?;
+        for (generated, hand_written) in keys {
+            assert_eq!(generated, hand_written);
+        }
+        Ok(())
+    });
+}
+
+#[test]
+#[cfg(feature = "inmemory")]
+fn single_row_reads_with_inmemory_cache() {
+    use std::sync::Arc;
+    use turbodiesel::cache_stats::CacheStats;
+    use turbodiesel::cacher::HashmapCache;
+
+    let handle = HashmapCache::new().handle();
+    let connection = &mut establish_connection();
+    connection.test_transaction::<_, diesel::result::Error, _>(|connection| {
+        let student = Student {
+            id: 100,
+            name: "Noa".to_string(),
+            dob: None,
+        };
+        diesel::insert_into(students::table)
+            .values(&student)
+            .execute(connection)?;
+
+        let stats = Arc::new(CacheStats::new());
+        let populated = students::table
+            .select((Student::as_select(), cache_key_expr("student", students::id)))
+            .filter(students::id.eq(100))
+            .populate_cache::<Student>(handle.clone())
+            .with_stats(stats.clone())
+            .first::<Student>(connection)?;
+        assert_eq!(populated, student);
+        assert_eq!((stats.populated(), stats.errors()), (1, 0));
+
+        // Rename the student without invalidating the cache, so a stale name
+        // shows that the row came from the cache.
+        diesel::update(students::table)
+            .set(students::name.eq("Noa2"))
+            .filter(students::id.eq(100))
+            .execute(connection)?;
+
+        let first = students::table
+            .select(Student::as_select())
+            .filter(students::id.eq(100))
+            .try_from_cache::<Student>(handle.clone(), "student:100")
+            .first::<Student>(connection)?;
+        assert_eq!(first, student);
+
+        let result = students::table
+            .select(Student::as_select())
+            .filter(students::id.eq(100))
+            .try_from_cache::<Student>(handle.clone(), "student:100")
+            .get_result::<Student>(connection)?;
+        assert_eq!(result, student);
+
+        // A miss still reads the row from the database.
+        let missing = students::table
+            .select(Student::as_select())
+            .filter(students::id.eq(101))
+            .try_from_cache::<Student>(handle.clone(), "student:101")
+            .first::<Student>(connection);
+        assert_eq!(missing, Err(diesel::result::Error::NotFound));
+        Ok(())
+    });
+}
+
+#[test]
+#[cfg(feature = "inmemory")]
+fn cache_coherence_with_inmemory_cache() {
+    use std::panic::{AssertUnwindSafe, catch_unwind};
+    use turbodiesel::cacher::{CacheHandle, HashmapCache};
+    use turbodiesel::test_utils::assert_cache_coherent;
+
+    let mut handle = HashmapCache::new().handle();
+    let connection = &mut establish_connection();
+    connection.test_transaction::<_, diesel::result::Error, _>(|connection| {
+        let students = [111, 112].map(|id| Student {
+            id,
+            name: format!("Student {}", id),
+            dob: None,
+        });
+        diesel::insert_into(students::table)
+            .values(&students[..])
+            .execute(connection)?;
+        let query = || {
+            students::table
+                .select((Student::as_select(), cache_key_expr("student", students::id)))
+                .filter(students::id.eq_any([111, 112]))
+        };
+        let keys = ["student:111", "student:112", "student:113"];
+
+        handle
+            .put(&"student:111".to_string(), &students[0])
+            .unwrap();
+        assert_cache_coherent::<Student, _, _, _>(&handle, connection, query(), &keys);
+
+        diesel::update(students::table)
+            .set(students::name.eq("Renamed"))
+            .filter(students::id.eq(111))
+            .execute(connection)?;
+        handle
+            .put(&"student:113".to_string(), &students[1])
+            .unwrap();
+        let panic = catch_unwind(AssertUnwindSafe(|| {
+            assert_cache_coherent::<Student, _, _, _>(&handle, connection, query(), &keys)
+        }))
+        .unwrap_err();
+        let message = panic.downcast_ref::<String>().unwrap();
+        assert!(message.contains("for 2 of 3 keys"), "{}", message);
+        assert!(
+            message.contains(r#"student:111: [1]: cached "Student 111", database "Renamed""#),
+            "{}",
+            message
+        );
+        assert!(
+            message.contains("student:113: cached, but no row in the database"),
+            "{}",
+            message
+        );
+        Ok(())
+    });
+}
+
+#[test]
+#[cfg(feature = "inmemory")]
+fn auto_keyed_reads_with_inmemory_cache() {
+    use diesel::pg::Pg;
+    use turbodiesel::cache_key::query_cache_key;
+    use turbodiesel::cacher::{CacheHandle, HashmapCache};
+
+    let handle = HashmapCache::new().handle();
+    let connection = &mut establish_connection();
+    connection.test_transaction::<_, diesel::result::Error, _>(|connection| {
+        let student = Student {
+            id: 102,
+            name: "Tal".to_string(),
+            dob: None,
+        };
+        diesel::insert_into(students::table)
+            .values(&student)
+            .execute(connection)?;
+        let query = || {
+            students::table
+                .select(Student::as_select())
+                .filter(students::id.eq(102))
+        };
+
+        let loaded = query()
+            .try_from_cache_auto::<Student>(handle.clone())
+            .get_result::<Student>(connection)?;
+        assert_eq!(loaded, student);
+        let key = query_cache_key::<Pg, _>(&query());
+        assert_eq!(handle.get::<Student>(&key).unwrap(), Some(student.clone()));
+
+        // The renamed row is not seen until the derived key is invalidated.
+        diesel::update(students::table)
+            .set(students::name.eq("Tal2"))
+            .filter(students::id.eq(102))
+            .execute(connection)?;
+        let cached = query()
+            .try_from_cache_auto::<Student>(handle.clone())
+            .get_result::<Student>(connection)?;
+        assert_eq!(cached, student);
+
+        handle.clone().delete(&key).unwrap();
+        let reloaded = query()
+            .try_from_cache_auto::<Student>(handle.clone())
+            .get_result::<Student>(connection)?;
+        assert_eq!(reloaded.name, "Tal2");
+        Ok(())
+    });
+}
+
+#[test]
+#[cfg(feature = "inmemory")]
+fn populate_by_ids_with_inmemory_cache() {
+    use turbodiesel::cacher::{CacheHandle, HashmapCache};
+
+    let handle = HashmapCache::new().handle();
+    let connection = &mut establish_connection();
+    connection.test_transaction::<_, diesel::result::Error, _>(|connection| {
+        let students = [104, 105].map(|id| Student {
+            id,
+            name: format!("Student {}", id),
+            dob: None,
+        });
+        diesel::insert_into(students::table)
+            .values(&students[..])
+            .execute(connection)?;
+
+        // Id 106 has no row, which is only logged.
+        let mut loaded = students::table
+            .select((Student::as_select(), students::id))
+            .populate_cache_by_ids::<Student, _, _, _>(
+                handle.clone(),
+                students::id,
+                vec![104, 105, 106],
+                |id| format!("student:{}", id),
+            )
+            .load::<Student>(connection)?;
+        loaded.sort_by_key(|student| student.id);
+        assert_eq!(loaded, students);
+
+        let keys = ["student:104", "student:105", "student:106"].map(String::from);
+        assert_eq!(
+            handle.get_multi::<Student>(&keys).unwrap(),
+            vec![Some(students[0].clone()), Some(students[1].clone()), None]
+        );
+        Ok(())
+    });
+}
+
+#[test]
+fn dry_run_explains_cache_operations() {
+    use turbodiesel::recording_cacher::{CacheReport, RecordingCache};
+
+    let cache = RecordingCache::dry_run();
+    let connection = &mut establish_connection();
+    connection.test_transaction::<_, diesel::result::Error, _>(|connection| {
+        let students = [125, 126].map(|id| Student {
+            id,
+            name: format!("Student {}", id),
+            dob: None,
+        });
+        diesel::insert_into(students::table)
+            .values(&students[..])
+            .execute(connection)?;
+
+        let mut loaded = students::table
+            .select((Student::as_select(), cache_key_expr("student", students::id)))
+            .filter(students::id.eq_any(vec![125, 126]))
+            .try_from_cache_multi::<Student, _>(
+                cache.clone(),
+                vec!["student:126".to_string(), "student:125".to_string()].into_iter(),
+            )
+            .load::<Student>(connection)?;
+        loaded.sort_by_key(|student| student.id);
+        assert_eq!(loaded, students);
+
+        diesel::update(students::table)
+            .set(students::name.eq("Nobody"))
+            .filter(students::id.eq(125))
+            .invalidate_key(cache.clone(), "student:125", InvalidationOrder::Before)
+            .execute(connection)?;
+
+        assert_eq!(
+            cache.report(),
+            CacheReport {
+                lookups: vec!["student:126".to_string(), "student:125".to_string()],
+                invalidated: vec!["student:125".to_string()],
+                ..CacheReport::default()
+            }
+        );
+        Ok(())
+    });
+}
+
+#[test]
+fn invalidation_order_with_recording_cache() {
+    use turbodiesel::cacher::CacheHandle;
+    use turbodiesel::recording_cacher::{CacheOperation, RecordingCache};
+
+    let mut cache = RecordingCache::new();
+    let key = "student:127".to_string();
+    let connection = &mut establish_connection();
+    connection.test_transaction::<_, diesel::result::Error, _>(|connection| {
+        diesel::insert_into(students::table)
+            .values(&Student {
+                id: 127,
+                name: "Noa".to_string(),
+                dob: None,
+            })
+            .execute(connection)?;
+        let rename = |name: &'static str| {
+            diesel::update(students::table)
+                .set(students::name.eq(name))
+                .filter(students::id.eq(127))
+        };
+
+        // Both deletes twice; the count is that of the first delete.
+        cache.put(&key, &"Noa".to_string()).unwrap();
+        cache.take_operations();
+        let counted = rename("Both")
+            .invalidate_key(cache.clone(), &key, InvalidationOrder::Both)
+            .execute_counted(connection)?;
+        assert_eq!(counted, (1, 1));
+        assert_eq!(
+            cache.take_operations(),
+            vec![
+                CacheOperation::Delete(key.clone()),
+                CacheOperation::Delete(key.clone())
+            ]
+        );
+
+        // After deletes once, once the update has run.
+        cache.put(&key, &"Both".to_string()).unwrap();
+        let counted = rename("After")
+            .invalidate_keys(
+                cache.clone(),
+                vec![key.clone()].into_iter(),
+                InvalidationOrder::After,
+            )
+            .execute_counted(connection)?;
+        assert_eq!(counted, (1, 1));
+        assert!(!cache.exists(&key).unwrap());
+        Ok(())
+    });
+}
+
+#[test]
+#[cfg(feature = "inmemory")]
+fn raw_sql_query_with_inmemory_cache() {
+    use diesel::sql_types::Integer;
+    use turbodiesel::cacher::{CacheHandle, HashmapCache};
+
+    let handle = HashmapCache::new().handle();
+    let connection = &mut establish_connection();
+    connection.test_transaction::<_, diesel::result::Error, _>(|connection| {
+        let students = [128, 129].map(|id| Student {
+            id,
+            name: format!("Student {}", id),
+            dob: None,
+        });
+        diesel::insert_into(students::table)
+            .values(&students[..])
+            .execute(connection)?;
+        let by_id = |id: i32| {
+            diesel::sql_query("SELECT id, name, dob FROM students WHERE id = $1")
+                .bind::<Integer, _>(id)
+                .into_boxed()
+        };
+
+        let loaded = by_id(128)
+            .try_from_cache_and_populate::<Student>(handle.clone(), "raw:student:128")
+            .load::<Student>(connection)?;
+        assert_eq!(loaded, vec![students[0].clone()]);
+        assert_eq!(
+            handle.get::<Student>(&"raw:student:128".to_string()).unwrap(),
+            Some(students[0].clone())
+        );
+
+        // A boxed query with a list of rows cached under a single key.
+        let key = "raw:students:128-129";
+        let listed = diesel::sql_query(
+            "SELECT id, name, dob FROM students WHERE id BETWEEN $1 AND $2 ORDER BY id",
+        )
+        .into_boxed()
+        .bind::<Integer, _>(128)
+        .bind::<Integer, _>(129)
+        .populate_cache_list::<Student>(handle.clone(), key)
+        .load::<Student>(connection)?;
+        assert_eq!(listed, students);
+
+        // Served from the cache, even though the rows are gone.
+        diesel::delete(students::table.filter(students::id.ge(128))).execute(connection)?;
+        let cached = by_id(128)
+            .try_from_cache::<Student>(handle.clone(), "raw:student:128")
+            .load::<Student>(connection)?;
+        assert_eq!(cached, vec![students[0].clone()]);
+        let cached = diesel::sql_query("SELECT id, name, dob FROM students")
+            .try_from_cache_list::<Student>(handle.clone(), key)
+            .load::<Student>(connection)?;
+        assert_eq!(cached, students);
+        Ok(())
+    });
+}
+
+#[test]
+#[cfg(feature = "inmemory")]
+fn populate_batched_with_inmemory_cache() {
+    use turbodiesel::cacher::{CacheHandle, HashmapCache};
+
+    let handle = HashmapCache::new().handle();
+    let connection = &mut establish_connection();
+    connection.test_transaction::<_, diesel::result::Error, _>(|connection| {
+        let students = (118..=122)
+            .map(|id| Student {
+                id,
+                name: format!("Student {}", id),
+                dob: None,
+            })
+            .collect::<Vec<_>>();
+        diesel::insert_into(students::table)
+            .values(&students)
+            .execute(connection)?;
+
+        let loaded = students::table
+            .select((Student::as_select(), cache_key_expr("student", students::id)))
+            .filter(students::id.between(118, 122))
+            .order(students::id)
+            .populate_cache_batched::<Student>(handle.clone(), 2)
+            .load::<Student>(connection)?;
+        assert_eq!(loaded, students);
+
+        let keys = (118..=122)
+            .map(|id| format!("student:{}", id))
+            .collect::<Vec<_>>();
+        let cached = handle.get_multi::<Student>(&keys).unwrap();
+        assert_eq!(cached, students.into_iter().map(Some).collect::<Vec<_>>());
+        Ok(())
+    });
+}
+
+#[test]
+#[cfg(feature = "inmemory")]
+fn populate_with_key_fn_with_inmemory_cache() {
+    use turbodiesel::cacher::{CacheHandle, HashmapCache};
+
+    let handle = HashmapCache::new().handle();
+    let connection = &mut establish_connection();
+    connection.test_transaction::<_, diesel::result::Error, _>(|connection| {
+        let students = [115, 116].map(|id| Student {
+            id,
+            name: format!("Student {}", id),
+            dob: None,
+        });
+        diesel::insert_into(students::table)
+            .values(&students[..])
+            .execute(connection)?;
+
+        let loaded = students::table
+            .select(Student::as_select())
+            .filter(students::id.eq_any([115, 116]))
+            .order(students::id)
+            .populate_cache_with_key_fn(handle.clone(), |student: &Student| {
+                format!("student:{}:{}", student.id, student.name)
+            })
+            .load::<Student>(connection)?;
+        assert_eq!(loaded, students);
+
+        let keys = ["student:115:Student 115", "student:116:Student 116"].map(String::from);
+        assert_eq!(
+            handle.get_multi::<Student>(&keys).unwrap(),
+            vec![Some(students[0].clone()), Some(students[1].clone())]
+        );
+        Ok(())
+    });
+}
+
+#[test]
+#[cfg(feature = "inmemory")]
+fn cacheable_keys_match_on_populate_and_invalidate() {
+    use turbodiesel::cache_key::Cacheable;
+    use turbodiesel::cacher::{CacheHandle, HashmapCache};
+
+    let handle = HashmapCache::new().handle();
+    let connection = &mut establish_connection();
+    connection.test_transaction::<_, diesel::result::Error, _>(|connection| {
+        let student = Student {
+            id: 132,
+            name: "Student 132".to_string(),
+            dob: None,
+        };
+        diesel::insert_into(students::table)
+            .values(&student)
+            .execute(connection)?;
+
+        students::table
+            .select(Student::as_select())
+            .filter(students::id.eq(132))
+            .populate_cache_with_key_fn(handle.clone(), Student::cache_key)
+            .load::<Student>(connection)?;
+        assert_eq!(
+            handle.get::<Student>(&Student::cache_key_for(132)).unwrap(),
+            Some(student.clone())
+        );
+
+        diesel::update(students::table)
+            .set(students::name.eq("Renamed"))
+            .filter(students::id.eq(student.id))
+            .invalidate_key(handle.clone(), &student.cache_key(), InvalidationOrder::Both)
+            .execute(connection)?;
+        assert!(!handle.exists(&student.cache_key()).unwrap());
+        Ok(())
+    });
+}
+
+#[test]
+#[cfg(feature = "inmemory")]
+fn runtime_selected_cache_backend() {
+    use turbodiesel::cacher::{CacheHandle, HashmapCache};
+    use turbodiesel::dyn_cacher::DynCacheHandle;
+    use turbodiesel::null_cacher::NullCache;
+
+    for in_memory in [true, false] {
+        let handle: DynCacheHandle = if in_memory {
+            Box::new(HashmapCache::new().handle())
+        } else {
+            Box::new(NullCache::new())
+        };
+        let connection = &mut establish_connection();
+        connection.test_transaction::<_, diesel::result::Error, _>(|connection| {
+            let student = Student {
+                id: 117,
+                name: "Student 117".to_string(),
+                dob: None,
+            };
+            diesel::insert_into(students::table)
+                .values(&student)
+                .execute(connection)?;
+
+            let key = "student:117";
+            let loaded = students::table
+                .select(Student::as_select())
+                .filter(students::id.eq(117))
+                .try_from_cache_and_populate::<Student>(handle.clone(), key)
+                .load::<Student>(connection)?;
+            assert_eq!(loaded, vec![student.clone()]);
+
+            let cached = handle.get::<Student>(&key.to_string()).unwrap();
+            assert_eq!(cached, in_memory.then_some(student));
+            Ok(())
+        });
+    }
+}
+
+#[test]
+#[cfg(feature = "inmemory")]
+fn populate_on_insert_and_upsert_with_inmemory_cache() {
+    use diesel::upsert::excluded;
+    use turbodiesel::cacher::{CacheHandle, HashmapCache};
+
+    let handle = HashmapCache::new().handle();
+    let key_fn = |student: &Student| format!("student:{}", student.id);
+    let connection = &mut establish_connection();
+    connection.test_transaction::<_, diesel::result::Error, _>(|connection| {
+        let students = [123, 124].map(|id| Student {
+            id,
+            name: format!("Student {}", id),
+            dob: None,
+        });
+        let inserted = diesel::insert_into(students::table)
+            .values(&students[..])
+            .returning(Student::as_returning())
+            .populate_on_insert(handle.clone(), key_fn)
+            .get_results::<Student>(connection)?;
+        assert_eq!(inserted, students);
+        let keys = ["student:123", "student:124"].map(String::from);
+        assert_eq!(
+            handle.get_multi::<Student>(&keys).unwrap(),
+            vec![Some(students[0].clone()), Some(students[1].clone())]
+        );
+
+        let renamed = Student {
+            name: "Renamed".to_string(),
+            ..students[0].clone()
+        };
+        let upserted = diesel::insert_into(students::table)
+            .values(&renamed)
+            .on_conflict(students::id)
+            .do_update()
+            .set(students::name.eq(excluded(students::name)))
+            .returning(Student::as_returning())
+            .populate_on_insert(handle.clone(), key_fn)
+            .get_result::<Student>(connection)?;
+        assert_eq!(upserted, renamed);
+        assert_eq!(handle.get::<Student>(&keys[0]).unwrap(), Some(renamed));
+        Ok(())
+    });
+}
+
+#[test]
+#[cfg(feature = "inmemory")]
+fn refresh_from_returning_with_inmemory_cache() {
+    use turbodiesel::cacher::{CacheHandle, HashmapCache};
+
+    let handle = HashmapCache::new().handle();
+    let key_fn = |student: &Student| format!("student:{}", student.id);
+    let connection = &mut establish_connection();
+    connection.test_transaction::<_, diesel::result::Error, _>(|connection| {
+        let students = [130, 131].map(|id| Student {
+            id,
+            name: format!("Student {}", id),
+            dob: None,
+        });
+        diesel::insert_into(students::table)
+            .values(&students[..])
+            .execute(connection)?;
+        let keys = ["student:130", "student:131"].map(String::from);
+        handle
+            .clone()
+            .put_multi(&[
+                (keys[0].clone(), students[0].clone()),
+                (keys[1].clone(), students[1].clone()),
+            ])
+            .unwrap();
+
+        let mut updated = diesel::update(students::table)
+            .set(students::name.eq("Renamed"))
+            .filter(students::id.eq_any([130, 131]))
+            .returning(Student::as_returning())
+            .refresh_from_returning(handle.clone(), key_fn)
+            .get_results::<Student>(connection)?;
+        updated.sort_by_key(|student| student.id);
+        let renamed = students.map(|student| Student {
+            name: "Renamed".to_string(),
+            ..student
+        });
+        assert_eq!(updated, renamed);
+        // The cache holds the new rows rather than missing them.
+        assert_eq!(
+            handle.get_multi::<Student>(&keys).unwrap(),
+            vec![Some(renamed[0].clone()), Some(renamed[1].clone())]
+        );
+        Ok(())
+    });
+}
+
+#[test]
+#[cfg(feature = "inmemory")]
+fn invalidate_from_returning_single_row_with_inmemory_cache() {
+    use turbodiesel::cacher::{CacheHandle, HashmapCache};
+
+    let handle = HashmapCache::new().handle();
+    let connection = &mut establish_connection();
+    connection.test_transaction::<_, diesel::result::Error, _>(|connection| {
+        let students = [135, 136].map(|id| Student {
+            id,
+            name: format!("Student {}", id),
+            dob: None,
+        });
+        diesel::insert_into(students::table)
+            .values(&students[..])
+            .execute(connection)?;
+        let keys = [135, 136].map(Student::cache_key_for);
+        handle
+            .clone()
+            .put_multi(&[
+                (keys[0].clone(), students[0].clone()),
+                (keys[1].clone(), students[1].clone()),
+            ])
+            .unwrap();
+
+        let deleted = diesel::delete(students::table)
+            .filter(students::id.eq(135))
+            .returning(students::id)
+            .invalidate_from_returning(handle.clone(), |id: &i32| Student::cache_key_for(*id))
+            .get_result::<i32>(connection)?;
+        assert_eq!(deleted, 135);
+        // Only the deleted row's key is gone.
+        assert_eq!(
+            handle.get_multi::<Student>(&keys).unwrap(),
+            vec![None, Some(students[1].clone())]
+        );
+        Ok(())
+    });
+}
+
+#[test]
+#[cfg(feature = "inmemory")]
+fn write_through_update_of_several_rows_invalidates_key() {
+    use turbodiesel::cacher::{CacheHandle, HashmapCache};
+
+    let handle = HashmapCache::new().handle();
+    let connection = &mut establish_connection();
+    connection.test_transaction::<_, diesel::result::Error, _>(|connection| {
+        let students = [143, 144].map(|id| Student {
+            id,
+            name: "Twin".to_string(),
+            dob: None,
+        });
+        diesel::insert_into(students::table)
+            .values(&students[..])
+            .execute(connection)?;
+        let key = "student:143".to_string();
+        handle.clone().put(&key, &students[0]).unwrap();
+
+        // Neither returned row is known to belong under the key.
+        let updated = diesel::update(students::table)
+            .set(students::name.eq("Twin2"))
+            .filter(students::name.eq("Twin"))
+            .returning(Student::as_returning())
+            .write_through_update::<Student>(handle.clone(), key.as_str())
+            .get_results::<Student>(connection)?;
+        assert_eq!(updated.len(), 2);
+        assert_eq!(handle.get::<Student>(&key).unwrap(), None);
+        Ok(())
+    });
+}
+
+#[test]
+#[cfg(feature = "inmemory")]
+fn invalidate_from_returning_multiple_rows_with_inmemory_cache() {
+    use turbodiesel::cache_key::Cacheable;
+    use turbodiesel::cacher::{CacheHandle, HashmapCache};
+
+    let handle = HashmapCache::new().handle();
+    let connection = &mut establish_connection();
+    connection.test_transaction::<_, diesel::result::Error, _>(|connection| {
+        let students = [137, 138, 139].map(|id| Student {
+            id,
+            name: if id == 139 { "Kept" } else { "Dropped" }.to_string(),
+            dob: None,
+        });
+        diesel::insert_into(students::table)
+            .values(&students[..])
+            .execute(connection)?;
+        let keys = [137, 138, 139].map(Student::cache_key_for);
+        handle
+            .clone()
+            .put_multi(
+                &keys
+                    .iter()
+                    .cloned()
+                    .zip(students.iter().cloned())
+                    .collect::<Vec<_>>(),
+            )
+            .unwrap();
+
+        // The filter is not on the key, so the keys come from the returned rows.
+        let mut deleted = diesel::delete(students::table)
+            .filter(students::name.eq("Dropped"))
+            .returning(Student::as_returning())
+            .invalidate_from_returning(handle.clone(), Student::cache_key)
+            .get_results::<Student>(connection)?;
+        deleted.sort_by_key(|student| student.id);
+        assert_eq!(deleted, students[..2]);
+        assert_eq!(
+            handle.get_multi::<Student>(&keys).unwrap(),
+            vec![None, None, Some(students[2].clone())]
+        );
+        Ok(())
+    });
+}
+
+#[test]
+#[cfg(feature = "inmemory")]
+fn populate_skips_null_keys_with_inmemory_cache() {
+    use diesel::dsl::sql;
+    use diesel::sql_types::{Nullable, Text};
+    use turbodiesel::cacher::{CacheHandle, HashmapCache};
+
+    let handle = HashmapCache::new().handle();
+    let connection = &mut establish_connection();
+    connection.test_transaction::<_, diesel::result::Error, _>(|connection| {
+        let students = [
+            Student {
+                id: 107,
+                name: "Dana".to_string(),
+                dob: None,
+            },
+            Student {
+                id: 108,
+                name: "Gil".to_string(),
+                dob: Some(date_from_string("2001-02-03")),
+            },
+        ];
+        diesel::insert_into(students::table)
+            .values(&students[..])
+            .execute(connection)?;
+
+        // The key is NULL for the student without a date of birth.
+        let mut loaded = students::table
+            .select((
+                Student::as_select(),
+                sql::<Nullable<Text>>("'dob:' || dob::text"),
+            ))
+            .filter(students::id.eq_any([107, 108]))
+            .populate_cache::<Student>(handle.clone())
+            .load::<Student>(connection)?;
+        loaded.sort_by_key(|student| student.id);
+        assert_eq!(loaded, students);
+
+        assert_eq!(handle.count_keys("*").unwrap(), 1);
+        assert_eq!(
+            handle.get::<Student>(&"dob:2001-02-03".to_string()).unwrap(),
+            Some(students[1].clone())
+        );
+        Ok(())
+    });
+}
+
+#[test]
+#[cfg(feature = "inmemory")]
+fn populate_with_row_ttl_with_inmemory_cache() {
+    use diesel::dsl::sql;
+    use diesel::sql_types::{BigInt, Nullable};
+    use std::time::Duration;
+    use turbodiesel::cacher::{CacheHandle, HashmapCache};
+
+    let handle = HashmapCache::new().handle();
+    let connection = &mut establish_connection();
+    connection.test_transaction::<_, diesel::result::Error, _>(|connection| {
+        let students = [
+            (140, Some("2010-05-06")),
+            (141, Some("1990-05-06")),
+            (142, None),
+        ]
+        .map(|(id, dob)| Student {
+            id,
+            name: format!("Student {}", id),
+            dob: dob.map(date_from_string),
+        });
+        diesel::insert_into(students::table)
+            .values(&students[..])
+            .execute(connection)?;
+
+        // Recent students are cached longer; those without a birth date never expire.
+        let ttl = sql::<Nullable<BigInt>>(
+            "(CASE WHEN dob IS NULL THEN NULL WHEN dob > '2000-01-01' THEN 3600 ELSE 60 END)::bigint",
+        );
+        let mut loaded = students::table
+            .select((
+                Student::as_select(),
+                cache_key_expr("student", students::id),
+                ttl,
+            ))
+            .filter(students::id.eq_any([140, 141, 142]))
+            .populate_cache_with_row_ttl::<Student>(handle.clone())
+            .load::<Student>(connection)?;
+        loaded.sort_by_key(|student| student.id);
+        assert_eq!(loaded, students);
+
+        let remaining = [140, 141, 142].map(|id| {
+            handle
+                .get_with_ttl::<Student>(&format!("student:{}", id))
+                .unwrap()
+                .unwrap()
+                .1
+        });
+        assert!(remaining[0].is_some_and(|r| r > Duration::from_secs(3500)));
+        assert!(remaining[1].is_some_and(|r| r <= Duration::from_secs(60)));
+        assert_eq!(remaining[2], None);
+        Ok(())
+    });
+}
+
+#[test]
+#[cfg(feature = "inmemory")]
+fn list_caching_with_inmemory_cache() {
+    use turbodiesel::cacher::{CacheHandle, HashmapCache};
+
+    let handle = HashmapCache::new().handle();
+    let key = "students:109-110".to_string();
+    let connection = &mut establish_connection();
+    connection.test_transaction::<_, diesel::result::Error, _>(|connection| {
+        let students = [109, 110].map(|id| Student {
+            id,
+            name: format!("Student {}", id),
+            dob: None,
+        });
+        diesel::insert_into(students::table)
+            .values(&students[..])
+            .execute(connection)?;
+
+        let query = || {
+            students::table
+                .select(Student::as_select())
+                .filter(students::id.eq_any([109, 110]))
+                .order(students::id)
+        };
+        let loaded = query()
+            .populate_cache_list::<Student>(handle.clone(), key.as_str())
+            .try_from_cache_list::<Student>(handle.clone(), key.as_str())
+            .load::<Student>(connection)?;
+        assert_eq!(loaded, students);
+        assert_eq!(
+            handle.get::<Vec<Student>>(&key).unwrap(),
+            Some(students.to_vec())
+        );
+
+        // The whole list is served from the cache once the rows are gone.
+        diesel::delete(students::table.filter(students::id.eq_any([109, 110])))
+            .execute(connection)?;
+        let cached = query()
+            .try_from_cache_list::<Student>(handle.clone(), key.as_str())
+            .load_iter::<Student, DefaultLoadingMode>(connection)?
+            .collect::<QueryResult<Vec<_>>>()?;
+        assert_eq!(cached, students);
+
+        // An empty result is cached as an empty list.
+        query()
+            .populate_cache_list::<Student>(handle.clone(), key.as_str())
+            .load::<Student>(connection)?;
+        assert_eq!(handle.get::<Vec<Student>>(&key).unwrap(), Some(vec![]));
+        Ok(())
+    });
+}
+
+#[test]
+#[cfg(feature = "inmemory")]
+fn stale_while_revalidate_with_inmemory_cache() {
+    use std::time::{Duration, Instant};
+    use turbodiesel::cacher::HashmapCache;
+
+    let handle = HashmapCache::new().handle();
+    let connection = &mut establish_connection();
+    connection.test_transaction::<_, diesel::result::Error, _>(|connection| {
+        let student = Student {
+            id: 103,
+            name: "Gil".to_string(),
+            dob: None,
+        };
+        diesel::insert_into(students::table)
+            .values(&student)
+            .execute(connection)?;
+        let mut renamed = student.clone();
+        renamed.name = "Gil2".to_string();
+        // The background refresh runs outside this transaction, so it returns
+        // the renamed row directly instead of querying for it.
+        let read = |connection: &mut PgConnection| {
+            let renamed = renamed.clone();
+            students::table
+                .select(Student::as_select())
+                .filter(students::id.eq(103))
+                .try_from_cache_stale_while_revalidate::<Student, _>(
+                    handle.clone(),
+                    "student:103",
+                    Duration::from_millis(50),
+                    Duration::from_secs(60),
+                    move || Ok(Some(renamed)),
+                )
+                .get_result::<Student>(connection)
+        };
+
+        // A miss reads and caches the row, which is then fresh.
+        assert_eq!(read(connection)?, student);
+        assert_eq!(read(connection)?, student);
+
+        // Once stale, the cached row is still served while it is refreshed.
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(read(connection)?, student);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while read(connection)? != renamed {
+            assert!(Instant::now() < deadline, "Stale row was never refreshed");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        Ok(())
+    });
+}
+
+#[test]
+#[cfg(feature = "inmemory")]
+fn stale_reads_start_one_refresh_with_inmemory_cache() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+    use turbodiesel::cacher::HashmapCache;
+
+    let handle = HashmapCache::new().handle();
+    let refreshes = Arc::new(AtomicUsize::new(0));
+    let released = Arc::new(AtomicBool::new(false));
+    let connection = &mut establish_connection();
+    connection.test_transaction::<_, diesel::result::Error, _>(|connection| {
+        let student = Student {
+            id: 104,
+            name: "Tal".to_string(),
+            dob: None,
+        };
+        diesel::insert_into(students::table)
+            .values(&student)
+            .execute(connection)?;
+        let mut renamed = student.clone();
+        renamed.name = "Tal2".to_string();
+        // Each refresh waits to be released, so that every stale read below
+        // happens while the first refresh is still in flight.
+        let read = |connection: &mut PgConnection| {
+            let (renamed, refreshes, released) =
+                (renamed.clone(), refreshes.clone(), released.clone());
+            students::table
+                .select(Student::as_select())
+                .filter(students::id.eq(104))
+                .try_from_cache_stale_while_revalidate::<Student, _>(
+                    handle.clone(),
+                    "student:104",
+                    Duration::from_millis(50),
+                    Duration::from_secs(60),
+                    move || {
+                        refreshes.fetch_add(1, Ordering::SeqCst);
+                        while !released.load(Ordering::SeqCst) {
+                            std::thread::sleep(Duration::from_millis(5));
+                        }
+                        Ok(Some(renamed))
+                    },
+                )
+                .get_result::<Student>(connection)
+        };
+
+        assert_eq!(read(connection)?, student);
+        std::thread::sleep(Duration::from_millis(60));
+        for _ in 0..5 {
+            assert_eq!(read(connection)?, student);
+        }
+        released.store(true, Ordering::SeqCst);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while read(connection)? != renamed {
+            assert!(Instant::now() < deadline, "Stale row was never refreshed");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(refreshes.load(Ordering::SeqCst), 1);
+        Ok(())
+    });
+}
+
+#[test]
+#[cfg(feature = "inmemory")]
+fn serve_stale_on_error_with_inmemory_cache() {
+    use std::time::Duration;
+    use turbodiesel::cacher::HashmapCache;
+
+    let handle = HashmapCache::new().handle();
+    let (ttl, grace) = (Duration::from_millis(50), Duration::from_secs(60));
+    let connection = &mut establish_connection();
+    connection.test_transaction::<_, diesel::result::Error, _>(|connection| {
+        let student = Student {
+            id: 133,
+            name: "Student 133".to_string(),
+            dob: None,
+        };
+        diesel::insert_into(students::table)
+            .values(&student)
+            .execute(connection)?;
+        let loaded = students::table
+            .select(Student::as_select())
+            .filter(students::id.eq(133))
+            .try_from_cache_serve_stale_on_error::<Student>(handle.clone(), "student:133", ttl, grace)
+            .get_result::<Student>(connection)?;
+        assert_eq!(loaded, student);
+        std::thread::sleep(Duration::from_millis(60));
+
+        // The entry has expired, so the database is read, and the query
+        // fails; the expired row is served instead.
+        let failing = |key: &str, connection: &mut PgConnection| {
+            diesel::sql_query("SELECT id, name, dob FROM no_such_table")
+                .try_from_cache_serve_stale_on_error::<Student>(handle.clone(), key, ttl, grace)
+                .get_result::<Student>(connection)
+        };
+        assert_eq!(failing("student:133", connection)?, student);
+        // Without a cached row, the error is returned.
+        assert!(failing("student:134", connection).is_err());
+        Ok(())
+    });
+}
+
+#[test]
+#[cfg(feature = "inmemory")]
+fn deferred_invalidation_with_inmemory_cache() {
+    use std::sync::Arc;
+    use turbodiesel::cacher::{CacheHandle, HashmapCache};
+    use turbodiesel::deferred_invalidation::DeferredInvalidations;
+
+    let mut handle = HashmapCache::new().handle();
+    let key = "student:-1".to_string();
+    handle.put(&key, &"cached".to_string()).unwrap();
+    let invalidations = Arc::new(DeferredInvalidations::new(handle.clone()));
+    let connection = &mut establish_connection();
+    // Matches no rows, so committing leaves the table unchanged.
+    let update = || {
+        diesel::update(students::table)
+            .set(students::name.eq("Nobody"))
+            .filter(students::id.eq(-1))
+    };
+
+    // A rolled-back transaction leaves the cache untouched.
+    let res = invalidations.transaction(connection, |connection| {
+        update()
+            .defer_invalidate_key(invalidations.clone(), &key)
+            .execute(connection)?;
+        assert_eq!(invalidations.pending(), vec![key.clone()]);
+        Err::<(), _>(diesel::result::Error::RollbackTransaction)
+    });
+    assert_eq!(res, Err(diesel::result::Error::RollbackTransaction));
+    assert!(invalidations.pending().is_empty());
+    assert!(handle.exists(&key).unwrap());
+
+    // The key is deleted only once the transaction has committed.
+    invalidations
+        .transaction(connection, |connection| {
+            update()
+                .defer_invalidate_key(invalidations.clone(), &key)
+                .execute(connection)?;
+            assert!(handle.exists(&key).unwrap());
+            Ok::<_, diesel::result::Error>(())
+        })
+        .expect("Error running transaction");
+    assert!(!handle.exists(&key).unwrap());
+}
+
+#[test]
+#[cfg(feature = "inmemory")]
+fn pattern_invalidation_with_inmemory_cache() {
+    use turbodiesel::cacher::{CacheHandle, HashmapCache};
+
+    let mut handle = HashmapCache::new().handle();
+    for key in ["student:125", "student:126", "course:125"] {
+        handle.put(&key.to_string(), &key.to_string()).unwrap();
+    }
+    let connection = &mut establish_connection();
+    connection.test_transaction::<_, diesel::result::Error, _>(|connection| {
+        let rows = diesel::update(students::table)
+            .set(students::name.eq("Nobody"))
+            .filter(students::id.eq(-1))
+            .invalidate_pattern(handle.clone(), "student:*")
+            .execute(connection)?;
+        assert_eq!(rows, 0);
+        assert!(!handle.exists(&"student:125".to_string()).unwrap());
+        assert!(!handle.exists(&"student:126".to_string()).unwrap());
+        assert!(handle.exists(&"course:125".to_string()).unwrap());
+        Ok(())
+    });
+}
+
+#[test]
+#[cfg(feature = "inmemory")]
+fn notify_driven_invalidation_with_inmemory_cache() {
+    use crate::pgutils::database_url;
+    use std::time::Duration;
+    use turbodiesel::cacher::{CacheHandle, HashmapCache};
+    use turbodiesel::pg_invalidation::PgInvalidationListener;
+
+    let mut handle = HashmapCache::new().handle();
+    for key in ["student:123", "student:124"] {
+        handle.put(&key.to_string(), &key.to_string()).unwrap();
+    }
+    let mut listener =
+        PgInvalidationListener::connect(&database_url(), "cache_invalidate_test", handle.clone())
+            .unwrap();
+
+    // Notifications are only delivered once their transaction commits, so
+    // this cannot run inside `test_transaction`.
+    let connection = &mut establish_connection();
+    diesel::sql_query("SELECT pg_notify('cache_invalidate_test', 'student:123')")
+        .execute(connection)
+        .unwrap();
+    diesel::sql_query("SELECT pg_notify('other_channel', 'student:124')")
+        .execute(connection)
+        .unwrap();
+
+    assert_eq!(listener.poll(Duration::from_secs(2)).unwrap(), 1);
+    assert!(!handle.exists(&"student:123".to_string()).unwrap());
+    assert!(handle.exists(&"student:124".to_string()).unwrap());
+}
+
+#[test]
+#[cfg(feature = "redis")]
+fn best_effort_invalidation_with_unreachable_redis() {
+    use turbodiesel::redis_cacher::RedisCache;
+
+    // Nothing listens on port 1, so every invalidation fails.
+    let handle = RedisCache::new("redis://127.0.0.1:1/")
+        .expect("Failed to create RedisCache")
+        .handle();
+    let connection = &mut establish_connection();
+    connection.test_transaction::<_, diesel::result::Error, _>(|connection| {
+        diesel::insert_into(students::table)
+            .values(&Student {
+                id: 114,
+                name: "Noa".to_string(),
+                dob: None,
+            })
+            .execute(connection)?;
+        let rename = |name: &'static str| {
+            diesel::update(students::table)
+                .set(students::name.eq(name))
+                .filter(students::id.eq(114))
+        };
+        let name = |connection: &mut PgConnection| {
+            students::table
+                .find(114)
+                .select(students::name)
+                .first::<String>(connection)
+        };
+
+        let res = rename("Strict")
+            .invalidate_key(handle.clone(), "student:114", InvalidationOrder::Before)
+            .execute(connection);
+        assert_eq!(res, Err(diesel::result::Error::RollbackTransaction));
+        assert_eq!(name(connection)?, "Noa");
+
+        let rows = rename("Best effort")
+            .invalidate_key_best_effort(handle.clone(), "student:114")
+            .execute(connection)?;
+        assert_eq!(rows, 1);
+        assert_eq!(name(connection)?, "Best effort");
+
+        set_best_effort(true);
+        let res = rename("Global")
+            .invalidate_keys(
+                handle.clone(),
+                vec!["student:114".to_string()].into_iter(),
+                InvalidationOrder::Before,
+            )
+            .execute(connection);
+        // A batch that cannot be cached does not fail the query either.
+        let batched = students::table
+            .select((Student::as_select(), cache_key_expr("student", students::id)))
+            .filter(students::id.eq(114))
+            .populate_cache_batched::<Student>(handle.clone(), 10)
+            .load::<Student>(connection);
+        set_best_effort(false);
+        assert_eq!(res, Ok(1));
+        assert_eq!(name(connection)?, "Global");
+        assert_eq!(batched.map(|rows| rows.len()), Ok(1));
+        Ok(())
+    });
+}
+
 #[tokio::test]
 #[cfg(feature = "redis")]
 async fn system_test_with_postgres_and_redis() {
@@ -154,7 +1399,7 @@ async fn inner_system_test_with_postgres_and_redis(postgres_url: String, redis_u
         .collect();
     assert_eq!(query_result, test_students);
 
-    let records_in_cache = handle.scan_keys("student:*").unwrap().capacity();
+    let records_in_cache = handle.count_keys("student:*").unwrap();
     assert_eq!(records_in_cache, 0);
 
     // Populate the cache with all students.
@@ -167,7 +1412,7 @@ async fn inner_system_test_with_postgres_and_redis(postgres_url: String, redis_u
         .map(|s| s.unwrap())
         .collect();
     assert_eq!(query_result, test_students);
-    let records_in_cache = handle.scan_keys("student:*").unwrap().capacity();
+    let records_in_cache = handle.count_keys("student:*").unwrap();
     assert_eq!(records_in_cache, 3);
 
     let mut cached_student: Option<Student> = cache.handle().get(&"student:2".to_string()).unwrap();
@@ -177,7 +1422,7 @@ async fn inner_system_test_with_postgres_and_redis(postgres_url: String, redis_u
     diesel::update(students::table)
         .set(students::dsl::name.eq("Ori2"))
         .filter(students::dsl::id.eq(2))
-        .invalidate_key(handle.clone(), "student:2")
+        .invalidate_key(handle.clone(), "student:2", InvalidationOrder::Before)
         .execute(connection)
         .expect("Error updating student");
 
@@ -221,7 +1466,7 @@ async fn inner_system_test_with_postgres_and_redis(postgres_url: String, redis_u
     // Select with trying the cache with two keys student 1 and 3.
     // Student 3 will result in the stale cached record.
     query_result = students::dsl::students
-        .select(Student::as_select())
+        .select(row_with_cache_key.clone())
         .filter(students::dsl::id.eq(3))
         .try_from_cache_multi::<Student, _>(
             handle.clone(),
@@ -304,7 +1549,7 @@ async fn inner_system_test_with_postgres_and_redis(postgres_url: String, redis_u
     diesel::update(students::table)
         .set(students::dsl::name.eq("Ori3"))
         .filter(students::dsl::id.eq(2))
-        .invalidate_key(handle.clone(), "student:2")
+        .invalidate_key(handle.clone(), "student:2", InvalidationOrder::Before)
         .execute(connection)
         .expect("Error updating student");
     // Verify that student 2 was not yet populated in cache.
@@ -394,6 +1639,24 @@ async fn inner_system_test_with_postgres_and_redis(postgres_url: String, redis_u
             dob: Some(date_from_string("1978-02-14")),
         }]
     );
+
+    // Update student 2 and write the returned row through to the cache.
+    let updated_student = diesel::update(students::table)
+        .set(students::dsl::name.eq("Ori5"))
+        .filter(students::dsl::id.eq(2))
+        .returning(Student::as_returning())
+        .write_through_update::<Student>(handle.clone(), "student:2")
+        .get_result::<Student>(connection)
+        .expect("Error updating student");
+    let expected_student = Student {
+        id: 2,
+        name: "Ori5".to_string(),
+        dob: Some(date_from_string("1978-02-14")),
+    };
+    assert_eq!(updated_student, expected_student);
+    // Verify that the cache holds the fresh value rather than being invalidated.
+    cached_student = cache.handle().get(&"student:2".to_string()).unwrap();
+    assert_eq!(cached_student, Some(expected_student));
 }
 
 #[test]
@@ -0,0 +1,148 @@
+//! Derive macros for `turbodiesel`. Use them through the re-exports in
+//! `turbodiesel::cache_key` rather than depending on this crate directly.
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{Data, DeriveInput, Fields, Ident, LitStr, Type, parse_macro_input};
+
+/// Implements `turbodiesel::cache_key::Cacheable` for a struct, building its
+/// key from a prefix and one or more of its fields, and adds an associated
+/// `cache_key_for` function building the same key from the field values,
+/// for lookups before the row has been loaded:
+///
+/// ```ignore
+/// #[derive(Cacheable)]
+/// #[cache(prefix = "student", key = "id")]
+/// pub struct Student {
+///     pub id: i32,
+///     pub name: String,
+/// }
+///
+/// assert_eq!(student.cache_key(), "student:2");
+/// assert_eq!(Student::cache_key_for(2), "student:2");
+///
+/// #[derive(Cacheable)]
+/// #[cache(prefix = "enrollment", key(tenant_id, id), separator = "/")]
+/// pub struct Enrollment {
+///     pub tenant_id: i32,
+///     pub id: i64,
+/// }
+///
+/// assert_eq!(Enrollment::cache_key_for(7, 2), "enrollment/7/2");
+/// ```
+///
+/// `cache_key_for` takes the key fields by value, in the order listed in
+/// `key`. The separator defaults to `:`, matching `KeyBuilder` and
+/// `cache_key_expr`.
+#[proc_macro_derive(Cacheable, attributes(cache))]
+pub fn derive_cacheable(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_cacheable(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand_cacheable(input: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let mut prefix = None;
+    let mut separator = None;
+    let mut key = Vec::new();
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("cache"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("prefix") {
+                prefix = Some(meta.value()?.parse::<LitStr>()?);
+                Ok(())
+            } else if meta.path.is_ident("separator") {
+                separator = Some(meta.value()?.parse::<LitStr>()?);
+                Ok(())
+            } else if meta.path.is_ident("key") {
+                if meta.input.peek(syn::Token![=]) {
+                    // `key = "id"`
+                    let field = meta.value()?.parse::<LitStr>()?;
+                    key.push(field.parse::<Ident>()?);
+                } else {
+                    // `key(tenant_id, id)`
+                    meta.parse_nested_meta(|field| {
+                        key.push(field.path.require_ident()?.clone());
+                        Ok(())
+                    })?;
+                }
+                Ok(())
+            } else {
+                Err(meta.error("expected `prefix`, `key` or `separator`"))
+            }
+        })?;
+    }
+    let prefix = prefix.ok_or_else(|| {
+        syn::Error::new_spanned(
+            &input.ident,
+            "missing `#[cache(prefix = \"...\")]` attribute",
+        )
+    })?;
+    if key.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            "missing `#[cache(key = \"...\")]` or `#[cache(key(...))]` attribute",
+        ));
+    }
+    let separator = separator.map_or_else(|| ":".to_string(), |separator| separator.value());
+
+    let Data::Struct(data) = &input.data else {
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            "Cacheable can only be derived for structs",
+        ));
+    };
+    let Fields::Named(fields) = &data.fields else {
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            "Cacheable can only be derived for structs with named fields",
+        ));
+    };
+    let types = key
+        .iter()
+        .map(|field| {
+            fields
+                .named
+                .iter()
+                .find(|f| f.ident.as_ref() == Some(field))
+                .map(|f| &f.ty)
+                .ok_or_else(|| {
+                    syn::Error::new_spanned(
+                        field,
+                        format!("no field `{}` on `{}`", field, input.ident),
+                    )
+                })
+        })
+        .collect::<syn::Result<Vec<&Type>>>()?;
+
+    // `{}` for the prefix and each key field, joined by the separator, with
+    // braces in the separator escaped.
+    let format = vec!["{}"; key.len() + 1].join(&separator.replace('{', "{{").replace('}', "}}"));
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::turbodiesel::cache_key::Cacheable for #name #ty_generics #where_clause {
+            const CACHE_PREFIX: &'static str = #prefix;
+
+            fn cache_key(&self) -> ::std::string::String {
+                ::std::format!(#format, Self::CACHE_PREFIX, #(self.#key),*)
+            }
+        }
+
+        impl #impl_generics #name #ty_generics #where_clause {
+            /// Builds the key `cache_key` returns for a row with these key
+            /// field values.
+            pub fn cache_key_for(#(#key: #types),*) -> ::std::string::String {
+                ::std::format!(
+                    #format,
+                    <Self as ::turbodiesel::cache_key::Cacheable>::CACHE_PREFIX,
+                    #(#key),*
+                )
+            }
+        }
+    })
+}